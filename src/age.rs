@@ -0,0 +1,902 @@
+//! Encryption of streams in the [age](https://age-encryption.org/v1) v1 format.
+//!
+//! Streams encrypted by [`Encrypt`](struct.Encrypt.html) can be decrypted using the
+//! `age` command line tool, and vice versa. The file key is wrapped for every
+//! recipient in a textual header, followed by a MAC over the header. The payload is
+//! encrypted with ChaCha20-Poly1305 in chunks of 64 KiB, the last of which is marked
+//! as such, so that truncation of the stream is detected.
+//!
+//! Recipients are either X25519 public keys, in the `age1...` encoding produced by
+//! `age-keygen`, or passphrases that are stretched using scrypt. A passphrase cannot
+//! be combined with other recipients.
+//!
+//! Unlike [`etm::Decrypt`](../cipher/etm/struct.Decrypt.html), decrypted chunks are
+//! released as soon as they have been authenticated, and a stream that has been
+//! truncated fails at its end.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::mem;
+use std::str;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, Future, Poll, Stream};
+use futures::future::{Join, JoinAll, join_all};
+use openssl;
+use openssl::symm::Cipher;
+
+use super::{Error, ErrorKind};
+use super::agreement::{self, GenerateKey, PrivateKey, PublicKey};
+use super::base64;
+use super::envelope::Rest;
+use super::hash::{self, Hmac, hkdf};
+use super::random::{Generator, RandomBytes};
+use super::secret::{Redacted, zeroize};
+
+/// Recipient that a stream is encrypted to.
+#[derive(Clone)]
+pub struct Recipient(RecipientKind);
+
+#[derive(Clone)]
+enum RecipientKind {
+    X25519(PublicKey),
+    Scrypt(Vec<u8>, u8)
+}
+
+impl Recipient {
+    /// Create a recipient from an X25519 public key.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the key is not an X25519 key.
+    pub fn x25519(key: &PublicKey) -> Result<Recipient, Error> {
+        if key.algorithm() != agreement::Algorithm::X25519 {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        Ok(Recipient(RecipientKind::X25519(key.clone())))
+    }
+
+    /// Parse a recipient in the `age1...` encoding.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the recipient cannot be parsed.
+    pub fn parse(recipient: &str) -> Result<Recipient, Error> {
+        let key = bech32::decode(recipient)
+            .and_then(|(hrp, key)| if hrp == RECIPIENT_HRP { Some(key) } else { None })
+            .ok_or(ErrorKind::Malformed)?;
+        Recipient::x25519(&PublicKey::from_bytes(agreement::Algorithm::X25519, &key)?)
+    }
+
+    /// Create a recipient from a passphrase, using the default scrypt work factor of 18.
+    pub fn scrypt(passphrase: &[u8]) -> Recipient {
+        Recipient(RecipientKind::Scrypt(passphrase.to_vec(), DEFAULT_WORK_FACTOR))
+    }
+
+    /// Create a recipient from a passphrase, using the given scrypt work factor,
+    /// which is the base-2 logarithm of the scrypt parameter N.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if `work_factor` is zero or exceeds
+    /// the limit of 22 that is accepted when decrypting.
+    pub fn scrypt_with_work_factor(passphrase: &[u8], work_factor: u8) -> Result<Recipient, Error> {
+        if work_factor == 0 || work_factor > MAX_WORK_FACTOR {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        Ok(Recipient(RecipientKind::Scrypt(passphrase.to_vec(), work_factor)))
+    }
+}
+
+impl Debug for Recipient {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self.0 {
+            RecipientKind::X25519(ref key) => f.debug_tuple("X25519").field(key).finish(),
+            RecipientKind::Scrypt(_, work_factor) => f.debug_struct("Scrypt")
+                .field("passphrase", &Redacted)
+                .field("work_factor", &work_factor)
+                .finish()
+        }
+    }
+}
+
+impl Drop for RecipientKind {
+    fn drop(&mut self) {
+        if let RecipientKind::Scrypt(ref mut passphrase, _) = *self {
+            zeroize(passphrase);
+        }
+    }
+}
+
+/// Credential used to decrypt a stream.
+#[derive(Clone)]
+pub struct Identity(IdentityKind);
+
+#[derive(Clone)]
+enum IdentityKind {
+    X25519(PrivateKey),
+    Scrypt(Vec<u8>)
+}
+
+impl Identity {
+    /// Create an identity from an X25519 private key.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the key is not an X25519 key.
+    pub fn x25519(key: &PrivateKey) -> Result<Identity, Error> {
+        if key.algorithm() != agreement::Algorithm::X25519 {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        Ok(Identity(IdentityKind::X25519(key.clone())))
+    }
+
+    /// Parse an identity in the `AGE-SECRET-KEY-1...` encoding.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the identity cannot be parsed.
+    pub fn parse(identity: &str) -> Result<Identity, Error> {
+        let mut key = bech32::decode(identity)
+            .and_then(|(hrp, key)| if hrp == IDENTITY_HRP { Some(key) } else { None })
+            .ok_or(ErrorKind::Malformed)?;
+        let result = PrivateKey::from_x25519_bytes(&key);
+        zeroize(&mut key);
+        Identity::x25519(&result?)
+    }
+
+    /// Create an identity from a passphrase.
+    pub fn scrypt(passphrase: &[u8]) -> Identity {
+        Identity(IdentityKind::Scrypt(passphrase.to_vec()))
+    }
+
+    /// Try to unwrap the file key from a stanza.
+    ///
+    /// Returns `None` if the stanza is meant for a different kind of identity,
+    /// or if the identity does not match.
+    fn unwrap(&self, stanza: &Stanza) -> Result<Option<FileKey>, Error> {
+        let args = &stanza.args;
+        let mut key = match self.0 {
+            IdentityKind::X25519(ref key) if args[0] == X25519_STANZA => {
+                if args.len() != 2 || stanza.body.len() != WRAPPED_KEY_LEN {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                let ephemeral = decode_arg(&args[1], X25519_KEY_LEN)?;
+                let peer = PublicKey::from_bytes(agreement::Algorithm::X25519, &ephemeral)?;
+                x25519_key(key, &peer, &ephemeral, &key.public_key()?.to_bytes()?)?
+            },
+            IdentityKind::Scrypt(ref passphrase) if args[0] == SCRYPT_STANZA => {
+                if args.len() != 3 || stanza.body.len() != WRAPPED_KEY_LEN {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                let salt = decode_arg(&args[1], SALT_LEN)?;
+                scrypt_key(passphrase, &salt, parse_work_factor(&args[2])?)?
+            },
+            _ => return Ok(None)
+        };
+        let mut file_key = [0u8; FILE_KEY_LEN];
+        let result = chacha20_poly1305(Mode::Open, &key, &[0; NONCE_LEN], &stanza.body);
+        zeroize(&mut key);
+        match result {
+            Ok(mut plaintext) => {
+                file_key.copy_from_slice(&plaintext);
+                zeroize(&mut plaintext);
+                Ok(Some(FileKey(file_key)))
+            },
+            Err(ref err) if err.kind() == ErrorKind::AuthenticationFailed => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self.0 {
+            IdentityKind::X25519(ref key) => f.debug_tuple("X25519").field(key).finish(),
+            IdentityKind::Scrypt(_) => f.debug_tuple("Scrypt").field(&Redacted).finish()
+        }
+    }
+}
+
+impl Drop for IdentityKind {
+    fn drop(&mut self) {
+        if let IdentityKind::Scrypt(ref mut passphrase) = *self {
+            zeroize(passphrase);
+        }
+    }
+}
+
+/// File key that is wiped from memory when dropped.
+struct FileKey([u8; FILE_KEY_LEN]);
+
+impl Drop for FileKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+/// Stream adapter that encrypts the data from the underlying stream to one or more recipients.
+///
+/// The first item yielded is the header, followed by the encrypted chunks.
+pub struct Encrypt<S> {
+    recipients: Vec<Recipient>,
+    state: EncryptState<S>
+}
+
+enum EncryptState<S> {
+    Generating(Join<RandomBytes, JoinAll<Vec<GenerateKey>>>, S),
+    Streaming(Payload<S>),
+    Done
+}
+
+impl<S: Debug> Debug for Encrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Encrypt")
+            .field("recipients", &self.recipients)
+            .finish()
+    }
+}
+
+impl<S: Stream> Encrypt<S> {
+    /// Create an encrypting stream adapter.
+    ///
+    /// The file key, as well as the salts and ephemeral keys needed for
+    /// the recipients, are generated using `generator`.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if there are no recipients, or if a
+    /// passphrase recipient is combined with other recipients.
+    pub fn new(generator: &Generator, recipients: Vec<Recipient>, inner: S) -> Result<Self, Error> {
+        let salts = recipients.iter().filter(|r| matches!(r.0, RecipientKind::Scrypt(..))).count();
+        if recipients.is_empty() || (salts > 0 && recipients.len() > 1) {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let ephemeral_keys = recipients.iter().filter_map(|r| match r.0 {
+            RecipientKind::X25519(_) => Some(PrivateKey::generate(agreement::Algorithm::X25519, generator)),
+            _ => None
+        }).collect::<Vec<_>>();
+        let random_bytes = generator.random_bytes(FILE_KEY_LEN + PAYLOAD_NONCE_LEN + salts * SALT_LEN);
+        let generating = random_bytes.join(join_all(ephemeral_keys));
+        Ok(Encrypt { recipients, state: EncryptState::Generating(generating, inner) })
+    }
+}
+
+impl<S: Stream> Stream for Encrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match mem::replace(&mut self.state, EncryptState::Done) {
+            EncryptState::Generating(mut generating, inner) => {
+                let (random, ephemeral_keys) = match generating.poll()? {
+                    Async::NotReady => {
+                        self.state = EncryptState::Generating(generating, inner);
+                        return Ok(Async::NotReady);
+                    },
+                    Async::Ready(generated) => generated
+                };
+                let result = {
+                    let (file_key, rest) = random.split_at(FILE_KEY_LEN);
+                    let (nonce, salts) = rest.split_at(PAYLOAD_NONCE_LEN);
+                    encode_header(&self.recipients, file_key, salts, &ephemeral_keys).and_then(|mut header| {
+                        header.extend_from_slice(nonce);
+                        Ok((header.freeze(), Payload::new(Mode::Seal, file_key, nonce, inner)?))
+                    })
+                };
+                if let Ok(mut random) = random.try_mut() {
+                    zeroize(&mut random);
+                }
+                let (header, payload) = result?;
+                self.state = EncryptState::Streaming(payload);
+                Ok(Async::Ready(Some(header)))
+            },
+            EncryptState::Streaming(mut payload) => {
+                let result = payload.poll();
+                self.state = EncryptState::Streaming(payload);
+                result
+            },
+            EncryptState::Done => Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// Stream adapter that decrypts data in the age format.
+///
+/// The identities are tried against every stanza of the header, until one
+/// of them is able to unwrap the file key. The stream fails with
+/// `ErrorKind::Malformed` if the header is longer than 64 KiB.
+pub struct Decrypt<S> {
+    identities: Vec<Identity>,
+    state: DecryptState<S>
+}
+
+enum DecryptState<S> {
+    Header(S, BytesMut),
+    Streaming(Payload<Rest<S>>),
+    Done
+}
+
+impl<S: Debug> Debug for Decrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Decrypt")
+            .field("identities", &self.identities)
+            .finish()
+    }
+}
+
+impl<S: Stream> Decrypt<S> {
+    /// Create a decrypting stream adapter, given the identities that may be
+    /// able to unwrap the file key.
+    pub fn new(identities: Vec<Identity>, inner: S) -> Self {
+        Decrypt { identities, state: DecryptState::Header(inner, BytesMut::new()) }
+    }
+}
+
+impl<S: Stream> Stream for Decrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, DecryptState::Done) {
+                DecryptState::Header(mut inner, mut buffer) => {
+                    match header_len(&buffer) {
+                        Some(len) if len > MAX_HEADER_LEN => return Err(Error::from(ErrorKind::Malformed).into()),
+                        Some(len) if buffer.len() >= len + PAYLOAD_NONCE_LEN => {
+                            let header = decode_header(&buffer[..len])?;
+                            let file_key = self.unwrap_file_key(&header)?;
+                            verify_header_mac(&file_key.0, &buffer[..header.mac_offset], &header.mac)?;
+                            let payload = buffer.split_off(len + PAYLOAD_NONCE_LEN).freeze();
+                            let nonce = &buffer[len..];
+                            let rest = Rest::new(payload, inner);
+                            self.state = DecryptState::Streaming(Payload::new(Mode::Open, &file_key.0, nonce, rest)?);
+                            continue;
+                        },
+                        Some(_) => (),
+                        None if buffer.len() > MAX_HEADER_LEN => return Err(Error::from(ErrorKind::Malformed).into()),
+                        None => ()
+                    }
+                    match inner.poll()? {
+                        Async::NotReady => {
+                            self.state = DecryptState::Header(inner, buffer);
+                            return Ok(Async::NotReady);
+                        },
+                        Async::Ready(None) => return Err(Error::from(ErrorKind::Malformed).into()),
+                        Async::Ready(Some(item)) => {
+                            buffer.extend_from_slice(item.as_ref());
+                            self.state = DecryptState::Header(inner, buffer);
+                        }
+                    }
+                },
+                DecryptState::Streaming(mut payload) => {
+                    let result = payload.poll();
+                    self.state = DecryptState::Streaming(payload);
+                    return result;
+                },
+                DecryptState::Done => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+impl<S> Decrypt<S> {
+    fn unwrap_file_key(&self, header: &Header) -> Result<FileKey, Error> {
+        // A passphrase must be the only recipient.
+        if header.stanzas.len() > 1 && header.stanzas.iter().any(|stanza| stanza.args[0] == SCRYPT_STANZA) {
+            return Err(ErrorKind::Malformed.into());
+        }
+        for stanza in &header.stanzas {
+            for identity in &self.identities {
+                if let Some(file_key) = identity.unwrap(stanza)? {
+                    return Ok(file_key);
+                }
+            }
+        }
+        Err(ErrorKind::UnknownKey.into())
+    }
+}
+
+/// Encryption or decryption of the payload in chunks, depending on the mode.
+struct Payload<S> {
+    inner: S,
+    mode: Mode,
+    key: [u8; PAYLOAD_KEY_LEN],
+    counter: u64,
+    buffer: BytesMut,
+    finished: bool
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Seal,
+    Open
+}
+
+impl<S> Payload<S> {
+    fn new(mode: Mode, file_key: &[u8], nonce: &[u8], inner: S) -> Result<Self, Error> {
+        let mut key = [0u8; PAYLOAD_KEY_LEN];
+        hkdf(hash::Algorithm::Sha256, nonce, file_key, PAYLOAD_INFO, &mut key)?;
+        Ok(Payload { inner, mode, key, counter: 0, buffer: BytesMut::new(), finished: false })
+    }
+
+    fn chunk_len(&self) -> usize {
+        match self.mode {
+            Mode::Seal => CHUNK_LEN,
+            Mode::Open => CHUNK_LEN + TAG_LEN
+        }
+    }
+
+    fn process(&mut self, len: usize, last: bool) -> Result<Bytes, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        for (i, byte) in nonce[3..11].iter_mut().enumerate() {
+            *byte = (self.counter >> (56 - 8 * i)) as u8;
+        }
+        nonce[11] = last as u8;
+        let chunk = self.buffer.split_to(len);
+        let output = chacha20_poly1305(self.mode, &self.key, &nonce, &chunk)?;
+        self.counter += 1;
+        Ok(Bytes::from(output))
+    }
+}
+
+impl<S: Stream> Stream for Payload<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            // A full chunk is only processed once more data follows it,
+            // since the last chunk has to be marked as such.
+            let chunk_len = self.chunk_len();
+            if self.buffer.len() > chunk_len {
+                return Ok(Async::Ready(Some(self.process(chunk_len, false)?)));
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => self.buffer.extend_from_slice(item.as_ref()),
+                Async::Ready(None) => {
+                    self.finished = true;
+                    let len = self.buffer.len();
+                    if self.mode == Mode::Open && (len < TAG_LEN || len == TAG_LEN && self.counter > 0) {
+                        return Err(Error::from(ErrorKind::Malformed).into());
+                    }
+                    return Ok(Async::Ready(Some(self.process(len, true)?)));
+                }
+            }
+        }
+    }
+}
+
+impl<S> Drop for Payload<S> {
+    fn drop(&mut self) {
+        zeroize(&mut self.key);
+    }
+}
+
+/// Seal or open a message using ChaCha20-Poly1305, with the tag following the ciphertext.
+fn chacha20_poly1305(mode: Mode, key: &[u8], nonce: &[u8], input: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = Cipher::chacha20_poly1305();
+    match mode {
+        Mode::Seal => {
+            let mut tag = [0u8; TAG_LEN];
+            let mut output = openssl::symm::encrypt_aead(cipher, key, Some(nonce), &[], input, &mut tag)
+                .map_err(Error::from)?;
+            output.extend_from_slice(&tag);
+            Ok(output)
+        },
+        Mode::Open => {
+            if input.len() < TAG_LEN {
+                return Err(ErrorKind::AuthenticationFailed.into());
+            }
+            let (ciphertext, tag) = input.split_at(input.len() - TAG_LEN);
+            openssl::symm::decrypt_aead(cipher, key, Some(nonce), &[], ciphertext, tag)
+                .map_err(|_| ErrorKind::AuthenticationFailed.into())
+        }
+    }
+}
+
+/// Derive the wrapping key for an X25519 stanza.
+fn x25519_key(key: &PrivateKey, peer: &PublicKey, ephemeral_public: &[u8],
+              recipient_public: &[u8]) -> Result<[u8; WRAPPING_KEY_LEN], Error> {
+    let mut salt = Vec::with_capacity(2 * X25519_KEY_LEN);
+    salt.extend_from_slice(ephemeral_public);
+    salt.extend_from_slice(recipient_public);
+    let mut wrapping_key = [0u8; WRAPPING_KEY_LEN];
+    key.derive(peer, hash::Algorithm::Sha256, &salt, X25519_INFO, &mut wrapping_key)?;
+    Ok(wrapping_key)
+}
+
+/// Derive the wrapping key for a scrypt stanza.
+fn scrypt_key(passphrase: &[u8], salt: &[u8], work_factor: u8) -> Result<[u8; WRAPPING_KEY_LEN], Error> {
+    let mut label = SCRYPT_LABEL.to_vec();
+    label.extend_from_slice(salt);
+    let n = 1u64 << work_factor;
+    let max_mem = SCRYPT_R * 128 * (n + SCRYPT_P + 2);
+    let mut wrapping_key = [0u8; WRAPPING_KEY_LEN];
+    openssl::pkcs5::scrypt(passphrase, &label, n, SCRYPT_R, SCRYPT_P, max_mem, &mut wrapping_key)
+        .map_err(Error::from)?;
+    Ok(wrapping_key)
+}
+
+fn header_mac(file_key: &[u8], header: &[u8]) -> Result<hash::Digest, Error> {
+    let mut mac_key = [0u8; MAC_KEY_LEN];
+    hkdf(hash::Algorithm::Sha256, &[], file_key, HEADER_INFO, &mut mac_key)?;
+    let hmac = Hmac::new(hash::Algorithm::Sha256, &mac_key);
+    zeroize(&mut mac_key);
+    let mut hmac = hmac?;
+    hmac.update(header)?;
+    hmac.finish()
+}
+
+fn verify_header_mac(file_key: &[u8], header: &[u8], mac: &[u8]) -> Result<(), Error> {
+    if header_mac(file_key, header)?.verify(mac) {
+        Ok(())
+    } else {
+        Err(ErrorKind::AuthenticationFailed.into())
+    }
+}
+
+const VERSION_LINE: &str = "age-encryption.org/v1";
+const STANZA_PREFIX: &str = "-> ";
+const MAC_MARK: &str = "---";
+const MAC_PREFIX: &str = "--- ";
+const X25519_STANZA: &str = "X25519";
+const SCRYPT_STANZA: &str = "scrypt";
+const RECIPIENT_HRP: &str = "age";
+const IDENTITY_HRP: &str = "age-secret-key-";
+
+const X25519_INFO: &[u8] = b"age-encryption.org/v1/X25519";
+const SCRYPT_LABEL: &[u8] = b"age-encryption.org/v1/scrypt";
+const HEADER_INFO: &[u8] = b"header";
+const PAYLOAD_INFO: &[u8] = b"payload";
+
+const FILE_KEY_LEN: usize = 16;
+const WRAPPING_KEY_LEN: usize = 32;
+const WRAPPED_KEY_LEN: usize = FILE_KEY_LEN + TAG_LEN;
+const X25519_KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const MAC_KEY_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+const PAYLOAD_NONCE_LEN: usize = 16;
+const PAYLOAD_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const CHUNK_LEN: usize = 64 * 1024;
+/// Limit on the size of a header, which has to be buffered before the file key can be unwrapped.
+const MAX_HEADER_LEN: usize = 64 * 1024;
+const LINE_LEN: usize = 64;
+
+const DEFAULT_WORK_FACTOR: u8 = 18;
+const MAX_WORK_FACTOR: u8 = 22;
+const SCRYPT_R: u64 = 8;
+const SCRYPT_P: u64 = 1;
+
+struct Stanza {
+    args: Vec<String>,
+    body: Vec<u8>
+}
+
+struct Header {
+    stanzas: Vec<Stanza>,
+    mac_offset: usize,
+    mac: Vec<u8>
+}
+
+/// Encode the header up to and including the MAC line, wrapping the file key
+/// for every recipient.
+fn encode_header(recipients: &[Recipient], file_key: &[u8], salts: &[u8],
+                 ephemeral_keys: &[PrivateKey]) -> Result<BytesMut, Error> {
+    let mut ephemeral_keys = ephemeral_keys.iter();
+    let mut salts = salts.chunks(SALT_LEN);
+    let mut header = String::new();
+    header.push_str(VERSION_LINE);
+    header.push('\n');
+    for recipient in recipients {
+        let (args, wrapping_key) = match recipient.0 {
+            RecipientKind::X25519(ref key) => {
+                let ephemeral = ephemeral_keys.next().ok_or(ErrorKind::Malformed)?;
+                let ephemeral_public = ephemeral.public_key()?.to_bytes()?;
+                let wrapping_key = x25519_key(ephemeral, key, &ephemeral_public, &key.to_bytes()?)?;
+                (vec![X25519_STANZA.to_owned(), base64::encode(&ephemeral_public, false)], wrapping_key)
+            },
+            RecipientKind::Scrypt(ref passphrase, work_factor) => {
+                let salt = salts.next().ok_or(ErrorKind::Malformed)?;
+                let wrapping_key = scrypt_key(passphrase, salt, work_factor)?;
+                (vec![SCRYPT_STANZA.to_owned(), base64::encode(salt, false), work_factor.to_string()], wrapping_key)
+            }
+        };
+        let mut wrapping_key = wrapping_key;
+        let body = chacha20_poly1305(Mode::Seal, &wrapping_key, &[0; NONCE_LEN], file_key);
+        zeroize(&mut wrapping_key);
+        header.push_str(STANZA_PREFIX);
+        header.push_str(&args.join(" "));
+        header.push('\n');
+        let body = base64::encode(&body?, false);
+        for line in body.as_bytes().chunks(LINE_LEN) {
+            header.push_str(str::from_utf8(line).expect("base64 is ASCII"));
+            header.push('\n');
+        }
+        if body.len().is_multiple_of(LINE_LEN) {
+            header.push('\n');
+        }
+    }
+    header.push_str(MAC_MARK);
+    let mac = header_mac(file_key, header.as_bytes())?;
+    header.push(' ');
+    header.push_str(&base64::encode(mac.as_ref(), false));
+    header.push('\n');
+    let mut encoded = BytesMut::with_capacity(header.len() + PAYLOAD_NONCE_LEN);
+    encoded.put_slice(header.as_bytes());
+    Ok(encoded)
+}
+
+/// Find the length of the header at the start of `input`, which ends with the MAC line.
+///
+/// Returns `None` if more input is needed.
+fn header_len(input: &[u8]) -> Option<usize> {
+    let mac_line = input.windows(MAC_PREFIX.len() + 1)
+        .position(|window| window[0] == b'\n' && &window[1..] == MAC_PREFIX.as_bytes())?;
+    input[mac_line + 1..].iter().position(|byte| *byte == b'\n').map(|end| mac_line + end + 2)
+}
+
+/// Decode a complete header, as delimited by `header_len`.
+fn decode_header(input: &[u8]) -> Result<Header, Error> {
+    let text = str::from_utf8(input).map_err(|_| ErrorKind::Malformed)?;
+    let mut lines = text[..text.len() - 1].split('\n');
+    if lines.next() != Some(VERSION_LINE) {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let mut stanzas = Vec::new();
+    loop {
+        let line = lines.next().ok_or(ErrorKind::Malformed)?;
+        if let Some(arg) = line.strip_prefix(MAC_PREFIX) {
+            let mac = decode_arg(arg, MAC_LEN)?;
+            let mac_offset = input.len() - line.len() - 1 + MAC_MARK.len();
+            if lines.next().is_some() {
+                return Err(ErrorKind::Malformed.into());
+            }
+            return Ok(Header { stanzas, mac_offset, mac });
+        }
+        if !line.starts_with(STANZA_PREFIX) {
+            return Err(ErrorKind::Malformed.into());
+        }
+        let args: Vec<String> = line[STANZA_PREFIX.len()..].split(' ').map(|arg| arg.to_owned()).collect();
+        if args.iter().any(|arg| arg.is_empty()) {
+            return Err(ErrorKind::Malformed.into());
+        }
+        let mut body = Vec::new();
+        loop {
+            let line = lines.next().ok_or(ErrorKind::Malformed)?;
+            if line.len() > LINE_LEN {
+                return Err(ErrorKind::Malformed.into());
+            }
+            body.extend(base64::decode(line.as_bytes(), false).ok_or(ErrorKind::Malformed)?);
+            if line.len() < LINE_LEN {
+                break;
+            }
+        }
+        stanzas.push(Stanza { args, body });
+    }
+}
+
+/// Decode a base64 argument of the expected length.
+fn decode_arg(arg: &str, len: usize) -> Result<Vec<u8>, Error> {
+    match base64::decode(arg.as_bytes(), false) {
+        Some(ref bytes) if bytes.len() == len => Ok(bytes.clone()),
+        _ => Err(ErrorKind::Malformed.into())
+    }
+}
+
+fn parse_work_factor(arg: &str) -> Result<u8, Error> {
+    if arg.starts_with('0') || !arg.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(ErrorKind::Malformed.into());
+    }
+    match arg.parse::<u8>() {
+        Ok(work_factor) if work_factor > 0 && work_factor <= MAX_WORK_FACTOR => Ok(work_factor),
+        _ => Err(ErrorKind::Malformed.into())
+    }
+}
+
+/// The Bech32 encoding used for keys, as specified in
+/// [BIP 173](https://github.com/bitcoin/bips/blob/master/bip-0173.mediawiki).
+mod bech32 {
+    const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const GENERATORS: [u32; 5] = [0x3b6a_57b2, 0x2650_8e6d, 0x1ea1_19fa, 0x3d42_33dd, 0x2a14_62b3];
+
+    fn polymod(values: &[u8]) -> u32 {
+        values.iter().fold(1u32, |chk, value| {
+            let top = chk >> 25;
+            let chk = (chk & 0x01ff_ffff) << 5 ^ *value as u32;
+            GENERATORS.iter().enumerate()
+                .filter(|&(i, _)| top >> i & 1 == 1)
+                .fold(chk, |chk, (_, generator)| chk ^ generator)
+        })
+    }
+
+    fn expand_hrp(hrp: &[u8]) -> Vec<u8> {
+        let mut values: Vec<u8> = hrp.iter().map(|c| c >> 5).collect();
+        values.push(0);
+        values.extend(hrp.iter().map(|c| c & 31));
+        values
+    }
+
+    /// Decode a string into its lowercase human-readable part and its data.
+    ///
+    /// Returns `None` if the string is not valid Bech32, or if its data
+    /// does not convert to whole bytes.
+    pub fn decode(input: &str) -> Option<(String, Vec<u8>)> {
+        if input.to_lowercase() != input && input.to_uppercase() != input {
+            return None;
+        }
+        let input = input.to_lowercase();
+        let separator = input.rfind('1')?;
+        let (hrp, data) = (&input.as_bytes()[..separator], &input.as_bytes()[separator + 1..]);
+        if hrp.is_empty() || data.len() < 6 || hrp.iter().any(|c| *c < 33 || *c > 126) {
+            return None;
+        }
+        let mut values = expand_hrp(hrp);
+        let data = data.iter()
+            .map(|c| CHARSET.iter().position(|x| x == c).map(|value| value as u8))
+            .collect::<Option<Vec<u8>>>()?;
+        values.extend_from_slice(&data);
+        if polymod(&values) != 1 {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(data.len() * 5 / 8);
+        let (mut acc, mut bits) = (0u32, 0);
+        for value in &data[..data.len() - 6] {
+            acc = acc << 5 | *value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((acc >> bits) as u8);
+            }
+        }
+        if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+            return None;
+        }
+        Some((String::from_utf8(hrp.to_vec()).ok()?, bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::{Future, Stream};
+    use futures::stream::{iter_ok, repeat};
+    use hex::FromHex;
+    use self::itertools::Itertools;
+
+    use agreement::{self, PrivateKey};
+    use random::Generator;
+    use super::{Decrypt, Encrypt, Error, ErrorKind, Identity, Recipient, bech32};
+
+    fn encrypt(recipients: Vec<Recipient>, chunks: Vec<Vec<u8>>) -> Vec<u8> {
+        let generator = Generator::new(1);
+        let encrypt = Encrypt::new(&generator, recipients, iter_ok::<_, Error>(chunks)).unwrap();
+        encrypt.wait().collect::<Result<Vec<_>, _>>().unwrap().into_iter().concat().to_vec()
+    }
+
+    fn decrypt(identities: Vec<Identity>, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = ciphertext.chunks(1000).map(|chunk| chunk.to_vec()).collect();
+        let decrypt = Decrypt::new(identities, iter_ok::<_, Error>(chunks));
+        decrypt.wait().collect::<Result<Vec<_>, _>>().map(|chunks| chunks.into_iter().concat().to_vec())
+    }
+
+    fn generate() -> PrivateKey {
+        PrivateKey::generate(agreement::Algorithm::X25519, &Generator::new(1)).wait().unwrap()
+    }
+
+    #[test]
+    fn bech32_vectors() {
+        // BIP 173, valid test vectors
+        assert_eq!(bech32::decode("A12UEL5L"), Some(("a".to_owned(), vec![])));
+        assert_eq!(bech32::decode("abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw"),
+                   Some(("abcdef".to_owned(), Vec::from_hex("00443214c74254b635cf84653a56d7c675be77df").unwrap())));
+        assert_eq!(bech32::decode("A12UEL5l"), None);
+        assert_eq!(bech32::decode("a12uel5m"), None);
+    }
+
+    #[test]
+    fn interop_vector() {
+        // Encrypted by an independent implementation of the format.
+        let identity = Identity::parse(
+            "AGE-SECRET-KEY-1QYPQXPQ9QCRSSZG2PVXQ6RS0ZQG3YYC5Z5TPWXQERGD3C8G7RUSQGPQYEE").unwrap();
+        let ciphertext = Vec::from_hex("\
+            6167652d656e6372797074696f6e2e6f72672f76310a2d3e20583235353139207679713466525634324f4945\
+            595564684178337858644c362b616f63687752374142673841315a696453340a733274784c61486a4b2f454c\
+            507a7a77346a41614f424a6f72707042674a614a644c4d554c314a576444340a2d2d2d203378497753474453\
+            447070543847375338417133457376354e6e6b795956504445796f6d7663526630644d0a73ecbec16f5cf924\
+            399c773c5162665b3ec1db5db31f7bdcead4b8c63a44c805c0de244b61e1d59dfd").unwrap();
+        assert_eq!(decrypt(vec![identity], &ciphertext).unwrap(), b"hello age");
+
+        let recipient = Recipient::parse("age1q73he0q5yzfu3d64msd3p6rvksnrwjk3d2598mgtmlqt9wrdr37q2vrn72").unwrap();
+        let ciphertext = encrypt(vec![recipient], vec![b"hello again".to_vec()]);
+        let identity = Identity::parse(
+            "AGE-SECRET-KEY-1QYPQXPQ9QCRSSZG2PVXQ6RS0ZQG3YYC5Z5TPWXQERGD3C8G7RUSQGPQYEE").unwrap();
+        assert_eq!(decrypt(vec![identity], &ciphertext).unwrap(), b"hello again");
+        assert_eq!(Recipient::parse("age1q73he0q5yzfu3d64msd3p6rvksnrwjk3d2598mgtmlqt9wrdr37q2vrn73")
+                       .unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn x25519_roundtrip() {
+        let key = generate();
+        let other = generate();
+        let recipients = vec![Recipient::x25519(&other.public_key().unwrap()).unwrap(),
+                              Recipient::x25519(&key.public_key().unwrap()).unwrap()];
+        for &len in &[0usize, 1, 64 * 1024 - 1, 64 * 1024, 64 * 1024 + 1, 3 * 64 * 1024] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let chunks = plaintext.chunks(10_000).map(|chunk| chunk.to_vec()).collect();
+            let ciphertext = encrypt(recipients.clone(), chunks);
+            let expected_chunks = if len == 0 { 1 } else { len.div_ceil(64 * 1024) };
+            assert!(ciphertext.len() > len + 16 * expected_chunks);
+            assert_eq!(decrypt(vec![Identity::x25519(&key).unwrap()], &ciphertext).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn scrypt_roundtrip() {
+        let ciphertext = encrypt(vec![Recipient::scrypt_with_work_factor(b"passphrase", 10).unwrap()],
+                                 vec![b"foo".to_vec(), b"bar".to_vec()]);
+        assert!(ciphertext.starts_with(b"age-encryption.org/v1\n-> scrypt "));
+        assert_eq!(decrypt(vec![Identity::scrypt(b"passphrase")], &ciphertext).unwrap(), b"foobar");
+        assert_eq!(decrypt(vec![Identity::scrypt(b"wrong")], &ciphertext).unwrap_err().kind(),
+                   ErrorKind::UnknownKey);
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        for &work_factor in &[0, 23] {
+            let err = Recipient::scrypt_with_work_factor(b"passphrase", work_factor).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unsupported);
+        }
+        let generator = Generator::new(1);
+        let x25519 = Recipient::x25519(&generate().public_key().unwrap()).unwrap();
+        let err = Encrypt::new(&generator, vec![], iter_ok::<Vec<u8>, Error>(vec![])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        let recipients = vec![Recipient::scrypt(b"passphrase"), x25519];
+        let err = Encrypt::new(&generator, recipients, iter_ok::<Vec<u8>, Error>(vec![])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn unknown_identity() {
+        let ciphertext = encrypt(vec![Recipient::x25519(&generate().public_key().unwrap()).unwrap()],
+                                 vec![b"foo".to_vec()]);
+        let identities = vec![Identity::x25519(&generate()).unwrap(), Identity::scrypt(b"foo")];
+        assert_eq!(decrypt(identities, &ciphertext).unwrap_err().kind(), ErrorKind::UnknownKey);
+    }
+
+    #[test]
+    fn truncated_and_tampered() {
+        let key = generate();
+        let plaintext = vec![7u8; 100_000];
+        let ciphertext = encrypt(vec![Recipient::x25519(&key.public_key().unwrap()).unwrap()], vec![plaintext]);
+        let identities = || vec![Identity::x25519(&key).unwrap()];
+
+        let header_len = ciphertext.len() - 100_000 - 2 * 16 - 16;
+        let truncated = &ciphertext[..header_len + 16 + 64 * 1024 + 16];
+        assert_eq!(decrypt(identities(), truncated).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+
+        // Replace a character of the header MAC, keeping it valid base64.
+        let mut tampered = ciphertext.clone();
+        tampered[header_len - 10] = if tampered[header_len - 10] == b'A' { b'B' } else { b'A' };
+        assert_eq!(decrypt(identities(), &tampered).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+
+        let mut tampered = ciphertext.clone();
+        let len = tampered.len();
+        tampered[len - 1] ^= 1;
+        assert_eq!(decrypt(identities(), &tampered).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+
+        assert_eq!(decrypt(identities(), &ciphertext[..10]).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn header_limit() {
+        let start = iter_ok::<_, Error>(vec![b"age-encryption.org/v1\n-> X25519 ".to_vec()]);
+        let decrypt = Decrypt::new(vec![Identity::x25519(&generate()).unwrap()], start.chain(repeat(vec![b'A'; 1000])));
+        assert_eq!(decrypt.wait().next().unwrap().unwrap_err().kind(), ErrorKind::Malformed);
+    }
+}
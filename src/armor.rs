@@ -0,0 +1,583 @@
+//! Textual armor for binary streams, in the style of PEM
+//! ([RFC 1421](https://tools.ietf.org/html/rfc1421)) and OpenPGP armor
+//! ([RFC 4880, section 6](https://tools.ietf.org/html/rfc4880#section-6)).
+//!
+//! The armored text looks as follows, where the headers, and the blank line following
+//! them, are only present if headers have been added:
+//!
+//! ```text
+//! -----BEGIN ENCRYPTED DATA-----
+//! Algorithm: aes-128-cbc
+//! IV: 000102030405060708090a0b0c0d0e0f
+//!
+//! q83vEjRWeJCrze8SNFZ4kKvN7xI0VniQq83vEjRWeJCrze8SNFZ4kKvN7xI0VniQ
+//! q83vEjRWeJA=
+//! =V0b4
+//! -----END ENCRYPTED DATA-----
+//! ```
+//!
+//! The armor adapters operate on any stream of bytes, so they can be wrapped around an
+//! [`Encrypt`](../cipher/struct.Encrypt.html) stream, or wrapped by a
+//! [`Decrypt`](../cipher/struct.Decrypt.html) stream. Headers can be used to carry the
+//! parameters needed for decryption, which are available from
+//! [`Dearmor::read_headers`](struct.Dearmor.html#method.read_headers) before the data is decrypted.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::mem;
+use std::str;
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Future, Poll, Stream};
+use openssl;
+
+use super::{Error, ErrorKind};
+use super::base64;
+use super::hash;
+
+/// Number of bytes per line, which are encoded as 64 characters.
+const LINE_LEN: usize = 48;
+
+/// Checksum that follows the data, to detect accidental modification of the armored text.
+///
+/// The checksum does not protect against deliberate modification, which requires
+/// authenticated encryption or a signature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Checksum {
+    /// No checksum.
+    None,
+    /// The 24-bit CRC used by OpenPGP.
+    Crc24,
+    /// A digest computed with the given algorithm.
+    Digest(hash::Algorithm),
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+/// Incremental computation of a checksum.
+enum ChecksumState {
+    None,
+    Crc24(u32),
+    Digest(openssl::hash::Hasher)
+}
+
+impl ChecksumState {
+    fn new(checksum: Checksum) -> Result<ChecksumState, Error> {
+        match checksum {
+            Checksum::None => Ok(ChecksumState::None),
+            Checksum::Crc24 => Ok(ChecksumState::Crc24(CRC_INIT)),
+            Checksum::Digest(algo) => openssl::hash::Hasher::new(algo.into_message_digest())
+                .map(ChecksumState::Digest)
+                .map_err(Error::from),
+            Checksum::_Donotmatch => unreachable!()
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) -> Result<(), Error> {
+        match *self {
+            ChecksumState::None => Ok(()),
+            ChecksumState::Crc24(ref mut crc) => {
+                *crc = crc24(*crc, data);
+                Ok(())
+            },
+            ChecksumState::Digest(ref mut hasher) => hasher.update(data).map_err(Error::from)
+        }
+    }
+
+    /// Compute the checksum, or return `None` if no checksum is used.
+    fn finish(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match *self {
+            ChecksumState::None => Ok(None),
+            ChecksumState::Crc24(crc) => Ok(Some(vec![(crc >> 16) as u8, (crc >> 8) as u8, crc as u8])),
+            ChecksumState::Digest(ref mut hasher) => {
+                hasher.finish().map(|digest| Some(digest.to_vec())).map_err(Error::from)
+            }
+        }
+    }
+}
+
+/// Check that a label consists of printable characters, and does not start or end with a hyphen.
+fn validate_label(label: &str) -> Result<(), Error> {
+    if label.is_empty() || label.starts_with('-') || label.ends_with('-') ||
+       !label.bytes().all(|c| c == b' ' || c.is_ascii_graphic()) {
+        return Err(ErrorKind::Malformed.into());
+    }
+    Ok(())
+}
+
+/// Stream adapter that encodes the data of its underlying stream as armored text.
+pub struct Armor<S> {
+    inner: S,
+    label: String,
+    headers: Vec<(String, String)>,
+    checksum: ChecksumState,
+    buffer: BytesMut,
+    started: bool,
+    finished: bool
+}
+
+impl<S: Debug> Debug for Armor<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Armor")
+            .field("inner", &self.inner)
+            .field("label", &self.label)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+impl<S: Stream> Armor<S> {
+    /// Create an armoring stream adapter, given the label of the BEGIN and END lines
+    /// and the checksum to append.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the label contains characters other than
+    /// printable ASCII, or starts or ends with a hyphen.
+    pub fn new(label: &str, checksum: Checksum, inner: S) -> Result<Self, Error> {
+        validate_label(label)?;
+        Ok(Armor {
+            inner,
+            label: label.to_owned(),
+            headers: Vec::new(),
+            checksum: ChecksumState::new(checksum)?,
+            buffer: BytesMut::new(),
+            started: false,
+            finished: false
+        })
+    }
+
+    /// Add a header, such as the algorithm or the IV used to encrypt the data.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the name is empty or contains a colon or
+    /// characters other than printable ASCII, or if the value contains control characters.
+    pub fn add_header(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        if name.is_empty() || name.contains(':') || !name.bytes().all(|c| c.is_ascii_graphic()) ||
+           value.chars().any(char::is_control) {
+            return Err(ErrorKind::Malformed.into());
+        }
+        self.headers.push((name.to_owned(), value.trim().to_owned()));
+        Ok(())
+    }
+
+    fn encode_lines(&mut self, len: usize, output: &mut String) -> Result<(), Error> {
+        let data = self.buffer.split_to(len);
+        self.checksum.update(&data)?;
+        for line in data.chunks(LINE_LEN) {
+            output.push_str(&base64::encode(line, true));
+            output.push('\n');
+        }
+        Ok(())
+    }
+}
+
+impl<S: Stream> Stream for Armor<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            let mut output = String::new();
+            if !self.started {
+                self.started = true;
+                output.push_str(&format!("-----BEGIN {}-----\n", self.label));
+                for (name, value) in &self.headers {
+                    output.push_str(&format!("{}: {}\n", name, value));
+                }
+                if !self.headers.is_empty() {
+                    output.push('\n');
+                }
+            }
+            match self.inner.poll()? {
+                Async::NotReady => if output.is_empty() {
+                    return Ok(Async::NotReady);
+                },
+                Async::Ready(Some(item)) => {
+                    self.buffer.extend_from_slice(item.as_ref());
+                    let len = self.buffer.len() - self.buffer.len() % LINE_LEN;
+                    self.encode_lines(len, &mut output)?;
+                },
+                Async::Ready(None) => {
+                    let len = self.buffer.len();
+                    self.encode_lines(len, &mut output)?;
+                    if let Some(checksum) = self.checksum.finish()? {
+                        output.push_str(&format!("={}\n", base64::encode(&checksum, true)));
+                    }
+                    output.push_str(&format!("-----END {}-----\n", self.label));
+                    self.finished = true;
+                }
+            }
+            if !output.is_empty() {
+                return Ok(Async::Ready(Some(Bytes::from(output))));
+            }
+        }
+    }
+}
+
+/// Stream adapter that decodes armored text from its underlying stream.
+///
+/// Any text before the BEGIN line and after the END line is ignored. If a checksum is
+/// expected, the armor must carry it, and the stream fails with `ErrorKind::DigestMismatch`
+/// at its end if it does not match. Armor that cannot be parsed, or whose label does not
+/// match, makes the stream fail with `ErrorKind::Malformed`.
+pub struct Dearmor<S> {
+    inner: S,
+    decoder: Decoder,
+    buffer: BytesMut,
+    pending: Vec<u8>,
+    finished: bool
+}
+
+impl<S: Debug> Debug for Dearmor<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Dearmor")
+            .field("inner", &self.inner)
+            .field("label", &self.decoder.label)
+            .finish()
+    }
+}
+
+impl<S: Stream> Dearmor<S> {
+    /// Create a dearmoring stream adapter, given the expected label and checksum.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the label is not valid, as for
+    /// [`Armor::new`](struct.Armor.html#method.new).
+    pub fn new(label: &str, checksum: Checksum, inner: S) -> Result<Self, Error> {
+        validate_label(label)?;
+        Ok(Dearmor {
+            inner,
+            decoder: Decoder::new(label, checksum)?,
+            buffer: BytesMut::new(),
+            pending: Vec::new(),
+            finished: false
+        })
+    }
+
+    /// Read the armor up to the end of its headers, returning a future that resolves
+    /// with the headers and this stream, which then yields the data.
+    pub fn read_headers(self) -> ReadHeaders<S> {
+        ReadHeaders { dearmor: Some(self) }
+    }
+
+    /// Feed the next chunk of the underlying stream to the decoder, or finish decoding
+    /// if the underlying stream has ended.
+    fn update(&mut self, item: Option<S::Item>) -> Result<(), Error>
+        where S::Item: AsRef<[u8]>
+    {
+        match item {
+            Some(item) => {
+                self.buffer.extend_from_slice(item.as_ref());
+                while let Some(pos) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                    let line = self.buffer.split_to(pos + 1);
+                    self.decoder.line(&line, &mut self.pending)?;
+                }
+                Ok(())
+            },
+            None => {
+                self.finished = true;
+                let line = self.buffer.take();
+                self.decoder.line(&line, &mut self.pending)?;
+                self.decoder.finish()
+            }
+        }
+    }
+}
+
+impl<S: Stream> Stream for Dearmor<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if !self.pending.is_empty() {
+                return Ok(Async::Ready(Some(Bytes::from(mem::take(&mut self.pending)))));
+            }
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(item) => self.update(item)?
+            }
+        }
+    }
+}
+
+/// Future returned by [`Dearmor::read_headers`](struct.Dearmor.html#method.read_headers).
+pub struct ReadHeaders<S> {
+    dearmor: Option<Dearmor<S>>
+}
+
+impl<S: Debug> Debug for ReadHeaders<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("ReadHeaders")
+            .field("dearmor", &self.dearmor)
+            .finish()
+    }
+}
+
+impl<S: Stream> Future for ReadHeaders<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = (Vec<(String, String)>, Dearmor<S>);
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, S::Error> {
+        {
+            let dearmor = self.dearmor.as_mut().expect("polled after completion");
+            while dearmor.decoder.state == DecoderState::Begin || dearmor.decoder.state == DecoderState::Headers {
+                // The underlying stream cannot end here, because decoding fails before that.
+                match dearmor.inner.poll()? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(item) => dearmor.update(item)?
+                }
+            }
+        }
+        let dearmor = self.dearmor.take().unwrap();
+        Ok(Async::Ready((dearmor.decoder.headers.clone(), dearmor)))
+    }
+}
+
+/// Line-based decoder of armor.
+struct Decoder {
+    label: String,
+    state: DecoderState,
+    headers: Vec<(String, String)>,
+    chars: Vec<u8>,
+    padded: bool,
+    checksum: ChecksumState,
+    expected: Option<Vec<u8>>
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DecoderState {
+    Begin,
+    Headers,
+    Body,
+    End
+}
+
+impl Decoder {
+    fn new(label: &str, checksum: Checksum) -> Result<Decoder, Error> {
+        Ok(Decoder {
+            label: label.to_owned(),
+            state: DecoderState::Begin,
+            headers: Vec::new(),
+            chars: Vec::new(),
+            padded: false,
+            checksum: ChecksumState::new(checksum)?,
+            expected: None
+        })
+    }
+
+    fn line(&mut self, line: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+        let line = trim(line);
+        match self.state {
+            DecoderState::Begin => if line == format!("-----BEGIN {}-----", self.label).as_bytes() {
+                self.state = DecoderState::Headers;
+            },
+            DecoderState::Headers => if line.is_empty() {
+                self.state = DecoderState::Body;
+            } else if let Some(pos) = line.iter().position(|c| *c == b':') {
+                let header = str::from_utf8(line).map_err(|err| Error::new(ErrorKind::Malformed, err))?;
+                self.headers.push((header[..pos].trim().to_owned(), header[pos + 1..].trim().to_owned()));
+            } else {
+                // Armor without headers starts with the data right away.
+                self.state = DecoderState::Body;
+                return self.line(line, output);
+            },
+            DecoderState::Body => if line.starts_with(b"-----") {
+                if line != format!("-----END {}-----", self.label).as_bytes() || !self.chars.is_empty() {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                match (self.checksum.finish()?, self.expected.take()) {
+                    (None, None) => {},
+                    (Some(checksum), Some(expected)) => if checksum != expected {
+                        return Err(ErrorKind::DigestMismatch.into());
+                    },
+                    _ => return Err(ErrorKind::Malformed.into())
+                }
+                self.state = DecoderState::End;
+            } else if line.starts_with(b"=") {
+                if self.expected.is_some() {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                self.expected = Some(base64::decode(&line[1..], true).ok_or(ErrorKind::Malformed)?);
+            } else {
+                if self.padded || self.expected.is_some() {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                self.chars.extend_from_slice(line);
+                let len = self.chars.len() - self.chars.len() % 4;
+                let data = base64::decode(&self.chars[..len], true).ok_or(ErrorKind::Malformed)?;
+                self.padded = self.chars[..len].ends_with(b"=");
+                self.chars.drain(..len);
+                self.checksum.update(&data)?;
+                output.extend_from_slice(&data);
+            },
+            DecoderState::End => {}
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        if self.state != DecoderState::End {
+            return Err(ErrorKind::Malformed.into());
+        }
+        Ok(())
+    }
+}
+
+fn trim(mut line: &[u8]) -> &[u8] {
+    while let Some((last, rest)) = line.split_last() {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        line = rest;
+    }
+    line
+}
+
+const CRC_INIT: u32 = 0xb7_04ce;
+const CRC_POLY: u32 = 0x186_4cfb;
+
+/// Update the 24-bit CRC used by OpenPGP armor.
+fn crc24(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= (*byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x100_0000 != 0 {
+                crc ^= CRC_POLY;
+            }
+        }
+    }
+    crc & 0xff_ffff
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+    use hex::{FromHex, ToHex};
+
+    use self::itertools::Itertools;
+
+    use cipher::{Algorithm, Config, Decrypt, Encrypt};
+    use hash;
+    use super::{Armor, Checksum, Dearmor, Error, ErrorKind, crc24, CRC_INIT};
+
+    fn armor(label: &str, checksum: Checksum, headers: &[(&str, &str)], chunks: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut armor = Armor::new(label, checksum, iter_ok::<_, Error>(chunks)).unwrap();
+        for &(name, value) in headers {
+            armor.add_header(name, value).unwrap();
+        }
+        armor.concat2().wait().unwrap().to_vec()
+    }
+
+    fn dearmor(label: &str, checksum: Checksum, armored: &[u8]) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = armored.chunks(7).map(|chunk| chunk.to_vec()).collect();
+        Dearmor::new(label, checksum, iter_ok::<_, Error>(chunks)).unwrap().concat2().wait().map(|data| data.to_vec())
+    }
+
+    #[test]
+    fn crc24_vector() {
+        assert_eq!(crc24(CRC_INIT, b""), 0xb704ce);
+        assert_eq!(crc24(CRC_INIT, b"123456789"), 0x21cf02);
+    }
+
+    #[test]
+    fn format() {
+        let armored = armor("TEST", Checksum::Crc24, &[("Comment", "hello")], vec![b"hello world".to_vec()]);
+        assert_eq!(armored,
+                   &b"-----BEGIN TEST-----\nComment: hello\n\naGVsbG8gd29ybGQ=\n=sDy3\n-----END TEST-----\n"[..]);
+        let armored = armor("TEST", Checksum::None, &[], vec![vec![0; 49]]);
+        assert_eq!(String::from_utf8(armored).unwrap(), format!("-----BEGIN TEST-----\n{}\nAA==\n-----END TEST-----\n",
+                                                                "A".repeat(64)));
+    }
+
+    #[test]
+    fn checksums() {
+        for &checksum in &[Checksum::None, Checksum::Crc24, Checksum::Digest(hash::Algorithm::Sha256)] {
+            let armored = armor("DATA", checksum, &[], vec![b"some data".to_vec()]);
+            assert_eq!(dearmor("DATA", checksum, &armored).unwrap(), b"some data");
+            let tampered = String::from_utf8(armored).unwrap().replace("c29tZSBkYXRh", "c29tZSBkYXRi");
+            let result = dearmor("DATA", checksum, tampered.as_bytes());
+            match checksum {
+                Checksum::None => assert_eq!(result.unwrap(), b"some datb"),
+                _ => assert_eq!(result.unwrap_err().kind(), ErrorKind::DigestMismatch)
+            }
+        }
+        let armored = armor("DATA", Checksum::Crc24, &[], vec![b"some data".to_vec()]);
+        assert_eq!(dearmor("DATA", Checksum::None, &armored).unwrap_err().kind(), ErrorKind::Malformed);
+        assert_eq!(dearmor("DATA", Checksum::Digest(hash::Algorithm::Sha256), &armored).unwrap_err().kind(),
+                   ErrorKind::DigestMismatch);
+        let armored = armor("DATA", Checksum::None, &[], vec![b"some data".to_vec()]);
+        assert_eq!(dearmor("DATA", Checksum::Crc24, &armored).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn malformed() {
+        let cases: [&[u8]; 5] = [
+            b"-----BEGIN DATA-----\naGVsbG8g\n",
+            b"-----BEGIN OTHER-----\naGVsbG8g\n-----END OTHER-----\n",
+            b"-----BEGIN DATA-----\naGV*\n-----END DATA-----\n",
+            b"-----BEGIN DATA-----\nZg==\nZg==\n-----END DATA-----\n",
+            b"-----BEGIN DATA-----\naGVsbG8\n-----END DATA-----\n"
+        ];
+        for armored in &cases {
+            assert_eq!(dearmor("DATA", Checksum::None, armored).unwrap_err().kind(), ErrorKind::Malformed);
+        }
+        assert!(Armor::new("-DATA", Checksum::None, iter_ok::<Vec<u8>, Error>(vec![])).is_err());
+        assert!(Dearmor::new("DA\nTA", Checksum::None, iter_ok::<Vec<u8>, Error>(vec![])).is_err());
+        let mut armor = Armor::new("DATA", Checksum::None, iter_ok::<Vec<u8>, Error>(vec![])).unwrap();
+        assert_eq!(armor.add_header("A:B", "").unwrap_err().kind(), ErrorKind::Malformed);
+        assert_eq!(armor.add_header("A", "B\nC").unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn encrypted_with_headers() {
+        let mut config = Config::new(Algorithm::Aes128Cbc);
+        config.key_mut().copy_from_slice(&[0x42; 16]);
+        config.iv_mut().unwrap().copy_from_slice(&[0x24; 16]);
+        let encrypt = Encrypt::new(&config, iter_ok::<_, Error>(vec![b"attack at dawn".to_vec()])).unwrap();
+        let mut armor = Armor::new("ENCRYPTED DATA", Checksum::Digest(hash::Algorithm::Sha256), encrypt).unwrap();
+        armor.add_header("Algorithm", "aes-128-cbc").unwrap();
+        armor.add_header("IV", &[0x24; 16].to_hex()).unwrap();
+        let armored = armor.concat2().wait().unwrap();
+
+        let chunks: Vec<Vec<u8>> = armored.chunks(5).map(|chunk| chunk.to_vec()).collect();
+        let checksum = Checksum::Digest(hash::Algorithm::Sha256);
+        let dearmor = Dearmor::new("ENCRYPTED DATA", checksum, iter_ok::<_, Error>(chunks)).unwrap();
+        let (headers, dearmor) = dearmor.read_headers().wait().unwrap();
+        assert_eq!(headers[0], ("Algorithm".to_owned(), "aes-128-cbc".to_owned()));
+        let mut config = Config::new(Algorithm::Aes128Cbc);
+        config.key_mut().copy_from_slice(&[0x42; 16]);
+        config.iv_mut().unwrap().copy_from_slice(&Vec::<u8>::from_hex(&headers[1].1).unwrap());
+        let decrypted = Decrypt::new(&config, dearmor).unwrap().concat2().wait().unwrap();
+        assert_eq!(&decrypted[..], b"attack at dawn");
+
+        let unarmored = Dearmor::new("X", Checksum::None, iter_ok::<_, Error>(vec![b"no armor".to_vec()])).unwrap();
+        assert_eq!(unarmored.read_headers().wait().unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    quickcheck! {
+        fn roundtrip(chunks: Vec<Vec<u8>>) -> bool {
+            let data = chunks.iter().cloned().concat();
+            let armored = armor("DATA", Checksum::Crc24, &[("Version", "1")], chunks);
+            armored.split(|byte| *byte == b'\n').all(|line| line.len() <= 64) &&
+                dearmor("DATA", Checksum::Crc24, &armored).unwrap() == data
+        }
+    }
+}
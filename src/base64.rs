@@ -0,0 +1,133 @@
+//! Base64 encoding with the standard alphabet, as specified in
+//! [RFC 4648](https://tools.ietf.org/html/rfc4648).
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const INVALID: u8 = 0xff;
+
+/// Encode the input, optionally padding the output with `=` to a multiple of 4 characters.
+pub(crate) fn encode(input: &[u8], padded: bool) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |acc, (i, byte)| acc | (*byte as u32) << (16 - 8 * i));
+        for i in 0..chunk.len() + 1 {
+            output.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+        if padded {
+            for _ in chunk.len()..3 {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+/// Decode the input, requiring padding if `padded` is set and rejecting it otherwise.
+///
+/// Returns `None` if the input contains characters outside the alphabet, or is
+/// not in canonical form, i.e. if unused bits in the last character are set.
+pub(crate) fn decode(input: &[u8], padded: bool) -> Option<Vec<u8>> {
+    let input = if padded {
+        if !input.len().is_multiple_of(4) {
+            return None;
+        }
+        let padding = input.iter().rev().take(2).take_while(|c| **c == b'=').count();
+        &input[..input.len() - padding]
+    } else {
+        input
+    };
+    if input.len() % 4 == 1 {
+        return None;
+    }
+    let mut output = Vec::with_capacity(input.len() * 3 / 4);
+    for chunk in input.chunks(4) {
+        let mut bits = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = decode_char(*c);
+            if value == INVALID {
+                return None;
+            }
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+        let len = chunk.len() - 1;
+        if bits & (0xffffff >> (8 * len)) != 0 {
+            return None;
+        }
+        for i in 0..len {
+            output.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(output)
+}
+
+/// Encode the input with the URL and filename safe alphabet, optionally padding the output.
+pub(crate) fn encode_url(input: &[u8], padded: bool) -> String {
+    encode(input, padded).replace('+', "-").replace('/', "_")
+}
+
+/// Decode input in the URL and filename safe alphabet, with the same rules for
+/// padding as [`decode`](fn.decode.html).
+pub(crate) fn decode_url(input: &[u8], padded: bool) -> Option<Vec<u8>> {
+    let input = input.iter().map(|c| match *c {
+        b'-' => b'+',
+        b'_' => b'/',
+        // Reject the characters of the standard alphabet.
+        b'+' | b'/' => INVALID,
+        c => c
+    }).collect::<Vec<_>>();
+    decode(&input, padded)
+}
+
+fn decode_char(c: u8) -> u8 {
+    match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => INVALID
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, decode_url, encode, encode_url};
+
+    #[test]
+    fn rfc4648_vectors() {
+        let vectors = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"),
+                       ("foob", "Zm9vYg=="), ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+        for &(plain, encoded) in &vectors {
+            assert_eq!(encode(plain.as_bytes(), true), encoded);
+            assert_eq!(decode(encoded.as_bytes(), true).unwrap(), plain.as_bytes());
+            let unpadded = encoded.trim_end_matches('=');
+            assert_eq!(encode(plain.as_bytes(), false), unpadded);
+            assert_eq!(decode(unpadded.as_bytes(), false).unwrap(), plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(decode(b"Zg==", false), None);
+        assert_eq!(decode(b"Zg", true), None);
+        assert_eq!(decode(b"Zh", false), None);
+        assert_eq!(decode(b"Z", false), None);
+        assert_eq!(decode(b"Zm9v\n", false), None);
+    }
+
+    #[test]
+    fn url_safe_alphabet() {
+        assert_eq!(encode_url(&[0xfb, 0xff, 0xbf], false), "-_-_");
+        assert_eq!(decode_url(b"-_-_", false).unwrap(), [0xfb, 0xff, 0xbf]);
+        assert_eq!(encode_url(&[0xfb, 0xff], true), "-_8=");
+        assert_eq!(decode_url(b"-_8=", true).unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode_url(b"+/+/", false), None);
+        assert_eq!(decode_url(b"Zm+/", true), None);
+        assert_eq!(decode_url(b"Zg==", false), None);
+    }
+
+    quickcheck! {
+        fn roundtrip(data: Vec<u8>, padded: bool) -> bool {
+            decode(encode(&data, padded).as_bytes(), padded) == Some(data)
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Authenticated encryption composed of a block cipher and HMAC.
+//!
+//! Encrypts the data using AES in CBC or CTR mode, and then computes an
+//! [HMAC](../../hash/struct.Hmac.html) over the initialization vector and the ciphertext
+//! (Encrypt-then-MAC). The authentication tag is appended to the end of the ciphertext.
+//!
+//! Separate keys for encryption and authentication are derived from a single
+//! secret using [HKDF](../../hash/fn.hkdf.html). This makes the composite useful
+//! where an AEAD mode such as GCM is not available.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::oneshot;
+use openssl;
+
+use super::super::{Error, ErrorKind};
+use super::super::hash::{self, Hmac, hkdf};
+use super::super::secret::{Redacted, zeroize};
+
+/// Configuration for authenticated encryption, holding the secret and the IV.
+#[derive(Clone)]
+pub struct Config {
+    algo: Algorithm,
+    secret: [u8; SECRET_LEN],
+    iv: [u8; IV_LEN]
+}
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Config")
+            .field("algo", &self.algo)
+            .field("secret", &Redacted)
+            .field("iv", &Redacted)
+            .finish()
+    }
+}
+
+impl Drop for Config {
+    fn drop(&mut self) {
+        zeroize(&mut self.secret);
+        zeroize(&mut self.iv);
+    }
+}
+
+impl Config {
+    /// Initialize a config given an algorithm.
+    pub fn new(algo: Algorithm) -> Config {
+        Config {
+            algo, secret: [0u8; SECRET_LEN], iv: [0u8; IV_LEN]
+        }
+    }
+
+    /// Get a mutable slice of bytes to set the secret from which
+    /// the encryption and authentication keys are derived.
+    pub fn key_mut(&mut self) -> &mut [u8] {
+        &mut self.secret
+    }
+
+    /// Get a mutable slice of bytes to set the initialization vector.
+    ///
+    /// The IV must be unique for every message encrypted with the same secret.
+    pub fn iv_mut(&mut self) -> &mut [u8] {
+        &mut self.iv
+    }
+
+    fn keys(&self) -> Result<(super::Config, Hmac), Error> {
+        let mut config = super::Config::new(self.algo.cipher());
+        hkdf(hash::Algorithm::Sha256, &[], &self.secret, ENCRYPTION_INFO, config.key_mut())?;
+        config.iv_mut().expect("cipher requires an iv").copy_from_slice(&self.iv);
+        let mut mac_key = [0u8; MAX_MAC_KEY_LEN];
+        let mac_key_len = self.algo.mac().digest_len();
+        let result = hkdf(hash::Algorithm::Sha256, &[], &self.secret, AUTHENTICATION_INFO, &mut mac_key[..mac_key_len])
+            .and_then(|_| Hmac::new(self.algo.mac(), &mac_key[..mac_key_len]));
+        zeroize(&mut mac_key);
+        let mut hmac = result?;
+        hmac.update(&self.iv)?;
+        Ok((config, hmac))
+    }
+}
+
+/// Stream adapter that encrypts the data from the underlying stream,
+/// and appends the authentication tag.
+#[derive(Debug)]
+pub struct Encrypt<S> {
+    inner: super::Encrypt<S>,
+    hmac: Option<Hmac>
+}
+
+impl<S: Stream> Encrypt<S> {
+    /// Create an encrypting stream adapter.
+    pub fn new(config: &Config, inner: S) -> Result<Self, Error> {
+        let (config, hmac) = config.keys()?;
+        Ok(Encrypt { inner: super::Encrypt::new(&config, inner)?, hmac: Some(hmac) })
+    }
+}
+
+impl<S: Stream> Stream for Encrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.inner.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(Some(chunk)) => {
+                if let Some(ref mut hmac) = self.hmac {
+                    hmac.update(&chunk)?;
+                }
+                Ok(Async::Ready(Some(chunk)))
+            },
+            Async::Ready(None) => match self.hmac.take() {
+                None => Ok(Async::Ready(None)),
+                Some(mut hmac) => {
+                    let tag = hmac.finish()?;
+                    Ok(Async::Ready(Some(Bytes::from(tag.as_ref()))))
+                }
+            }
+        }
+    }
+}
+
+/// Stream adapter that verifies and decrypts the data from the underlying stream.
+///
+/// The plaintext is held back until the authentication tag has been verified,
+/// and then yielded as a single chunk. If the tag does not match, the stream fails
+/// with `ErrorKind::AuthenticationFailed` and no plaintext is released.
+///
+/// Use [`split`](#method.split) to decrypt large streams without buffering.
+#[derive(Debug)]
+pub struct Decrypt<S> {
+    inner: super::Decrypt<Verify<S>>,
+    buffer: Option<BytesMut>,
+    receiver: oneshot::Receiver<Result<(), Error>>
+}
+
+impl<S: Stream> Decrypt<S> {
+    /// Create a decrypting stream adapter.
+    pub fn new(config: &Config, inner: S) -> Result<Self, Error> {
+        let (config, hmac) = config.keys()?;
+        let (sender, receiver) = oneshot::channel();
+        let verify = Verify {
+            inner, hmac: Some(hmac), pending: BytesMut::new(), sender: Some(sender)
+        };
+        Ok(Decrypt {
+            inner: super::Decrypt(config.stream(verify, openssl::symm::Mode::Decrypt)?),
+            buffer: Some(BytesMut::new()),
+            receiver
+        })
+    }
+
+    /// Split the stream adapter into two halves, one to receive the result of
+    /// the verification, and one to decrypt the stream without buffering.
+    ///
+    /// The decrypting half (`SplitDecrypt`) yields plaintext as it is decrypted,
+    /// before the authentication tag has been verified. The plaintext must not be
+    /// acted upon until the verifying half (`Verification`) has resolved successfully.
+    pub fn split(self) -> (Verification, SplitDecrypt<S>) {
+        let verification = Verification { receiver: self.receiver };
+        let buffered = self.buffer.and_then(|buffer| {
+            if buffer.is_empty() { None } else { Some(buffer.freeze()) }
+        });
+        let decrypt = SplitDecrypt { inner: self.inner, buffered };
+        (verification, decrypt)
+    }
+}
+
+impl<S: Stream> Stream for Decrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(chunk)) => {
+                    if let Some(ref mut buffer) = self.buffer {
+                        buffer.extend_from_slice(&chunk);
+                    }
+                },
+                Async::Ready(None) => return Ok(Async::Ready(self.buffer.take().map(BytesMut::freeze)))
+            }
+        }
+    }
+}
+
+/// The receiving half of a split decryption process.
+///
+/// This is a future that resolves as soon as the stream has been fully
+/// consumed and the authentication tag has been verified.
+/// It fails with `ErrorKind::AuthenticationFailed` if the tag does not match,
+/// or if the decrypting half is dropped before the tag has been verified.
+///
+/// See [`Decrypt::split`](struct.Decrypt.html#method.split) for more information.
+#[derive(Debug)]
+pub struct Verification {
+    receiver: oneshot::Receiver<Result<(), Error>>
+}
+
+impl Future for Verification {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.receiver.poll() {
+            Err(_) => Err(ErrorKind::AuthenticationFailed.into()),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result.map(Async::Ready)
+        }
+    }
+}
+
+/// The decrypting half of a split decryption process.
+///
+/// See [`Decrypt::split`](struct.Decrypt.html#method.split) for more information.
+#[derive(Debug)]
+pub struct SplitDecrypt<S> {
+    inner: super::Decrypt<Verify<S>>,
+    buffered: Option<Bytes>
+}
+
+impl<S: Stream> Stream for SplitDecrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.buffered.take() {
+            Some(chunk) => Ok(Async::Ready(Some(chunk))),
+            None => self.inner.poll()
+        }
+    }
+}
+
+/// Stream adapter that strips the authentication tag from the end of the stream,
+/// and verifies it before signalling the end of the stream.
+#[derive(Debug)]
+struct Verify<S> {
+    inner: S,
+    hmac: Option<Hmac>,
+    pending: BytesMut,
+    sender: Option<oneshot::Sender<Result<(), Error>>>
+}
+
+impl<S: Stream> Stream for Verify<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => {
+                    let tag_len = match self.hmac {
+                        None => return Ok(Async::Ready(None)),
+                        Some(ref hmac) => hmac.algorithm().digest_len()
+                    };
+                    self.pending.extend_from_slice(item.as_ref());
+                    if self.pending.len() > tag_len {
+                        let len = self.pending.len() - tag_len;
+                        let chunk = self.pending.split_to(len).freeze();
+                        if let Some(ref mut hmac) = self.hmac {
+                            hmac.update(&chunk)?;
+                        }
+                        return Ok(Async::Ready(Some(chunk)));
+                    }
+                },
+                Async::Ready(None) => {
+                    let mut hmac = match self.hmac.take() {
+                        None => return Ok(Async::Ready(None)),
+                        Some(hmac) => hmac
+                    };
+                    let verified = hmac.finish()?.verify(&self.pending);
+                    if let Some(sender) = self.sender.take() {
+                        let result = if verified { Ok(()) } else { Err(ErrorKind::AuthenticationFailed.into()) };
+                        sender.send(result).ok();
+                    }
+                    if !verified {
+                        return Err(Error::from(ErrorKind::AuthenticationFailed).into());
+                    }
+                    return Ok(Async::Ready(None));
+                }
+            }
+        }
+    }
+}
+
+const SECRET_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const MAX_MAC_KEY_LEN: usize = 64;
+const ENCRYPTION_INFO: &[u8] = b"cryptonite etm encryption key";
+const AUTHENTICATION_INFO: &[u8] = b"cryptonite etm authentication key";
+
+/// Algorithm that can be used for authenticated encryption.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Algorithm {
+    /// AES with 128-bit keys in Cipher Block Chaining mode, authenticated using HMAC-SHA-256.
+    Aes128CbcHmacSha256,
+    /// AES with 256-bit keys in Cipher Block Chaining mode, authenticated using HMAC-SHA-256.
+    Aes256CbcHmacSha256,
+    /// AES with 256-bit keys in Cipher Block Chaining mode, authenticated using HMAC-SHA-512.
+    Aes256CbcHmacSha512,
+    /// AES with 128-bit keys in Counter mode, authenticated using HMAC-SHA-256.
+    Aes128CtrHmacSha256,
+    /// AES with 256-bit keys in Counter mode, authenticated using HMAC-SHA-256.
+    Aes256CtrHmacSha256,
+    /// AES with 256-bit keys in Counter mode, authenticated using HMAC-SHA-512.
+    Aes256CtrHmacSha512,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Algorithm {
+    fn cipher(self) -> super::Algorithm {
+        match self {
+            Algorithm::Aes128CbcHmacSha256 => super::Algorithm::Aes128Cbc,
+            Algorithm::Aes256CbcHmacSha256 => super::Algorithm::Aes256Cbc,
+            Algorithm::Aes256CbcHmacSha512 => super::Algorithm::Aes256Cbc,
+            Algorithm::Aes128CtrHmacSha256 => super::Algorithm::Aes128Ctr,
+            Algorithm::Aes256CtrHmacSha256 => super::Algorithm::Aes256Ctr,
+            Algorithm::Aes256CtrHmacSha512 => super::Algorithm::Aes256Ctr,
+            Algorithm::_Donotmatch => unreachable!()
+        }
+    }
+
+    fn mac(self) -> hash::Algorithm {
+        match self {
+            Algorithm::Aes256CbcHmacSha512 | Algorithm::Aes256CtrHmacSha512 => hash::Algorithm::Sha512,
+            _ => hash::Algorithm::Sha256
+        }
+    }
+
+    /// Get the length of the authentication tag appended to the ciphertext.
+    pub fn tag_len(self) -> usize {
+        self.mac().digest_len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+    use quickcheck::{Arbitrary, Gen};
+    use self::itertools::Itertools;
+
+    use super::{Algorithm, Config, Decrypt, Encrypt, Error, ErrorKind, MAX_MAC_KEY_LEN};
+
+    const ALL_ALGOS: [Algorithm; 6] = [
+        Algorithm::Aes128CbcHmacSha256,
+        Algorithm::Aes256CbcHmacSha256,
+        Algorithm::Aes256CbcHmacSha512,
+        Algorithm::Aes128CtrHmacSha256,
+        Algorithm::Aes256CtrHmacSha256,
+        Algorithm::Aes256CtrHmacSha512,
+    ];
+
+    impl Arbitrary for Config {
+        fn arbitrary<G: Gen>(g: &mut G) -> Config {
+            let algo = *g.choose(&ALL_ALGOS).unwrap();
+            let mut config = Config::new(algo);
+            g.fill_bytes(config.key_mut());
+            g.fill_bytes(config.iv_mut());
+            config
+        }
+    }
+
+    fn encrypt(config: &Config, data: &[u8]) -> Vec<u8> {
+        let inner = iter_ok::<_, Error>(vec![data.to_vec()]);
+        Encrypt::new(config, inner).unwrap().wait()
+            .collect::<Result<Vec<_>, Error>>().unwrap()
+            .into_iter().concat().to_vec()
+    }
+
+    quickcheck! {
+        fn roundtrip(config: Config, chunks: Vec<Vec<u8>>) -> bool {
+            let data: Vec<u8> = chunks.iter().cloned().concat();
+            let ciphertext = encrypt(&config, &data);
+            let inner = iter_ok::<_, Error>(ciphertext.chunks(5).map(|chunk| chunk.to_vec()).collect::<Vec<_>>());
+            let plaintext = Decrypt::new(&config, inner).unwrap().wait()
+                .collect::<Result<Vec<_>, Error>>().unwrap();
+            plaintext.len() == 1 && plaintext[0] == data
+        }
+    }
+
+    quickcheck! {
+        fn split_roundtrip(config: Config, chunks: Vec<Vec<u8>>) -> bool {
+            let data: Vec<u8> = chunks.iter().cloned().concat();
+            let inner = iter_ok::<_, Error>(vec![encrypt(&config, &data)]);
+            let (verification, decrypt) = Decrypt::new(&config, inner).unwrap().split();
+            let plaintext = decrypt.wait().collect::<Result<Vec<_>, Error>>().unwrap();
+            verification.wait().is_ok() && plaintext.into_iter().concat() == data
+        }
+    }
+
+    #[test]
+    fn tampered() {
+        let mut config = Config::new(Algorithm::Aes128CbcHmacSha256);
+        config.key_mut().copy_from_slice(&[7u8; 32]);
+        let ciphertext = encrypt(&config, &[42u8; 100]);
+        assert_eq!(ciphertext.len(), 112 + 32);
+        for &index in [0, 50, 111, 112, 143].iter() {
+            let mut tampered = ciphertext.clone();
+            tampered[index] ^= 1;
+            let mut decrypt = Decrypt::new(&config, iter_ok::<_, Error>(vec![tampered.clone()])).unwrap().wait();
+            assert_eq!(decrypt.next().unwrap().unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+            let (verification, decrypt) = Decrypt::new(&config, iter_ok::<_, Error>(vec![tampered])).unwrap().split();
+            let result = decrypt.wait().collect::<Result<Vec<_>, Error>>();
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+            assert_eq!(verification.wait().unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let mut config = Config::new(Algorithm::Aes256CtrHmacSha512);
+        config.key_mut().copy_from_slice(&[7u8; 32]);
+        let ciphertext = encrypt(&config, b"foobar");
+        for len in 0..ciphertext.len() {
+            let inner = iter_ok::<_, Error>(vec![ciphertext[..len].to_vec()]);
+            let result = Decrypt::new(&config, inner).unwrap().wait().collect::<Result<Vec<_>, Error>>();
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+        }
+    }
+
+    #[test]
+    fn iv_authenticated() {
+        let mut config = Config::new(Algorithm::Aes128CtrHmacSha256);
+        config.key_mut().copy_from_slice(&[7u8; 32]);
+        let ciphertext = encrypt(&config, b"foobar");
+        config.iv_mut()[0] = 1;
+        let inner = iter_ok::<_, Error>(vec![ciphertext]);
+        let result = Decrypt::new(&config, inner).unwrap().wait().collect::<Result<Vec<_>, Error>>();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+    }
+
+    #[test]
+    fn max_mac_key_len() {
+        let max_len = ALL_ALGOS.iter().map(|algo| algo.tag_len()).max().unwrap();
+        assert_eq!(max_len, MAX_MAC_KEY_LEN);
+    }
+}
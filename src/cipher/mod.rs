@@ -0,0 +1,962 @@
+//! Symmetric ciphers for encryption and decryption of streams.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::ops::{Deref, DerefMut};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, Poll, Stream};
+use openssl;
+use openssl_sys as ffi;
+
+use super::{Error, ErrorKind};
+use super::secret::{Redacted, SecretBuf, zeroize};
+
+pub mod etm;
+pub mod sector;
+pub mod wrap;
+
+/// Configuration for stream adapters.
+///
+/// The key and IV are wiped from memory when the config is dropped.
+#[derive(Clone)]
+pub struct Config {
+    algo: Algorithm,
+    padding: Padding,
+    key: Key,
+    iv: [u8; MAX_IV_LEN]
+}
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Config")
+            .field("algo", &self.algo)
+            .field("padding", &self.padding)
+            .field("key", &Redacted)
+            .field("iv", &Redacted)
+            .finish()
+    }
+}
+
+impl Drop for Config {
+    fn drop(&mut self) {
+        zeroize(&mut self.key);
+        zeroize(&mut self.iv);
+    }
+}
+
+impl Config {
+    /// Initialize a config given an algorithm.
+    pub fn new(algo: Algorithm) -> Config {
+        Config {
+            algo, padding: Padding::Pkcs7, key: Key::Inline([0u8; MAX_KEY_LEN]), iv: [0u8; MAX_IV_LEN]
+        }
+    }
+
+    /// Initialize a config given an algorithm, storing the key
+    /// in a [`SecretBuf`](../secret/struct.SecretBuf.html) that is locked into memory.
+    ///
+    /// Fails if the memory cannot be locked, for instance because
+    /// the limit on locked memory for the process has been reached.
+    pub fn new_locked(algo: Algorithm) -> Result<Config, Error> {
+        Ok(Config {
+            algo, padding: Padding::Pkcs7, key: Key::Locked(SecretBuf::new(MAX_KEY_LEN)?), iv: [0u8; MAX_IV_LEN]
+        })
+    }
+
+    /// Set the padding scheme to be used for block cipher modes.
+    ///
+    /// Defaults to `Padding::Pkcs7`. The padding scheme is ignored for
+    /// modes that operate as stream ciphers, such as CTR and CFB.
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+
+    /// Get a mutable slice of bytes to set the encryption key
+    /// to be used for the cipher.
+    pub fn key_mut(&mut self) -> &mut [u8] {
+        let key_len = self.algo.key_len();
+        &mut self.key[..key_len]
+    }
+
+    pub(crate) fn key(&self) -> &[u8] {
+        &self.key[..self.algo.key_len()]
+    }
+
+    /// Get a mutable slice of bytes to set the [initialization vector]
+    /// (https://en.wikipedia.org/wiki/Initialization_vector)
+    /// to be used for the cipher.
+    ///
+    /// Returns `None` if the selected algorithm does not require an IV.
+    pub fn iv_mut(&mut self) -> Option<&mut [u8]> {
+        match self.algo.iv_len() {
+            None => None,
+            Some(iv_len) => Some(&mut self.iv[..iv_len])
+        }
+    }
+
+    /// Create a decrypting stream adapter that starts at a byte offset into the ciphertext.
+    ///
+    /// The underlying stream is expected to carry the ciphertext starting at `offset`,
+    /// for instance a byte range read from encrypted storage.
+    /// The counter block is advanced to the block containing `offset`, and the
+    /// key stream for the bytes preceding `offset` within that block is discarded.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the selected algorithm does not operate in counter mode.
+    pub fn decrypt_at<S: Stream>(&self, offset: u64, inner: S) -> Result<Decrypt<S>, Error> {
+        if !self.algo.is_ctr() {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let cipher = self.algo.into_cipher()?;
+        let block_size = CTR_BLOCK_LEN as u64;
+        let mut iv = [0u8; MAX_IV_LEN];
+        iv.copy_from_slice(&self.iv);
+        add_counter(&mut iv[..CTR_BLOCK_LEN], offset / block_size);
+        let mut stream = self.stream_with_iv(inner, openssl::symm::Mode::Decrypt, &iv)?;
+        let skip = (offset % block_size) as usize;
+        let mut discard = [0u8; CTR_BLOCK_LEN * 2];
+        stream.crypter.update(&[0u8; CTR_BLOCK_LEN][..skip], &mut discard[..skip + cipher.block_size()])
+            .map_err(Error::from)?;
+        Ok(Decrypt(stream))
+    }
+
+    fn stream<S>(&self, inner: S, mode: openssl::symm::Mode) -> Result<CipherStream<S>, Error> {
+        self.stream_with_iv(inner, mode, &self.iv)
+    }
+
+    fn stream_with_iv<S>(&self, inner: S, mode: openssl::symm::Mode, iv: &[u8]) -> Result<CipherStream<S>, Error> {
+        let cipher = self.algo.into_cipher()?;
+        let block_size = cipher.block_size();
+        let iv = cipher.iv_len().map(|iv_len| &iv[..iv_len]);
+        let key = &self.key[..cipher.key_len()];
+        let padding = if block_size == 1 { Padding::None } else { self.padding };
+        let mut crypter = openssl::symm::Crypter::new(cipher, mode, key, iv)
+            .map_err(Error::from)?;
+        crypter.pad(padding == Padding::Pkcs7);
+        let mut block_crypter = None;
+        if padding == Padding::CiphertextStealing {
+            if iv.is_none() {
+                return Err(ErrorKind::Unsupported.into());
+            }
+            if let openssl::symm::Mode::Decrypt = mode {
+                let zero_iv = [0u8; MAX_IV_LEN];
+                let mut crypter = openssl::symm::Crypter::new(cipher, mode, key, Some(&zero_iv[..block_size]))
+                    .map_err(Error::from)?;
+                crypter.pad(false);
+                block_crypter = Some(crypter);
+            }
+        }
+        Ok(CipherStream {
+            inner, crypter, block_crypter, block_size, mode, padding,
+            pending: BytesMut::new(),
+            unaligned: 0,
+            finalized: false
+        })
+    }
+}
+
+/// Storage for the key of a config.
+#[derive(Clone)]
+enum Key {
+    Inline([u8; MAX_KEY_LEN]),
+    Locked(SecretBuf)
+}
+
+impl Deref for Key {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Key::Inline(ref key) => key,
+            Key::Locked(ref key) => key
+        }
+    }
+}
+
+impl DerefMut for Key {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match *self {
+            Key::Inline(ref mut key) => key,
+            Key::Locked(ref mut key) => key
+        }
+    }
+}
+
+/// Add `n` to a big-endian counter block, wrapping around on overflow.
+fn add_counter(block: &mut [u8], mut n: u64) {
+    let mut carry = 0u64;
+    for byte in block.iter_mut().rev() {
+        let sum = *byte as u64 + (n & 0xff) + carry;
+        *byte = sum as u8;
+        carry = sum >> 8;
+        n >>= 8;
+    }
+}
+
+/// Stream adapter that transparently encrypts the data from the underlying stream.
+#[derive(Debug)]
+pub struct Encrypt<S>(CipherStream<S>);
+
+impl<S: Stream> Encrypt<S> {
+    /// Create an encrypting stream adapter.
+    pub fn new(config: &Config, inner: S) -> Result<Self, Error> {
+        config.stream(inner, openssl::symm::Mode::Encrypt).map(Encrypt)
+    }
+}
+
+impl<S: Stream> Stream for Encrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error; 
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
+
+/// Stream adapter that transparently decrypts the data from the underlying stream.
+#[derive(Debug)]
+pub struct Decrypt<S>(CipherStream<S>);
+
+impl<S: Stream> Decrypt<S> {
+    /// Create a decrypting stream adapter.
+    pub fn new(config: &Config, inner: S) -> Result<Self, Error> {
+        config.stream(inner, openssl::symm::Mode::Decrypt).map(Decrypt)
+    }
+}
+
+impl<S: Stream> Stream for Decrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error; 
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
+
+struct CipherStream<S> {
+    inner: S,
+    finalized: bool,
+    crypter: openssl::symm::Crypter,
+    block_crypter: Option<openssl::symm::Crypter>,
+    block_size: usize,
+    mode: openssl::symm::Mode,
+    padding: Padding,
+    pending: BytesMut,
+    unaligned: usize
+}
+
+impl<S: Debug> Debug for CipherStream<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("CipherStream")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<S> CipherStream<S> {
+    fn is_decrypt(&self) -> bool {
+        match self.mode {
+            openssl::symm::Mode::Encrypt => false,
+            openssl::symm::Mode::Decrypt => true
+        }
+    }
+
+    fn crypt(&mut self, input: &[u8]) -> Result<Bytes, Error> {
+        let mut output = BytesMut::with_capacity(input.len() + self.block_size);
+        unsafe {
+            let len = self.crypter.update(input, output.bytes_mut())
+                .map_err(Error::from)?;
+            output.advance_mut(len);
+        }
+        Ok(output.freeze())
+    }
+
+    fn crypt_final(&mut self) -> Result<Bytes, Error> {
+        let kind = if self.is_decrypt() || self.padding != Padding::Pkcs7 {
+            ErrorKind::BadPadding
+        } else {
+            ErrorKind::Backend
+        };
+        let mut output = BytesMut::with_capacity(self.block_size);
+        unsafe {
+            let len = self.crypter.finalize(output.bytes_mut())
+                .map_err(|err| Error::new(kind, err))?;
+            output.advance_mut(len);
+        }
+        Ok(output.freeze())
+    }
+
+    fn update(&mut self, input: &[u8]) -> Result<Bytes, Error> {
+        self.unaligned = (self.unaligned + input.len() % self.block_size) % self.block_size;
+        match self.padding {
+            Padding::CiphertextStealing => {
+                // Hold back the last two blocks, which are processed when finishing.
+                self.pending.extend_from_slice(input);
+                let keep = 2 * self.block_size;
+                if self.pending.len() <= keep {
+                    return Ok(Bytes::new());
+                }
+                let len = self.pending.len() - keep;
+                let data = self.pending.split_to(len);
+                self.crypt(&data)
+            },
+            Padding::Iso7816 | Padding::AnsiX923 if self.is_decrypt() => {
+                // Hold back the last block, which contains the padding.
+                let output = self.crypt(input)?;
+                self.pending.extend_from_slice(&output);
+                if self.pending.len() <= self.block_size {
+                    return Ok(Bytes::new());
+                }
+                let len = self.pending.len() - self.block_size;
+                Ok(self.pending.split_to(len).freeze())
+            },
+            _ => self.crypt(input)
+        }
+    }
+
+    fn finish(&mut self) -> Result<Bytes, Error> {
+        match self.padding {
+            Padding::CiphertextStealing => self.finish_stealing(),
+            Padding::Iso7816 | Padding::AnsiX923 => {
+                if self.is_decrypt() {
+                    self.crypt_final()?;
+                    let len = self.padding.unpad(&self.pending).ok_or(ErrorKind::BadPadding)?;
+                    Ok(self.pending.split_to(len).freeze())
+                } else {
+                    let padding = self.padding.pad(self.unaligned, self.block_size);
+                    let mut output = BytesMut::from(self.crypt(&padding)?);
+                    output.extend_from_slice(&self.crypt_final()?);
+                    Ok(output.freeze())
+                }
+            },
+            _ => self.crypt_final()
+        }
+    }
+
+    /// Process the last two blocks using ciphertext stealing (CBC-CS3),
+    /// where the final ciphertext block is truncated and swapped with the
+    /// one preceding it.
+    fn finish_stealing(&mut self) -> Result<Bytes, Error> {
+        let block_size = self.block_size;
+        let pending = self.pending.take();
+        if pending.len() <= block_size {
+            if pending.len() < block_size && !pending.is_empty() {
+                return Err(ErrorKind::BadPadding.into());
+            }
+            let mut output = BytesMut::from(self.crypt(&pending)?);
+            output.extend_from_slice(&self.crypt_final()?);
+            return Ok(output.freeze());
+        }
+        let last_len = if self.unaligned == 0 { block_size } else { self.unaligned };
+        let head_len = pending.len() - block_size - last_len;
+        let mut output = BytesMut::from(self.crypt(&pending[..head_len])?);
+        let (penultimate, last) = pending[head_len..].split_at(block_size);
+        let mut block = [0u8; MAX_BLOCK_LEN];
+        if self.is_decrypt() {
+            // The last full ciphertext block decrypts to the zero-padded final plaintext
+            // block, XOR the penultimate ciphertext block. Its tail recovers the stolen bytes.
+            let decrypted = {
+                let block_crypter = self.block_crypter.as_mut().expect("missing block crypter");
+                let mut decrypted = [0u8; 2 * MAX_BLOCK_LEN];
+                block_crypter.update(penultimate, &mut decrypted[..2 * block_size])
+                    .map_err(Error::from)?;
+                decrypted
+            };
+            block[..last_len].copy_from_slice(last);
+            block[last_len..block_size].copy_from_slice(&decrypted[last_len..block_size]);
+            output.extend_from_slice(&self.crypt(&block[..block_size])?);
+            for (byte, key) in block[..last_len].iter_mut().zip(&decrypted[..last_len]) {
+                *byte ^= *key;
+            }
+            output.extend_from_slice(&block[..last_len]);
+        } else {
+            let stolen = self.crypt(penultimate)?;
+            block[..last_len].copy_from_slice(last);
+            output.extend_from_slice(&self.crypt(&block[..block_size])?);
+            output.extend_from_slice(&stolen[..last_len]);
+        }
+        self.crypt_final()?;
+        Ok(output.freeze())
+    }
+}
+
+impl<S: Stream> Stream for CipherStream<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error; 
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.finalized {
+            return Ok(Async::Ready(None));
+        }
+        match self.inner.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => {
+                self.finalized = true;
+                Ok(Async::Ready(Some(self.finish()?)))
+            },
+            Async::Ready(Some(item)) => {
+                Ok(Async::Ready(Some(self.update(item.as_ref())?)))
+            }
+        }
+    }
+}
+
+/// Padding scheme used to extend the data to a multiple of the block size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Padding {
+    /// Padding as specified in PKCS #7, where each padding byte holds the padding length.
+    Pkcs7,
+    /// No padding. The length of the data must be a multiple of the block size.
+    None,
+    /// Padding as specified in ISO/IEC 7816-4, using a single `0x80` byte followed by zeros.
+    Iso7816,
+    /// Padding as specified in ANSI X9.23, using zeros followed by the padding length.
+    AnsiX923,
+    /// Ciphertext stealing in the CBC-CS3 variant, which keeps the ciphertext
+    /// the same length as the plaintext.
+    ///
+    /// Only supported for CBC mode. The data must be at least one block long.
+    CiphertextStealing,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Padding {
+    fn pad(self, unaligned: usize, block_size: usize) -> Vec<u8> {
+        let mut padding = vec![0u8; block_size - unaligned];
+        match self {
+            Padding::Iso7816 => padding[0] = 0x80,
+            Padding::AnsiX923 => *padding.last_mut().unwrap() = (block_size - unaligned) as u8,
+            _ => unreachable!()
+        }
+        padding
+    }
+
+    /// Get the length of the data in a final block without the padding.
+    fn unpad(self, block: &[u8]) -> Option<usize> {
+        let (&last, rest) = block.split_last()?;
+        match self {
+            Padding::Iso7816 => {
+                let len = block.iter().rposition(|byte| *byte != 0)?;
+                if block[len] == 0x80 { Some(len) } else { None }
+            },
+            Padding::AnsiX923 => {
+                let padding_len = last as usize;
+                if padding_len == 0 || padding_len > block.len() {
+                    return None;
+                }
+                let len = block.len() - padding_len;
+                if rest[len..].iter().all(|byte| *byte == 0) { Some(len) } else { None }
+            },
+            _ => unreachable!()
+        }
+    }
+}
+
+pub(crate) const MAX_IV_LEN: usize = 16;
+pub(crate) const MAX_KEY_LEN: usize = 32;
+const MAX_BLOCK_LEN: usize = 16;
+const CTR_BLOCK_LEN: usize = 16;
+
+/// Algorithm that can be used to encrypt or decrypt data.
+///
+/// ARIA and Camellia in CTR mode may not be available in all builds of OpenSSL,
+/// in which case creating a stream adapter fails with `ErrorKind::Unsupported`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Algorithm {
+    /// AES algorithm with 128-bit keys in Electronic Codebook mode.
+    Aes128Ecb,
+    /// AES algorithm with 128-bit keys in Cipher Block Chaining mode.
+    Aes128Cbc,
+    /// AES algorithm with 128-bit keys in Counter mode.
+    Aes128Ctr,
+    /// AES algorithm with 128-bit keys in Cipher Feedback mode with 1-bit feedback.
+    Aes128Cfb1,
+    /// AES algorithm with 128-bit keys in Cipher Feedback mode with 128-bit feedback.
+    Aes128Cfb128,
+    /// AES algorithm with 128-bit keys in Cipher Feedback mode with 8-bit feedback.
+    Aes128Cfb8,
+    /// AES algorithm with 128-bit keys in Output Feedback mode.
+    Aes128Ofb,
+    /// AES algorithm with 192-bit keys in Electronic Codebook mode.
+    Aes192Ecb,
+    /// AES algorithm with 192-bit keys in Cipher Block Chaining mode.
+    Aes192Cbc,
+    /// AES algorithm with 192-bit keys in Counter mode.
+    Aes192Ctr,
+    /// AES algorithm with 192-bit keys in Cipher Feedback mode with 1-bit feedback.
+    Aes192Cfb1,
+    /// AES algorithm with 192-bit keys in Cipher Feedback mode with 128-bit feedback.
+    Aes192Cfb128,
+    /// AES algorithm with 192-bit keys in Cipher Feedback mode with 8-bit feedback.
+    Aes192Cfb8,
+    /// AES algorithm with 192-bit keys in Output Feedback mode.
+    Aes192Ofb,
+    /// AES algorithm with 256-bit keys in Electronic Codebook mode.
+    Aes256Ecb,
+    /// AES algorithm with 256-bit keys in Cipher Block Chaining mode.
+    Aes256Cbc,
+    /// AES algorithm with 256-bit keys in Counter mode.
+    Aes256Ctr,
+    /// AES algorithm with 256-bit keys in Cipher Feedback mode with 1-bit feedback.
+    Aes256Cfb1,
+    /// AES algorithm with 256-bit keys in Cipher Feedback mode with 128-bit feedback.
+    Aes256Cfb128,
+    /// AES algorithm with 256-bit keys in Cipher Feedback mode with 8-bit feedback.
+    Aes256Cfb8,
+    /// AES algorithm with 256-bit keys in Output Feedback mode.
+    Aes256Ofb,
+    /// Camellia algorithm with 128-bit keys in Cipher Block Chaining mode.
+    Camellia128Cbc,
+    /// Camellia algorithm with 128-bit keys in Counter mode.
+    Camellia128Ctr,
+    /// Camellia algorithm with 128-bit keys in Cipher Feedback mode with 128-bit feedback.
+    Camellia128Cfb128,
+    /// Camellia algorithm with 128-bit keys in Output Feedback mode.
+    Camellia128Ofb,
+    /// Camellia algorithm with 256-bit keys in Cipher Block Chaining mode.
+    Camellia256Cbc,
+    /// Camellia algorithm with 256-bit keys in Counter mode.
+    Camellia256Ctr,
+    /// Camellia algorithm with 256-bit keys in Cipher Feedback mode with 128-bit feedback.
+    Camellia256Cfb128,
+    /// Camellia algorithm with 256-bit keys in Output Feedback mode.
+    Camellia256Ofb,
+    /// ARIA algorithm with 128-bit keys in Cipher Block Chaining mode.
+    Aria128Cbc,
+    /// ARIA algorithm with 128-bit keys in Counter mode.
+    Aria128Ctr,
+    /// ARIA algorithm with 128-bit keys in Cipher Feedback mode with 128-bit feedback.
+    Aria128Cfb128,
+    /// ARIA algorithm with 128-bit keys in Output Feedback mode.
+    Aria128Ofb,
+    /// ARIA algorithm with 256-bit keys in Cipher Block Chaining mode.
+    Aria256Cbc,
+    /// ARIA algorithm with 256-bit keys in Counter mode.
+    Aria256Ctr,
+    /// ARIA algorithm with 256-bit keys in Cipher Feedback mode with 128-bit feedback.
+    Aria256Cfb128,
+    /// ARIA algorithm with 256-bit keys in Output Feedback mode.
+    Aria256Ofb,
+    /// SM4 algorithm in Cipher Block Chaining mode.
+    Sm4Cbc,
+    /// SM4 algorithm in Counter mode.
+    Sm4Ctr,
+    /// SM4 algorithm in Cipher Feedback mode with 128-bit feedback.
+    Sm4Cfb128,
+    /// SM4 algorithm in Output Feedback mode.
+    Sm4Ofb,
+    /// Triple DES algorithm with three 64-bit keys in Cipher Block Chaining mode.
+    TripleDesCbc,
+    /// Triple DES algorithm with three 64-bit keys in Cipher Feedback mode with 64-bit feedback.
+    TripleDesCfb64,
+    /// Triple DES algorithm with three 64-bit keys in Output Feedback mode.
+    TripleDesOfb,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Algorithm {
+    pub(crate) fn into_cipher(self) -> Result<openssl::symm::Cipher, Error> {
+        use openssl::symm::Cipher;
+        use self::Algorithm::*;
+        Ok(match self {
+            Aes128Ecb => Cipher::aes_128_ecb(),
+            Aes128Cbc => Cipher::aes_128_cbc(),
+            Aes128Ctr => Cipher::aes_128_ctr(),
+            Aes128Cfb1 => Cipher::aes_128_cfb1(),
+            Aes128Cfb128 => Cipher::aes_128_cfb128(),
+            Aes128Cfb8 => Cipher::aes_128_cfb8(),
+            Aes128Ofb => Cipher::aes_128_ofb(),
+            Aes192Ecb => Cipher::aes_192_ecb(),
+            Aes192Cbc => Cipher::aes_192_cbc(),
+            Aes192Ctr => Cipher::aes_192_ctr(),
+            Aes192Cfb1 => Cipher::aes_192_cfb1(),
+            Aes192Cfb128 => Cipher::aes_192_cfb128(),
+            Aes192Cfb8 => Cipher::aes_192_cfb8(),
+            Aes192Ofb => Cipher::aes_192_ofb(),
+            Aes256Ecb => Cipher::aes_256_ecb(),
+            Aes256Cbc => Cipher::aes_256_cbc(),
+            Aes256Ctr => Cipher::aes_256_ctr(),
+            Aes256Cfb1 => Cipher::aes_256_cfb1(),
+            Aes256Cfb128 => Cipher::aes_256_cfb128(),
+            Aes256Cfb8 => Cipher::aes_256_cfb8(),
+            Aes256Ofb => Cipher::aes_256_ofb(),
+            Camellia128Cbc => Cipher::camellia_128_cbc(),
+            Camellia128Ctr => return cipher_by_name(b"camellia-128-ctr\0"),
+            Camellia128Cfb128 => Cipher::camellia_128_cfb128(),
+            Camellia128Ofb => Cipher::camellia_128_ofb(),
+            Camellia256Cbc => Cipher::camellia_256_cbc(),
+            Camellia256Ctr => return cipher_by_name(b"camellia-256-ctr\0"),
+            Camellia256Cfb128 => Cipher::camellia_256_cfb128(),
+            Camellia256Ofb => Cipher::camellia_256_ofb(),
+            Aria128Cbc => return cipher_by_name(b"aria-128-cbc\0"),
+            Aria128Ctr => return cipher_by_name(b"aria-128-ctr\0"),
+            Aria128Cfb128 => return cipher_by_name(b"aria-128-cfb\0"),
+            Aria128Ofb => return cipher_by_name(b"aria-128-ofb\0"),
+            Aria256Cbc => return cipher_by_name(b"aria-256-cbc\0"),
+            Aria256Ctr => return cipher_by_name(b"aria-256-ctr\0"),
+            Aria256Cfb128 => return cipher_by_name(b"aria-256-cfb\0"),
+            Aria256Ofb => return cipher_by_name(b"aria-256-ofb\0"),
+            Sm4Cbc => Cipher::sm4_cbc(),
+            Sm4Ctr => Cipher::sm4_ctr(),
+            Sm4Cfb128 => Cipher::sm4_cfb128(),
+            Sm4Ofb => Cipher::sm4_ofb(),
+            TripleDesCbc => Cipher::des_ede3_cbc(),
+            TripleDesCfb64 => Cipher::des_ede3_cfb64(),
+            TripleDesOfb => Cipher::des_ede3_ofb(),
+            _Donotmatch => unreachable!()
+        })
+    }
+
+    /// Get the required key length for the algorithm.
+    pub fn key_len(self) -> usize  {
+        use self::Algorithm::*;
+        match self.into_cipher() {
+            Ok(cipher) => cipher.key_len(),
+            // The ciphers looked up by name can be missing from the OpenSSL build.
+            Err(_) => match self {
+                Camellia256Ctr | Aria256Cbc | Aria256Ctr | Aria256Cfb128 | Aria256Ofb => 32,
+                _ => 16
+            }
+        }
+    }
+
+    /// Get the required IV length for the algorithm.
+    ///
+    /// Returns `None` if the algorithm does not require an IV.
+    pub fn iv_len(self) -> Option<usize> {
+        // All of the ciphers looked up by name take a 128-bit IV.
+        self.into_cipher().map(|cipher| cipher.iv_len()).unwrap_or(Some(16))
+    }
+
+    fn is_ctr(self) -> bool {
+        use self::Algorithm::*;
+        matches!(self, Aes128Ctr | Aes192Ctr | Aes256Ctr |
+                       Camellia128Ctr | Camellia256Ctr |
+                       Aria128Ctr | Aria256Ctr | Sm4Ctr)
+    }
+}
+
+/// Look up a cipher that the `openssl` crate provides no constructor for,
+/// which is the case for ARIA and for Camellia in CTR mode.
+///
+/// Fails with `ErrorKind::Unsupported` if the cipher is not part of the OpenSSL build.
+fn cipher_by_name(name: &'static [u8]) -> Result<openssl::symm::Cipher, Error> {
+    ffi::init();
+    unsafe {
+        let cipher = ffi::EVP_get_cipherbyname(name.as_ptr() as *const _);
+        if cipher.is_null() {
+            Err(ErrorKind::Unsupported.into())
+        } else {
+            Ok(openssl::symm::Cipher::from_ptr(cipher))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use bytes::Bytes;
+    use futures::Stream;
+    use hex::ToHex;
+    use self::itertools::Itertools;
+    use quickcheck::{Arbitrary, Gen};
+    use super::{Algorithm, Config, Error, ErrorKind, Encrypt, Decrypt, Padding, MAX_BLOCK_LEN, MAX_KEY_LEN, MAX_IV_LEN, CTR_BLOCK_LEN, add_counter};
+
+    const ALL_ALGOS: [Algorithm; 44] = [
+        Algorithm::Aes128Ecb,
+        Algorithm::Aes128Cbc,
+        Algorithm::Aes128Ctr,
+        Algorithm::Aes128Cfb1,
+        Algorithm::Aes128Cfb128,
+        Algorithm::Aes128Cfb8,
+        Algorithm::Aes128Ofb,
+        Algorithm::Aes192Ecb,
+        Algorithm::Aes192Cbc,
+        Algorithm::Aes192Ctr,
+        Algorithm::Aes192Cfb1,
+        Algorithm::Aes192Cfb128,
+        Algorithm::Aes192Cfb8,
+        Algorithm::Aes192Ofb,
+        Algorithm::Aes256Ecb,
+        Algorithm::Aes256Cbc,
+        Algorithm::Aes256Ctr,
+        Algorithm::Aes256Cfb1,
+        Algorithm::Aes256Cfb128,
+        Algorithm::Aes256Cfb8,
+        Algorithm::Aes256Ofb,
+        Algorithm::Camellia128Cbc,
+        Algorithm::Camellia128Ctr,
+        Algorithm::Camellia128Cfb128,
+        Algorithm::Camellia128Ofb,
+        Algorithm::Camellia256Cbc,
+        Algorithm::Camellia256Ctr,
+        Algorithm::Camellia256Cfb128,
+        Algorithm::Camellia256Ofb,
+        Algorithm::Aria128Cbc,
+        Algorithm::Aria128Ctr,
+        Algorithm::Aria128Cfb128,
+        Algorithm::Aria128Ofb,
+        Algorithm::Aria256Cbc,
+        Algorithm::Aria256Ctr,
+        Algorithm::Aria256Cfb128,
+        Algorithm::Aria256Ofb,
+        Algorithm::Sm4Cbc,
+        Algorithm::Sm4Ctr,
+        Algorithm::Sm4Cfb128,
+        Algorithm::Sm4Ofb,
+        Algorithm::TripleDesCbc,
+        Algorithm::TripleDesCfb64,
+        Algorithm::TripleDesOfb,
+    ];
+
+    impl Arbitrary for Config {
+        fn arbitrary<G: Gen>(g: &mut G) -> Config {
+            let algo = *g.choose(&ALL_ALGOS).unwrap();
+            let mut config = Config::new(algo);
+            g.fill_bytes(config.key_mut());
+            if let Some(iv) = config.iv_mut() {
+                g.fill_bytes(iv);
+            }
+            config
+        }
+    }
+
+    quickcheck! {
+        fn roundtrip(config: Config, chunks: Vec<Vec<u8>>) -> bool {
+            let inner = ::futures::stream::iter_ok::<_, Error>(chunks.clone());
+            let encrypt = Encrypt::new(&config, inner)
+                .expect("encrypt build failed");
+            let decrypt = Decrypt::new(&config, encrypt)
+                .expect("decrypt build failed");
+            let roundtrip_chunks: Vec<Bytes> = decrypt.wait().collect::<Result<Vec<_>, Error>>()
+                .expect("rountrip collect failed");
+            let roundtrip_data = roundtrip_chunks.into_iter().concat();
+            let data: Vec<u8> = chunks.into_iter().concat();
+            data.as_slice() == roundtrip_data.as_ref()
+        }
+    }
+
+    const ALL_PADDINGS: [Padding; 5] = [
+        Padding::Pkcs7,
+        Padding::None,
+        Padding::Iso7816,
+        Padding::AnsiX923,
+        Padding::CiphertextStealing,
+    ];
+
+    fn process(config: &Config, decrypt: bool, chunks: Vec<Vec<u8>>) -> Result<Vec<u8>, Error> {
+        let inner = ::futures::stream::iter_ok::<_, Error>(chunks);
+        let chunks = if decrypt {
+            Decrypt::new(config, inner)?.wait().collect::<Result<Vec<_>, Error>>()?
+        } else {
+            Encrypt::new(config, inner)?.wait().collect::<Result<Vec<_>, Error>>()?
+        };
+        Ok(chunks.into_iter().concat().to_vec())
+    }
+
+    quickcheck! {
+        fn padding_roundtrip(key: Vec<u8>, cbc: bool, padding: usize, chunks: Vec<Vec<u8>>) -> bool {
+            let algo = if cbc { Algorithm::Aes128Cbc } else { Algorithm::Aes128Ecb };
+            let padding = ALL_PADDINGS[padding % ALL_PADDINGS.len()];
+            let mut config = Config::new(algo);
+            config.set_padding(padding);
+            for (dst, src) in config.key_mut().iter_mut().zip(key) { *dst = src; }
+            let data: Vec<u8> = chunks.iter().cloned().concat();
+            let ciphertext = match process(&config, false, chunks) {
+                Ok(ciphertext) => ciphertext,
+                Err(err) => return match padding {
+                    Padding::None => err.kind() == ErrorKind::BadPadding && !data.len().is_multiple_of(16),
+                    Padding::CiphertextStealing if cbc => err.kind() == ErrorKind::BadPadding && data.len() < 16,
+                    Padding::CiphertextStealing => err.kind() == ErrorKind::Unsupported,
+                    _ => false
+                }
+            };
+            let len_ok = match padding {
+                Padding::None | Padding::CiphertextStealing => ciphertext.len() == data.len(),
+                _ => ciphertext.len() == (data.len() / 16 + 1) * 16
+            };
+            let chunks = ciphertext.chunks(7).map(|chunk| chunk.to_vec()).collect();
+            len_ok && process(&config, true, chunks).unwrap() == data
+        }
+    }
+
+    #[test]
+    fn ciphertext_stealing_vectors() {
+        // Test vectors from RFC 3962, Appendix B.
+        let plaintext = b"I would like the General Gau's Chicken, please, and wonton soup.";
+        let vectors = [
+            (17, "c6353568f2bf8cb4d8a580362da7ff7f97"),
+            (31, "fc00783e0efdb2c1d445d4c8eff7ed2297687268d6ecccc0c07b25e25ecfe5"),
+            (32, "39312523a78662d5be7fcbcc98ebf5a897687268d6ecccc0c07b25e25ecfe584"),
+            (47, "97687268d6ecccc0c07b25e25ecfe584b3fffd940c16a18c1b5549d2f838029e\
+                  39312523a78662d5be7fcbcc98ebf5"),
+            (64, "97687268d6ecccc0c07b25e25ecfe58439312523a78662d5be7fcbcc98ebf5a8\
+                  4807efe836ee89a526730dbc2f7bc8409dad8bbb96c4cdc03bc103e1a194bbd8"),
+        ];
+        let mut config = Config::new(Algorithm::Aes128Cbc);
+        config.set_padding(Padding::CiphertextStealing);
+        config.key_mut().copy_from_slice(b"chicken teriyaki");
+        for &(len, expected) in vectors.iter() {
+            let ciphertext = process(&config, false, vec![plaintext[..len].to_vec()]).unwrap();
+            assert_eq!(ciphertext.to_hex(), expected);
+            let decrypted = process(&config, true, vec![ciphertext]).unwrap();
+            assert_eq!(&decrypted[..], &plaintext[..len]);
+        }
+    }
+
+    #[test]
+    fn padding_bytes() {
+        let mut config = Config::new(Algorithm::Aes128Cbc);
+        config.key_mut().copy_from_slice(b"YELLOW SUBMARINE");
+        let expected = [
+            (Padding::Pkcs7, [0x41u8, 0x42, 0x43, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13]),
+            (Padding::Iso7816, [0x41u8, 0x42, 0x43, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            (Padding::AnsiX923, [0x41u8, 0x42, 0x43, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 13]),
+        ];
+        for &(padding, ref block) in expected.iter() {
+            config.set_padding(padding);
+            let ciphertext = process(&config, false, vec![b"ABC".to_vec()]).unwrap();
+            config.set_padding(Padding::None);
+            let decrypted = process(&config, true, vec![ciphertext]).unwrap();
+            assert_eq!(&decrypted[..], &block[..]);
+        }
+    }
+
+    #[test]
+    fn invalid_padding() {
+        let mut config = Config::new(Algorithm::Aes128Ecb);
+        config.key_mut().copy_from_slice(b"YELLOW SUBMARINE");
+        config.set_padding(Padding::None);
+        let ciphertext = process(&config, false, vec![vec![0x41u8; 16]]).unwrap();
+        for &padding in [Padding::Iso7816, Padding::AnsiX923].iter() {
+            config.set_padding(padding);
+            let err = process(&config, true, vec![ciphertext.clone()]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BadPadding);
+        }
+    }
+
+    quickcheck! {
+        fn decrypt_at(key: Vec<u8>, iv: Vec<u8>, data: Vec<u8>, offset: usize) -> bool {
+            let mut config = Config::new(Algorithm::Aes128Ctr);
+            for (dst, src) in config.key_mut().iter_mut().zip(key) { *dst = src; }
+            for (dst, src) in config.iv_mut().unwrap().iter_mut().zip(iv) { *dst = src; }
+            let offset = if data.is_empty() { 0 } else { offset % data.len() };
+            let inner = ::futures::stream::iter_ok::<_, Error>(vec![data.clone()]);
+            let ciphertext = Encrypt::new(&config, inner).unwrap()
+                .wait().collect::<Result<Vec<_>, Error>>().unwrap()
+                .into_iter().concat();
+            let inner = ::futures::stream::iter_ok::<_, Error>(vec![ciphertext[offset..].to_vec()]);
+            let plaintext = config.decrypt_at(offset as u64, inner).unwrap()
+                .wait().collect::<Result<Vec<_>, Error>>().unwrap()
+                .into_iter().concat();
+            plaintext.as_ref() == &data[offset..]
+        }
+    }
+
+    #[test]
+    fn add_counter_carry() {
+        let mut block = [0u8, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe];
+        add_counter(&mut block, 3);
+        assert_eq!(block, [0u8, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let mut block = [0xffu8; 16];
+        add_counter(&mut block, 1);
+        assert_eq!(block, [0u8; 16]);
+    }
+
+    #[test]
+    fn decrypt_at_requires_ctr() {
+        let config = Config::new(Algorithm::Aes128Cbc);
+        let inner = ::futures::stream::iter_ok::<Vec<u8>, Error>(vec![]);
+        assert_eq!(config.decrypt_at(1, inner).unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn bad_padding() {
+        let mut config = Config::new(Algorithm::Aes128Cbc);
+        config.key_mut().copy_from_slice(b"YELLOW SUBMARINE");
+        let inner = ::futures::stream::iter_ok::<_, Error>(vec![vec![0u8; 16]]);
+        let result = Decrypt::new(&config, inner).unwrap().wait().collect::<Result<Vec<_>, Error>>();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::BadPadding);
+    }
+
+    #[test]
+    fn debug_redacted() {
+        let mut config = Config::new(Algorithm::Aes128Cbc);
+        config.key_mut().copy_from_slice(b"YELLOW SUBMARINE");
+        let debug = format!("{:?}", config);
+        assert_eq!(debug, "Config { algo: Aes128Cbc, padding: Pkcs7, key: <redacted>, iv: <redacted> }");
+    }
+
+    #[test]
+    fn locked_roundtrip() {
+        let mut config = Config::new_locked(Algorithm::Aes256Ctr).unwrap();
+        config.key_mut().copy_from_slice(&[42u8; 32]);
+        let inner = ::futures::stream::iter_ok::<_, Error>(vec!["foo", "bar"]);
+        let decrypt = Decrypt::new(&config, Encrypt::new(&config.clone(), inner).unwrap()).unwrap();
+        let data = decrypt.wait().collect::<Result<Vec<_>, Error>>().unwrap().into_iter().concat();
+        assert_eq!(data.as_ref(), b"foobar");
+    }
+
+    #[test]
+    fn known_answers() {
+        // Single-block vectors from RFC 3713 (Camellia) and GB/T 32907-2016 (SM4),
+        // using CBC with a zero IV to encrypt a single block.
+        let block = [0x01u8, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54, 0x32, 0x10];
+        let vectors = [
+            (Algorithm::Camellia128Cbc, "67673138549669730857065648eabe43"),
+            (Algorithm::Sm4Cbc, "681edf34d206965e86b3e94f536e4246"),
+        ];
+        for &(algo, expected) in vectors.iter() {
+            let mut config = Config::new(algo);
+            config.set_padding(Padding::None);
+            config.key_mut().copy_from_slice(&block);
+            let ciphertext = process(&config, false, vec![block.to_vec()]).unwrap();
+            assert_eq!(ciphertext.to_hex(), expected);
+        }
+    }
+
+    #[test]
+    fn algorithm_params() {
+        for algo in ALL_ALGOS.iter() {
+            let cipher = match algo.into_cipher() {
+                Ok(cipher) => cipher,
+                // ARIA may be disabled in the OpenSSL build.
+                Err(ref err) if err.kind() == ErrorKind::Unsupported => continue,
+                Err(err) => panic!("{:?}: {}", algo, err)
+            };
+            assert_eq!(algo.key_len(), cipher.key_len(), "{:?}", algo);
+            assert_eq!(algo.iv_len(), cipher.iv_len(), "{:?}", algo);
+            assert!(cipher.block_size() <= MAX_BLOCK_LEN, "{:?}", algo);
+            if algo.is_ctr() {
+                assert_eq!(algo.iv_len(), Some(CTR_BLOCK_LEN), "{:?}", algo);
+            }
+        }
+    }
+
+    #[test]
+    fn max_key_len() {
+        let max_key_len = ALL_ALGOS.iter().map(|algo| algo.key_len()).max().unwrap();
+        assert_eq!(max_key_len, MAX_KEY_LEN);
+    }
+
+    #[test]
+    fn max_iv_len() {
+        let max_iv_len = ALL_ALGOS.iter().filter_map(|algo| algo.iv_len()).max().unwrap();
+        assert_eq!(max_iv_len, MAX_IV_LEN);
+    }
+}
@@ -0,0 +1,368 @@
+//! Sector-based encryption for block-device style storage.
+//!
+//! Unlike the stream adapters in the parent module, sectors are encrypted
+//! independently of each other, using the sector number as a tweak.
+//! This allows any sector to be read or rewritten without touching its neighbours.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, Poll, Stream};
+use openssl;
+
+use super::super::{Error, ErrorKind};
+use super::super::secret::{Redacted, zeroize};
+
+/// Configuration for sector encryption.
+#[derive(Clone)]
+pub struct Config {
+    algo: Algorithm,
+    key: [u8; MAX_KEY_LEN],
+    sector_size: usize
+}
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Config")
+            .field("algo", &self.algo)
+            .field("key", &Redacted)
+            .field("sector_size", &self.sector_size)
+            .finish()
+    }
+}
+
+impl Drop for Config {
+    fn drop(&mut self) {
+        zeroize(&mut self.key);
+    }
+}
+
+impl Config {
+    /// Initialize a config given an algorithm and the size of a sector in bytes.
+    ///
+    /// Fails with `ErrorKind::Malformed` if `sector_size` is smaller than
+    /// the block size of the cipher (16 bytes).
+    pub fn new(algo: Algorithm, sector_size: usize) -> Result<Config, Error> {
+        if sector_size < BLOCK_LEN {
+            return Err(ErrorKind::Malformed.into());
+        }
+        Ok(Config {
+            algo, key: [0u8; MAX_KEY_LEN], sector_size
+        })
+    }
+
+    /// Get a mutable slice of bytes to set the encryption key
+    /// to be used for the cipher.
+    ///
+    /// XTS mode uses two keys of equal length, so the slice is twice
+    /// as long as the key size of the underlying block cipher.
+    pub fn key_mut(&mut self) -> &mut [u8] {
+        let key_len = self.algo.key_len();
+        &mut self.key[..key_len]
+    }
+
+    /// Get the size of a sector in bytes.
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    /// Encrypt the contents of a single sector.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the data is shorter than the block size
+    /// of the cipher (16 bytes), which ciphertext stealing requires.
+    pub fn encrypt_sector(&self, sector: u64, data: &[u8]) -> Result<Bytes, Error> {
+        self.crypt_sector(openssl::symm::Mode::Encrypt, sector, data)
+    }
+
+    /// Decrypt the contents of a single sector.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the data is shorter than the block size
+    /// of the cipher (16 bytes).
+    pub fn decrypt_sector(&self, sector: u64, data: &[u8]) -> Result<Bytes, Error> {
+        self.crypt_sector(openssl::symm::Mode::Decrypt, sector, data)
+    }
+
+    fn crypt_sector(&self, mode: openssl::symm::Mode, sector: u64, data: &[u8]) -> Result<Bytes, Error> {
+        if data.len() < BLOCK_LEN {
+            return Err(ErrorKind::Malformed.into());
+        }
+        let cipher = self.algo.into_cipher();
+        let key = &self.key[..cipher.key_len()];
+        let tweak = tweak(sector);
+        let mut crypter = openssl::symm::Crypter::new(cipher, mode, key, Some(&tweak))
+            .map_err(Error::from)?;
+        let mut output = BytesMut::with_capacity(data.len() + cipher.block_size());
+        unsafe {
+            let len = crypter.update(data, output.bytes_mut())
+                .map_err(Error::from)?;
+            output.advance_mut(len);
+            let len = crypter.finalize(output.bytes_mut())
+                .map_err(Error::from)?;
+            output.advance_mut(len);
+        }
+        Ok(output.freeze())
+    }
+}
+
+/// Encode a sector number as a tweak, following IEEE P1619.
+fn tweak(sector: u64) -> [u8; BLOCK_LEN] {
+    let mut tweak = [0u8; BLOCK_LEN];
+    for (i, byte) in tweak.iter_mut().take(8).enumerate() {
+        *byte = (sector >> (8 * i)) as u8;
+    }
+    tweak
+}
+
+/// Stream adapter that encrypts the data from the underlying stream sector by sector.
+///
+/// The data is regrouped into sectors of the configured size, each of which is
+/// yielded as a separate item. If the data does not end on a sector boundary,
+/// the last sector is shorter than the others. It must still be at least as long as
+/// the block size of the cipher (16 bytes), otherwise the stream fails with
+/// `ErrorKind::Malformed` when it reaches the end of the data.
+#[derive(Debug)]
+pub struct EncryptSectors<S>(SectorStream<S>);
+
+impl<S: Stream> EncryptSectors<S> {
+    /// Create an encrypting stream adapter, starting at the given sector number.
+    pub fn new(config: &Config, first_sector: u64, inner: S) -> Self {
+        EncryptSectors(SectorStream::new(config, openssl::symm::Mode::Encrypt, first_sector, inner))
+    }
+}
+
+impl<S: Stream> Stream for EncryptSectors<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
+
+/// Stream adapter that decrypts the data from the underlying stream sector by sector.
+///
+/// See [`EncryptSectors`](struct.EncryptSectors.html) for how data is grouped into sectors.
+#[derive(Debug)]
+pub struct DecryptSectors<S>(SectorStream<S>);
+
+impl<S: Stream> DecryptSectors<S> {
+    /// Create a decrypting stream adapter, starting at the given sector number.
+    pub fn new(config: &Config, first_sector: u64, inner: S) -> Self {
+        DecryptSectors(SectorStream::new(config, openssl::symm::Mode::Decrypt, first_sector, inner))
+    }
+}
+
+impl<S: Stream> Stream for DecryptSectors<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.0.poll()
+    }
+}
+
+struct SectorStream<S> {
+    inner: S,
+    config: Config,
+    mode: openssl::symm::Mode,
+    sector: u64,
+    buffer: BytesMut,
+    finished: bool
+}
+
+impl<S: Debug> Debug for SectorStream<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("SectorStream")
+            .field("inner", &self.inner)
+            .field("sector", &self.sector)
+            .finish()
+    }
+}
+
+impl<S: Stream> SectorStream<S> {
+    fn new(config: &Config, mode: openssl::symm::Mode, sector: u64, inner: S) -> Self {
+        SectorStream {
+            inner, mode, sector,
+            config: config.clone(),
+            buffer: BytesMut::new(),
+            finished: false
+        }
+    }
+
+    fn next_sector(&mut self, len: usize) -> Result<Bytes, Error> {
+        let data = self.buffer.split_to(len);
+        let output = self.config.crypt_sector(self.mode, self.sector, &data)?;
+        self.sector = self.sector.wrapping_add(1);
+        Ok(output)
+    }
+}
+
+impl<S: Stream> Stream for SectorStream<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let sector_size = self.config.sector_size;
+            if self.buffer.len() >= sector_size {
+                let output = self.next_sector(sector_size)?;
+                return Ok(Async::Ready(Some(output)));
+            }
+            if self.finished {
+                if self.buffer.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+                let len = self.buffer.len();
+                let output = self.next_sector(len)?;
+                return Ok(Async::Ready(Some(output)));
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => self.finished = true,
+                Async::Ready(Some(item)) => self.buffer.extend_from_slice(item.as_ref())
+            }
+        }
+    }
+}
+
+const BLOCK_LEN: usize = 16;
+const MAX_KEY_LEN: usize = 64;
+
+/// Algorithm that can be used to encrypt or decrypt sectors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Algorithm {
+    /// AES algorithm with two 128-bit keys in XEX-based tweaked-codebook mode with ciphertext stealing.
+    Aes128Xts,
+    /// AES algorithm with two 256-bit keys in XEX-based tweaked-codebook mode with ciphertext stealing.
+    Aes256Xts,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Algorithm {
+    fn into_cipher(self) -> openssl::symm::Cipher {
+        use openssl::symm::Cipher;
+        use self::Algorithm::*;
+        match self {
+            Aes128Xts => Cipher::aes_128_xts(),
+            Aes256Xts => Cipher::aes_256_xts(),
+            _Donotmatch => unreachable!()
+        }
+    }
+
+    /// Get the required key length for the algorithm.
+    ///
+    /// This is the combined length of both keys.
+    pub fn key_len(self) -> usize  {
+        self.into_cipher().key_len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use bytes::Bytes;
+    use futures::Stream;
+    use hex::ToHex;
+    use self::itertools::Itertools;
+    use quickcheck::{Arbitrary, Gen};
+    use super::{Algorithm, Config, Error, ErrorKind, EncryptSectors, DecryptSectors, MAX_KEY_LEN};
+
+    const ALL_ALGOS: [Algorithm; 2] = [
+        Algorithm::Aes128Xts,
+        Algorithm::Aes256Xts,
+    ];
+
+    impl Arbitrary for Config {
+        fn arbitrary<G: Gen>(g: &mut G) -> Config {
+            let algo = *g.choose(&ALL_ALGOS).unwrap();
+            let sector_size = g.gen_range(16, 600);
+            let mut config = Config::new(algo, sector_size).unwrap();
+            g.fill_bytes(config.key_mut());
+            config
+        }
+    }
+
+    quickcheck! {
+        fn roundtrip(config: Config, first_sector: u64, chunks: Vec<Vec<u8>>) -> bool {
+            let data: Vec<u8> = chunks.iter().cloned().concat();
+            let inner = ::futures::stream::iter_ok::<_, Error>(chunks);
+            let encrypt = EncryptSectors::new(&config, first_sector, inner);
+            let decrypt = DecryptSectors::new(&config, first_sector, encrypt);
+            let result = decrypt.wait().collect::<Result<Vec<_>, Error>>();
+            let last_len = data.len() % config.sector_size();
+            if last_len != 0 && last_len < 16 {
+                return result.unwrap_err().kind() == ErrorKind::Malformed;
+            }
+            let roundtrip_sectors: Vec<Bytes> = result.expect("roundtrip collect failed");
+            let sectors_ok = roundtrip_sectors.iter().dropping_back(1)
+                .all(|sector| sector.len() == config.sector_size());
+            let roundtrip_data = roundtrip_sectors.into_iter().concat();
+            sectors_ok && data.as_slice() == roundtrip_data.as_ref()
+        }
+    }
+
+    #[test]
+    fn ieee_p1619_vector_2() {
+        let mut config = Config::new(Algorithm::Aes128Xts, 32).unwrap();
+        config.key_mut()[..16].copy_from_slice(&[0x11; 16]);
+        config.key_mut()[16..].copy_from_slice(&[0x22; 16]);
+        let ciphertext = config.encrypt_sector(0x3333333333, &[0x44; 32]).unwrap();
+        assert_eq!(ciphertext.to_hex(), "c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0");
+        let plaintext = config.decrypt_sector(0x3333333333, &ciphertext).unwrap();
+        assert_eq!(plaintext.as_ref(), &[0x44; 32][..]);
+    }
+
+    #[test]
+    fn sectors_differ() {
+        let mut config = Config::new(Algorithm::Aes256Xts, 16).unwrap();
+        for (i, byte) in config.key_mut().iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let inner = ::futures::stream::iter_ok::<_, Error>(vec![vec![0u8; 32]]);
+        let sectors = EncryptSectors::new(&config, 7, inner)
+            .wait().collect::<Result<Vec<_>, Error>>().unwrap();
+        assert_eq!(sectors.len(), 2);
+        assert!(sectors[0] != sectors[1]);
+        assert_eq!(sectors[1], config.encrypt_sector(8, &[0u8; 16]).unwrap());
+    }
+
+    #[test]
+    fn short_sectors() {
+        assert_eq!(Config::new(Algorithm::Aes128Xts, 15).unwrap_err().kind(), ErrorKind::Malformed);
+        let mut config = Config::new(Algorithm::Aes128Xts, 32).unwrap();
+        for (i, byte) in config.key_mut().iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(config.encrypt_sector(0, &[0; 15]).unwrap_err().kind(), ErrorKind::Malformed);
+        assert_eq!(config.decrypt_sector(0, &[]).unwrap_err().kind(), ErrorKind::Malformed);
+        assert_eq!(config.encrypt_sector(0, &[0; 17]).unwrap().len(), 17);
+
+        // A final sector of 15 bytes cannot be encrypted, but one of 16 bytes can.
+        let inner = ::futures::stream::iter_ok::<_, Error>(vec![vec![0u8; 47]]);
+        let mut sectors = EncryptSectors::new(&config, 0, inner).wait();
+        assert_eq!(sectors.next().unwrap().unwrap().len(), 32);
+        assert_eq!(sectors.next().unwrap().unwrap_err().kind(), ErrorKind::Malformed);
+        let inner = ::futures::stream::iter_ok::<_, Error>(vec![vec![0u8; 48]]);
+        let sectors = EncryptSectors::new(&config, 0, inner).wait().collect::<Result<Vec<_>, Error>>().unwrap();
+        assert_eq!(sectors.iter().map(|sector| sector.len()).collect::<Vec<_>>(), vec![32, 16]);
+    }
+
+    #[test]
+    fn max_key_len() {
+        let max_key_len = ALL_ALGOS.iter().map(|algo| algo.key_len()).max().unwrap();
+        assert_eq!(max_key_len, MAX_KEY_LEN);
+    }
+}
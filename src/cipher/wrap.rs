@@ -0,0 +1,378 @@
+//! Key wrapping for storing encryption keys under a key-encryption key.
+//!
+//! Implements the AES key wrap algorithm as specified in
+//! [RFC 3394](https://tools.ietf.org/html/rfc3394), and its padded variant as
+//! specified in [RFC 5649](https://tools.ietf.org/html/rfc5649).
+//! Wrapped keys are integrity-protected, so unwrapping with the wrong
+//! key-encryption key or tampered input fails with an error.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use openssl;
+
+use super::super::{Error, ErrorKind};
+use super::super::secret::{Redacted, zeroize};
+
+/// Configuration for key wrapping, holding the key-encryption key.
+#[derive(Clone)]
+pub struct Config {
+    algo: Algorithm,
+    key: [u8; MAX_KEY_LEN]
+}
+
+impl Debug for Config {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Config")
+            .field("algo", &self.algo)
+            .field("key", &Redacted)
+            .finish()
+    }
+}
+
+impl Drop for Config {
+    fn drop(&mut self) {
+        zeroize(&mut self.key);
+    }
+}
+
+impl Config {
+    /// Initialize a config given an algorithm.
+    pub fn new(algo: Algorithm) -> Config {
+        Config {
+            algo, key: [0u8; MAX_KEY_LEN]
+        }
+    }
+
+    /// Get a mutable slice of bytes to set the key-encryption key.
+    pub fn key_mut(&mut self) -> &mut [u8] {
+        let key_len = self.algo.key_len();
+        &mut self.key[..key_len]
+    }
+
+    /// Wrap the key of a cipher configuration.
+    ///
+    /// Only the key is wrapped. The initialization vector is not secret and
+    /// needs to be stored or transmitted separately.
+    pub fn wrap_config(&self, config: &super::Config) -> Result<Vec<u8>, Error> {
+        self.wrap(config.key())
+    }
+
+    /// Unwrap a key into a cipher configuration for the given algorithm.
+    ///
+    /// The initialization vector of the returned configuration is zeroed.
+    /// Fails if the wrapped key does not have the length required by the algorithm.
+    pub fn unwrap_config(&self, algo: super::Algorithm, wrapped: &[u8]) -> Result<super::Config, Error> {
+        let mut key = self.unwrap(wrapped)?;
+        let mut config = super::Config::new(algo);
+        let valid = key.len() == config.key_mut().len();
+        if valid {
+            config.key_mut().copy_from_slice(&key);
+        }
+        zeroize(&mut key);
+        if !valid {
+            return Err(ErrorKind::InvalidKeyLength.into());
+        }
+        Ok(config)
+    }
+
+    /// Wrap a key.
+    ///
+    /// Fails with `ErrorKind::InvalidKeyLength` if the algorithm does not use padding
+    /// and the key length is not a multiple of 8 bytes, or shorter than 16 bytes,
+    /// or if the algorithm uses padding and the key is empty.
+    pub fn wrap(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut block = Block::new(&self.key[..self.algo.key_len()], self.algo, openssl::symm::Mode::Encrypt)?;
+        if self.algo.is_padded() {
+            // The length is encoded in 32 bits, and must not be zero (RFC 5649, section 3).
+            if key.is_empty() || key.len() > u32::MAX as usize {
+                return Err(ErrorKind::InvalidKeyLength.into());
+            }
+            let mut iv = [0u8; SEMIBLOCK_LEN];
+            iv[..4].copy_from_slice(&PADDED_IV_PREFIX);
+            for (i, byte) in iv[4..].iter_mut().enumerate() {
+                *byte = (key.len() as u32 >> (24 - 8 * i)) as u8;
+            }
+            let padded_len = key.len().div_ceil(SEMIBLOCK_LEN) * SEMIBLOCK_LEN;
+            let mut output = vec![0u8; SEMIBLOCK_LEN + padded_len];
+            output[..SEMIBLOCK_LEN].copy_from_slice(&iv);
+            output[SEMIBLOCK_LEN..SEMIBLOCK_LEN + key.len()].copy_from_slice(key);
+            if padded_len == SEMIBLOCK_LEN {
+                let mut input = [0u8; 2 * SEMIBLOCK_LEN];
+                input.copy_from_slice(&output);
+                let result = block.apply(&input, &mut output);
+                zeroize(&mut input);
+                result?;
+            } else {
+                wrap_semiblocks(&mut block, &mut output)?;
+            }
+            Ok(output)
+        } else {
+            if !key.len().is_multiple_of(SEMIBLOCK_LEN) || key.len() < 2 * SEMIBLOCK_LEN {
+                return Err(ErrorKind::InvalidKeyLength.into());
+            }
+            let mut output = vec![0u8; SEMIBLOCK_LEN + key.len()];
+            output[..SEMIBLOCK_LEN].copy_from_slice(&DEFAULT_IV);
+            output[SEMIBLOCK_LEN..].copy_from_slice(key);
+            wrap_semiblocks(&mut block, &mut output)?;
+            Ok(output)
+        }
+    }
+
+    /// Unwrap a key, verifying its integrity.
+    pub fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, Error> {
+        if !wrapped.len().is_multiple_of(SEMIBLOCK_LEN) || wrapped.len() < 2 * SEMIBLOCK_LEN {
+            return Err(ErrorKind::AuthenticationFailed.into());
+        }
+        let mut block = Block::new(&self.key[..self.algo.key_len()], self.algo, openssl::symm::Mode::Decrypt)?;
+        let mut output = wrapped.to_vec();
+        let key = self.unwrap_in_place(&mut block, &mut output)
+            .map(|key_len| output[SEMIBLOCK_LEN..SEMIBLOCK_LEN + key_len].to_vec());
+        zeroize(&mut output);
+        key
+    }
+
+    /// Unwrap a key in place, returning the length of the key that follows the initial value.
+    fn unwrap_in_place(&self, block: &mut Block, data: &mut [u8]) -> Result<usize, Error> {
+        if self.algo.is_padded() {
+            if data.len() == 2 * SEMIBLOCK_LEN {
+                let mut input = [0u8; 2 * SEMIBLOCK_LEN];
+                input.copy_from_slice(data);
+                block.apply(&input, data)?;
+            } else {
+                unwrap_semiblocks(block, data)?;
+            }
+            let padded_len = data.len() - SEMIBLOCK_LEN;
+            let key_len = data[4..SEMIBLOCK_LEN].iter()
+                .fold(0usize, |acc, byte| (acc << 8) | *byte as usize);
+            let valid = openssl::memcmp::eq(&data[..4], &PADDED_IV_PREFIX)
+                && key_len <= padded_len && key_len + SEMIBLOCK_LEN > padded_len
+                && data[SEMIBLOCK_LEN + key_len..].iter().all(|byte| *byte == 0);
+            if !valid {
+                return Err(ErrorKind::AuthenticationFailed.into());
+            }
+            Ok(key_len)
+        } else {
+            unwrap_semiblocks(block, data)?;
+            if !openssl::memcmp::eq(&data[..SEMIBLOCK_LEN], &DEFAULT_IV) {
+                return Err(ErrorKind::AuthenticationFailed.into());
+            }
+            Ok(data.len() - SEMIBLOCK_LEN)
+        }
+    }
+}
+
+/// Apply the wrapping function `W` to a buffer holding the
+/// initial value followed by the key data, in place.
+fn wrap_semiblocks(block: &mut Block, data: &mut [u8]) -> Result<(), Error> {
+    let n = data.len() / SEMIBLOCK_LEN - 1;
+    let mut input = [0u8; 2 * SEMIBLOCK_LEN];
+    let mut output = [0u8; 2 * SEMIBLOCK_LEN];
+    for j in 0..6 {
+        for i in 1..n + 1 {
+            input[..SEMIBLOCK_LEN].copy_from_slice(&data[..SEMIBLOCK_LEN]);
+            input[SEMIBLOCK_LEN..].copy_from_slice(&data[i * SEMIBLOCK_LEN..(i + 1) * SEMIBLOCK_LEN]);
+            block.apply(&input, &mut output)?;
+            data[..SEMIBLOCK_LEN].copy_from_slice(&output[..SEMIBLOCK_LEN]);
+            xor_counter(&mut data[..SEMIBLOCK_LEN], (n * j + i) as u64);
+            data[i * SEMIBLOCK_LEN..(i + 1) * SEMIBLOCK_LEN].copy_from_slice(&output[SEMIBLOCK_LEN..]);
+        }
+    }
+    zeroize(&mut input);
+    zeroize(&mut output);
+    Ok(())
+}
+
+/// Apply the inverse wrapping function `W⁻¹` to a buffer holding
+/// the wrapped key, in place.
+fn unwrap_semiblocks(block: &mut Block, data: &mut [u8]) -> Result<(), Error> {
+    let n = data.len() / SEMIBLOCK_LEN - 1;
+    let mut input = [0u8; 2 * SEMIBLOCK_LEN];
+    let mut output = [0u8; 2 * SEMIBLOCK_LEN];
+    for j in (0..6).rev() {
+        for i in (1..n + 1).rev() {
+            input[..SEMIBLOCK_LEN].copy_from_slice(&data[..SEMIBLOCK_LEN]);
+            xor_counter(&mut input[..SEMIBLOCK_LEN], (n * j + i) as u64);
+            input[SEMIBLOCK_LEN..].copy_from_slice(&data[i * SEMIBLOCK_LEN..(i + 1) * SEMIBLOCK_LEN]);
+            block.apply(&input, &mut output)?;
+            data[..SEMIBLOCK_LEN].copy_from_slice(&output[..SEMIBLOCK_LEN]);
+            data[i * SEMIBLOCK_LEN..(i + 1) * SEMIBLOCK_LEN].copy_from_slice(&output[SEMIBLOCK_LEN..]);
+        }
+    }
+    zeroize(&mut input);
+    zeroize(&mut output);
+    Ok(())
+}
+
+fn xor_counter(semiblock: &mut [u8], t: u64) {
+    for (i, byte) in semiblock.iter_mut().enumerate() {
+        *byte ^= (t >> (56 - 8 * i)) as u8;
+    }
+}
+
+/// Raw AES block cipher.
+struct Block {
+    crypter: openssl::symm::Crypter,
+    buffer: [u8; 4 * SEMIBLOCK_LEN]
+}
+
+impl Block {
+    fn new(key: &[u8], algo: Algorithm, mode: openssl::symm::Mode) -> Result<Block, Error> {
+        let mut crypter = openssl::symm::Crypter::new(algo.into_cipher(), mode, key, None)
+            .map_err(Error::from)?;
+        crypter.pad(false);
+        Ok(Block { crypter, buffer: [0u8; 4 * SEMIBLOCK_LEN] })
+    }
+
+    fn apply(&mut self, input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+        let len = self.crypter.update(input, &mut self.buffer)
+            .map_err(Error::from)?;
+        output[..len].copy_from_slice(&self.buffer[..len]);
+        Ok(())
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        zeroize(&mut self.buffer);
+    }
+}
+
+const SEMIBLOCK_LEN: usize = 8;
+const MAX_KEY_LEN: usize = 32;
+const DEFAULT_IV: [u8; SEMIBLOCK_LEN] = [0xa6; SEMIBLOCK_LEN];
+const PADDED_IV_PREFIX: [u8; 4] = [0xa6, 0x59, 0x59, 0xa6];
+
+/// Algorithm that can be used to wrap or unwrap keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Algorithm {
+    /// AES key wrap with a 128-bit key-encryption key (RFC 3394).
+    Aes128Kw,
+    /// AES key wrap with padding with a 128-bit key-encryption key (RFC 5649).
+    Aes128Kwp,
+    /// AES key wrap with a 192-bit key-encryption key (RFC 3394).
+    Aes192Kw,
+    /// AES key wrap with padding with a 192-bit key-encryption key (RFC 5649).
+    Aes192Kwp,
+    /// AES key wrap with a 256-bit key-encryption key (RFC 3394).
+    Aes256Kw,
+    /// AES key wrap with padding with a 256-bit key-encryption key (RFC 5649).
+    Aes256Kwp,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Algorithm {
+    fn into_cipher(self) -> openssl::symm::Cipher {
+        use openssl::symm::Cipher;
+        use self::Algorithm::*;
+        match self {
+            Aes128Kw | Aes128Kwp => Cipher::aes_128_ecb(),
+            Aes192Kw | Aes192Kwp => Cipher::aes_192_ecb(),
+            Aes256Kw | Aes256Kwp => Cipher::aes_256_ecb(),
+            _Donotmatch => unreachable!()
+        }
+    }
+
+    fn is_padded(self) -> bool {
+        matches!(self, Algorithm::Aes128Kwp | Algorithm::Aes192Kwp | Algorithm::Aes256Kwp)
+    }
+
+    /// Get the required length of the key-encryption key for the algorithm.
+    pub fn key_len(self) -> usize  {
+        self.into_cipher().key_len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hex::{FromHex, ToHex};
+    use ErrorKind;
+    use super::{Algorithm, Config};
+    use super::super::{Algorithm as CipherAlgorithm, Config as CipherConfig};
+
+    fn config(algo: Algorithm, key: &str) -> Config {
+        let mut config = Config::new(algo);
+        config.key_mut().copy_from_slice(&Vec::<u8>::from_hex(key).unwrap());
+        config
+    }
+
+    fn roundtrip(config: &Config, key: &str, wrapped: &str) {
+        let key = Vec::<u8>::from_hex(key).unwrap();
+        let output = config.wrap(&key).unwrap();
+        assert_eq!(output.to_hex(), wrapped);
+        assert_eq!(config.unwrap(&output).unwrap(), key);
+    }
+
+    #[test]
+    fn rfc3394_128_bit_kek() {
+        let config = config(Algorithm::Aes128Kw, "000102030405060708090a0b0c0d0e0f");
+        roundtrip(&config, "00112233445566778899aabbccddeeff",
+                  "1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5");
+    }
+
+    #[test]
+    fn rfc3394_192_bit_kek() {
+        let config = config(Algorithm::Aes192Kw, "000102030405060708090a0b0c0d0e0f1011121314151617");
+        roundtrip(&config, "00112233445566778899aabbccddeeff",
+                  "96778b25ae6ca435f92b5b97c050aed2468ab8a17ad84e5d");
+    }
+
+    #[test]
+    fn rfc3394_256_bit_kek() {
+        let config = config(Algorithm::Aes256Kw, "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+        roundtrip(&config, "00112233445566778899aabbccddeeff000102030405060708090a0b0c0d0e0f",
+                  "28c9f404c4b810f4cbccb35cfb87f8263f5786e2d80ed326cbc7f0e71a99f43bfb988b9b7a02dd21");
+    }
+
+    #[test]
+    fn rfc5649_padded() {
+        let config = config(Algorithm::Aes128Kwp, "000102030405060708090a0b0c0d0e0f");
+        roundtrip(&config, "c37b7e6492584340bed12207808941155068f738",
+                  "e1f7176ecbd75d42e82b24f989a2816c209c6ef2d1aa94d2a3e60284900d03a2");
+        roundtrip(&config, "466f7250617369", "be80535e12e9394c8f8df26bd9528a35");
+    }
+
+    #[test]
+    fn wrap_invalid_length() {
+        let config = config(Algorithm::Aes128Kw, "000102030405060708090a0b0c0d0e0f");
+        assert_eq!(config.wrap(b"short").unwrap_err().kind(), ErrorKind::InvalidKeyLength);
+    }
+
+    #[test]
+    fn wrap_padded_empty() {
+        let config = config(Algorithm::Aes128Kwp, "000102030405060708090a0b0c0d0e0f");
+        assert_eq!(config.wrap(b"").unwrap_err().kind(), ErrorKind::InvalidKeyLength);
+        assert_eq!(config.wrap(b"k").unwrap().len(), 16);
+    }
+
+    #[test]
+    fn unwrap_tampered() {
+        let config = config(Algorithm::Aes128Kw, "000102030405060708090a0b0c0d0e0f");
+        let mut wrapped = Vec::<u8>::from_hex("1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5").unwrap();
+        wrapped[10] ^= 1;
+        assert_eq!(config.unwrap(&wrapped).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+        assert_eq!(config.unwrap(&wrapped[..16]).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+    }
+
+    #[test]
+    fn unwrap_wrong_kek() {
+        let config = config(Algorithm::Aes128Kwp, "000102030405060708090a0b0c0d0e0f");
+        let wrapped = config.wrap(b"data key").unwrap();
+        let other = super::Config::new(Algorithm::Aes128Kwp);
+        assert!(other.unwrap(&wrapped).is_err());
+    }
+
+    #[test]
+    fn wrap_cipher_config() {
+        let kek = config(Algorithm::Aes256Kw, "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+        let mut config = CipherConfig::new(CipherAlgorithm::Aes128Ctr);
+        config.key_mut().copy_from_slice(b"YELLOW SUBMARINE");
+        let wrapped = kek.wrap_config(&config).unwrap();
+        let mut unwrapped = kek.unwrap_config(CipherAlgorithm::Aes128Ctr, &wrapped).unwrap();
+        assert_eq!(unwrapped.key_mut(), b"YELLOW SUBMARINE");
+        assert_eq!(kek.unwrap_config(CipherAlgorithm::Aes256Ctr, &wrapped).unwrap_err().kind(),
+                   ErrorKind::InvalidKeyLength);
+    }
+}
@@ -0,0 +1,273 @@
+//! Encoding and decoding of the subset of ASN.1 DER and BER that CMS uses.
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Poll, Stream};
+
+use super::super::{Error, ErrorKind};
+
+pub const INTEGER: u8 = 0x02;
+pub const OCTET_STRING: u8 = 0x04;
+pub const NULL: u8 = 0x05;
+pub const OID: u8 = 0x06;
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+pub const CONTEXT_0: u8 = 0xa0;
+pub const CONTEXT_1: u8 = 0xa1;
+pub const IMPLICIT_0: u8 = 0x80;
+
+/// End-of-contents octets, which terminate an indefinite length.
+pub const END_OF_CONTENTS: [u8; 2] = [0, 0];
+
+/// Append an element with a definite length.
+pub fn encode(tag: u8, content: &[u8], output: &mut Vec<u8>) {
+    output.push(tag);
+    encode_len(content.len(), output);
+    output.extend_from_slice(content);
+}
+
+/// Encode an element with a definite length, whose content is the concatenation of `parts`.
+pub fn element(tag: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let content = parts.concat();
+    let mut output = Vec::with_capacity(content.len() + 6);
+    encode(tag, &content, &mut output);
+    output
+}
+
+fn encode_len(len: usize, output: &mut Vec<u8>) {
+    if len < 0x80 {
+        output.push(len as u8);
+    } else {
+        let bytes = (len as u64).to_be_bytes();
+        let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+        output.push(0x80 | (bytes.len() - skip) as u8);
+        output.extend_from_slice(&bytes[skip..]);
+    }
+}
+
+/// Decode the tag and length at the start of `input`.
+///
+/// Returns `None` if more input is needed, or the tag, the length (which is `None`
+/// if it is indefinite) and the length of the header.
+pub fn decode_header(input: &[u8]) -> Result<Option<(u8, Option<usize>, usize)>, Error> {
+    if input.len() < 2 {
+        return Ok(None);
+    }
+    let tag = input[0];
+    if tag & 0x1f == 0x1f {
+        return Err(ErrorKind::Unsupported.into());
+    }
+    let first = input[1] as usize;
+    if first < 0x80 {
+        return Ok(Some((tag, Some(first), 2)));
+    }
+    if first == 0x80 {
+        // Only constructed elements may have an indefinite length.
+        if tag & 0x20 == 0 {
+            return Err(ErrorKind::Malformed.into());
+        }
+        return Ok(Some((tag, None, 2)));
+    }
+    let len_len = first & 0x7f;
+    if len_len > 4 {
+        return Err(ErrorKind::Malformed.into());
+    }
+    if input.len() < 2 + len_len {
+        return Ok(None);
+    }
+    let len = input[2..2 + len_len].iter().fold(0usize, |acc, byte| acc << 8 | *byte as usize);
+    Ok(Some((tag, Some(len), 2 + len_len)))
+}
+
+/// Reader over elements with definite lengths, which are completely available.
+#[derive(Clone, Copy)]
+pub struct Reader<'a> {
+    input: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(input: &'a [u8]) -> Reader<'a> {
+        Reader { input }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    pub fn peek(&self) -> Option<u8> {
+        self.input.first().cloned()
+    }
+
+    /// Read the next element, returning its whole encoding and its content.
+    pub fn read_element(&mut self, tag: u8) -> Result<(&'a [u8], &'a [u8]), Error> {
+        match decode_header(self.input)? {
+            Some((actual, Some(len), header_len)) if actual == tag && self.input.len() >= header_len + len => {
+                let (element, rest) = self.input.split_at(header_len + len);
+                self.input = rest;
+                Ok((element, &element[header_len..]))
+            },
+            _ => Err(ErrorKind::Malformed.into())
+        }
+    }
+
+    /// Read the content of the next element.
+    pub fn read(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        self.read_element(tag).map(|(_, content)| content)
+    }
+
+    /// Read the content of the next element, if it has the given tag.
+    pub fn read_optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, Error> {
+        if self.peek() == Some(tag) {
+            self.read(tag).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read a constructed element, returning a reader over its content.
+    pub fn nested(&mut self, tag: u8) -> Result<Reader<'a>, Error> {
+        self.read(tag).map(Reader::new)
+    }
+
+    /// Read a small non-negative integer.
+    pub fn read_small_integer(&mut self) -> Result<u32, Error> {
+        let content = self.read(INTEGER)?;
+        if content.is_empty() || content.len() > 4 || content[0] & 0x80 != 0 {
+            return Err(ErrorKind::Malformed.into());
+        }
+        Ok(content.iter().fold(0, |acc, byte| acc << 8 | *byte as u32))
+    }
+
+    /// Fail if there are elements left.
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.is_empty() { Ok(()) } else { Err(ErrorKind::Malformed.into()) }
+    }
+}
+
+/// Stream adapter that yields the content of an octet string, given the underlying stream
+/// positioned after its header, and its length (which is `None` if it is indefinite).
+///
+/// A constructed octet string consists of primitive octet strings, whose contents are
+/// concatenated. Any data following the octet string is ignored.
+pub struct Octets<S> {
+    inner: S,
+    buffer: BytesMut,
+    /// Remaining length of the constructed string, if it is definite.
+    outer: Option<usize>,
+    /// Remaining length of the current primitive string.
+    remaining: usize,
+    constructed: bool,
+    finished: bool
+}
+
+impl<S> Octets<S> {
+    pub fn primitive(len: usize, inner: S) -> Self {
+        Octets { inner, buffer: BytesMut::new(), outer: None, remaining: len, constructed: false, finished: false }
+    }
+
+    pub fn constructed(len: Option<usize>, inner: S) -> Self {
+        Octets { inner, buffer: BytesMut::new(), outer: len, remaining: 0, constructed: true, finished: false }
+    }
+
+    /// Decode the header of the next primitive string, returning `false` if more input is needed.
+    fn next_string(&mut self) -> Result<bool, Error> {
+        if self.outer == Some(0) || (self.outer.is_none() && self.buffer.starts_with(&END_OF_CONTENTS)) {
+            self.finished = true;
+            return Ok(true);
+        }
+        match decode_header(&self.buffer)? {
+            None => Ok(false),
+            Some((OCTET_STRING, Some(len), header_len)) => {
+                if let Some(outer) = self.outer {
+                    self.outer = Some(outer.checked_sub(header_len + len).ok_or(ErrorKind::Malformed)?);
+                }
+                self.buffer.advance(header_len);
+                self.remaining = len;
+                Ok(true)
+            },
+            Some(_) => Err(ErrorKind::Malformed.into())
+        }
+    }
+}
+
+impl<S: Stream> Stream for Octets<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            if self.remaining > 0 {
+                if !self.buffer.is_empty() {
+                    let len = self.remaining.min(self.buffer.len());
+                    self.remaining -= len;
+                    return Ok(Async::Ready(Some(self.buffer.split_to(len).freeze())));
+                }
+            } else if !self.constructed {
+                self.finished = true;
+                continue;
+            } else if self.next_string()? {
+                continue;
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => self.buffer.extend_from_slice(item.as_ref()),
+                Async::Ready(None) => return Err(Error::from(ErrorKind::Malformed).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+
+    use super::{Error, Octets, Reader, decode_header, element, INTEGER, OCTET_STRING, SEQUENCE};
+
+    #[test]
+    fn lengths() {
+        for &len in &[0, 127, 128, 255, 256, 70_000] {
+            let encoded = element(OCTET_STRING, &[&vec![0; len]]);
+            let (tag, decoded, header_len) = decode_header(&encoded).unwrap().unwrap();
+            assert_eq!((tag, decoded, header_len + len), (OCTET_STRING, Some(len), encoded.len()));
+            assert_eq!(decode_header(&encoded[..header_len - 1]).unwrap(), None);
+        }
+        assert_eq!(decode_header(&[0x30, 0x80]).unwrap(), Some((SEQUENCE, None, 2)));
+        assert!(decode_header(&[0x04, 0x80]).is_err());
+    }
+
+    #[test]
+    fn reader() {
+        let encoded = element(SEQUENCE, &[&element(INTEGER, &[&[1]]), &element(OCTET_STRING, &[b"abc"])]);
+        let mut reader = Reader::new(&encoded);
+        let mut sequence = reader.nested(SEQUENCE).unwrap();
+        assert_eq!(sequence.read_small_integer().unwrap(), 1);
+        assert_eq!(sequence.read_optional(INTEGER).unwrap(), None);
+        assert_eq!(sequence.read(OCTET_STRING).unwrap(), b"abc");
+        assert!(sequence.finish().is_ok() && reader.finish().is_ok());
+        assert!(Reader::new(&encoded[..encoded.len() - 1]).read(SEQUENCE).is_err());
+    }
+
+    fn octets(octets: Octets<::futures::stream::IterOk<::std::vec::IntoIter<Vec<u8>>, Error>>) -> Result<Vec<u8>, Error> {
+        octets.concat2().wait().map(|data| data.to_vec())
+    }
+
+    #[test]
+    fn constructed_octets() {
+        let mut encoded = [element(OCTET_STRING, &[b"hello "]), element(OCTET_STRING, &[b"world"])].concat();
+        let len = encoded.len();
+        encoded.extend_from_slice(b"trailing");
+        let chunks = |encoded: &[u8]| encoded.chunks(3).map(|chunk| chunk.to_vec()).collect::<Vec<_>>();
+        assert_eq!(octets(Octets::constructed(Some(len), iter_ok(chunks(&encoded)))).unwrap(), b"hello world");
+        assert!(octets(Octets::constructed(None, iter_ok(chunks(&encoded)))).is_err());
+        encoded.truncate(len);
+        encoded.extend_from_slice(&[0, 0]);
+        assert_eq!(octets(Octets::constructed(None, iter_ok(chunks(&encoded)))).unwrap(), b"hello world");
+        assert_eq!(octets(Octets::primitive(5, iter_ok(chunks(&encoded)))).unwrap(), [4, 6, b'h', b'e', b'l']);
+    }
+}
@@ -0,0 +1,502 @@
+//! `EnvelopedData` with RSA key transport, as specified in
+//! [RFC 5652, section 6](https://tools.ietf.org/html/rfc5652#section-6).
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::mem;
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Future, Poll, Stream};
+use openssl::rsa::Padding;
+
+use super::super::{Error, ErrorKind};
+use super::super::cipher;
+use super::super::envelope::Rest;
+use super::super::random::{Generator, RandomBytes};
+use super::super::secret::zeroize;
+use super::{Certificate, Identity, ID_DATA, ID_ENVELOPED_DATA, RSA_ENCRYPTION};
+use super::der::{self, Octets, Reader};
+
+const IV_LEN: usize = 16;
+const KTRI_VERSION: u8 = 0;
+const MAX_KEY_LEN: usize = 32;
+
+/// Content-encryption algorithm.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Algorithm {
+    /// AES with 128-bit keys in Cipher Block Chaining mode.
+    Aes128Cbc,
+    /// AES with 192-bit keys in Cipher Block Chaining mode.
+    Aes192Cbc,
+    /// AES with 256-bit keys in Cipher Block Chaining mode.
+    Aes256Cbc,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+const ALGORITHM_OIDS: [(Algorithm, &[u8]); 3] = [
+    (Algorithm::Aes128Cbc, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x02]),
+    (Algorithm::Aes192Cbc, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x16]),
+    (Algorithm::Aes256Cbc, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a])
+];
+
+impl Algorithm {
+    fn oid(self) -> &'static [u8] {
+        ALGORITHM_OIDS.iter().find(|&&(algo, _)| algo == self).map(|&(_, oid)| oid).unwrap()
+    }
+
+    fn from_oid(oid: &[u8]) -> Option<Algorithm> {
+        ALGORITHM_OIDS.iter().find(|&&(_, algo_oid)| algo_oid == oid).map(|&(algo, _)| algo)
+    }
+
+    fn cipher(self) -> cipher::Algorithm {
+        match self {
+            Algorithm::Aes128Cbc => cipher::Algorithm::Aes128Cbc,
+            Algorithm::Aes192Cbc => cipher::Algorithm::Aes192Cbc,
+            Algorithm::Aes256Cbc => cipher::Algorithm::Aes256Cbc,
+            Algorithm::_Donotmatch => unreachable!()
+        }
+    }
+
+    fn config(self, key: &[u8], iv: &[u8]) -> cipher::Config {
+        let mut config = cipher::Config::new(self.cipher());
+        config.key_mut().copy_from_slice(key);
+        config.iv_mut().unwrap().copy_from_slice(iv);
+        config
+    }
+}
+
+/// Stream adapter that encrypts the data from the underlying stream as `EnvelopedData`.
+///
+/// The first item yielded is the header, followed by the encrypted content
+/// and the end-of-contents octets of the enclosing structures.
+pub struct Encrypt<S> {
+    algorithm: Algorithm,
+    recipients: Vec<Certificate>,
+    state: EncryptState<S>
+}
+
+enum EncryptState<S> {
+    Generating(RandomBytes, S),
+    Streaming(Chunks<cipher::Encrypt<S>>),
+    Done
+}
+
+impl<S: Debug> Debug for Encrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Encrypt")
+            .field("algorithm", &self.algorithm)
+            .field("recipients", &self.recipients)
+            .finish()
+    }
+}
+
+impl<S: Stream> Encrypt<S> {
+    /// Create an encrypting stream adapter.
+    ///
+    /// The content-encryption key and the IV are generated using `generator`.
+    /// Fails with `ErrorKind::Unsupported` if there are no recipients; the
+    /// stream fails with the same error if the key of a recipient is not an
+    /// RSA key.
+    pub fn new(generator: &Generator, algorithm: Algorithm, recipients: Vec<Certificate>,
+               inner: S) -> Result<Self, Error> {
+        if recipients.is_empty() {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let random_bytes = generator.random_bytes(algorithm.cipher().key_len() + IV_LEN);
+        Ok(Encrypt { algorithm, recipients, state: EncryptState::Generating(random_bytes, inner) })
+    }
+
+    fn encode_header(&self, key: &[u8], iv: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut recipient_infos = Vec::new();
+        for recipient in &self.recipients {
+            let rsa = recipient.pkey.rsa().map_err(|_| Error::from(ErrorKind::Unsupported))?;
+            let mut encrypted_key = vec![0; rsa.size() as usize];
+            let len = rsa.public_encrypt(key, &mut encrypted_key, Padding::PKCS1).map_err(Error::from)?;
+            recipient_infos.extend_from_slice(&der::element(der::SEQUENCE, &[
+                &der::element(der::INTEGER, &[&[KTRI_VERSION]]),
+                &recipient.issuer_and_serial,
+                &der::element(der::SEQUENCE, &[&der::element(der::OID, &[RSA_ENCRYPTION]), &der::element(der::NULL, &[])]),
+                &der::element(der::OCTET_STRING, &[&encrypted_key[..len]])
+            ]));
+        }
+        let mut header = vec![der::SEQUENCE, 0x80];
+        der::encode(der::OID, ID_ENVELOPED_DATA, &mut header);
+        header.extend_from_slice(&[der::CONTEXT_0, 0x80, der::SEQUENCE, 0x80]);
+        der::encode(der::INTEGER, &[0], &mut header);
+        der::encode(der::SET, &recipient_infos, &mut header);
+        header.extend_from_slice(&[der::SEQUENCE, 0x80]);
+        der::encode(der::OID, ID_DATA, &mut header);
+        header.extend_from_slice(&der::element(der::SEQUENCE, &[
+            &der::element(der::OID, &[self.algorithm.oid()]),
+            &der::element(der::OCTET_STRING, &[iv])
+        ]));
+        header.extend_from_slice(&[der::CONTEXT_0, 0x80]);
+        Ok(header)
+    }
+}
+
+impl<S: Stream> Stream for Encrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match mem::replace(&mut self.state, EncryptState::Done) {
+            EncryptState::Generating(mut random_bytes, inner) => {
+                let random = match random_bytes.poll()? {
+                    Async::NotReady => {
+                        self.state = EncryptState::Generating(random_bytes, inner);
+                        return Ok(Async::NotReady);
+                    },
+                    Async::Ready(random) => random
+                };
+                let result = {
+                    let (key, iv) = random.split_at(self.algorithm.cipher().key_len());
+                    self.encode_header(key, iv).and_then(|header| {
+                        Ok((header, cipher::Encrypt::new(&self.algorithm.config(key, iv), inner)?))
+                    })
+                };
+                if let Ok(mut random) = random.try_mut() {
+                    zeroize(&mut random);
+                }
+                let (header, encrypt) = result?;
+                // The encrypted content is followed by the end of the encrypted
+                // content info, the enveloped data and the content info.
+                self.state = EncryptState::Streaming(Chunks::new(5, encrypt));
+                Ok(Async::Ready(Some(Bytes::from(header))))
+            },
+            EncryptState::Streaming(mut chunks) => {
+                let result = chunks.poll();
+                self.state = EncryptState::Streaming(chunks);
+                result
+            },
+            EncryptState::Done => Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// Stream adapter that encodes the data of its underlying stream as the primitive
+/// octet strings of a constructed octet string with an indefinite length.
+///
+/// At the end, the given number of end-of-contents octets are written, which terminate
+/// the string itself and the enclosing elements with indefinite lengths.
+struct Chunks<S> {
+    inner: S,
+    end_of_contents: usize,
+    finished: bool
+}
+
+impl<S> Chunks<S> {
+    fn new(end_of_contents: usize, inner: S) -> Self {
+        Chunks { inner, end_of_contents, finished: false }
+    }
+}
+
+impl<S: Stream> Stream for Chunks<S>
+    where S::Item: AsRef<[u8]>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => if !item.as_ref().is_empty() {
+                    let mut output = Vec::with_capacity(item.as_ref().len() + 6);
+                    der::encode(der::OCTET_STRING, item.as_ref(), &mut output);
+                    return Ok(Async::Ready(Some(Bytes::from(output))));
+                },
+                Async::Ready(None) => {
+                    self.finished = true;
+                    return Ok(Async::Ready(Some(Bytes::from(der::END_OF_CONTENTS.repeat(self.end_of_contents)))));
+                }
+            }
+        }
+    }
+}
+
+/// Stream adapter that decrypts `EnvelopedData`.
+///
+/// The recipient information matching one of the identities is used to decrypt
+/// the content-encryption key. The stream fails with `ErrorKind::UnknownKey` if
+/// there is no such recipient, and with `ErrorKind::BadPadding` if the key cannot
+/// be decrypted.
+///
+/// Like [`cipher::Decrypt`](../cipher/struct.Decrypt.html), the content is not
+/// authenticated. Any data following the encrypted content is ignored.
+pub struct Decrypt<S> {
+    identities: Vec<Identity>,
+    state: DecryptState<S>
+}
+
+enum DecryptState<S> {
+    Header(S, BytesMut),
+    Streaming(cipher::Decrypt<Octets<Rest<S>>>),
+    Done
+}
+
+impl<S: Debug> Debug for Decrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Decrypt")
+            .field("identities", &self.identities)
+            .finish()
+    }
+}
+
+impl<S: Stream> Decrypt<S> {
+    /// Create a decrypting stream adapter, given the identities of the possible recipients.
+    pub fn new(identities: Vec<Identity>, inner: S) -> Self {
+        Decrypt { identities, state: DecryptState::Header(inner, BytesMut::new()) }
+    }
+
+    fn decrypt_key(&self, header: &Header, key: &mut [u8]) -> Result<(), Error> {
+        let mut recipient_infos = Reader::new(&header.recipient_infos);
+        while !recipient_infos.is_empty() {
+            // Recipient infos other than key transport are tagged explicitly.
+            if recipient_infos.peek() != Some(der::SEQUENCE) {
+                let tag = recipient_infos.peek().unwrap();
+                recipient_infos.read(tag)?;
+                continue;
+            }
+            let mut ktri = recipient_infos.nested(der::SEQUENCE)?;
+            if ktri.read_small_integer()? != KTRI_VERSION as u32 {
+                continue;
+            }
+            let (rid, _) = ktri.read_element(der::SEQUENCE)?;
+            let mut algorithm = ktri.nested(der::SEQUENCE)?;
+            if algorithm.read(der::OID)? != RSA_ENCRYPTION {
+                continue;
+            }
+            let encrypted_key = ktri.read(der::OCTET_STRING)?;
+            let identity = match self.identities.iter().find(|identity| identity.certificate.issuer_and_serial == rid) {
+                None => continue,
+                Some(identity) => identity
+            };
+            let rsa = identity.key.pkey.rsa().map_err(|_| Error::from(ErrorKind::Unsupported))?;
+            let mut decrypted = vec![0; rsa.size() as usize];
+            let result = rsa.private_decrypt(encrypted_key, &mut decrypted, Padding::PKCS1);
+            let result = match result {
+                Ok(len) if len == key.len() => {
+                    key.copy_from_slice(&decrypted[..len]);
+                    Ok(())
+                },
+                _ => Err(ErrorKind::BadPadding.into())
+            };
+            zeroize(&mut decrypted);
+            return result;
+        }
+        Err(ErrorKind::UnknownKey.into())
+    }
+}
+
+impl<S: Stream> Stream for Decrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, DecryptState::Done) {
+                DecryptState::Header(mut inner, mut buffer) => {
+                    if let Some(header) = decode_header(&buffer)? {
+                        let mut key = [0u8; MAX_KEY_LEN];
+                        let key_len = header.algorithm.cipher().key_len();
+                        let result = self.decrypt_key(&header, &mut key[..key_len]);
+                        let config = header.algorithm.config(&key[..key_len], &header.iv);
+                        zeroize(&mut key);
+                        result?;
+                        let rest = Rest::new(buffer.split_off(header.offset).freeze(), inner);
+                        let octets = match header.content {
+                            Content::Primitive(len) => Octets::primitive(len, rest),
+                            Content::Constructed(len) => Octets::constructed(len, rest)
+                        };
+                        self.state = DecryptState::Streaming(cipher::Decrypt::new(&config, octets)?);
+                        continue;
+                    }
+                    match inner.poll()? {
+                        Async::NotReady => {
+                            self.state = DecryptState::Header(inner, buffer);
+                            return Ok(Async::NotReady);
+                        },
+                        Async::Ready(None) => return Err(Error::from(ErrorKind::Malformed).into()),
+                        Async::Ready(Some(item)) => {
+                            buffer.extend_from_slice(item.as_ref());
+                            self.state = DecryptState::Header(inner, buffer);
+                        }
+                    }
+                },
+                DecryptState::Streaming(mut decrypt) => {
+                    let result = decrypt.poll();
+                    self.state = DecryptState::Streaming(decrypt);
+                    return result;
+                },
+                DecryptState::Done => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// The structures preceding the encrypted content.
+struct Header {
+    recipient_infos: Vec<u8>,
+    algorithm: Algorithm,
+    iv: Vec<u8>,
+    /// Offset of the encrypted content, after its header.
+    offset: usize,
+    content: Content
+}
+
+enum Content {
+    Primitive(usize),
+    Constructed(Option<usize>)
+}
+
+/// Decode the structures up to the start of the encrypted content.
+///
+/// Returns `None` if more input is needed.
+fn decode_header(input: &[u8]) -> Result<Option<Header>, Error> {
+    let mut pos = 0;
+    macro_rules! header {
+        ($tag:expr) => {
+            match der::decode_header(&input[pos..])? {
+                None => return Ok(None),
+                Some((tag, len, header_len)) => {
+                    if tag != $tag {
+                        return Err(ErrorKind::Malformed.into());
+                    }
+                    pos += header_len;
+                    len
+                }
+            }
+        }
+    }
+    macro_rules! element {
+        ($tag:expr) => {{
+            let len = header!($tag).ok_or(ErrorKind::Malformed)?;
+            if input.len() < pos + len {
+                return Ok(None);
+            }
+            pos += len;
+            &input[pos - len..pos]
+        }}
+    }
+    header!(der::SEQUENCE);
+    if element!(der::OID) != ID_ENVELOPED_DATA {
+        return Err(ErrorKind::Unsupported.into());
+    }
+    header!(der::CONTEXT_0);
+    header!(der::SEQUENCE);
+    let _version = element!(der::INTEGER);
+    match input.get(pos) {
+        None => return Ok(None),
+        Some(&der::CONTEXT_0) => { let _originator_info = element!(der::CONTEXT_0); },
+        Some(_) => {}
+    }
+    let recipient_infos = element!(der::SET).to_vec();
+    header!(der::SEQUENCE);
+    let _content_type = element!(der::OID);
+    let mut algorithm = Reader::new(element!(der::SEQUENCE));
+    let oid = algorithm.read(der::OID)?;
+    let algorithm_params = algorithm.read(der::OCTET_STRING)?;
+    let algorithm = Algorithm::from_oid(oid).ok_or(ErrorKind::Unsupported)?;
+    if algorithm_params.len() != IV_LEN {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let content = match input.get(pos) {
+        None => return Ok(None),
+        Some(&der::IMPLICIT_0) => Content::Primitive(header!(der::IMPLICIT_0).ok_or(ErrorKind::Malformed)?),
+        Some(&der::CONTEXT_0) => Content::Constructed(header!(der::CONTEXT_0)),
+        Some(_) => return Err(ErrorKind::Malformed.into())
+    };
+    Ok(Some(Header { recipient_infos, algorithm, iv: algorithm_params.to_vec(), offset: pos, content }))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::Stream;
+    use futures::stream::iter_ok;
+
+    use self::itertools::Itertools;
+
+    use random::Generator;
+    use super::{Algorithm, Decrypt, Encrypt, Error, ErrorKind};
+    use super::super::{Certificate, Identity, PrivateKey};
+    use super::super::test::{EC_CERTIFICATE, RSA_CERTIFICATE, RSA_KEY, der};
+
+    fn identity() -> Identity {
+        Identity::new(&Certificate::from_pem(RSA_CERTIFICATE.as_bytes()).unwrap(),
+                      &PrivateKey::from_pem(RSA_KEY.as_bytes()).unwrap())
+    }
+
+    fn encrypt(algorithm: Algorithm, recipients: Vec<Certificate>, chunks: Vec<Vec<u8>>) -> Result<Vec<u8>, Error> {
+        let generator = Generator::new(1);
+        let encrypt = Encrypt::new(&generator, algorithm, recipients, iter_ok::<_, Error>(chunks))?;
+        encrypt.wait().collect::<Result<Vec<_>, _>>().map(|chunks| chunks.into_iter().concat().to_vec())
+    }
+
+    fn decrypt(identities: Vec<Identity>, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = message.chunks(7).map(|chunk| chunk.to_vec()).collect();
+        let decrypt = Decrypt::new(identities, iter_ok::<_, Error>(chunks));
+        decrypt.wait().collect::<Result<Vec<_>, _>>().map(|chunks| chunks.into_iter().concat().to_vec())
+    }
+
+    #[test]
+    fn openssl_vectors() {
+        // openssl cms -encrypt -binary -aes256 -outform DER
+        assert_eq!(decrypt(vec![identity()], &der(OPENSSL_DEFINITE)).unwrap(), b"hello from openssl cms\n");
+        // openssl cms -encrypt -binary -stream -aes128 -outform DER
+        assert_eq!(decrypt(vec![identity()], &der(OPENSSL_INDEFINITE)).unwrap(), b"hello from openssl cms\n");
+    }
+
+    #[test]
+    fn roundtrip() {
+        let certificate = Certificate::from_pem(RSA_CERTIFICATE.as_bytes()).unwrap();
+        let data = (0..10_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let chunks = data.chunks(1000).map(|chunk| chunk.to_vec()).collect::<Vec<_>>();
+        for &algorithm in &[Algorithm::Aes128Cbc, Algorithm::Aes192Cbc, Algorithm::Aes256Cbc] {
+            let message = encrypt(algorithm, vec![certificate.clone()], chunks.clone()).unwrap();
+            assert_eq!(decrypt(vec![identity()], &message).unwrap(), data);
+        }
+        let message = encrypt(Algorithm::Aes128Cbc, vec![certificate], vec![]).unwrap();
+        assert_eq!(decrypt(vec![identity()], &message).unwrap(), b"");
+    }
+
+    #[test]
+    fn unsupported_and_unknown_keys() {
+        let ec_certificate = Certificate::from_pem(EC_CERTIFICATE.as_bytes()).unwrap();
+        assert_eq!(encrypt(Algorithm::Aes128Cbc, vec![ec_certificate], vec![]).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(encrypt(Algorithm::Aes128Cbc, vec![], vec![]).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(decrypt(vec![], &der(OPENSSL_DEFINITE)).unwrap_err().kind(), ErrorKind::UnknownKey);
+    }
+
+    #[test]
+    fn truncated() {
+        let message = der(OPENSSL_INDEFINITE);
+        for &len in &[10, 100, message.len() - 20] {
+            assert!(decrypt(vec![identity()], &message[..len]).is_err());
+        }
+    }
+
+    const OPENSSL_DEFINITE: &str = "MIIBLAYJKoZIhvcNAQcDoIIBHTCCARkCAQAxgcUwgcICAQAwKzATMREwDwYDVQQDDAhjbXMtdGVzdAIUHMM/8zmUxDHpJiIW\
+wdUfcJXEmwwwDQYJKoZIhvcNAQEBBQAEgYAXML2vFQ23J6TQnG7E0pLomWtnQxN7HWX6+Z5BayjaqecVZR0S0A5is288+gwq\
+4Q+cWCTwnw22uVDlSic10pnupxf5jDPCfI63Zxa60Jg6T48PU9z0MghfY89QSKFN/JPA1dvEkrAHmj5uzDAQUwzLvicuqtJL\
+6gt0+wjkBlj/gTBMBgkqhkiG9w0BBwEwHQYJYIZIAWUDBAEqBBDvE9U40p3BR0NNvyE9vhVpgCCLuhyRtI2Flid4CAPwRKZF\
+ESfHUc3wsruV3EYlLlaGtQ==";
+    const OPENSSL_INDEFINITE: &str = "MIAGCSqGSIb3DQEHA6CAMIACAQAxgcUwgcICAQAwKzATMREwDwYDVQQDDAhjbXMtdGVzdAIUHMM/8zmUxDHpJiIWwdUfcJXE\
+mwwwDQYJKoZIhvcNAQEBBQAEgYA2R5gNiW66O2LWS3WaIjtOlMMczu4OXiu0lquLwICYpdpF9PbUcqrXgLlnUqPn4TsVkSzd\
+1RDes0ziSAoVGbuVjK7mBvM0PKdYPFho312rNCr5xN3rbEIMVePbLW6FBp6+Q/dEr5ApbCBFDRzAGevjxAcLcLlFJIYeMjgy\
+xSH9TzCABgkqhkiG9w0BBwEwHQYJYIZIAWUDBAECBBBhlEYitYg/K212rb1nKFwpoIAEEP1fOMlMoJvswoFt5FCpGFQEEPD9\
+5BeBudseEB1VnlQhCGkAAAAAAAAAAAAA";
+}
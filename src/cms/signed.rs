@@ -0,0 +1,435 @@
+//! Detached `SignedData`, as specified in
+//! [RFC 5652, section 5](https://tools.ietf.org/html/rfc5652#section-5).
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use futures::{Async, Future, Poll, Stream};
+
+use super::super::{Error, ErrorKind};
+use super::super::hash::{self, Digest, Hash, SplitDigest, SplitHash};
+use super::super::sign::{self, SigningKey, VerifyingKey};
+use super::{Certificate, ID_DATA, ID_SIGNED_DATA, RSA_ENCRYPTION};
+use super::der::{self, Reader};
+
+const SIGNED_DATA_VERSION: u8 = 1;
+const SIGNER_INFO_VERSION: u8 = 1;
+
+const CONTENT_TYPE: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x03];
+const MESSAGE_DIGEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04];
+
+const DIGEST_OIDS: [(hash::Algorithm, &[u8]); 3] = [
+    (hash::Algorithm::Sha256, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01]),
+    (hash::Algorithm::Sha384, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02]),
+    (hash::Algorithm::Sha512, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03])
+];
+
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+/// Signature algorithms, along with their digest algorithm and the object identifier
+/// of the signature algorithm. RSA signatures carry `NULL` parameters.
+const SIGNATURE_ALGORITHMS: [(sign::Algorithm, hash::Algorithm, &[u8]); 6] = [
+    (sign::Algorithm::RsaPkcs1v15Sha256, hash::Algorithm::Sha256, RSA_ENCRYPTION),
+    (sign::Algorithm::RsaPkcs1v15Sha384, hash::Algorithm::Sha384, RSA_ENCRYPTION),
+    (sign::Algorithm::RsaPkcs1v15Sha512, hash::Algorithm::Sha512, RSA_ENCRYPTION),
+    (sign::Algorithm::EcdsaP256Sha256, hash::Algorithm::Sha256, ECDSA_WITH_SHA256),
+    (sign::Algorithm::EcdsaP384Sha384, hash::Algorithm::Sha384, ECDSA_WITH_SHA384),
+    (sign::Algorithm::Ed25519, hash::Algorithm::Sha512, ED25519)
+];
+
+/// Object identifiers of `sha256WithRSAEncryption` and friends, which some
+/// implementations use instead of `rsaEncryption`.
+const RSA_WITH_DIGEST_OIDS: [(hash::Algorithm, &[u8]); 3] = [
+    (hash::Algorithm::Sha256, &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b]),
+    (hash::Algorithm::Sha384, &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c]),
+    (hash::Algorithm::Sha512, &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d])
+];
+
+fn digest_oid(algorithm: hash::Algorithm) -> &'static [u8] {
+    DIGEST_OIDS.iter().find(|&&(algo, _)| algo == algorithm).map(|&(_, oid)| oid).unwrap()
+}
+
+fn digest_algorithm(oid: &[u8]) -> Option<hash::Algorithm> {
+    DIGEST_OIDS.iter().find(|&&(_, algo_oid)| algo_oid == oid).map(|&(algo, _)| algo)
+}
+
+/// Find the signature algorithm, given its object identifier and the digest algorithm.
+fn signature_algorithm(oid: &[u8], digest: hash::Algorithm) -> Option<sign::Algorithm> {
+    let oid = RSA_WITH_DIGEST_OIDS.iter()
+        .find(|&&(algo, algo_oid)| algo == digest && algo_oid == oid)
+        .map(|_| RSA_ENCRYPTION)
+        .unwrap_or(oid);
+    SIGNATURE_ALGORITHMS.iter()
+        .find(|&&(_, algo_digest, algo_oid)| algo_digest == digest && algo_oid == oid)
+        .map(|&(algo, _, _)| algo)
+}
+
+/// Encode the signed attributes as a `SET`, which is what the signature covers.
+///
+/// In the `SignerInfo` they are stored with an implicit `[0]` tag instead.
+fn signed_attributes(digest: &[u8]) -> Vec<u8> {
+    let mut attributes = [
+        der::element(der::SEQUENCE, &[&der::element(der::OID, &[CONTENT_TYPE]),
+                                      &der::element(der::SET, &[&der::element(der::OID, &[ID_DATA])])]),
+        der::element(der::SEQUENCE, &[&der::element(der::OID, &[MESSAGE_DIGEST]),
+                                      &der::element(der::SET, &[&der::element(der::OCTET_STRING, &[digest])])])
+    ];
+    // The elements of a SET OF are sorted by their encoding in DER.
+    attributes.sort();
+    der::element(der::SET, &attributes.iter().map(|attribute| &attribute[..]).collect::<Vec<_>>())
+}
+
+/// Certificate of a signer along with its private key and the signature algorithm.
+#[derive(Clone)]
+pub struct Signer {
+    certificate: Certificate,
+    key: SigningKey,
+    algorithm: sign::Algorithm,
+    digest: hash::Algorithm
+}
+
+impl Signer {
+    /// Create a signer from a certificate, the matching private key and a signature algorithm.
+    ///
+    /// Pure Ed25519 can be used, since the signature covers the signed attributes,
+    /// which carry the digest of the data.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the algorithm cannot be used with CMS,
+    /// such as RSA with PSS padding.
+    pub fn new(certificate: &Certificate, key: &SigningKey, algorithm: sign::Algorithm) -> Result<Signer, Error> {
+        let digest = SIGNATURE_ALGORITHMS.iter()
+            .find(|&&(algo, _, _)| algo == algorithm)
+            .map(|&(_, digest, _)| digest)
+            .ok_or(ErrorKind::Unsupported)?;
+        Ok(Signer { certificate: certificate.clone(), key: key.clone(), algorithm, digest })
+    }
+
+    /// Encode the `ContentInfo` holding the `SignedData`, given the digest of the data.
+    fn encode(&self, digest: &Digest) -> Result<Vec<u8>, Error> {
+        let attributes = signed_attributes(digest.as_ref());
+        let signature = self.key.sign_message(self.algorithm, &attributes)?;
+        let oid = SIGNATURE_ALGORITHMS.iter()
+            .find(|&&(algo, _, _)| algo == self.algorithm)
+            .map(|&(_, _, oid)| oid)
+            .unwrap();
+        let params = if oid == RSA_ENCRYPTION { der::element(der::NULL, &[]) } else { vec![] };
+        let digest_algorithm = der::element(der::SEQUENCE, &[&der::element(der::OID, &[digest_oid(self.digest)])]);
+        let signer_info = der::element(der::SEQUENCE, &[
+            &der::element(der::INTEGER, &[&[SIGNER_INFO_VERSION]]),
+            &self.certificate.issuer_and_serial,
+            &digest_algorithm,
+            &[&[der::CONTEXT_0], &attributes[1..]].concat(),
+            &der::element(der::SEQUENCE, &[&der::element(der::OID, &[oid]), &params]),
+            &der::element(der::OCTET_STRING, &[signature.as_ref()])
+        ]);
+        let signed_data = der::element(der::SEQUENCE, &[
+            &der::element(der::INTEGER, &[&[SIGNED_DATA_VERSION]]),
+            &der::element(der::SET, &[&digest_algorithm]),
+            &der::element(der::SEQUENCE, &[&der::element(der::OID, &[ID_DATA])]),
+            &der::element(der::CONTEXT_0, &[self.certificate.to_der()]),
+            &der::element(der::SET, &[&signer_info])
+        ]);
+        Ok(der::element(der::SEQUENCE, &[
+            &der::element(der::OID, &[ID_SIGNED_DATA]),
+            &der::element(der::CONTEXT_0, &[&signed_data])
+        ]))
+    }
+}
+
+impl Debug for Signer {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Signer")
+            .field("certificate", &self.certificate)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+/// Stream adapter that computes detached `SignedData` over the data while forwarding it.
+///
+/// The `SignedData` is encoded in DER as a `ContentInfo`, and includes the
+/// certificate of the signer.
+#[derive(Debug)]
+pub struct Sign<S> {
+    inner: Hash<S>,
+    signer: Signer
+}
+
+impl<S: Stream> Sign<S> {
+    /// Given a signer, create a new stream adapter.
+    pub fn new(signer: &Signer, inner: S) -> Result<Self, Error> {
+        Ok(Sign { inner: Hash::new(signer.digest, inner)?, signer: signer.clone() })
+    }
+
+    /// Compute the `SignedData` and reset the internal hashing state.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the key of the signer is not
+    /// suitable for the signature algorithm.
+    pub fn signed_data(&mut self) -> Result<Vec<u8>, Error> {
+        let digest = self.inner.digest()?;
+        self.signer.encode(&digest)
+    }
+
+    /// Split the stream adapter into two halves, one to receive the computed `SignedData`,
+    /// and one to compute it over the stream.
+    ///
+    /// See [`hash::Hash::split`](../hash/struct.Hash.html#method.split) for more information.
+    pub fn split(self) -> (SplitSignedData, SplitHash<S>) {
+        let (digest, hash) = self.inner.split();
+        (SplitSignedData { digest, signer: self.signer }, hash)
+    }
+
+    /// Extract the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+impl<S: Stream> Stream for Sign<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        self.inner.poll()
+    }
+}
+
+/// The receiving half of a split signing process.
+///
+/// This is a future that resolves with the `SignedData` as soon as the stream
+/// has been fully consumed.
+/// It resolves with `None` when the computing half is dropped prematurely.
+///
+/// See [`Sign::split`](struct.Sign.html#method.split) for more information.
+#[derive(Debug)]
+pub struct SplitSignedData {
+    digest: SplitDigest,
+    signer: Signer
+}
+
+impl Future for SplitSignedData {
+    type Item = Option<Vec<u8>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.digest.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::Ready(Some(digest)) => self.signer.encode(&digest).map(|signed_data| Async::Ready(Some(signed_data)))
+        }
+    }
+}
+
+/// Stream adapter that verifies detached `SignedData` over the data while forwarding it.
+///
+/// The stream fails with `ErrorKind::DigestMismatch` at its end if the data does not
+/// match the signed digest. The data must not be acted upon until the stream
+/// has ended successfully.
+#[derive(Debug)]
+pub struct Verify<S> {
+    inner: Hash<S>,
+    expected: Option<Vec<u8>>
+}
+
+impl<S: Stream> Verify<S> {
+    /// Given DER-encoded `SignedData` and the certificates of the possible signers,
+    /// create a new stream adapter.
+    ///
+    /// The signature over the signed attributes is verified right away, using the
+    /// first signer whose certificate is given. Certificates included in the
+    /// `SignedData` are ignored.
+    ///
+    /// Fails with `ErrorKind::UnknownKey` if there is no such signer, with
+    /// `ErrorKind::BadSignature` if the signature is invalid, and with
+    /// `ErrorKind::Unsupported` if the content is not detached or there are no
+    /// signed attributes.
+    pub fn new(signed_data: &[u8], certificates: &[Certificate], inner: S) -> Result<Self, Error> {
+        let mut content_info = Reader::new(signed_data).nested(der::SEQUENCE)?;
+        if content_info.read(der::OID)? != ID_SIGNED_DATA {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let mut signed_data = content_info.nested(der::CONTEXT_0)?.nested(der::SEQUENCE)?;
+        signed_data.read(der::INTEGER)?;
+        signed_data.read(der::SET)?;
+        let mut encapsulated = signed_data.nested(der::SEQUENCE)?;
+        encapsulated.read(der::OID)?;
+        if !encapsulated.is_empty() {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        signed_data.read_optional(der::CONTEXT_0)?;
+        signed_data.read_optional(der::CONTEXT_1)?;
+        let mut signer_infos = signed_data.nested(der::SET)?;
+        signed_data.finish()?;
+        while !signer_infos.is_empty() {
+            let mut signer_info = signer_infos.nested(der::SEQUENCE)?;
+            if signer_info.read_small_integer()? != SIGNER_INFO_VERSION as u32 {
+                continue;
+            }
+            let (sid, _) = signer_info.read_element(der::SEQUENCE)?;
+            let certificate = match certificates.iter().find(|certificate| certificate.issuer_and_serial == sid) {
+                None => continue,
+                Some(certificate) => certificate
+            };
+            let digest = digest_algorithm(signer_info.nested(der::SEQUENCE)?.read(der::OID)?)
+                .ok_or(ErrorKind::Unsupported)?;
+            let attributes = signer_info.read_optional(der::CONTEXT_0)?.ok_or(ErrorKind::Unsupported)?;
+            let signature_oid = signer_info.nested(der::SEQUENCE)?.read(der::OID)?;
+            let algorithm = signature_algorithm(signature_oid, digest).ok_or(ErrorKind::Unsupported)?;
+            let signature = signer_info.read(der::OCTET_STRING)?;
+
+            let key = VerifyingKey::from_der(&certificate.pkey.public_key_to_der().map_err(Error::from)?)?;
+            key.verify_message(algorithm, &der::element(der::SET, &[attributes]), signature)?;
+            let expected = find_message_digest(attributes)?;
+            return Ok(Verify { inner: Hash::new(digest, inner)?, expected: Some(expected) });
+        }
+        Err(ErrorKind::UnknownKey.into())
+    }
+
+    /// Extract the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+/// Check the content type among the signed attributes, and return the message digest.
+fn find_message_digest(attributes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut attributes = Reader::new(attributes);
+    let (mut content_type, mut message_digest) = (None, None);
+    while !attributes.is_empty() {
+        let mut attribute = attributes.nested(der::SEQUENCE)?;
+        let oid = attribute.read(der::OID)?;
+        let mut values = attribute.nested(der::SET)?;
+        if oid == CONTENT_TYPE {
+            content_type = Some(values.read(der::OID)?);
+        } else if oid == MESSAGE_DIGEST {
+            message_digest = Some(values.read(der::OCTET_STRING)?);
+        }
+    }
+    match (content_type, message_digest) {
+        (Some(ID_DATA), Some(digest)) => Ok(digest.to_vec()),
+        (Some(_), Some(_)) => Err(ErrorKind::Unsupported.into()),
+        _ => Err(ErrorKind::Malformed.into())
+    }
+}
+
+impl<S: Stream> Stream for Verify<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.inner.poll()? {
+            Async::Ready(None) => {
+                if let Some(expected) = self.expected.take() {
+                    if !self.inner.digest()?.verify(&expected) {
+                        return Err(Error::from(ErrorKind::DigestMismatch).into());
+                    }
+                }
+                Ok(Async::Ready(None))
+            },
+            ready => Ok(ready)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+
+    use sign::{self, SigningKey};
+    use super::{Error, ErrorKind, Sign, Signer, Verify};
+    use super::super::Certificate;
+    use super::super::test::{EC_CERTIFICATE, EC_KEY, RSA_CERTIFICATE, RSA_KEY, der};
+
+    fn verify(signed_data: &[u8], certificates: &[Certificate], chunks: Vec<&'static str>) -> Result<(), Error> {
+        let verify = Verify::new(signed_data, certificates, iter_ok::<_, Error>(chunks.clone()))?;
+        assert_eq!(verify.wait().collect::<Result<Vec<_>, _>>()?, chunks);
+        Ok(())
+    }
+
+    fn certificates() -> Vec<Certificate> {
+        vec![Certificate::from_pem(EC_CERTIFICATE.as_bytes()).unwrap(),
+             Certificate::from_pem(RSA_CERTIFICATE.as_bytes()).unwrap()]
+    }
+
+    #[test]
+    fn openssl_vectors() {
+        // openssl cms -sign -binary -outform DER -md sha256
+        let message = vec!["hello from ", "openssl cms\n"];
+        assert!(verify(&der(OPENSSL_RSA), &certificates(), message.clone()).is_ok());
+        assert!(verify(&der(OPENSSL_EC), &certificates(), message).is_ok());
+        let err = verify(&der(OPENSSL_RSA), &certificates(), vec!["hello from openssl cms"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DigestMismatch);
+        let err = verify(&der(OPENSSL_RSA), &certificates()[..1], vec![]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownKey);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let rsa = SigningKey::from_pem(RSA_KEY.as_bytes()).unwrap();
+        let ec = SigningKey::from_pem(EC_KEY.as_bytes()).unwrap();
+        let signers = [
+            (&certificates()[1], &rsa, sign::Algorithm::RsaPkcs1v15Sha256),
+            (&certificates()[1], &rsa, sign::Algorithm::RsaPkcs1v15Sha512),
+            (&certificates()[0], &ec, sign::Algorithm::EcdsaP256Sha256)
+        ];
+        for &(certificate, key, algorithm) in &signers {
+            let signer = Signer::new(certificate, key, algorithm).unwrap();
+            let (signed_data, sign) = Sign::new(&signer, iter_ok::<_, Error>(vec!["foo", "bar"])).unwrap().split();
+            assert_eq!(sign.wait().collect::<Result<Vec<_>, _>>().unwrap(), vec!["foo", "bar"]);
+            let signed_data = signed_data.wait().unwrap().unwrap();
+            assert!(verify(&signed_data, &certificates(), vec!["foo", "bar"]).is_ok());
+            assert_eq!(verify(&signed_data, &certificates(), vec!["foo", "baz"]).unwrap_err().kind(), ErrorKind::DigestMismatch);
+
+            let mut tampered = signed_data.clone();
+            let len = tampered.len();
+            tampered[len - 10] ^= 1;
+            assert_eq!(verify(&tampered, &certificates(), vec![]).unwrap_err().kind(), ErrorKind::BadSignature);
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        let key = SigningKey::from_pem(RSA_KEY.as_bytes()).unwrap();
+        let err = Signer::new(&certificates()[1], &key, sign::Algorithm::RsaPssSha256).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        let signer = Signer::new(&certificates()[0], &key, sign::Algorithm::EcdsaP256Sha256).unwrap();
+        let mut sign = Sign::new(&signer, iter_ok::<Vec<&str>, Error>(vec![])).unwrap();
+        assert_eq!(sign.signed_data().unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    const OPENSSL_RSA: &str = "MIID/AYJKoZIhvcNAQcCoIID7TCCA+kCAQExDTALBglghkgBZQMEAgEwCwYJKoZIhvcNAQcBoIICCDCCAgQwggFtoAMCAQIC\
+FBzDP/M5lMQx6SYiFsHVH3CVxJsMMA0GCSqGSIb3DQEBCwUAMBMxETAPBgNVBAMMCGNtcy10ZXN0MCAXDTI2MTAxODEzNDQ0\
+NFoYDzIxMjYwOTI0MTM0NDQ0WjATMREwDwYDVQQDDAhjbXMtdGVzdDCBnzANBgkqhkiG9w0BAQEFAAOBjQAwgYkCgYEAyt/+\
+WMWJyfSZ+ctnxIU/+FjSziuhEFvaNgqCNSEajd29ksBSf/Sr0XsiYgIVjRRoxDV+/2zlU8/Fz+ed4P6DnGjmgQgrceDgLFPQ\
+PSIn1kxGv6tV49u/mqSWVepx1IRWOfLmRWe31YwfFI4A6nkjvaunRRPy6liorHj383WB79sCAwEAAaNTMFEwHQYDVR0OBBYE\
+FN9t3jBtCH3Bj3xUCEHRFBUOAzuAMB8GA1UdIwQYMBaAFN9t3jBtCH3Bj3xUCEHRFBUOAzuAMA8GA1UdEwEB/wQFMAMBAf8w\
+DQYJKoZIhvcNAQELBQADgYEAULooHog91+gZw7JBsMQwhhDW9wF18WXlLvIYgLnvTEq0lQNHYP2f1LsLVABcPMSewdAf9MLn\
+ws2DUMkKRQFtC3MUe0Dka5gxuQKbe8DtojGmkpkGy9obzupYMUS/LZAAdNXyTKHzPMzWf/nk6QOAxm3n0VSNyRARejGdDtZz\
+76AxggG6MIIBtgIBATArMBMxETAPBgNVBAMMCGNtcy10ZXN0AhQcwz/zOZTEMekmIhbB1R9wlcSbDDALBglghkgBZQMEAgGg\
+geQwGAYJKoZIhvcNAQkDMQsGCSqGSIb3DQEHATAcBgkqhkiG9w0BCQUxDxcNMjYxMDE4MTM0NDQ0WjAvBgkqhkiG9w0BCQQx\
+IgQgJZj8TnXWHmfBWTkYkIlD/XFIW75RfSZOndVA6GbwcGUweQYJKoZIhvcNAQkPMWwwajALBglghkgBZQMEASowCwYJYIZI\
+AWUDBAEWMAsGCWCGSAFlAwQBAjAKBggqhkiG9w0DBzAOBggqhkiG9w0DAgICAIAwDQYIKoZIhvcNAwICAUAwBwYFKw4DAgcw\
+DQYIKoZIhvcNAwICASgwDQYJKoZIhvcNAQEBBQAEgYAEjswbbQclPSBagZ4BMKCpFix4PMCmJ0C6BzxGWHrlRm2A8uLynVCt\
+xrPDcT+8nAJojcyeBLwIsfkcUqCuC6Qsd/J+0joFWJyR2s1fWmi4dXp+fu/5Gx3M4A7kO1F2MB7OW8FRvVZP/TWhObN5rErs\
++TYD+RMJjBAK1qlPm9qknw==";
+    const OPENSSL_EC: &str = "MIIDMgYJKoZIhvcNAQcCoIIDIzCCAx8CAQExDTALBglghkgBZQMEAgEwCwYJKoZIhvcNAQcBoIIBfTCCAXkwggEfoAMCAQIC\
+FCeDWyCQd8o/6jn3OpFO4Wqy1mkvMAoGCCqGSM49BAMCMBExDzANBgNVBAMMBmNtcy1lYzAgFw0yNjEwMTgxMzQ0NDRaGA8y\
+MTI2MDkyNDEzNDQ0NFowETEPMA0GA1UEAwwGY21zLWVjMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE+X5Vl29pRzy2IRPg\
+99K6sxjcqW2XCPkchH6xxh4FPjlCRPvk9RspxaUp3Xt0/jX2NUxGIG08vzrXFi0iFtYSoKNTMFEwHQYDVR0OBBYEFO5NpuP9\
+9tM76w0VDvjUXyIGmnwTMB8GA1UdIwQYMBaAFO5NpuP99tM76w0VDvjUXyIGmnwTMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZI\
+zj0EAwIDSAAwRQIgblAARBgWIQozA6gxseXYaFtb5BfSuZP92qdxicICqMECIQD9OtgsAlJnKK4XMV5DWQMGaHnjbGLV5P1I\
+Yr+/ccCOTzGCAXswggF3AgEBMCkwETEPMA0GA1UEAwwGY21zLWVjAhQng1sgkHfKP+o59zqRTuFqstZpLzALBglghkgBZQME\
+AgGggeQwGAYJKoZIhvcNAQkDMQsGCSqGSIb3DQEHATAcBgkqhkiG9w0BCQUxDxcNMjYxMDE4MTM0NDQ0WjAvBgkqhkiG9w0B\
+CQQxIgQgJZj8TnXWHmfBWTkYkIlD/XFIW75RfSZOndVA6GbwcGUweQYJKoZIhvcNAQkPMWwwajALBglghkgBZQMEASowCwYJ\
+YIZIAWUDBAEWMAsGCWCGSAFlAwQBAjAKBggqhkiG9w0DBzAOBggqhkiG9w0DAgICAIAwDQYIKoZIhvcNAwICAUAwBwYFKw4D\
+AgcwDQYIKoZIhvcNAwICASgwCgYIKoZIzj0EAwIERzBFAiEAh8hrFye0zWedFWza9cMCc8W0Q14qgVIwXxr8W+HHQY8CIGGp\
+ovSo18rIr+gjDotfxG5OOp0Lbh2mAc+5MvTS+5xB";
+}
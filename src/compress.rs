@@ -0,0 +1,598 @@
+//! Compression of streams, to be applied before encryption.
+//!
+//! Encrypted data does not compress, so data that is meant to be compressed has to be
+//! compressed before it is encrypted. [`CompressEncrypt`](struct.CompressEncrypt.html)
+//! and [`DecryptDecompress`](struct.DecryptDecompress.html) combine both steps.
+//!
+//! Deflate and gzip are provided by [zlib](https://zlib.net/) through the
+//! [`flate2`](https://docs.rs/flate2) crate, and zstd by the [`zstd`](https://docs.rs/zstd) crate.
+//!
+//! Note that compressing secret data together with data controlled by an attacker can
+//! reveal the secret data through the length of the ciphertext, as in the
+//! [CRIME](https://en.wikipedia.org/wiki/CRIME) attack.
+
+use std::cmp;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use bytes::Bytes;
+use flate2::{self, Compression, FlushCompress, FlushDecompress, Status};
+use futures::{Async, Poll, Stream};
+use zstd;
+use zstd::stream::raw::{self, Operation, OutBuffer};
+
+use super::{Error, ErrorKind};
+use super::cipher;
+use super::hash::{self, Digest, Hash};
+
+/// Number of bytes of output which are produced at a time.
+const CHUNK_LEN: usize = 16 * 1024;
+
+/// Compression format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Format {
+    /// Raw deflate data, as specified in [RFC 1951](https://tools.ietf.org/html/rfc1951).
+    Deflate,
+    /// Gzip, as specified in [RFC 1952](https://tools.ietf.org/html/rfc1952).
+    Gzip,
+    /// Zstandard, as specified in [RFC 8878](https://tools.ietf.org/html/rfc8878).
+    Zstd,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Format {
+    /// Get the range of supported compression levels.
+    fn levels(self) -> (i32, i32) {
+        match self {
+            Format::Deflate | Format::Gzip => (0, 9),
+            Format::Zstd => (1, 22),
+            Format::_Donotmatch => unreachable!()
+        }
+    }
+}
+
+/// Configuration of compression and decompression.
+#[derive(Clone, Debug)]
+pub struct Config {
+    format: Format,
+    level: Option<i32>,
+    max_size: Option<u64>
+}
+
+impl Config {
+    /// Create a new configuration for the given format, using the default compression level
+    /// of the format and no limit on the size of decompressed data.
+    pub fn new(format: Format) -> Config {
+        Config { format, level: None, max_size: None }
+    }
+
+    /// Set the compression level, which ranges from 0 to 9 for deflate and gzip,
+    /// and from 1 to 22 for zstd. Higher levels compress better, but more slowly.
+    pub fn set_level(&mut self, level: i32) {
+        self.level = Some(level);
+    }
+
+    /// Set the maximum number of bytes of decompressed data.
+    ///
+    /// Decompression fails with `ErrorKind::LimitExceeded` as soon as the limit is
+    /// exceeded, to protect against small inputs that decompress to huge outputs.
+    /// No more than one byte beyond the limit is decompressed.
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = Some(max_size);
+    }
+}
+
+/// Stream adapter that compresses the data of its underlying stream.
+pub struct Compress<S> {
+    inner: S,
+    codec: Codec,
+    finished: bool
+}
+
+impl<S: Debug> Debug for Compress<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Compress")
+            .field("inner", &self.inner)
+            .field("format", &self.codec.format)
+            .finish()
+    }
+}
+
+impl<S: Stream> Compress<S> {
+    /// Create a compressing stream adapter.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the compression level is out of range
+    /// for the format.
+    pub fn new(config: &Config, inner: S) -> Result<Self, Error> {
+        if let Some(level) = config.level {
+            let (min, max) = config.format.levels();
+            if level < min || level > max {
+                return Err(ErrorKind::Unsupported.into());
+            }
+        }
+        Ok(Compress { inner, codec: Codec::new(config.format, Some(config.level))?, finished: false })
+    }
+}
+
+impl<S: Stream> Stream for Compress<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            let mut output = Vec::new();
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => self.codec.compress(item.as_ref(), &mut output)?,
+                Async::Ready(None) => {
+                    self.finished = true;
+                    self.codec.finish(&mut output)?;
+                }
+            }
+            if !output.is_empty() {
+                return Ok(Async::Ready(Some(Bytes::from(output))));
+            }
+        }
+    }
+}
+
+/// Stream adapter that decompresses the data of its underlying stream.
+///
+/// If the data is not valid, is truncated, or is followed by trailing data, the stream
+/// fails with `ErrorKind::Malformed`.
+pub struct Decompress<S> {
+    inner: S,
+    codec: Codec,
+    max_size: Option<u64>,
+    size: u64,
+    ended: bool,
+    finished: bool
+}
+
+impl<S: Debug> Debug for Decompress<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Decompress")
+            .field("inner", &self.inner)
+            .field("format", &self.codec.format)
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+impl<S: Stream> Decompress<S> {
+    /// Create a decompressing stream adapter.
+    pub fn new(config: &Config, inner: S) -> Result<Self, Error> {
+        Ok(Decompress {
+            inner,
+            codec: Codec::new(config.format, None)?,
+            max_size: config.max_size,
+            size: 0,
+            ended: false,
+            finished: false
+        })
+    }
+
+    fn update(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+        if self.ended {
+            if input.is_empty() {
+                return Ok(());
+            }
+            return Err(ErrorKind::Malformed.into());
+        }
+        let limit = self.max_size.map_or(u64::MAX, |max_size| max_size - self.size);
+        self.ended = self.codec.decompress(input, output, limit)?;
+        self.size += output.len() as u64;
+        Ok(())
+    }
+}
+
+impl<S: Stream> Stream for Decompress<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            let mut output = Vec::new();
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => self.update(item.as_ref(), &mut output)?,
+                Async::Ready(None) => {
+                    self.finished = true;
+                    if !self.ended {
+                        return Err(Error::from(ErrorKind::Malformed).into());
+                    }
+                }
+            }
+            if !output.is_empty() {
+                return Ok(Async::Ready(Some(Bytes::from(output))));
+            }
+        }
+    }
+}
+
+/// Stream adapter that compresses the data of its underlying stream, and then encrypts it.
+pub struct CompressEncrypt<S>(cipher::Encrypt<Compress<S>>);
+
+impl<S: Debug> Debug for CompressEncrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple("CompressEncrypt")
+            .field(&self.0)
+            .finish()
+    }
+}
+
+impl<S: Stream> CompressEncrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    /// Create a stream adapter that compresses and encrypts, given the configurations
+    /// of both steps.
+    pub fn new(compression: &Config, encryption: &cipher::Config, inner: S) -> Result<Self, Error> {
+        Ok(CompressEncrypt(cipher::Encrypt::new(encryption, Compress::new(compression, inner)?)?))
+    }
+}
+
+impl<S: Stream> Stream for CompressEncrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        self.0.poll()
+    }
+}
+
+/// Stream adapter that decrypts the data of its underlying stream, and then decompresses it,
+/// optionally computing a digest of the decompressed data.
+pub struct DecryptDecompress<S> {
+    inner: Hashed<Decompress<cipher::Decrypt<S>>>
+}
+
+enum Hashed<S> {
+    Plain(S),
+    Hash(Hash<S>)
+}
+
+impl<S: Debug> Debug for DecryptDecompress<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut f = f.debug_struct("DecryptDecompress");
+        match self.inner {
+            Hashed::Plain(ref inner) => f.field("inner", inner),
+            Hashed::Hash(ref inner) => f.field("inner", inner)
+        };
+        f.finish()
+    }
+}
+
+impl<S: Stream> DecryptDecompress<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    /// Create a stream adapter that decrypts and decompresses, given the configurations
+    /// of both steps.
+    pub fn new(compression: &Config, decryption: &cipher::Config, inner: S) -> Result<Self, Error> {
+        let inner = Decompress::new(compression, cipher::Decrypt::new(decryption, inner)?)?;
+        Ok(DecryptDecompress { inner: Hashed::Plain(inner) })
+    }
+
+    /// Create a stream adapter that decrypts and decompresses like
+    /// [`new`](#method.new), and computes a digest of the decompressed data.
+    pub fn with_digest(compression: &Config, decryption: &cipher::Config, algo: hash::Algorithm, inner: S)
+        -> Result<Self, Error>
+    {
+        let inner = Decompress::new(compression, cipher::Decrypt::new(decryption, inner)?)?;
+        Ok(DecryptDecompress { inner: Hashed::Hash(Hash::new(algo, inner)?) })
+    }
+
+    /// Compute the digest of the decompressed data, or return `None` if the stream adapter
+    /// was created without a hash algorithm.
+    ///
+    /// The digest covers all data that has been yielded by the stream so far, so it should
+    /// only be computed after the stream has been fully consumed.
+    pub fn digest(&mut self) -> Result<Option<Digest>, Error> {
+        match self.inner {
+            Hashed::Plain(_) => Ok(None),
+            Hashed::Hash(ref mut hash) => hash.digest().map(Some)
+        }
+    }
+}
+
+impl<S: Stream> Stream for DecryptDecompress<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        match self.inner {
+            Hashed::Plain(ref mut inner) => inner.poll(),
+            Hashed::Hash(ref mut inner) => inner.poll()
+        }
+    }
+}
+
+/// Window size of deflate and gzip data, as the base-2 logarithm of its size in bytes.
+const WINDOW_BITS: u8 = 15;
+
+/// Compressor or decompressor of one of the supported formats.
+struct Codec {
+    format: Format,
+    state: CodecState
+}
+
+enum CodecState {
+    Deflate(flate2::Compress),
+    Inflate(flate2::Decompress),
+    ZstdEncoder(raw::Encoder<'static>),
+    ZstdDecoder(raw::Decoder<'static>)
+}
+
+impl Codec {
+    /// Create a compressor with the given level, which is `None` for the default level,
+    /// or a decompressor if no level is given at all.
+    fn new(format: Format, level: Option<Option<i32>>) -> Result<Codec, Error> {
+        let deflate_level = |level: Option<i32>| {
+            level.map_or(Compression::default(), |level| Compression::new(level as u32))
+        };
+        let state = match (format, level) {
+            (Format::Deflate, Some(level)) => CodecState::Deflate(flate2::Compress::new(deflate_level(level), false)),
+            (Format::Deflate, None) => CodecState::Inflate(flate2::Decompress::new(false)),
+            (Format::Gzip, Some(level)) => {
+                CodecState::Deflate(flate2::Compress::new_gzip(deflate_level(level), WINDOW_BITS))
+            },
+            (Format::Gzip, None) => CodecState::Inflate(flate2::Decompress::new_gzip(WINDOW_BITS)),
+            (Format::Zstd, Some(level)) => {
+                let encoder = raw::Encoder::new(level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))
+                    .map_err(|err| Error::new(ErrorKind::Unsupported, err))?;
+                CodecState::ZstdEncoder(encoder)
+            },
+            (Format::Zstd, None) => {
+                CodecState::ZstdDecoder(raw::Decoder::new().map_err(|err| Error::new(ErrorKind::Backend, err))?)
+            },
+            (Format::_Donotmatch, _) => unreachable!()
+        };
+        Ok(Codec { format, state })
+    }
+
+    /// Compress the input, appending the compressed data to the output.
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+        self.compress_chunk(input, false, output)
+    }
+
+    /// Finish compression, appending the remaining compressed data to the output.
+    fn finish(&mut self, output: &mut Vec<u8>) -> Result<(), Error> {
+        self.compress_chunk(&[], true, output)
+    }
+
+    fn compress_chunk(&mut self, input: &[u8], finish: bool, output: &mut Vec<u8>) -> Result<(), Error> {
+        let mut input = input;
+        loop {
+            let start = output.len();
+            output.resize(start + CHUNK_LEN, 0);
+            let (read, written, ended) = match self.state {
+                CodecState::Deflate(ref mut stream) => {
+                    let (total_in, total_out) = (stream.total_in(), stream.total_out());
+                    let flush = if finish { FlushCompress::Finish } else { FlushCompress::None };
+                    let status = stream.compress(input, &mut output[start..], flush)
+                        .map_err(|err| Error::new(ErrorKind::Backend, err))?;
+                    ((stream.total_in() - total_in) as usize, (stream.total_out() - total_out) as usize,
+                     status == Status::StreamEnd)
+                },
+                CodecState::ZstdEncoder(ref mut encoder) => if finish {
+                    let mut buffer = OutBuffer::around(&mut output[start..]);
+                    let remaining = encoder.finish(&mut buffer, true)
+                        .map_err(|err| Error::new(ErrorKind::Backend, err))?;
+                    (0, buffer.pos(), remaining == 0)
+                } else {
+                    let status = encoder.run_on_buffers(input, &mut output[start..])
+                        .map_err(|err| Error::new(ErrorKind::Backend, err))?;
+                    (status.bytes_read, status.bytes_written, false)
+                },
+                CodecState::Inflate(_) | CodecState::ZstdDecoder(_) => unreachable!()
+            };
+            output.truncate(start + written);
+            input = &input[read..];
+            if ended || (!finish && input.is_empty() && written < CHUNK_LEN) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Decompress the input, appending the decompressed data to the output, and return
+    /// whether the end of the compressed data has been reached.
+    ///
+    /// Fails with `ErrorKind::LimitExceeded` if more than `limit` bytes would be appended.
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>, limit: u64) -> Result<bool, Error> {
+        let mut input = input;
+        loop {
+            let start = output.len();
+            // Decompress at most one byte more than the limit allows, so that it is enforced exactly.
+            let len = cmp::min(CHUNK_LEN as u64, limit.saturating_sub(start as u64).saturating_add(1)) as usize;
+            output.resize(start + len, 0);
+            let (read, written, ended) = match self.state {
+                CodecState::Inflate(ref mut stream) => {
+                    let (total_in, total_out) = (stream.total_in(), stream.total_out());
+                    let status = stream.decompress(input, &mut output[start..], FlushDecompress::None)
+                        .map_err(|err| Error::new(ErrorKind::Malformed, err))?;
+                    ((stream.total_in() - total_in) as usize, (stream.total_out() - total_out) as usize,
+                     status == Status::StreamEnd)
+                },
+                CodecState::ZstdDecoder(ref mut decoder) => {
+                    let status = decoder.run_on_buffers(input, &mut output[start..])
+                        .map_err(|err| Error::new(ErrorKind::Malformed, err))?;
+                    // A frame has been decoded and flushed completely.
+                    (status.bytes_read, status.bytes_written, status.remaining == 0)
+                },
+                CodecState::Deflate(_) | CodecState::ZstdEncoder(_) => unreachable!()
+            };
+            output.truncate(start + written);
+            input = &input[read..];
+            if output.len() as u64 > limit {
+                return Err(ErrorKind::LimitExceeded.into());
+            }
+            if ended {
+                if !input.is_empty() {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                return Ok(true);
+            }
+            if input.is_empty() && written < len {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+    use hex::FromHex;
+
+    use self::itertools::Itertools;
+
+    use cipher;
+    use hash;
+    use super::{Compress, CompressEncrypt, Config, Decompress, DecryptDecompress, Error, ErrorKind, Format};
+
+    const FORMATS: [Format; 3] = [Format::Deflate, Format::Gzip, Format::Zstd];
+
+    fn compress(config: &Config, chunks: Vec<Vec<u8>>) -> Vec<u8> {
+        Compress::new(config, iter_ok::<_, Error>(chunks)).unwrap().concat2().wait().unwrap().to_vec()
+    }
+
+    fn decompress(config: &Config, data: &[u8], chunk_len: usize) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = data.chunks(chunk_len).map(|chunk| chunk.to_vec()).collect();
+        Decompress::new(config, iter_ok::<_, Error>(chunks)).unwrap().concat2().wait().map(|data| data.to_vec())
+    }
+
+    #[test]
+    fn known_data() {
+        let cases = [
+            (Format::Deflate, "cb48cdc9c95728cf2fca490100"),
+            (Format::Gzip, "1f8b0800000000000203cb48cdc9c95728cf2fca49010085114a0d0b000000"),
+            (Format::Zstd, "28b52ffd005859000068656c6c6f20776f726c64")
+        ];
+        for &(format, data) in &cases {
+            let data = Vec::<u8>::from_hex(data).unwrap();
+            for chunk_len in 1..5 {
+                assert_eq!(decompress(&Config::new(format), &data, chunk_len).unwrap(), b"hello world");
+            }
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let data = b"abcdefgh".iter().cycle().take(100_000).cloned().collect::<Vec<u8>>();
+        for &format in &FORMATS {
+            let (min, max) = format.levels();
+            for &level in &[min, max] {
+                let mut config = Config::new(format);
+                config.set_level(level);
+                let compressed = compress(&config, vec![data.clone()]);
+                assert!(compressed.len() < data.len() / 10 || level == 0);
+                assert_eq!(decompress(&config, &compressed, 1000).unwrap(), data);
+            }
+            for &level in &[min - 1, max + 1] {
+                let mut config = Config::new(format);
+                config.set_level(level);
+                let input = iter_ok::<Vec<u8>, Error>(vec![]);
+                assert_eq!(Compress::new(&config, input).unwrap_err().kind(), ErrorKind::Unsupported);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for &format in &FORMATS {
+            let config = Config::new(format);
+            let compressed = compress(&config, vec![b"hello world".to_vec()]);
+            let truncated = &compressed[..compressed.len() - 1];
+            assert_eq!(decompress(&config, truncated, 3).unwrap_err().kind(), ErrorKind::Malformed);
+            let mut trailing = compressed.clone();
+            trailing.push(0);
+            assert_eq!(decompress(&config, &trailing, 3).unwrap_err().kind(), ErrorKind::Malformed);
+            assert_eq!(decompress(&config, b"garbage data", 3).unwrap_err().kind(), ErrorKind::Malformed);
+            assert_eq!(decompress(&config, b"", 3).unwrap_err().kind(), ErrorKind::Malformed);
+        }
+    }
+
+    #[test]
+    fn max_size() {
+        let data = vec![0; 1_000_000];
+        for &format in &FORMATS {
+            let mut config = Config::new(format);
+            let compressed = compress(&config, vec![data.clone()]);
+            config.set_max_size(data.len() as u64);
+            assert_eq!(decompress(&config, &compressed, 100).unwrap(), data);
+            config.set_max_size(data.len() as u64 - 1);
+            assert_eq!(decompress(&config, &compressed, 100).unwrap_err().kind(), ErrorKind::LimitExceeded);
+            assert_eq!(decompress(&config, &compressed, compressed.len()).unwrap_err().kind(),
+                       ErrorKind::LimitExceeded);
+            config.set_max_size(0);
+            assert_eq!(decompress(&config, &compressed, compressed.len()).unwrap_err().kind(),
+                       ErrorKind::LimitExceeded);
+            let empty = compress(&config, vec![]);
+            assert_eq!(decompress(&config, &empty, empty.len()).unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn pipeline() {
+        let mut encryption = cipher::Config::new(cipher::Algorithm::Aes256Cbc);
+        encryption.key_mut().copy_from_slice(&[0x42; 32]);
+        encryption.iv_mut().unwrap().copy_from_slice(&[0x24; 16]);
+        let data = b"backup ".iter().cycle().take(10_000).cloned().collect::<Vec<u8>>();
+        for &format in &FORMATS {
+            let compression = Config::new(format);
+            let input = iter_ok::<_, Error>(data.chunks(1000).map(|chunk| chunk.to_vec()).collect::<Vec<_>>());
+            let encrypted = CompressEncrypt::new(&compression, &encryption, input).unwrap().concat2().wait().unwrap();
+            assert!(encrypted.len() < 1000);
+
+            let input = iter_ok::<_, Error>(encrypted.chunks(7).map(|chunk| chunk.to_vec()).collect::<Vec<_>>());
+            let mut decrypt = DecryptDecompress::with_digest(&compression, &encryption, hash::Algorithm::Sha256, input)
+                .unwrap();
+            let decrypted = decrypt.by_ref().concat2().wait().unwrap();
+            assert_eq!(&decrypted[..], &data[..]);
+            let digest = decrypt.digest().unwrap().unwrap();
+            let mut hash = hash::Hash::new(hash::Algorithm::Sha256, iter_ok::<_, Error>(vec![data.clone()])).unwrap();
+            hash.by_ref().collect().wait().unwrap();
+            assert_eq!(digest.as_ref(), hash.digest().unwrap().as_ref());
+
+            let input = iter_ok::<_, Error>(vec![encrypted.to_vec()]);
+            let mut decrypt = DecryptDecompress::new(&compression, &encryption, input).unwrap();
+            assert_eq!(&decrypt.by_ref().concat2().wait().unwrap()[..], &data[..]);
+            assert!(decrypt.digest().unwrap().is_none());
+        }
+    }
+
+    quickcheck! {
+        fn roundtrip(chunks: Vec<Vec<u8>>, chunk_len: usize) -> bool {
+            let data = chunks.iter().cloned().concat();
+            FORMATS.iter().all(|&format| {
+                let config = Config::new(format);
+                let compressed = compress(&config, chunks.clone());
+                decompress(&config, &compressed, chunk_len % 64 + 1).unwrap() == data
+            })
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Stream adapters that encode binary data as text, and decode it again.
+//!
+//! This is useful to transport the output of an [`Encrypt`](../cipher/struct.Encrypt.html)
+//! stream in a text format such as JSON or email. Groups of bytes or characters which are
+//! split across chunks of the underlying stream are buffered until they are complete.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Poll, Stream};
+use hex::{FromHex, ToHex};
+
+use super::{Error, ErrorKind};
+use super::base64;
+
+/// Text encoding of binary data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Encoding {
+    /// Base64 with the standard alphabet and padding, as specified in
+    /// [RFC 4648, section 4](https://tools.ietf.org/html/rfc4648#section-4).
+    Base64,
+    /// Base64 with the URL and filename safe alphabet and without padding, as specified in
+    /// [RFC 4648, section 5](https://tools.ietf.org/html/rfc4648#section-5).
+    Base64Url,
+    /// Lowercase hexadecimal.
+    Hex,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Encoding {
+    /// Get the number of bytes which are encoded together.
+    fn input_group_len(self) -> usize {
+        match self {
+            Encoding::Base64 | Encoding::Base64Url => 3,
+            _ => 1
+        }
+    }
+
+    /// Get the number of characters which are decoded together.
+    fn output_group_len(self) -> usize {
+        match self {
+            Encoding::Base64 | Encoding::Base64Url => 4,
+            _ => 2
+        }
+    }
+
+    fn encode(self, input: &[u8]) -> String {
+        match self {
+            Encoding::Base64 => base64::encode(input, true),
+            Encoding::Base64Url => base64::encode_url(input, false),
+            Encoding::Hex => input.to_hex(),
+            Encoding::_Donotmatch => unreachable!()
+        }
+    }
+
+    /// Decode complete groups of characters, of which only the last may be padded.
+    fn decode(self, input: &[u8], padded: bool) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Base64 => base64::decode(input, padded).ok_or_else(|| ErrorKind::Malformed.into()),
+            Encoding::Base64Url => base64::decode_url(input, padded).ok_or_else(|| ErrorKind::Malformed.into()),
+            Encoding::Hex => Vec::<u8>::from_hex(input).map_err(|err| Error::new(ErrorKind::Malformed, err)),
+            Encoding::_Donotmatch => unreachable!()
+        }
+    }
+}
+
+/// Stream adapter that encodes the data of its underlying stream as text.
+pub struct Encode<S> {
+    inner: S,
+    encoding: Encoding,
+    buffer: BytesMut,
+    finished: bool
+}
+
+impl<S: Debug> Debug for Encode<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Encode")
+            .field("inner", &self.inner)
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+impl<S: Stream> Encode<S> {
+    /// Create an encoding stream adapter.
+    pub fn new(encoding: Encoding, inner: S) -> Self {
+        Encode { inner, encoding, buffer: BytesMut::new(), finished: false }
+    }
+}
+
+impl<S: Stream> Stream for Encode<S>
+    where S::Item: AsRef<[u8]>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            let data = match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => {
+                    self.buffer.extend_from_slice(item.as_ref());
+                    let len = self.buffer.len() - self.buffer.len() % self.encoding.input_group_len();
+                    self.buffer.split_to(len)
+                },
+                Async::Ready(None) => {
+                    self.finished = true;
+                    self.buffer.take()
+                }
+            };
+            if !data.is_empty() {
+                return Ok(Async::Ready(Some(Bytes::from(self.encoding.encode(&data)))));
+            }
+        }
+    }
+}
+
+/// Stream adapter that decodes text from its underlying stream.
+///
+/// ASCII whitespace, such as line breaks, is ignored. Base64 is accepted both with and
+/// without padding, in the alphabet of the encoding. If the text is not valid, the stream
+/// fails with `ErrorKind::Malformed`.
+pub struct Decode<S> {
+    inner: S,
+    encoding: Encoding,
+    chars: Vec<u8>,
+    padded: bool,
+    finished: bool
+}
+
+impl<S: Debug> Debug for Decode<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Decode")
+            .field("inner", &self.inner)
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+impl<S: Stream> Decode<S> {
+    /// Create a decoding stream adapter.
+    pub fn new(encoding: Encoding, inner: S) -> Self {
+        Decode { inner, encoding, chars: Vec::new(), padded: false, finished: false }
+    }
+
+    fn update(&mut self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        for byte in input.iter().filter(|byte| !byte.is_ascii_whitespace()) {
+            // Padding can only occur at the very end.
+            if self.padded {
+                return Err(ErrorKind::Malformed.into());
+            }
+            self.chars.push(*byte);
+        }
+        let len = self.chars.len() - self.chars.len() % self.encoding.output_group_len();
+        self.padded = self.chars[..len].ends_with(b"=");
+        let data = self.encoding.decode(&self.chars[..len], self.padded)?;
+        self.chars.drain(..len);
+        Ok(data)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, Error> {
+        if self.chars.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.encoding.decode(&self.chars, false)
+    }
+}
+
+impl<S: Stream> Stream for Decode<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            let data = match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => self.update(item.as_ref())?,
+                Async::Ready(None) => {
+                    self.finished = true;
+                    self.finish()?
+                }
+            };
+            if !data.is_empty() {
+                return Ok(Async::Ready(Some(Bytes::from(data))));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+
+    use self::itertools::Itertools;
+
+    use super::{Decode, Encode, Encoding, Error, ErrorKind};
+
+    fn encode(encoding: Encoding, chunks: Vec<Vec<u8>>) -> Vec<u8> {
+        Encode::new(encoding, iter_ok::<_, Error>(chunks)).concat2().wait().unwrap().to_vec()
+    }
+
+    fn decode(encoding: Encoding, text: &[u8], chunk_len: usize) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = text.chunks(chunk_len).map(|chunk| chunk.to_vec()).collect();
+        Decode::new(encoding, iter_ok::<_, Error>(chunks)).concat2().wait().map(|data| data.to_vec())
+    }
+
+    #[test]
+    fn split_groups() {
+        let chunks = vec![b"f".to_vec(), b"oob".to_vec(), vec![], b"a\xfb\xff".to_vec()];
+        assert_eq!(encode(Encoding::Base64, chunks.clone()), b"Zm9vYmH7/w==");
+        assert_eq!(encode(Encoding::Base64Url, chunks.clone()), b"Zm9vYmH7_w");
+        assert_eq!(encode(Encoding::Hex, chunks), b"666f6f6261fbff");
+        for chunk_len in 1..8 {
+            assert_eq!(decode(Encoding::Base64, b"Zm9v\r\nYmH7/w==\r\n", chunk_len).unwrap(), b"foob\x61\xfb\xff");
+            assert_eq!(decode(Encoding::Base64, b"Zm9vYmH7/w", chunk_len).unwrap(), b"foob\x61\xfb\xff");
+            assert_eq!(decode(Encoding::Base64Url, b"Zm9vYmH7_w==", chunk_len).unwrap(), b"foob\x61\xfb\xff");
+            assert_eq!(decode(Encoding::Hex, b"666F6f62 61fbff", chunk_len).unwrap(), b"foob\x61\xfb\xff");
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let cases: [(Encoding, &[u8]); 7] = [
+            (Encoding::Base64, b"Zm9vY"), (Encoding::Base64, b"Zg==Zg=="), (Encoding::Base64, b"Zm9v_w"),
+            (Encoding::Base64Url, b"Zm9v/w"), (Encoding::Base64, b"Zh=="),
+            (Encoding::Hex, b"666"), (Encoding::Hex, b"6g")
+        ];
+        for &(encoding, text) in &cases {
+            for chunk_len in 1..4 {
+                assert_eq!(decode(encoding, text, chunk_len).unwrap_err().kind(), ErrorKind::Malformed);
+            }
+        }
+    }
+
+    quickcheck! {
+        fn roundtrip(chunks: Vec<Vec<u8>>, chunk_len: usize) -> bool {
+            let data = chunks.iter().cloned().concat();
+            [Encoding::Base64, Encoding::Base64Url, Encoding::Hex].iter().all(|&encoding| {
+                let text = encode(encoding, chunks.clone());
+                decode(encoding, &text, chunk_len % 16 + 1).unwrap() == data
+            })
+        }
+    }
+}
@@ -0,0 +1,540 @@
+//! Envelope encryption of streams.
+//!
+//! Every stream is encrypted with a freshly generated data key, using
+//! [authenticated encryption](../cipher/etm/index.html).
+//! The data key is wrapped by a key-encryption key, which is managed by
+//! a [`KeyProvider`](trait.KeyProvider.html), and stored in a header
+//! that precedes the ciphertext.
+//!
+//! The header has the following layout, with lengths in big-endian byte order:
+//!
+//! | Field        | Size           |
+//! |--------------|----------------|
+//! | magic `CNVL` | 4 bytes        |
+//! | version      | 1 byte         |
+//! | algorithm    | 1 byte         |
+//! | key id       | 1 byte length  |
+//! | wrapped key  | 2 bytes length |
+//! | iv           | 1 byte length  |
+//! | header MAC   | 32 bytes       |
+//!
+//! The header MAC is an HMAC-SHA-256 over the preceding fields, keyed with a key
+//! derived from the data key, so that the algorithm, key id and IV cannot be modified.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, Future, Poll, Stream};
+use futures::future;
+use openssl;
+
+use super::{Error, ErrorKind};
+use super::cipher::{etm, wrap};
+use super::hash::{self, Hmac, hkdf};
+use super::hybrid::{algorithm_by_id, algorithm_id};
+use super::random::{Generator, RandomBytes};
+use super::secret::zeroize;
+
+/// Future resolving with a wrapped or unwrapped key.
+pub type KeyFuture = Box<dyn Future<Item = Vec<u8>, Error = Error> + Send>;
+
+/// Provider of a key-encryption key.
+///
+/// Implementations may keep the key-encryption key locally, or delegate
+/// wrapping and unwrapping to a remote key management service.
+pub trait KeyProvider: Send + Sync {
+    /// Get the identifier of the key-encryption key.
+    ///
+    /// The identifier is stored in the header in plain text, and is used to
+    /// select the provider when decrypting. It must not exceed 255 bytes.
+    fn key_id(&self) -> &str;
+
+    /// Wrap a data key.
+    fn wrap_key(&self, key: &[u8]) -> KeyFuture;
+
+    /// Unwrap a data key that was previously wrapped by this provider.
+    fn unwrap_key(&self, wrapped: &[u8]) -> KeyFuture;
+}
+
+/// Key provider that keeps the key-encryption key in local memory.
+pub struct LocalKeyProvider {
+    key_id: String,
+    config: wrap::Config
+}
+
+impl Debug for LocalKeyProvider {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("LocalKeyProvider")
+            .field("key_id", &self.key_id)
+            .finish()
+    }
+}
+
+impl LocalKeyProvider {
+    /// Create a provider from a key wrapping configuration.
+    pub fn new<I: Into<String>>(key_id: I, config: wrap::Config) -> Self {
+        LocalKeyProvider { key_id: key_id.into(), config }
+    }
+
+    /// Create a provider by reading the key-encryption key from a file.
+    ///
+    /// The file is expected to contain exactly 16 or 32 bytes, which are used
+    /// as a key for AES key wrap with padding of the respective strength.
+    pub fn open<I: Into<String>, P: AsRef<Path>>(key_id: I, path: P) -> Result<Self, IoError> {
+        let mut key = Vec::new();
+        let result = File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut key))
+            .and_then(|len| {
+                let algo = match len {
+                    16 => wrap::Algorithm::Aes128Kwp,
+                    32 => wrap::Algorithm::Aes256Kwp,
+                    _ => return Err(IoError::new(IoErrorKind::InvalidData, "key file must contain 16 or 32 bytes"))
+                };
+                let mut config = wrap::Config::new(algo);
+                config.key_mut().copy_from_slice(&key);
+                Ok(config)
+            });
+        zeroize(&mut key);
+        result.map(|config| LocalKeyProvider::new(key_id, config))
+    }
+}
+
+impl KeyProvider for LocalKeyProvider {
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn wrap_key(&self, key: &[u8]) -> KeyFuture {
+        Box::new(future::result(self.config.wrap(key)))
+    }
+
+    fn unwrap_key(&self, wrapped: &[u8]) -> KeyFuture {
+        Box::new(future::result(self.config.unwrap(wrapped)))
+    }
+}
+
+/// Stream adapter that encrypts the data from the underlying stream under a fresh data key.
+///
+/// The first item yielded is the header, followed by the ciphertext and the authentication tag.
+pub struct Encrypt<S> {
+    provider: Arc<dyn KeyProvider>,
+    algo: etm::Algorithm,
+    state: EncryptState<S>
+}
+
+enum EncryptState<S> {
+    Generating(RandomBytes, S),
+    Wrapping(KeyFuture, etm::Config, S),
+    Streaming(etm::Encrypt<S>),
+    Done
+}
+
+impl<S: Debug> Debug for Encrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Encrypt")
+            .field("key_id", &self.provider.key_id())
+            .field("algo", &self.algo)
+            .finish()
+    }
+}
+
+impl<S: Stream> Encrypt<S> {
+    /// Create an encrypting stream adapter.
+    ///
+    /// The data key is generated using `generator`, and wrapped using `provider`.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the algorithm cannot be recorded in the header,
+    /// and with `ErrorKind::Malformed` if the key id of the provider is longer than 255 bytes.
+    pub fn new(generator: &Generator, provider: Arc<dyn KeyProvider>, algo: etm::Algorithm, inner: S)
+        -> Result<Self, Error>
+    {
+        algorithm_id(algo)?;
+        if provider.key_id().len() > MAX_KEY_ID_LEN {
+            return Err(ErrorKind::Malformed.into());
+        }
+        let random_bytes = generator.random_bytes(DATA_KEY_LEN + IV_LEN);
+        Ok(Encrypt { provider, algo, state: EncryptState::Generating(random_bytes, inner) })
+    }
+}
+
+impl<S: Stream> Stream for Encrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, EncryptState::Done) {
+                EncryptState::Generating(mut random_bytes, inner) => {
+                    let bytes = match random_bytes.poll()? {
+                        Async::NotReady => {
+                            self.state = EncryptState::Generating(random_bytes, inner);
+                            return Ok(Async::NotReady);
+                        },
+                        Async::Ready(bytes) => bytes
+                    };
+                    let mut config = etm::Config::new(self.algo);
+                    let (key, iv) = bytes.split_at(DATA_KEY_LEN);
+                    config.key_mut().copy_from_slice(key);
+                    config.iv_mut().copy_from_slice(iv);
+                    let wrapping = self.provider.wrap_key(key);
+                    self.state = EncryptState::Wrapping(wrapping, config, inner);
+                },
+                EncryptState::Wrapping(mut wrapping, mut config, inner) => {
+                    let wrapped = match wrapping.poll()? {
+                        Async::NotReady => {
+                            self.state = EncryptState::Wrapping(wrapping, config, inner);
+                            return Ok(Async::NotReady);
+                        },
+                        Async::Ready(wrapped) => wrapped
+                    };
+                    let mut iv = [0u8; IV_LEN];
+                    iv.copy_from_slice(config.iv_mut());
+                    let header = encode_header(self.algo, self.provider.key_id(), &wrapped, &iv, config.key_mut())?;
+                    self.state = EncryptState::Streaming(etm::Encrypt::new(&config, inner)?);
+                    return Ok(Async::Ready(Some(header)));
+                },
+                EncryptState::Streaming(mut encrypt) => {
+                    let result = encrypt.poll();
+                    self.state = EncryptState::Streaming(encrypt);
+                    return result;
+                },
+                EncryptState::Done => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Stream adapter that decrypts envelope-encrypted data from the underlying stream.
+///
+/// The data key is unwrapped by the provider whose key id matches the one in the header,
+/// so that data encrypted under previous key-encryption keys remains readable
+/// after a key rotation. Once the data key is unwrapped, the header MAC is verified,
+/// and as with [`etm::Decrypt`](../cipher/etm/struct.Decrypt.html), the plaintext is only
+/// released once the authentication tag at the end of the stream has been verified.
+pub struct Decrypt<S> {
+    providers: Vec<Arc<dyn KeyProvider>>,
+    state: DecryptState<S>
+}
+
+enum DecryptState<S> {
+    Header(S, BytesMut),
+    Unwrapping(KeyFuture, etm::Config, Bytes, Rest<S>),
+    Streaming(Box<etm::Decrypt<Rest<S>>>),
+    Done
+}
+
+impl<S: Debug> Debug for Decrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let key_ids: Vec<&str> = self.providers.iter().map(|provider| provider.key_id()).collect();
+        f.debug_struct("Decrypt")
+            .field("key_ids", &key_ids)
+            .finish()
+    }
+}
+
+impl<S: Stream> Decrypt<S> {
+    /// Create a decrypting stream adapter, given the providers of all
+    /// key-encryption keys that may have been used to wrap the data key.
+    pub fn new(providers: Vec<Arc<dyn KeyProvider>>, inner: S) -> Self {
+        Decrypt { providers, state: DecryptState::Header(inner, BytesMut::new()) }
+    }
+}
+
+impl<S: Stream> Stream for Decrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, DecryptState::Done) {
+                DecryptState::Header(mut inner, mut buffer) => {
+                    if let Some((header, len)) = decode_header(&buffer)? {
+                        let provider = self.providers.iter()
+                            .find(|provider| provider.key_id() == header.key_id)
+                            .ok_or(Error::from(ErrorKind::UnknownKey))?;
+                        let unwrapping = provider.unwrap_key(header.wrapped_key);
+                        let mut config = etm::Config::new(header.algo);
+                        config.iv_mut().copy_from_slice(header.iv);
+                        let rest = Rest::new(buffer.split_off(len).freeze(), inner);
+                        self.state = DecryptState::Unwrapping(unwrapping, config, buffer.freeze(), rest);
+                        continue;
+                    }
+                    match inner.poll()? {
+                        Async::NotReady => {
+                            self.state = DecryptState::Header(inner, buffer);
+                            return Ok(Async::NotReady);
+                        },
+                        Async::Ready(None) => return Err(Error::from(ErrorKind::Malformed).into()),
+                        Async::Ready(Some(item)) => {
+                            buffer.extend_from_slice(item.as_ref());
+                            self.state = DecryptState::Header(inner, buffer);
+                        }
+                    }
+                },
+                DecryptState::Unwrapping(mut unwrapping, mut config, header, rest) => {
+                    let mut key = match unwrapping.poll()? {
+                        Async::NotReady => {
+                            self.state = DecryptState::Unwrapping(unwrapping, config, header, rest);
+                            return Ok(Async::NotReady);
+                        },
+                        Async::Ready(key) => key
+                    };
+                    if key.len() != DATA_KEY_LEN {
+                        zeroize(&mut key);
+                        return Err(Error::from(ErrorKind::InvalidKeyLength).into());
+                    }
+                    config.key_mut().copy_from_slice(&key);
+                    let mac = header_mac(&key, &header[..header.len() - MAC_LEN]);
+                    zeroize(&mut key);
+                    if !openssl::memcmp::eq(mac?.as_ref(), &header[header.len() - MAC_LEN..]) {
+                        return Err(Error::from(ErrorKind::AuthenticationFailed).into());
+                    }
+                    self.state = DecryptState::Streaming(Box::new(etm::Decrypt::new(&config, rest)?));
+                },
+                DecryptState::Streaming(mut decrypt) => {
+                    let result = decrypt.poll();
+                    self.state = DecryptState::Streaming(decrypt);
+                    return result;
+                },
+                DecryptState::Done => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// The remainder of a stream after the header, including any
+/// bytes that were read past the end of the header.
+pub(crate) struct Rest<S> {
+    head: Option<Bytes>,
+    inner: S
+}
+
+impl<S> Rest<S> {
+    pub(crate) fn new(head: Bytes, inner: S) -> Self {
+        Rest { head: Some(head), inner }
+    }
+}
+
+impl<S: Stream> Stream for Rest<S>
+    where S::Item: AsRef<[u8]>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(head) = self.head.take() {
+            if !head.is_empty() {
+                return Ok(Async::Ready(Some(head)));
+            }
+        }
+        match self.inner.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::Ready(Some(item)) => Ok(Async::Ready(Some(Bytes::from(item.as_ref()))))
+        }
+    }
+}
+
+const MAGIC: &[u8] = b"CNVL";
+const VERSION: u8 = 2;
+const DATA_KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+const MAX_KEY_ID_LEN: usize = 0xff;
+const HEADER_INFO: &[u8] = b"cryptonite envelope header";
+
+struct Header<'a> {
+    algo: etm::Algorithm,
+    key_id: &'a str,
+    wrapped_key: &'a [u8],
+    iv: &'a [u8]
+}
+
+/// Encode the header, followed by its MAC keyed with the data key.
+fn encode_header(algo: etm::Algorithm, key_id: &str, wrapped_key: &[u8], iv: &[u8], data_key: &[u8])
+    -> Result<Bytes, Error>
+{
+    let algo_id = algorithm_id(algo)?;
+    if key_id.len() > MAX_KEY_ID_LEN || wrapped_key.len() > 0xffff {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let mut header = BytesMut::with_capacity(MAGIC.len() + 6 + key_id.len() + wrapped_key.len() + iv.len() + MAC_LEN);
+    header.put_slice(MAGIC);
+    header.put_u8(VERSION);
+    header.put_u8(algo_id);
+    header.put_u8(key_id.len() as u8);
+    header.put_slice(key_id.as_bytes());
+    header.put_u8((wrapped_key.len() >> 8) as u8);
+    header.put_u8(wrapped_key.len() as u8);
+    header.put_slice(wrapped_key);
+    header.put_u8(iv.len() as u8);
+    header.put_slice(iv);
+    let mac = header_mac(data_key, &header)?;
+    header.put_slice(mac.as_ref());
+    Ok(header.freeze())
+}
+
+/// Decode a header from the start of `input`.
+///
+/// Returns `None` if more input is needed, or the header along with its length,
+/// including the MAC, which is only verified once the data key is unwrapped.
+fn decode_header<'a>(input: &'a [u8]) -> Result<Option<(Header<'a>, usize)>, Error> {
+    let mut pos = 0;
+    macro_rules! take {
+        ($len:expr) => {{
+            let len = $len;
+            if input.len() < pos + len {
+                return Ok(None);
+            }
+            pos += len;
+            &input[pos - len..pos]
+        }}
+    }
+    if take!(MAGIC.len()) != MAGIC || take!(1)[0] != VERSION {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let algo = algorithm_by_id(take!(1)[0])?;
+    let key_id_len = take!(1)[0] as usize;
+    let key_id = ::std::str::from_utf8(take!(key_id_len)).map_err(|_| ErrorKind::Malformed)?;
+    let wrapped_key_len = {
+        let len = take!(2);
+        (len[0] as usize) << 8 | len[1] as usize
+    };
+    let wrapped_key = take!(wrapped_key_len);
+    let iv_len = take!(1)[0] as usize;
+    let iv = take!(iv_len);
+    if iv_len != IV_LEN {
+        return Err(ErrorKind::InvalidIv.into());
+    }
+    let _mac = take!(MAC_LEN);
+    Ok(Some((Header { algo, key_id, wrapped_key, iv }, pos)))
+}
+
+fn header_mac(data_key: &[u8], header: &[u8]) -> Result<hash::Digest, Error> {
+    let mut mac_key = [0u8; MAC_LEN];
+    hkdf(hash::Algorithm::Sha256, &[], data_key, HEADER_INFO, &mut mac_key)?;
+    let hmac = Hmac::new(hash::Algorithm::Sha256, &mac_key);
+    zeroize(&mut mac_key);
+    let mut hmac = hmac?;
+    hmac.update(header)?;
+    hmac.finish()
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use futures::Stream;
+    use futures::stream::iter_ok;
+    use self::itertools::Itertools;
+
+    use cipher::{etm, wrap};
+    use random::Generator;
+    use super::{Decrypt, Encrypt, Error, ErrorKind, KeyProvider, LocalKeyProvider};
+
+    fn provider(key_id: &str, key_byte: u8) -> Arc<dyn KeyProvider> {
+        let mut config = wrap::Config::new(wrap::Algorithm::Aes256Kwp);
+        for byte in config.key_mut() {
+            *byte = key_byte;
+        }
+        Arc::new(LocalKeyProvider::new(key_id, config))
+    }
+
+    fn encrypt(provider: Arc<dyn KeyProvider>, chunks: Vec<&'static str>) -> Vec<u8> {
+        let generator = Generator::new(1);
+        let encrypt = Encrypt::new(&generator, provider, etm::Algorithm::Aes256CtrHmacSha256, iter_ok::<_, Error>(chunks))
+            .unwrap();
+        encrypt.wait().collect::<Result<Vec<_>, _>>().unwrap().into_iter().concat().to_vec()
+    }
+
+    fn decrypt(providers: Vec<Arc<dyn KeyProvider>>, ciphertext: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = ciphertext.chunks(5).map(|chunk| chunk.to_vec()).collect();
+        let decrypt = Decrypt::new(providers, iter_ok::<_, Error>(chunks));
+        decrypt.wait().collect::<Result<Vec<_>, _>>().map(|chunks| chunks.into_iter().concat().to_vec())
+    }
+
+    #[test]
+    fn roundtrip() {
+        let ciphertext = encrypt(provider("current", 1), vec!["foo", "bar", "baz"]);
+        let plaintext = decrypt(vec![provider("current", 1)], ciphertext).unwrap();
+        assert_eq!(plaintext, b"foobarbaz");
+    }
+
+    #[test]
+    fn rotation() {
+        let ciphertext = encrypt(provider("old", 1), vec!["foo", "bar", "baz"]);
+        let plaintext = decrypt(vec![provider("new", 2), provider("old", 1)], ciphertext).unwrap();
+        assert_eq!(plaintext, b"foobarbaz");
+    }
+
+    #[test]
+    fn unknown_key() {
+        let ciphertext = encrypt(provider("old", 1), vec!["foo"]);
+        assert_eq!(decrypt(vec![provider("new", 2)], ciphertext).unwrap_err().kind(), ErrorKind::UnknownKey);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let ciphertext = encrypt(provider("current", 1), vec!["foo"]);
+        assert_eq!(decrypt(vec![provider("current", 2)], ciphertext).unwrap_err().kind(),
+                   ErrorKind::AuthenticationFailed);
+    }
+
+    #[test]
+    fn tampered_header() {
+        let ciphertext = encrypt(provider("current", 1), vec!["foo"]);
+        // Flip a bit in the algorithm, and in the last byte of the IV, just before the MAC.
+        let iv_end = ciphertext.len() - "foo".len() - 32 - 32;
+        for &index in [5, iv_end - 1].iter() {
+            let mut tampered = ciphertext.clone();
+            tampered[index] ^= 1;
+            assert_eq!(decrypt(vec![provider("current", 1)], tampered).unwrap_err().kind(),
+                       ErrorKind::AuthenticationFailed);
+        }
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut ciphertext = encrypt(provider("current", 1), vec![]);
+        let header_len = ciphertext.len() - etm::Algorithm::Aes256CtrHmacSha256.tag_len();
+        ciphertext.truncate(header_len - 1);
+        assert_eq!(decrypt(vec![provider("current", 1)], ciphertext).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn long_key_id() {
+        let generator = Generator::new(1);
+        let input = iter_ok::<Vec<u8>, Error>(vec![]);
+        let result = Encrypt::new(&generator, provider(&"k".repeat(256), 1), etm::Algorithm::Aes256CtrHmacSha256, input);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Malformed);
+        let ciphertext = encrypt(provider(&"k".repeat(255), 1), vec!["foo"]);
+        assert_eq!(decrypt(vec![provider(&"k".repeat(255), 1)], ciphertext).unwrap(), b"foo");
+    }
+
+    #[test]
+    fn local_key_file() {
+        let path = env::temp_dir().join("cryptonite-envelope-test.key");
+        File::create(&path).unwrap().write_all(&[7u8; 32]).unwrap();
+        let provider = LocalKeyProvider::open("file", &path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let ciphertext = encrypt(Arc::new(provider), vec!["foo", "bar"]);
+        let plaintext = decrypt(vec![self::provider("file", 7)], ciphertext).unwrap();
+        assert_eq!(plaintext, b"foobar");
+    }
+}
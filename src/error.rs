@@ -1,27 +1,195 @@
 use std::error::{Error as StdError};
 use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::io::{Error as IoError};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 
 use openssl;
 
 /// An error that can occur when performing crypto operations.
 #[derive(Debug)]
-pub struct Error(pub(crate) openssl::error::ErrorStack);
+pub struct Error {
+    kind: ErrorKind,
+    source: Option<Box<dyn StdError + Send + Sync>>
+}
+
+/// A list of general categories of errors.
+///
+/// Use [`Error::kind`](struct.Error.html#method.kind) to find out which kind of error occurred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum ErrorKind {
+    /// A key does not have the length required by the algorithm.
+    InvalidKeyLength,
+    /// An initialization vector or nonce is missing or has the wrong length.
+    InvalidIv,
+    /// The padding of decrypted data is invalid, or the data does not
+    /// have a length that the padding scheme can handle.
+    BadPadding,
+    /// Authenticated data or a wrapped key failed its integrity check.
+    AuthenticationFailed,
+    /// A computed digest does not match the expected one.
+    DigestMismatch,
+    /// A signature does not match the signed data.
+    BadSignature,
+    /// The input is not in the expected format.
+    Malformed,
+    /// A token is older than its time-to-live, or is dated too far in the future.
+    Expired,
+    /// The input exceeds a configured size limit, such as the maximum size of decompressed data.
+    LimitExceeded,
+    /// No key matching the input is available.
+    UnknownKey,
+    /// The requested operation is not supported by the algorithm.
+    Unsupported,
+    /// The executor backing an asynchronous operation has shut down.
+    ExecutorShutdown,
+    /// An I/O error occurred while accessing the operating system.
+    Io,
+    /// The cryptographic backend reported an error.
+    Backend,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl ErrorKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::InvalidKeyLength => "invalid key length",
+            ErrorKind::InvalidIv => "invalid initialization vector",
+            ErrorKind::BadPadding => "bad padding",
+            ErrorKind::AuthenticationFailed => "authentication failed",
+            ErrorKind::DigestMismatch => "digest mismatch",
+            ErrorKind::BadSignature => "bad signature",
+            ErrorKind::Malformed => "malformed input",
+            ErrorKind::Expired => "token expired",
+            ErrorKind::LimitExceeded => "size limit exceeded",
+            ErrorKind::UnknownKey => "no matching key available",
+            ErrorKind::Unsupported => "operation not supported",
+            ErrorKind::ExecutorShutdown => "executor has shut down",
+            ErrorKind::Io => "i/o error",
+            ErrorKind::Backend => "cryptographic backend error",
+            ErrorKind::_Donotmatch => unreachable!()
+        }
+    }
+
+    fn io_kind(self) -> IoErrorKind {
+        match self {
+            ErrorKind::InvalidKeyLength |
+            ErrorKind::InvalidIv |
+            ErrorKind::Unsupported => IoErrorKind::InvalidInput,
+            ErrorKind::BadPadding |
+            ErrorKind::AuthenticationFailed |
+            ErrorKind::DigestMismatch |
+            ErrorKind::BadSignature |
+            ErrorKind::Malformed |
+            ErrorKind::Expired |
+            ErrorKind::LimitExceeded => IoErrorKind::InvalidData,
+            ErrorKind::UnknownKey => IoErrorKind::NotFound,
+            ErrorKind::ExecutorShutdown |
+            ErrorKind::Io |
+            ErrorKind::Backend |
+            ErrorKind::_Donotmatch => IoErrorKind::Other
+        }
+    }
+}
+
+impl Error {
+    /// Create an error of the given kind, caused by an underlying error.
+    ///
+    /// This is useful for implementors of traits such as
+    /// [`KeyProvider`](envelope/trait.KeyProvider.html), which need to
+    /// report their own failures.
+    pub fn new<E>(kind: ErrorKind, source: E) -> Error
+        where E: Into<Box<dyn StdError + Send + Sync>>
+    {
+        Error { kind, source: Some(source.into()) }
+    }
+
+    /// Get the kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error { kind, source: None }
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Error {
+        Error::new(ErrorKind::Backend, err)
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(err: IoError) -> Error {
+        Error::new(ErrorKind::Io, err)
+    }
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        self.0.fmt(f)
+        match self.source {
+            None => f.write_str(self.kind.as_str()),
+            Some(ref source) => write!(f, "{}: {}", self.kind.as_str(), source)
+        }
     }
 }
 
 impl StdError for Error {
     fn description(&self) -> &str {
-        self.0.description()
+        self.kind.as_str()
+    }
+
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self.source {
+            None => None,
+            Some(ref source) => Some(&**source)
+        }
     }
 }
 
 impl From<Error> for IoError {
     fn from(err: Error) -> IoError {
-        err.0.into()
+        let kind = match err.source {
+            Some(ref source) if err.kind == ErrorKind::Io => match source.downcast_ref::<IoError>() {
+                Some(source) => source.kind(),
+                None => err.kind.io_kind()
+            },
+            _ => err.kind.io_kind()
+        };
+        IoError::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as StdError;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+    use openssl;
+
+    use super::{Error, ErrorKind};
+
+    #[test]
+    fn io_kind() {
+        let err: IoError = Error::from(ErrorKind::BadPadding).into();
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+        let err: IoError = Error::from(ErrorKind::InvalidKeyLength).into();
+        assert_eq!(err.kind(), IoErrorKind::InvalidInput);
+        let err: IoError = Error::from(ErrorKind::ExecutorShutdown).into();
+        assert_eq!(err.kind(), IoErrorKind::Other);
+    }
+
+    #[test]
+    fn source() {
+        let err = Error::from(openssl::error::ErrorStack::get());
+        assert_eq!(err.kind(), ErrorKind::Backend);
+        assert!(err.source().is_some());
+        let err = Error::from(ErrorKind::Malformed);
+        assert!(err.source().is_none());
+        assert_eq!(err.to_string(), "malformed input");
     }
 }
@@ -0,0 +1,362 @@
+//! Fernet tokens, as specified in the [Fernet specification](https://github.com/fernet/spec/blob/master/Spec.md).
+//!
+//! A token contains the time at which it was created, a random IV and the plaintext
+//! encrypted using AES-128 in CBC mode, authenticated using HMAC-SHA256. Tokens are
+//! compatible with other implementations, such as the one of the Python `cryptography`
+//! package.
+//!
+//! Keys can be rotated by creating a [`Fernet`](struct.Fernet.html) instance with
+//! several keys: tokens are always created using the first key, but can be decrypted
+//! using any of them.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{Async, Future, Poll};
+use openssl;
+
+use super::{Error, ErrorKind};
+use super::base64;
+use super::cipher;
+use super::hash::{self, Hmac};
+use super::random::{Generator, RandomBytes};
+use super::secret::{Redacted, zeroize};
+
+const VERSION: u8 = 0x80;
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const HMAC_LEN: usize = 32;
+const HEADER_LEN: usize = 1 + 8 + IV_LEN;
+const MAX_CLOCK_SKEW: u64 = 60;
+
+/// Key consisting of a 128-bit signing key and a 128-bit encryption key.
+#[derive(Clone)]
+pub struct Key {
+    bytes: [u8; KEY_LEN]
+}
+
+impl Key {
+    /// Create a key from its 32 bytes, the signing key followed by the encryption key.
+    ///
+    /// Fails with `ErrorKind::InvalidKeyLength` if the key does not have a length of 32 bytes.
+    pub fn from_bytes(key: &[u8]) -> Result<Key, Error> {
+        if key.len() != KEY_LEN {
+            return Err(ErrorKind::InvalidKeyLength.into());
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        bytes.copy_from_slice(key);
+        Ok(Key { bytes })
+    }
+
+    /// Parse a key encoded in padded URL-safe base64, the format used by other implementations.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the key is not valid base64, and with
+    /// `ErrorKind::InvalidKeyLength` if it does not decode to 32 bytes.
+    pub fn from_base64(key: &str) -> Result<Key, Error> {
+        let mut bytes = base64::decode_url(key.as_bytes(), true).ok_or(ErrorKind::Malformed)?;
+        let key = Key::from_bytes(&bytes);
+        zeroize(&mut bytes);
+        key
+    }
+
+    /// Encode the key in padded URL-safe base64.
+    pub fn to_base64(&self) -> String {
+        base64::encode_url(&self.bytes, true)
+    }
+
+    /// Generate a new random key using the given generator.
+    pub fn generate(generator: &Generator) -> GenerateKey {
+        GenerateKey { random: generator.random_bytes(KEY_LEN) }
+    }
+
+    fn signing_key(&self) -> &[u8] {
+        &self.bytes[..KEY_LEN / 2]
+    }
+
+    fn encryption_key(&self) -> &[u8] {
+        &self.bytes[KEY_LEN / 2..]
+    }
+
+    /// Compute the HMAC over the token, excluding the HMAC itself.
+    fn hmac(&self, data: &[u8]) -> Result<hash::Digest, Error> {
+        let mut hmac = Hmac::new(hash::Algorithm::Sha256, self.signing_key())?;
+        hmac.update(data)?;
+        hmac.finish()
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        zeroize(&mut self.bytes);
+    }
+}
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple("Key").field(&Redacted).finish()
+    }
+}
+
+/// Future returning a newly generated key.
+///
+/// See [`Key::generate`](struct.Key.html#method.generate).
+#[derive(Debug)]
+pub struct GenerateKey {
+    random: RandomBytes
+}
+
+impl Future for GenerateKey {
+    type Item = Key;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let random = match self.random.poll()? {
+            Async::NotReady => return Ok(Async::NotReady),
+            Async::Ready(random) => random
+        };
+        let key = Key::from_bytes(&random);
+        if let Ok(mut random) = random.try_mut() {
+            zeroize(&mut random);
+        }
+        key.map(Async::Ready)
+    }
+}
+
+/// Encrypts and decrypts Fernet tokens using a list of keys.
+#[derive(Clone, Debug)]
+pub struct Fernet {
+    keys: Vec<Key>
+}
+
+impl Fernet {
+    /// Create an instance that uses a single key.
+    pub fn new(key: Key) -> Fernet {
+        Fernet { keys: vec![key] }
+    }
+
+    /// Create an instance for key rotation, which encrypts using the first key and
+    /// decrypts using any of the keys.
+    ///
+    /// Fails with `ErrorKind::UnknownKey` if the list of keys is empty.
+    pub fn with_keys(keys: Vec<Key>) -> Result<Fernet, Error> {
+        if keys.is_empty() {
+            return Err(ErrorKind::UnknownKey.into());
+        }
+        Ok(Fernet { keys })
+    }
+
+    /// Encrypt a plaintext into a token dated with the current time.
+    pub fn encrypt(&self, generator: &Generator, plaintext: &[u8]) -> EncryptToken {
+        self.encrypt_at_time(generator, plaintext, now())
+    }
+
+    /// Encrypt a plaintext into a token dated with the given time, in seconds since the Unix epoch.
+    pub fn encrypt_at_time(&self, generator: &Generator, plaintext: &[u8], timestamp: u64) -> EncryptToken {
+        EncryptToken {
+            key: self.keys[0].clone(),
+            plaintext: plaintext.to_vec(),
+            timestamp,
+            iv: generator.random_bytes(IV_LEN)
+        }
+    }
+
+    /// Decrypt a token, returning the plaintext.
+    ///
+    /// If a time-to-live is given, the token must not be older than it, nor be dated more
+    /// than 60 seconds in the future.
+    ///
+    /// Fails with `ErrorKind::AuthenticationFailed` if the token cannot be authenticated using
+    /// any of the keys, with `ErrorKind::Expired` if it is outside of its time-to-live, and
+    /// with `ErrorKind::Malformed` if it is not a valid token.
+    pub fn decrypt(&self, token: &str, ttl: Option<Duration>) -> Result<Vec<u8>, Error> {
+        self.decrypt_at_time(token, ttl, now())
+    }
+
+    /// Decrypt a token as if the current time was the given time, in seconds since the Unix epoch.
+    ///
+    /// See [`decrypt`](#method.decrypt) for more information.
+    pub fn decrypt_at_time(&self, token: &str, ttl: Option<Duration>, now: u64) -> Result<Vec<u8>, Error> {
+        let (key, data) = self.authenticate(token)?;
+        if let Some(ttl) = ttl {
+            let timestamp = timestamp(&data);
+            if timestamp.saturating_add(ttl.as_secs()) < now || now.saturating_add(MAX_CLOCK_SKEW) < timestamp {
+                return Err(ErrorKind::Expired.into());
+            }
+        }
+        decrypt_data(key, &data)
+    }
+
+    /// Get the time at which an authenticated token was created, in seconds since the Unix epoch.
+    pub fn extract_timestamp(&self, token: &str) -> Result<u64, Error> {
+        self.authenticate(token).map(|(_, data)| timestamp(&data))
+    }
+
+    /// Re-encrypt a token using the first key, keeping its original timestamp.
+    ///
+    /// This allows tokens created with older keys to be migrated to the current key.
+    pub fn rotate(&self, generator: &Generator, token: &str) -> Result<EncryptToken, Error> {
+        let (key, data) = self.authenticate(token)?;
+        let mut plaintext = decrypt_data(key, &data)?;
+        let encrypt = self.encrypt_at_time(generator, &plaintext, timestamp(&data));
+        zeroize(&mut plaintext);
+        Ok(encrypt)
+    }
+
+    /// Decode a token and find the key which authenticates it, returning the key and the
+    /// token without its HMAC.
+    fn authenticate(&self, token: &str) -> Result<(&Key, Vec<u8>), Error> {
+        let mut data = base64::decode_url(token.as_bytes(), true).ok_or(ErrorKind::Malformed)?;
+        if data.len() < HEADER_LEN + IV_LEN + HMAC_LEN || !(data.len() - HEADER_LEN - HMAC_LEN).is_multiple_of(IV_LEN) ||
+           data[0] != VERSION {
+            return Err(ErrorKind::Malformed.into());
+        }
+        let mac = data.split_off(data.len() - HMAC_LEN);
+        for key in &self.keys {
+            if key.hmac(&data)?.verify(&mac) {
+                return Ok((key, data));
+            }
+        }
+        Err(ErrorKind::AuthenticationFailed.into())
+    }
+}
+
+/// Future returning a newly created token.
+///
+/// See [`Fernet::encrypt`](struct.Fernet.html#method.encrypt).
+pub struct EncryptToken {
+    key: Key,
+    plaintext: Vec<u8>,
+    timestamp: u64,
+    iv: RandomBytes
+}
+
+impl Future for EncryptToken {
+    type Item = String;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let iv = match self.iv.poll()? {
+            Async::NotReady => return Ok(Async::NotReady),
+            Async::Ready(iv) => iv
+        };
+        let cipher = cipher::Algorithm::Aes128Cbc.into_cipher()?;
+        let ciphertext = openssl::symm::encrypt(cipher, self.key.encryption_key(), Some(&iv), &self.plaintext)
+            .map_err(Error::from)?;
+        let mut token = Vec::with_capacity(HEADER_LEN + ciphertext.len() + HMAC_LEN);
+        token.push(VERSION);
+        for i in (0..8).rev() {
+            token.push((self.timestamp >> (8 * i)) as u8);
+        }
+        token.extend_from_slice(&iv);
+        token.extend_from_slice(&ciphertext);
+        let mac = self.key.hmac(&token)?;
+        token.extend_from_slice(mac.as_ref());
+        Ok(Async::Ready(base64::encode_url(&token, true)))
+    }
+}
+
+impl Drop for EncryptToken {
+    fn drop(&mut self) {
+        zeroize(&mut self.plaintext);
+    }
+}
+
+impl Debug for EncryptToken {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("EncryptToken")
+            .field("key", &self.key)
+            .field("plaintext", &Redacted)
+            .field("timestamp", &self.timestamp)
+            .finish()
+    }
+}
+
+/// Decrypt the ciphertext of an authenticated token.
+fn decrypt_data(key: &Key, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = cipher::Algorithm::Aes128Cbc.into_cipher()?;
+    openssl::symm::decrypt(cipher, key.encryption_key(), Some(&data[9..HEADER_LEN]), &data[HEADER_LEN..])
+        .map_err(|err| Error::new(ErrorKind::BadPadding, err))
+}
+
+fn timestamp(data: &[u8]) -> u64 {
+    data[1..9].iter().fold(0, |acc, byte| acc << 8 | *byte as u64)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::Future;
+
+    use ErrorKind;
+    use random::Generator;
+    use super::{Fernet, Key};
+
+    // Test vector from the Fernet specification.
+    const KEY: &str = "cw_0x689RpI-jtRR7oE8h_eQsKImvJapLeSbXpwF4e4=";
+    const TOKEN: &str = "gAAAAAAdwJ6wAAECAwQFBgcICQoLDA0ODy021cpGVWKZ_eEwCGM4BLLF_5CV9dOPmrhuVUPgJobwOz7JcbmrR64jVmpU4IwqDA==";
+    const TIMESTAMP: u64 = 499162800;
+
+    #[test]
+    fn spec_vector() {
+        let fernet = Fernet::new(Key::from_base64(KEY).unwrap());
+        assert_eq!(fernet.extract_timestamp(TOKEN).unwrap(), TIMESTAMP);
+        assert_eq!(fernet.decrypt_at_time(TOKEN, Some(Duration::from_secs(60)), TIMESTAMP + 60).unwrap(), b"hello");
+        assert_eq!(fernet.decrypt(TOKEN, None).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn ttl() {
+        let fernet = Fernet::new(Key::from_base64(KEY).unwrap());
+        let ttl = Some(Duration::from_secs(60));
+        assert_eq!(fernet.decrypt_at_time(TOKEN, ttl, TIMESTAMP + 61).unwrap_err().kind(), ErrorKind::Expired);
+        assert_eq!(fernet.decrypt_at_time(TOKEN, ttl, TIMESTAMP - 60).unwrap(), b"hello");
+        assert_eq!(fernet.decrypt_at_time(TOKEN, ttl, TIMESTAMP - 61).unwrap_err().kind(), ErrorKind::Expired);
+        assert_eq!(fernet.decrypt(TOKEN, ttl).unwrap_err().kind(), ErrorKind::Expired);
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let fernet = Fernet::new(Key::from_base64(KEY).unwrap());
+        let other = Fernet::new(Key::from_bytes(&[0; 32]).unwrap());
+        assert_eq!(other.decrypt(TOKEN, None).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+        let tampered = TOKEN.replace("gAAAAAAdwJ6w", "gAAAAAAdwJ6x");
+        assert_eq!(fernet.decrypt(&tampered, None).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+        let unpadded = TOKEN.trim_end_matches('=');
+        assert_eq!(fernet.decrypt(unpadded, None).unwrap_err().kind(), ErrorKind::Malformed);
+        let version = TOKEN.replacen("gA", "gQ", 1);
+        assert_eq!(fernet.decrypt(&version, None).unwrap_err().kind(), ErrorKind::Malformed);
+        assert_eq!(fernet.decrypt(&TOKEN[..60], None).unwrap_err().kind(), ErrorKind::Malformed);
+        assert_eq!(Key::from_base64("AAAA").unwrap_err().kind(), ErrorKind::InvalidKeyLength);
+        assert_eq!(Key::from_base64(&KEY[1..]).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn rotation() {
+        let generator = Generator::new(1);
+        let old = Key::generate(&generator).wait().unwrap();
+        let new = Key::generate(&generator).wait().unwrap();
+        assert_eq!(Key::from_base64(&new.to_base64()).unwrap().to_base64(), new.to_base64());
+        let token = Fernet::new(old.clone()).encrypt_at_time(&generator, b"secret", 1000).wait().unwrap();
+
+        let fernet = Fernet::with_keys(vec![new.clone(), old]).unwrap();
+        assert_eq!(fernet.decrypt(&token, None).unwrap(), b"secret");
+        let rotated = fernet.rotate(&generator, &token).unwrap().wait().unwrap();
+        assert_eq!(fernet.extract_timestamp(&rotated).unwrap(), 1000);
+        assert_eq!(Fernet::new(new).decrypt(&rotated, None).unwrap(), b"secret");
+        assert_eq!(Fernet::with_keys(vec![]).unwrap_err().kind(), ErrorKind::UnknownKey);
+    }
+
+    quickcheck! {
+        fn roundtrip(plaintext: Vec<u8>) -> bool {
+            let generator = Generator::new(1);
+            let fernet = Fernet::new(Key::from_bytes(&[7; 32]).unwrap());
+            let token = fernet.encrypt(&generator, &plaintext).wait().unwrap();
+            fernet.decrypt(&token, Some(Duration::from_secs(10))).unwrap() == plaintext
+        }
+    }
+}
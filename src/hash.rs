@@ -6,8 +6,11 @@ use futures::{Async, Future, Poll, Stream};
 use futures::sync::oneshot;
 use hex::ToHex;
 use openssl;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
 
-use super::Error;
+use super::{Error, ErrorKind};
+use super::secret::zeroize;
 
 /// Stream adapter that computes a hash over the data while forwarding it.
 #[derive(Debug)]
@@ -21,6 +24,13 @@ impl<S: Stream> Hash<S> {
         Ok(Hash { inner: HashInner::new(algo, inner)? })
     }
 
+    /// Given an algorithm and a key, create a new stream adapter that
+    /// computes a keyed-hash message authentication code
+    /// ([HMAC](https://tools.ietf.org/html/rfc2104)) instead of a plain digest.
+    pub fn hmac(algo: Algorithm, key: &[u8], inner: S) -> Result<Self, Error> {
+        Ok(Hash { inner: HashInner::hmac(algo, key, inner)? })
+    }
+
     /// Compute the hash digest and reset the internal hashing state.
     pub fn digest(&mut self) -> Result<Digest, Error> {
         self.inner.digest()
@@ -128,8 +138,31 @@ impl<S: Stream> Stream for SplitHash<S>
 
 struct HashInner<S> {
     inner: S,
-    hasher: openssl::hash::Hasher,
-    algorithm: Algorithm
+    hasher: Hasher
+}
+
+enum Hasher {
+    Plain(openssl::hash::Hasher, Algorithm),
+    Hmac(Hmac)
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) -> Result<(), Error> {
+        match *self {
+            Hasher::Plain(ref mut hasher, _) => hasher.update(data).map_err(Error::from),
+            Hasher::Hmac(ref mut hmac) => hmac.update(data)
+        }
+    }
+
+    fn finish(&mut self) -> Result<Digest, Error> {
+        match *self {
+            Hasher::Plain(ref mut hasher, algorithm) => {
+                let bytes = hasher.finish().map_err(Error::from)?.to_vec();
+                Ok(Digest { bytes, algorithm })
+            },
+            Hasher::Hmac(ref mut hmac) => hmac.finish()
+        }
+    }
 }
 
 impl<S: Debug> Debug for HashInner<S> {
@@ -143,17 +176,16 @@ impl<S: Debug> Debug for HashInner<S> {
 impl<S: Stream> HashInner<S> {
     fn new(algorithm: Algorithm, inner: S) -> Result<Self, Error> {
         let hasher = openssl::hash::Hasher::new(algorithm.into_message_digest())
-            .map_err(Error)?;
-        Ok(HashInner { inner, hasher, algorithm })
+            .map_err(Error::from)?;
+        Ok(HashInner { inner, hasher: Hasher::Plain(hasher, algorithm) })
+    }
+
+    fn hmac(algorithm: Algorithm, key: &[u8], inner: S) -> Result<Self, Error> {
+        Ok(HashInner { inner, hasher: Hasher::Hmac(Hmac::new(algorithm, key)?) })
     }
 
     fn digest(&mut self) -> Result<Digest, Error> {
-        self.hasher.finish2().map(|bytes| {
-            Digest {
-                bytes: bytes,
-                algorithm: self.algorithm
-            }
-        }).map_err(Error)
+        self.hasher.finish()
     }
 
     fn into_inner(self) -> S {
@@ -173,17 +205,17 @@ impl<S: Stream> Stream for HashInner<S>
             Async::NotReady => Ok(Async::NotReady),
             Async::Ready(None) => Ok(Async::Ready(None)),
             Async::Ready(Some(item)) => {
-                self.hasher.update(item.as_ref()).map_err(Error)?;
+                self.hasher.update(item.as_ref())?;
                 Ok(Async::Ready(Some(item)))
             }
         }
     }
 }
 
-/// Stack-allocated binary hash digest.
+/// Binary hash digest, as computed by the chosen algorithm.
 #[derive(Debug)]
 pub struct Digest {
-    bytes: openssl::hash::DigestBytes,
+    bytes: Vec<u8>,
     algorithm: Algorithm
 }
 
@@ -197,16 +229,112 @@ impl Digest {
     pub fn to_hex_string(&self) -> String {
         self.bytes.to_hex()
     }
+
+    /// Compare the digest to an expected value in constant time.
+    ///
+    /// This should be used instead of `==` when checking message authentication codes.
+    pub fn verify(&self, expected: &[u8]) -> bool {
+        self.bytes.len() == expected.len() && openssl::memcmp::eq(&self.bytes, expected)
+    }
 }
 
 impl AsRef<[u8]> for Digest {
     fn as_ref(&self) -> &[u8] {
-        &*self.bytes
+        &self.bytes
+    }
+}
+
+/// Incremental computation of a keyed-hash message authentication code
+/// ([HMAC](https://tools.ietf.org/html/rfc2104)).
+///
+/// Use [`Hash::hmac`](struct.Hash.html#method.hmac) to authenticate a stream.
+pub struct Hmac {
+    pkey: PKey<Private>,
+    signer: Signer<'static>,
+    algorithm: Algorithm
+}
+
+impl Hmac {
+    /// Given an algorithm and a key of any length, create a new HMAC instance.
+    pub fn new(algorithm: Algorithm, key: &[u8]) -> Result<Hmac, Error> {
+        // Keys are padded with zeros to the block length, so an empty key is equivalent to
+        // a single zero byte. OpenSSL rejects empty keys, so substitute the latter.
+        let key = if key.is_empty() { &[0u8][..] } else { key };
+        let pkey = PKey::hmac(key).map_err(Error::from)?;
+        let signer = Signer::new(algorithm.into_message_digest(), &pkey).map_err(Error::from)?;
+        Ok(Hmac { pkey, signer, algorithm })
+    }
+
+    /// Get the algorithm that is used to compute the authentication code.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Feed data into the computation.
+    pub fn update(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.signer.update(data).map_err(Error::from)
+    }
+
+    /// Compute the authentication code and reset the internal state,
+    /// so that the instance can be used for a new message with the same key.
+    pub fn finish(&mut self) -> Result<Digest, Error> {
+        let bytes = self.signer.sign_to_vec().map_err(Error::from)?;
+        self.signer = Signer::new(self.algorithm.into_message_digest(), &self.pkey).map_err(Error::from)?;
+        Ok(Digest { bytes, algorithm: self.algorithm })
     }
 }
 
+impl Debug for Hmac {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Hmac")
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+/// Derive key material from a secret using
+/// [HKDF](https://tools.ietf.org/html/rfc5869), filling the output buffer.
+///
+/// The salt is optional and may be empty. The info string binds the derived
+/// key material to its purpose, so that different keys can be derived from the same secret.
+///
+/// Fails with `ErrorKind::InvalidKeyLength` if the output is longer than
+/// 255 times the digest length of the algorithm.
+pub fn hkdf(algorithm: Algorithm, salt: &[u8], secret: &[u8], info: &[u8], output: &mut [u8]) -> Result<(), Error> {
+    let digest_len = algorithm.digest_len();
+    if output.len() > 255 * digest_len {
+        return Err(ErrorKind::InvalidKeyLength.into());
+    }
+    let mut hmac = Hmac::new(algorithm, salt)?;
+    hmac.update(secret)?;
+    let mut prk = hmac.finish()?;
+    let mut hmac = Hmac::new(algorithm, &prk.bytes)?;
+    zeroize(&mut prk.bytes);
+    let mut block: Option<Digest> = None;
+    for (i, chunk) in output.chunks_mut(digest_len).enumerate() {
+        if let Some(ref previous) = block {
+            hmac.update(previous.as_ref())?;
+        }
+        hmac.update(info)?;
+        hmac.update(&[i as u8 + 1])?;
+        let next = hmac.finish()?;
+        chunk.copy_from_slice(&next.as_ref()[..chunk.len()]);
+        if let Some(mut previous) = block.take() {
+            zeroize(&mut previous.bytes);
+        }
+        block = Some(next);
+    }
+    if let Some(mut previous) = block {
+        zeroize(&mut previous.bytes);
+    }
+    Ok(())
+}
+
 /// Algorithm that can be used to hash data.
 #[derive(Clone, Copy, Debug, PartialEq)]
+// The hidden `_Donotmatch` variant predates `#[non_exhaustive]`, and is kept on the public
+// enums of this crate since removing it would break matches that name it.
+#[allow(clippy::manual_non_exhaustive)]
 pub enum Algorithm {
     /// MD-5
     Md5,
@@ -226,7 +354,7 @@ pub enum Algorithm {
 }
 
 impl Algorithm {
-    fn into_message_digest(self) -> openssl::hash::MessageDigest {
+    pub(crate) fn into_message_digest(self) -> openssl::hash::MessageDigest {
         match self {
             Algorithm::Md5 => openssl::hash::MessageDigest::md5(),
             Algorithm::Sha1 => openssl::hash::MessageDigest::sha1(),
@@ -237,6 +365,19 @@ impl Algorithm {
             Algorithm::_Donotmatch => unreachable!()
         }
     }
+
+    /// Get the length of digests computed by the algorithm.
+    pub fn digest_len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha224 => 28,
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha384 => 48,
+            Algorithm::Sha512 => 64,
+            Algorithm::_Donotmatch => unreachable!()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -244,7 +385,9 @@ mod test {
     use futures::{Future, Stream};
     use futures::stream::iter_ok;
 
-    use super::{Algorithm, Error,  Hash};
+    use hex::{FromHex, ToHex};
+
+    use super::{Algorithm, Error,  Hash, Hmac, hkdf};
 
     #[test]
     fn sha1() {
@@ -275,4 +418,53 @@ mod test {
         drop(split_hash);
         assert!(split_digest.wait().unwrap().is_none());
     }
+
+    #[test]
+    fn hmac_sha256() {
+        // Test case 2 from RFC 4231.
+        let input = iter_ok::<_, Error>(vec!["what do ya want ", "for nothing?"]);
+        let mut hash = Hash::hmac(Algorithm::Sha256, b"Jefe", input).unwrap();
+        hash.by_ref().wait().collect::<Result<Vec<_>, _>>().unwrap();
+        let digest = hash.digest().unwrap();
+        let expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        assert_eq!(digest.to_hex_string(), expected);
+        assert!(digest.verify(&Vec::<u8>::from_hex(expected).unwrap()));
+        assert!(!digest.verify(&[0u8; 32]));
+        assert!(!digest.verify(&[]));
+    }
+
+    #[test]
+    fn hmac_long_key() {
+        // Test case 6 from RFC 4231.
+        let mut hmac = Hmac::new(Algorithm::Sha512, &[0xaau8; 131]).unwrap();
+        hmac.update(b"Test Using Larger Than Block-Size Key - Hash Key First").unwrap();
+        assert_eq!(hmac.finish().unwrap().to_hex_string(),
+                   "80b24263c7c1a3ebb71493c1dd7be8b49b46d1f41b4aeec1121b013783f8f352\
+                    6b56d037e05f2598bd0fd2215d6a1e5295e64f73f63f0aec8b915a985d786598");
+        hmac.update(b"Test Using Larger Than Block-Size Key - Hash Key First").unwrap();
+        assert_eq!(hmac.finish().unwrap().to_hex_string(),
+                   "80b24263c7c1a3ebb71493c1dd7be8b49b46d1f41b4aeec1121b013783f8f352\
+                    6b56d037e05f2598bd0fd2215d6a1e5295e64f73f63f0aec8b915a985d786598");
+    }
+
+    #[test]
+    fn hmac_empty_key() {
+        let mut hmac = Hmac::new(Algorithm::Sha256, b"").unwrap();
+        assert_eq!(hmac.finish().unwrap().to_hex_string(),
+                   "b613679a0814d9ec772f95d778c35fc5ff1697c493715653c6c712144292c5ad");
+    }
+
+    #[test]
+    fn hkdf_sha256() {
+        // Test case 1 from RFC 5869.
+        let secret = [0x0bu8; 22];
+        let salt = Vec::<u8>::from_hex("000102030405060708090a0b0c").unwrap();
+        let info = Vec::<u8>::from_hex("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        let mut output = [0u8; 42];
+        hkdf(Algorithm::Sha256, &salt, &secret, &info, &mut output).unwrap();
+        assert_eq!(output.to_hex(), "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf\
+                                     34007208d5b887185865");
+        let mut output = vec![0u8; 255 * 32 + 1];
+        assert!(hkdf(Algorithm::Sha256, &salt, &secret, &info, &mut output).is_err());
+    }
 }
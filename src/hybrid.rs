@@ -0,0 +1,373 @@
+//! Hybrid public-key encryption of streams.
+//!
+//! The sender generates an ephemeral key pair for every stream, and agrees on a
+//! shared secret with the recipient's public key using [key agreement](../agreement/index.html).
+//! The secret and IV for [authenticated encryption](../cipher/etm/index.html) are derived
+//! from the shared secret using HKDF-SHA-256, salted with both public keys.
+//! Only the holder of the recipient's private key can decrypt the stream.
+//!
+//! The ephemeral public key is stored in a header that precedes the ciphertext:
+//!
+//! | Field                | Size          |
+//! |----------------------|---------------|
+//! | magic `CNHY`         | 4 bytes       |
+//! | version              | 1 byte        |
+//! | key agreement        | 1 byte        |
+//! | algorithm            | 1 byte        |
+//! | ephemeral public key | 1 byte length |
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::mem;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, Future, Poll, Stream};
+
+use super::{Error, ErrorKind};
+use super::agreement::{self, GenerateKey, PrivateKey, PublicKey};
+use super::cipher::etm;
+use super::envelope::Rest;
+use super::hash;
+use super::random::Generator;
+use super::secret::zeroize;
+
+/// Stream adapter that encrypts the data from the underlying stream to a recipient's public key.
+///
+/// The first item yielded is the header, followed by the ciphertext and the authentication tag.
+pub struct Encrypt<S> {
+    recipient: PublicKey,
+    algo: etm::Algorithm,
+    state: EncryptState<S>
+}
+
+enum EncryptState<S> {
+    Generating(GenerateKey, S),
+    Streaming(etm::Encrypt<S>),
+    Done
+}
+
+impl<S: Debug> Debug for Encrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Encrypt")
+            .field("recipient", &self.recipient)
+            .field("algo", &self.algo)
+            .finish()
+    }
+}
+
+impl<S: Stream> Encrypt<S> {
+    /// Create an encrypting stream adapter.
+    ///
+    /// The ephemeral key is generated using `generator`, for the key agreement
+    /// algorithm of the recipient's public key.
+    pub fn new(generator: &Generator, recipient: &PublicKey, algo: etm::Algorithm, inner: S) -> Self {
+        let generating = PrivateKey::generate(recipient.algorithm(), generator);
+        Encrypt { recipient: recipient.clone(), algo, state: EncryptState::Generating(generating, inner) }
+    }
+}
+
+impl<S: Stream> Stream for Encrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match mem::replace(&mut self.state, EncryptState::Done) {
+            EncryptState::Generating(mut generating, inner) => {
+                let ephemeral = match generating.poll()? {
+                    Async::NotReady => {
+                        self.state = EncryptState::Generating(generating, inner);
+                        return Ok(Async::NotReady);
+                    },
+                    Async::Ready(ephemeral) => ephemeral
+                };
+                let ephemeral_public = ephemeral.public_key()?.to_bytes()?;
+                let recipient_public = self.recipient.to_bytes()?;
+                let config = derive_config(&ephemeral, &self.recipient, &ephemeral_public,
+                                           &recipient_public, self.algo)?;
+                let header = encode_header(self.recipient.algorithm(), self.algo, &ephemeral_public)?;
+                self.state = EncryptState::Streaming(etm::Encrypt::new(&config, inner)?);
+                Ok(Async::Ready(Some(header)))
+            },
+            EncryptState::Streaming(mut encrypt) => {
+                let result = encrypt.poll();
+                self.state = EncryptState::Streaming(encrypt);
+                result
+            },
+            EncryptState::Done => Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// Stream adapter that decrypts data encrypted to a public key, using the matching private key.
+///
+/// As with [`etm::Decrypt`](../cipher/etm/struct.Decrypt.html), the plaintext is only
+/// released once the authentication tag at the end of the stream has been verified.
+pub struct Decrypt<S> {
+    key: PrivateKey,
+    state: DecryptState<S>
+}
+
+enum DecryptState<S> {
+    Header(S, BytesMut),
+    Streaming(Box<etm::Decrypt<Rest<S>>>),
+    Done
+}
+
+impl<S: Debug> Debug for Decrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Decrypt")
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl<S: Stream> Decrypt<S> {
+    /// Create a decrypting stream adapter, given the recipient's private key.
+    pub fn new(key: &PrivateKey, inner: S) -> Self {
+        Decrypt { key: key.clone(), state: DecryptState::Header(inner, BytesMut::new()) }
+    }
+}
+
+impl<S: Stream> Stream for Decrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, DecryptState::Done) {
+                DecryptState::Header(mut inner, mut buffer) => {
+                    if let Some((header, len)) = decode_header(&buffer)? {
+                        if header.agreement != self.key.algorithm() {
+                            return Err(Error::from(ErrorKind::UnknownKey).into());
+                        }
+                        let ephemeral = PublicKey::from_bytes(header.agreement, header.ephemeral_key)?;
+                        let recipient_public = self.key.public_key()?.to_bytes()?;
+                        let config = derive_config(&self.key, &ephemeral, header.ephemeral_key,
+                                                   &recipient_public, header.algo)?;
+                        let rest = Rest::new(buffer.split_off(len).freeze(), inner);
+                        self.state = DecryptState::Streaming(Box::new(etm::Decrypt::new(&config, rest)?));
+                        continue;
+                    }
+                    match inner.poll()? {
+                        Async::NotReady => {
+                            self.state = DecryptState::Header(inner, buffer);
+                            return Ok(Async::NotReady);
+                        },
+                        Async::Ready(None) => return Err(Error::from(ErrorKind::Malformed).into()),
+                        Async::Ready(Some(item)) => {
+                            buffer.extend_from_slice(item.as_ref());
+                            self.state = DecryptState::Header(inner, buffer);
+                        }
+                    }
+                },
+                DecryptState::Streaming(mut decrypt) => {
+                    let result = decrypt.poll();
+                    self.state = DecryptState::Streaming(decrypt);
+                    return result;
+                },
+                DecryptState::Done => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Derive the configuration for authenticated encryption from the shared secret.
+///
+/// Both public keys are used as the salt, and the algorithm identifiers as part of the info,
+/// so that the derived secret is bound to the header.
+fn derive_config(key: &PrivateKey, peer: &PublicKey, ephemeral_public: &[u8], recipient_public: &[u8],
+                 algo: etm::Algorithm) -> Result<etm::Config, Error> {
+    let mut salt = Vec::with_capacity(ephemeral_public.len() + recipient_public.len());
+    salt.extend_from_slice(ephemeral_public);
+    salt.extend_from_slice(recipient_public);
+    let mut info = INFO.to_vec();
+    info.push(agreement_id(peer.algorithm())?);
+    info.push(algorithm_id(algo)?);
+
+    let mut config = etm::Config::new(algo);
+    let mut okm = [0u8; OKM_LEN];
+    let result = key.derive(peer, hash::Algorithm::Sha256, &salt, &info, &mut okm);
+    if result.is_ok() {
+        let (secret, iv) = okm.split_at(config.key_mut().len());
+        config.key_mut().copy_from_slice(secret);
+        config.iv_mut().copy_from_slice(iv);
+    }
+    zeroize(&mut okm);
+    result.map(|()| config)
+}
+
+const MAGIC: &[u8] = b"CNHY";
+const VERSION: u8 = 1;
+const INFO: &[u8] = b"cryptonite hybrid encryption";
+const OKM_LEN: usize = 48;
+
+/// Key agreement algorithms, indexed by their identifier in headers.
+///
+/// The identifiers are shared with the [`recipient`](../recipient/index.html) format.
+const AGREEMENTS: [agreement::Algorithm; 2] = [
+    agreement::Algorithm::X25519,
+    agreement::Algorithm::EcdhP256,
+];
+
+/// Encryption algorithms, indexed by their identifier in headers.
+const ALGORITHMS: [etm::Algorithm; 6] = [
+    etm::Algorithm::Aes128CbcHmacSha256,
+    etm::Algorithm::Aes256CbcHmacSha256,
+    etm::Algorithm::Aes256CbcHmacSha512,
+    etm::Algorithm::Aes128CtrHmacSha256,
+    etm::Algorithm::Aes256CtrHmacSha256,
+    etm::Algorithm::Aes256CtrHmacSha512,
+];
+
+/// Get the identifier of a key agreement algorithm.
+///
+/// Fails with `ErrorKind::Unsupported` if the algorithm has no identifier.
+pub(crate) fn agreement_id(agreement: agreement::Algorithm) -> Result<u8, Error> {
+    AGREEMENTS.iter().position(|a| *a == agreement).map(|id| id as u8).ok_or_else(|| ErrorKind::Unsupported.into())
+}
+
+/// Get the key agreement algorithm with the given identifier.
+///
+/// Fails with `ErrorKind::Malformed` if there is no such algorithm.
+pub(crate) fn agreement_by_id(id: u8) -> Result<agreement::Algorithm, Error> {
+    AGREEMENTS.get(id as usize).cloned().ok_or_else(|| ErrorKind::Malformed.into())
+}
+
+/// Get the identifier of an encryption algorithm.
+///
+/// Fails with `ErrorKind::Unsupported` if the algorithm has no identifier.
+pub(crate) fn algorithm_id(algo: etm::Algorithm) -> Result<u8, Error> {
+    ALGORITHMS.iter().position(|a| *a == algo).map(|id| id as u8).ok_or_else(|| ErrorKind::Unsupported.into())
+}
+
+/// Get the encryption algorithm with the given identifier.
+///
+/// Fails with `ErrorKind::Malformed` if there is no such algorithm.
+pub(crate) fn algorithm_by_id(id: u8) -> Result<etm::Algorithm, Error> {
+    ALGORITHMS.get(id as usize).cloned().ok_or_else(|| ErrorKind::Malformed.into())
+}
+
+struct Header<'a> {
+    agreement: agreement::Algorithm,
+    algo: etm::Algorithm,
+    ephemeral_key: &'a [u8]
+}
+
+fn encode_header(agreement: agreement::Algorithm, algo: etm::Algorithm, ephemeral_key: &[u8]) -> Result<Bytes, Error> {
+    let mut header = BytesMut::with_capacity(MAGIC.len() + 4 + ephemeral_key.len());
+    header.put_slice(MAGIC);
+    header.put_u8(VERSION);
+    header.put_u8(agreement_id(agreement)?);
+    header.put_u8(algorithm_id(algo)?);
+    header.put_u8(ephemeral_key.len() as u8);
+    header.put_slice(ephemeral_key);
+    Ok(header.freeze())
+}
+
+/// Decode a header from the start of `input`.
+///
+/// Returns `None` if more input is needed, or the header along with its length.
+fn decode_header<'a>(input: &'a [u8]) -> Result<Option<(Header<'a>, usize)>, Error> {
+    let mut pos = 0;
+    macro_rules! take {
+        ($len:expr) => {{
+            let len = $len;
+            if input.len() < pos + len {
+                return Ok(None);
+            }
+            pos += len;
+            &input[pos - len..pos]
+        }}
+    }
+    if take!(MAGIC.len()) != MAGIC || take!(1)[0] != VERSION {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let agreement = agreement_by_id(take!(1)[0])?;
+    let algo = algorithm_by_id(take!(1)[0])?;
+    let ephemeral_key_len = take!(1)[0] as usize;
+    let ephemeral_key = take!(ephemeral_key_len);
+    Ok(Some((Header { agreement, algo, ephemeral_key }, pos)))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+    use self::itertools::Itertools;
+
+    use agreement::{self, PrivateKey, PublicKey};
+    use cipher::etm;
+    use random::Generator;
+    use super::{Decrypt, Encrypt, Error, ErrorKind};
+
+    fn encrypt(recipient: &PublicKey, chunks: Vec<&'static str>) -> Vec<u8> {
+        let generator = Generator::new(1);
+        let encrypt = Encrypt::new(&generator, recipient, etm::Algorithm::Aes256CtrHmacSha256,
+                                   iter_ok::<_, Error>(chunks));
+        encrypt.wait().collect::<Result<Vec<_>, _>>().unwrap().into_iter().concat().to_vec()
+    }
+
+    fn decrypt(key: &PrivateKey, ciphertext: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = ciphertext.chunks(5).map(|chunk| chunk.to_vec()).collect();
+        let decrypt = Decrypt::new(key, iter_ok::<_, Error>(chunks));
+        decrypt.wait().collect::<Result<Vec<_>, _>>().map(|chunks| chunks.into_iter().concat().to_vec())
+    }
+
+    fn generate(algo: agreement::Algorithm) -> PrivateKey {
+        PrivateKey::generate(algo, &Generator::new(1)).wait().unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        for &algo in &[agreement::Algorithm::X25519, agreement::Algorithm::EcdhP256] {
+            let key = generate(algo);
+            let ciphertext = encrypt(&key.public_key().unwrap(), vec!["foo", "bar", "baz"]);
+            assert_eq!(decrypt(&key, ciphertext).unwrap(), b"foobarbaz");
+        }
+    }
+
+    #[test]
+    fn ephemeral_keys_differ() {
+        let key = generate(agreement::Algorithm::X25519);
+        let first = encrypt(&key.public_key().unwrap(), vec!["foo"]);
+        let second = encrypt(&key.public_key().unwrap(), vec!["foo"]);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let key = generate(agreement::Algorithm::X25519);
+        let ciphertext = encrypt(&key.public_key().unwrap(), vec!["foo"]);
+        let other = generate(agreement::Algorithm::X25519);
+        assert_eq!(decrypt(&other, ciphertext.clone()).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+        let other = generate(agreement::Algorithm::EcdhP256);
+        assert_eq!(decrypt(&other, ciphertext).unwrap_err().kind(), ErrorKind::UnknownKey);
+    }
+
+    #[test]
+    fn tampered_header() {
+        let key = generate(agreement::Algorithm::X25519);
+        let mut ciphertext = encrypt(&key.public_key().unwrap(), vec!["foo"]);
+        ciphertext[6] = 3;
+        assert_eq!(decrypt(&key, ciphertext.clone()).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+        ciphertext[6] = 42;
+        assert_eq!(decrypt(&key, ciphertext).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let key = generate(agreement::Algorithm::EcdhP256);
+        let mut ciphertext = encrypt(&key.public_key().unwrap(), vec![]);
+        let tag_len = etm::Algorithm::Aes256CtrHmacSha256.tag_len();
+        let len = ciphertext.len() - tag_len - 1;
+        ciphertext.truncate(len);
+        assert_eq!(decrypt(&key, ciphertext).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+}
@@ -0,0 +1,330 @@
+//! Minimal JSON parser and serializer for JOSE headers and keys, as specified in
+//! [RFC 8259](https://tools.ietf.org/html/rfc8259).
+//!
+//! Numbers are kept as their textual representation, since they are never
+//! interpreted, and objects with duplicate member names are rejected.
+
+use std::char;
+use std::str;
+
+use super::super::{Error, ErrorKind};
+
+const MAX_DEPTH: usize = 32;
+
+/// JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>)
+}
+
+impl Value {
+    /// Get the member of an object with the given name.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => {
+                members.iter().find(|(key, _)| key == name).map(|(_, value)| value)
+            },
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref string) => Some(string),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self {
+            Value::Array(ref values) => Some(values),
+            _ => None
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Value)]> {
+        match *self {
+            Value::Object(ref members) => Some(members),
+            _ => None
+        }
+    }
+
+    /// Get a string member, failing with `ErrorKind::Malformed` if it has another type.
+    pub fn get_str(&self, name: &str) -> Result<Option<&str>, Error> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(value) => value.as_str().map(Some).ok_or_else(|| ErrorKind::Malformed.into())
+        }
+    }
+
+    /// Serialize the value without any whitespace.
+    pub fn to_json(&self) -> String {
+        let mut output = String::new();
+        self.write(&mut output);
+        output
+    }
+
+    fn write(&self, output: &mut String) {
+        match *self {
+            Value::Null => output.push_str("null"),
+            Value::Bool(value) => output.push_str(if value { "true" } else { "false" }),
+            Value::Number(ref number) => output.push_str(number),
+            Value::String(ref string) => write_string(string, output),
+            Value::Array(ref values) => {
+                output.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        output.push(',');
+                    }
+                    value.write(output);
+                }
+                output.push(']');
+            },
+            Value::Object(ref members) => {
+                output.push('{');
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        output.push(',');
+                    }
+                    write_string(name, output);
+                    output.push(':');
+                    value.write(output);
+                }
+                output.push('}');
+            }
+        }
+    }
+}
+
+fn write_string(string: &str, output: &mut String) {
+    output.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c)
+        }
+    }
+    output.push('"');
+}
+
+/// Parse a JSON document, failing with `ErrorKind::Malformed` if it is invalid.
+pub fn parse(input: &[u8]) -> Result<Value, Error> {
+    let mut parser = Parser { input, pos: 0 };
+    let value = parser.value(0)?;
+    parser.whitespace();
+    if parser.pos != input.len() {
+        return Err(ErrorKind::Malformed.into());
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+    fn whitespace(&mut self) {
+        while let Some(&b' ') | Some(&b'\t') | Some(&b'\n') | Some(&b'\r') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<u8, Error> {
+        let c = *self.input.get(self.pos).ok_or(ErrorKind::Malformed)?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: &[u8]) -> Result<(), Error> {
+        if !self.input[self.pos..].starts_with(expected) {
+            return Err(ErrorKind::Malformed.into());
+        }
+        self.pos += expected.len();
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        if depth > MAX_DEPTH {
+            return Err(ErrorKind::Malformed.into());
+        }
+        self.whitespace();
+        match self.input.get(self.pos).cloned() {
+            Some(b'n') => self.expect(b"null").map(|_| Value::Null),
+            Some(b't') => self.expect(b"true").map(|_| Value::Bool(true)),
+            Some(b'f') => self.expect(b"false").map(|_| Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.input.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.value(depth + 1)?);
+                    self.whitespace();
+                    match self.next()? {
+                        b',' => continue,
+                        b']' => return Ok(Value::Array(values)),
+                        _ => return Err(ErrorKind::Malformed.into())
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members: Vec<(String, Value)> = Vec::new();
+                self.whitespace();
+                if self.input.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    if self.input.get(self.pos) != Some(&b'"') {
+                        return Err(ErrorKind::Malformed.into());
+                    }
+                    let name = self.string()?;
+                    if members.iter().any(|(key, _)| *key == name) {
+                        return Err(ErrorKind::Malformed.into());
+                    }
+                    self.whitespace();
+                    self.expect(b":")?;
+                    let value = self.value(depth + 1)?;
+                    members.push((name, value));
+                    self.whitespace();
+                    match self.next()? {
+                        b',' => continue,
+                        b'}' => return Ok(Value::Object(members)),
+                        _ => return Err(ErrorKind::Malformed.into())
+                    }
+                }
+            },
+            Some(b'-') | Some(b'0'..=b'9') => self.number().map(Value::Number),
+            _ => Err(ErrorKind::Malformed.into())
+        }
+    }
+
+    fn number(&mut self) -> Result<String, Error> {
+        let start = self.pos;
+        if self.input.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        match self.next()? {
+            b'0' => {},
+            b'1'..=b'9' => self.digits(),
+            _ => return Err(ErrorKind::Malformed.into())
+        }
+        if self.input.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            self.digits_required()?;
+        }
+        if let Some(&b'e') | Some(&b'E') = self.input.get(self.pos) {
+            self.pos += 1;
+            if let Some(&b'+') | Some(&b'-') = self.input.get(self.pos) {
+                self.pos += 1;
+            }
+            self.digits_required()?;
+        }
+        Ok(str::from_utf8(&self.input[start..self.pos]).unwrap().to_owned())
+    }
+
+    fn digits(&mut self) {
+        while let Some(b'0'..=b'9') = self.input.get(self.pos).cloned() {
+            self.pos += 1;
+        }
+    }
+
+    fn digits_required(&mut self) -> Result<(), Error> {
+        let start = self.pos;
+        self.digits();
+        if self.pos == start { Err(ErrorKind::Malformed.into()) } else { Ok(()) }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.pos += 1;
+        let mut output = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => return String::from_utf8(output).map_err(|_| ErrorKind::Malformed.into()),
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.escaped_char()?,
+                        _ => return Err(ErrorKind::Malformed.into())
+                    };
+                    let mut buf = [0u8; 4];
+                    output.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                },
+                c if c < 0x20 => return Err(ErrorKind::Malformed.into()),
+                c => output.push(c)
+            }
+        }
+    }
+
+    /// Decode the character of a `\u` escape, which may be a surrogate pair.
+    fn escaped_char(&mut self) -> Result<char, Error> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                self.expect(b"\\u")?;
+                let low = self.hex4()?;
+                if !(0xdc00..=0xdfff).contains(&low) {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+            },
+            code => code
+        };
+        char::from_u32(code).ok_or_else(|| ErrorKind::Malformed.into())
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = (self.next()? as char).to_digit(16).ok_or(ErrorKind::Malformed)?;
+            code = code << 4 | digit;
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Value, parse};
+
+    #[test]
+    fn parse_and_serialize() {
+        let value = parse(b" {\"alg\" : \"HS256\", \"a\\u00e9\\ud83d\\ude00\":[1, -2.5e+3, true, null, {}], \"x\":\"\\\"\\n\"}\r\n").unwrap();
+        assert_eq!(value.get("alg"), Some(&Value::String("HS256".to_owned())));
+        assert_eq!(value.get("a\u{e9}\u{1f600}").unwrap().as_array().unwrap().len(), 5);
+        assert_eq!(value.to_json(), "{\"alg\":\"HS256\",\"a\u{e9}\u{1f600}\":[1,-2.5e+3,true,null,{}],\"x\":\"\\\"\\n\"}");
+        assert_eq!(parse(value.to_json().as_bytes()).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        for input in &["", "{", "{\"a\":1,}", "[1 2]", "01", "1.", "\"\\x\"", "\"\\ud800\"", "{\"a\":1,\"a\":2}",
+                       "{} {}", "tru", "\"\n\"", "[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]"] {
+            assert!(parse(input.as_bytes()).is_err(), "{}", input);
+        }
+    }
+}
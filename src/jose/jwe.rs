@@ -0,0 +1,566 @@
+//! JSON Web Encryption (JWE), as specified in [RFC 7516](https://tools.ietf.org/html/rfc7516).
+//!
+//! The content encryption key is either the shared key itself (`dir`), or a random key
+//! wrapped for each recipient using AES key wrap (`A128KW`, `A192KW` and `A256KW`).
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use futures::{Async, Future, Poll};
+use openssl;
+use openssl::symm::Cipher;
+
+use super::super::{Error, ErrorKind};
+use super::super::base64;
+use super::super::cipher;
+use super::super::cipher::wrap;
+use super::super::hash::{self, Hmac};
+use super::super::random::{Generator, RandomBytes};
+use super::super::secret::{Redacted, zeroize};
+use super::{Jwk, candidate_keys, decode_header, merge_headers};
+use super::json::{self, Value};
+
+const GCM_TAG_LEN: usize = 16;
+
+/// Algorithm used to determine the content encryption key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Algorithm {
+    /// Use the shared symmetric key as the content encryption key
+    Direct,
+    /// AES key wrap with a 128-bit key
+    A128Kw,
+    /// AES key wrap with a 192-bit key
+    A192Kw,
+    /// AES key wrap with a 256-bit key
+    A256Kw,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+const ALGORITHMS: [(Algorithm, &str); 4] = [
+    (Algorithm::Direct, "dir"), (Algorithm::A128Kw, "A128KW"),
+    (Algorithm::A192Kw, "A192KW"), (Algorithm::A256Kw, "A256KW")
+];
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        ALGORITHMS.iter().find(|&&(algo, _)| algo == self).map(|&(_, name)| name).unwrap()
+    }
+
+    fn from_name(name: &str) -> Option<Algorithm> {
+        ALGORITHMS.iter().find(|&&(_, algo_name)| algo_name == name).map(|&(algo, _)| algo)
+    }
+
+    fn wrap_algorithm(self) -> Option<wrap::Algorithm> {
+        match self {
+            Algorithm::A128Kw => Some(wrap::Algorithm::Aes128Kw),
+            Algorithm::A192Kw => Some(wrap::Algorithm::Aes192Kw),
+            Algorithm::A256Kw => Some(wrap::Algorithm::Aes256Kw),
+            _ => None
+        }
+    }
+
+    /// Get the length of the shared key used with the given content encryption.
+    fn key_len(self, encryption: Encryption) -> usize {
+        match self.wrap_algorithm() {
+            Some(wrap) => wrap.key_len(),
+            None => encryption.key_len()
+        }
+    }
+
+    /// Get the shared key, failing if it has the wrong type or length.
+    fn shared_key(self, encryption: Encryption, key: &Jwk) -> Result<&[u8], Error> {
+        let key = key.symmetric_key().ok_or(ErrorKind::Unsupported)?;
+        if key.len() != self.key_len(encryption) {
+            return Err(ErrorKind::InvalidKeyLength.into());
+        }
+        Ok(key)
+    }
+}
+
+/// Algorithm used to encrypt and authenticate the content.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Encryption {
+    /// AES-GCM with a 128-bit key
+    A128Gcm,
+    /// AES-GCM with a 192-bit key
+    A192Gcm,
+    /// AES-GCM with a 256-bit key
+    A256Gcm,
+    /// AES-128 in CBC mode, authenticated with HMAC-SHA-256
+    A128CbcHs256,
+    /// AES-192 in CBC mode, authenticated with HMAC-SHA-384
+    A192CbcHs384,
+    /// AES-256 in CBC mode, authenticated with HMAC-SHA-512
+    A256CbcHs512,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+const ENCRYPTIONS: [(Encryption, &str); 6] = [
+    (Encryption::A128Gcm, "A128GCM"), (Encryption::A192Gcm, "A192GCM"), (Encryption::A256Gcm, "A256GCM"),
+    (Encryption::A128CbcHs256, "A128CBC-HS256"), (Encryption::A192CbcHs384, "A192CBC-HS384"),
+    (Encryption::A256CbcHs512, "A256CBC-HS512")
+];
+
+impl Encryption {
+    fn name(self) -> &'static str {
+        ENCRYPTIONS.iter().find(|&&(enc, _)| enc == self).map(|&(_, name)| name).unwrap()
+    }
+
+    fn from_name(name: &str) -> Option<Encryption> {
+        ENCRYPTIONS.iter().find(|&&(_, enc_name)| enc_name == name).map(|&(enc, _)| enc)
+    }
+
+    /// Get the MAC digest, or `None` for AES-GCM.
+    fn hmac(self) -> Option<hash::Algorithm> {
+        match self {
+            Encryption::A128CbcHs256 => Some(hash::Algorithm::Sha256),
+            Encryption::A192CbcHs384 => Some(hash::Algorithm::Sha384),
+            Encryption::A256CbcHs512 => Some(hash::Algorithm::Sha512),
+            _ => None
+        }
+    }
+
+    fn cipher(self) -> Result<Cipher, Error> {
+        match self {
+            Encryption::A128Gcm => Ok(Cipher::aes_128_gcm()),
+            Encryption::A192Gcm => Ok(Cipher::aes_192_gcm()),
+            Encryption::A256Gcm => Ok(Cipher::aes_256_gcm()),
+            Encryption::A128CbcHs256 => cipher::Algorithm::Aes128Cbc.into_cipher(),
+            Encryption::A192CbcHs384 => cipher::Algorithm::Aes192Cbc.into_cipher(),
+            Encryption::A256CbcHs512 => cipher::Algorithm::Aes256Cbc.into_cipher(),
+            Encryption::_Donotmatch => unreachable!()
+        }
+    }
+
+    /// Get the length of the content encryption key, which for AES-CBC includes the MAC key.
+    fn key_len(self) -> usize {
+        match self.hmac() {
+            Some(digest) => digest.digest_len(),
+            None => self.cipher().map(|cipher| cipher.key_len()).unwrap_or(0)
+        }
+    }
+
+    fn iv_len(self) -> usize {
+        match self.hmac() {
+            Some(_) => 16,
+            None => 12
+        }
+    }
+
+    /// Encrypt the plaintext, returning the ciphertext and the authentication tag.
+    fn encrypt(self, key: &[u8], iv: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let cipher = self.cipher()?;
+        match self.hmac() {
+            None => {
+                let mut tag = vec![0u8; GCM_TAG_LEN];
+                let ciphertext = openssl::symm::encrypt_aead(cipher, key, Some(iv), aad, plaintext, &mut tag)
+                    .map_err(Error::from)?;
+                Ok((ciphertext, tag))
+            },
+            Some(digest) => {
+                let (mac_key, enc_key) = key.split_at(key.len() / 2);
+                let ciphertext = openssl::symm::encrypt(cipher, enc_key, Some(iv), plaintext).map_err(Error::from)?;
+                let tag = cbc_hmac_tag(digest, mac_key, iv, aad, &ciphertext)?;
+                Ok((ciphertext, tag))
+            }
+        }
+    }
+
+    /// Authenticate and decrypt the ciphertext, failing with `ErrorKind::AuthenticationFailed`
+    /// if the tag does not match.
+    fn decrypt(self, key: &[u8], iv: &[u8], aad: &[u8], ciphertext: &[u8], tag: &[u8]) -> Result<Vec<u8>, Error> {
+        let cipher = self.cipher()?;
+        match self.hmac() {
+            None => {
+                if tag.len() != GCM_TAG_LEN {
+                    return Err(ErrorKind::AuthenticationFailed.into());
+                }
+                openssl::symm::decrypt_aead(cipher, key, Some(iv), aad, ciphertext, tag)
+                    .map_err(|_| ErrorKind::AuthenticationFailed.into())
+            },
+            Some(digest) => {
+                let (mac_key, enc_key) = key.split_at(key.len() / 2);
+                let expected = cbc_hmac_tag(digest, mac_key, iv, aad, ciphertext)?;
+                if tag.len() != expected.len() || !openssl::memcmp::eq(tag, &expected) {
+                    return Err(ErrorKind::AuthenticationFailed.into());
+                }
+                openssl::symm::decrypt(cipher, enc_key, Some(iv), ciphertext)
+                    .map_err(|_| ErrorKind::BadPadding.into())
+            }
+        }
+    }
+}
+
+/// Compute the truncated tag of AES-CBC with HMAC, as specified in RFC 7518, section 5.2.
+fn cbc_hmac_tag(digest: hash::Algorithm, key: &[u8], iv: &[u8], aad: &[u8],
+                ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut hmac = Hmac::new(digest, key)?;
+    hmac.update(aad)?;
+    hmac.update(iv)?;
+    hmac.update(ciphertext)?;
+    let aad_bits = (aad.len() as u64) * 8;
+    hmac.update(&[(aad_bits >> 56) as u8, (aad_bits >> 48) as u8, (aad_bits >> 40) as u8, (aad_bits >> 32) as u8,
+                  (aad_bits >> 24) as u8, (aad_bits >> 16) as u8, (aad_bits >> 8) as u8, aad_bits as u8])?;
+    let mut tag = hmac.finish()?.as_ref().to_vec();
+    tag.truncate(key.len());
+    Ok(tag)
+}
+
+/// Encrypt a plaintext for a single recipient, returning the JWE in compact serialization.
+///
+/// The protected header contains both algorithms, and the key ID if the key has one.
+///
+/// Fails with `ErrorKind::Unsupported` if the key is not a symmetric key, and with
+/// `ErrorKind::InvalidKeyLength` if its length does not match the algorithm.
+pub fn encrypt_compact(generator: &Generator, algorithm: Algorithm, encryption: Encryption, key: &Jwk,
+                       plaintext: &[u8]) -> Result<EncryptJwe, Error> {
+    EncryptJwe::new(generator, encryption, &[(algorithm, key)], plaintext, None, true)
+}
+
+/// Encrypt a plaintext for one or more recipients, returning the JWE in general JSON
+/// serialization.
+///
+/// The protected header contains the content encryption algorithm, while the key
+/// management algorithm and the key ID are placed in the header of each recipient.
+/// The additional authenticated data, if any, is included in the JWE.
+///
+/// The `dir` algorithm can only be used with a single recipient. See
+/// [`encrypt_compact`](fn.encrypt_compact.html) for more information.
+pub fn encrypt_json(generator: &Generator, encryption: Encryption, recipients: &[(Algorithm, &Jwk)],
+                    plaintext: &[u8], aad: Option<&[u8]>) -> Result<EncryptJwe, Error> {
+    EncryptJwe::new(generator, encryption, recipients, plaintext, aad, false)
+}
+
+/// Decrypt a JWE in compact serialization, returning the plaintext.
+///
+/// The key is selected using the key ID in the header if there is one, and must have
+/// the length required by the algorithms in the header.
+///
+/// Fails with `ErrorKind::UnknownKey` if there is no such key, with `ErrorKind::AuthenticationFailed`
+/// if the JWE cannot be authenticated, and with `ErrorKind::Unsupported` if the header contains
+/// critical extensions or requires decompression.
+pub fn decrypt_compact(jwe: &str, keys: &[Jwk]) -> Result<Vec<u8>, Error> {
+    let parts = jwe.split('.').collect::<Vec<_>>();
+    if parts.len() != 5 {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let decoded = parts[1..].iter()
+        .map(|part| base64::decode_url(part.as_bytes(), false).ok_or_else(|| ErrorKind::Malformed.into()))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let header = decode_header(parts[0])?;
+    decrypt(&header, &decoded[0], &decoded[1], &decoded[2], &decoded[3], parts[0].as_bytes(), keys)
+}
+
+/// Decrypt a JWE in general or flattened JSON serialization, returning the plaintext.
+///
+/// The JWE is decrypted using the first recipient for which a key can be found. See
+/// [`decrypt_compact`](fn.decrypt_compact.html) for more information.
+pub fn decrypt_json(jwe: &str, keys: &[Jwk]) -> Result<Vec<u8>, Error> {
+    let jwe = json::parse(jwe.as_bytes())?;
+    let decode = |name: &str| -> Result<Option<Vec<u8>>, Error> {
+        match jwe.get_str(name)? {
+            None => Ok(None),
+            Some(value) => base64::decode_url(value.as_bytes(), false).map(Some)
+                .ok_or_else(|| ErrorKind::Malformed.into())
+        }
+    };
+    let iv = decode("iv")?.ok_or(ErrorKind::Malformed)?;
+    let ciphertext = decode("ciphertext")?.ok_or(ErrorKind::Malformed)?;
+    let tag = decode("tag")?.ok_or(ErrorKind::Malformed)?;
+    let protected = jwe.get_str("protected")?;
+    let mut aad = protected.unwrap_or("").to_owned();
+    if let Some(extra) = jwe.get_str("aad")? {
+        aad.push('.');
+        aad.push_str(extra);
+    }
+    let mut shared = match protected {
+        None => Value::Object(vec![]),
+        Some(protected) => decode_header(protected)?
+    };
+    if let Some(unprotected) = jwe.get("unprotected") {
+        shared = merge_headers(&[&shared, unprotected])?;
+    }
+
+    let flattened = [jwe.clone()];
+    let recipients = match jwe.get("recipients") {
+        None => &flattened[..],
+        Some(recipients) => recipients.as_array().ok_or(ErrorKind::Malformed)?
+    };
+    let mut result = Err(ErrorKind::UnknownKey.into());
+    for recipient in recipients {
+        let header = match recipient.get("header") {
+            None => shared.clone(),
+            Some(header) => merge_headers(&[&shared, header])?
+        };
+        let encrypted_key = match recipient.get_str("encrypted_key")? {
+            None => vec![],
+            Some(value) => base64::decode_url(value.as_bytes(), false).ok_or(ErrorKind::Malformed)?
+        };
+        match decrypt(&header, &encrypted_key, &iv, &ciphertext, &tag, aad.as_bytes(), keys) {
+            Ok(plaintext) => return Ok(plaintext),
+            Err(ref err) if err.kind() == ErrorKind::UnknownKey => {},
+            Err(err) => result = Err(err)
+        }
+    }
+    result
+}
+
+fn decrypt(header: &Value, encrypted_key: &[u8], iv: &[u8], ciphertext: &[u8], tag: &[u8], aad: &[u8],
+           keys: &[Jwk]) -> Result<Vec<u8>, Error> {
+    if header.get("crit").is_some() || header.get("zip").is_some() {
+        return Err(ErrorKind::Unsupported.into());
+    }
+    let algorithm = header.get_str("alg")?.ok_or(ErrorKind::Malformed)?;
+    let algorithm = Algorithm::from_name(algorithm).ok_or(ErrorKind::Unsupported)?;
+    let encryption = header.get_str("enc")?.ok_or(ErrorKind::Malformed)?;
+    let encryption = Encryption::from_name(encryption).ok_or(ErrorKind::Unsupported)?;
+    if iv.len() != encryption.iv_len() {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let mut result = Err(ErrorKind::UnknownKey.into());
+    for key in candidate_keys(keys, header.get_str("kid")?) {
+        let shared_key = match algorithm.shared_key(encryption, key) {
+            Ok(shared_key) => shared_key,
+            Err(_) => continue
+        };
+        let mut content_key = match algorithm.wrap_algorithm() {
+            None if encrypted_key.is_empty() => shared_key.to_vec(),
+            None => return Err(ErrorKind::Malformed.into()),
+            Some(wrap) => {
+                let mut config = wrap::Config::new(wrap);
+                config.key_mut().copy_from_slice(shared_key);
+                match config.unwrap(encrypted_key) {
+                    Ok(content_key) => content_key,
+                    Err(err) => {
+                        result = Err(err);
+                        continue;
+                    }
+                }
+            }
+        };
+        result = if content_key.len() == encryption.key_len() {
+            encryption.decrypt(&content_key, iv, aad, ciphertext, tag)
+        } else {
+            Err(ErrorKind::Malformed.into())
+        };
+        zeroize(&mut content_key);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// Future returned by [`encrypt_compact`](fn.encrypt_compact.html) and
+/// [`encrypt_json`](fn.encrypt_json.html), which resolves to the serialized JWE.
+pub struct EncryptJwe {
+    encryption: Encryption,
+    recipients: Vec<(Algorithm, Jwk)>,
+    plaintext: Vec<u8>,
+    aad: Option<Vec<u8>>,
+    compact: bool,
+    random: RandomBytes
+}
+
+impl EncryptJwe {
+    fn new(generator: &Generator, encryption: Encryption, recipients: &[(Algorithm, &Jwk)], plaintext: &[u8],
+           aad: Option<&[u8]>, compact: bool) -> Result<EncryptJwe, Error> {
+        let direct = recipients.iter().any(|&(algorithm, _)| algorithm == Algorithm::Direct);
+        if recipients.is_empty() || (direct && recipients.len() > 1) {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        for &(algorithm, key) in recipients {
+            algorithm.shared_key(encryption, key)?;
+        }
+        let random_len = if direct { 0 } else { encryption.key_len() } + encryption.iv_len();
+        Ok(EncryptJwe {
+            encryption,
+            recipients: recipients.iter().map(|&(algorithm, key)| (algorithm, key.clone())).collect(),
+            plaintext: plaintext.to_vec(),
+            aad: aad.map(|aad| aad.to_vec()),
+            compact,
+            random: generator.random_bytes(random_len)
+        })
+    }
+
+    fn encrypt(&self, random: &[u8]) -> Result<String, Error> {
+        let encryption = self.encryption;
+        let (content_key, iv) = match self.recipients[0] {
+            (Algorithm::Direct, ref key) => (key.symmetric_key().unwrap(), random),
+            _ => random.split_at(encryption.key_len())
+        };
+        let mut encrypted_keys = Vec::with_capacity(self.recipients.len());
+        for &(algorithm, ref key) in &self.recipients {
+            encrypted_keys.push(match algorithm.wrap_algorithm() {
+                None => vec![],
+                Some(wrap) => {
+                    let mut config = wrap::Config::new(wrap);
+                    config.key_mut().copy_from_slice(key.symmetric_key().unwrap());
+                    config.wrap(content_key)?
+                }
+            });
+        }
+
+        let header = |algorithm: Algorithm, key: &Jwk, with_enc: bool| {
+            let mut members = vec![("alg".to_owned(), Value::String(algorithm.name().to_owned()))];
+            if with_enc {
+                members.push(("enc".to_owned(), Value::String(encryption.name().to_owned())));
+            }
+            if let Some(key_id) = key.key_id() {
+                members.push(("kid".to_owned(), Value::String(key_id.to_owned())));
+            }
+            Value::Object(members)
+        };
+        let protected = if self.compact {
+            let (algorithm, ref key) = self.recipients[0];
+            header(algorithm, key, true)
+        } else {
+            Value::Object(vec![("enc".to_owned(), Value::String(encryption.name().to_owned()))])
+        };
+        let protected = base64::encode_url(protected.to_json().as_bytes(), false);
+        let mut aad = protected.clone();
+        if let Some(ref extra) = self.aad {
+            aad.push('.');
+            aad.push_str(&base64::encode_url(extra, false));
+        }
+        let (ciphertext, tag) = encryption.encrypt(content_key, iv, aad.as_bytes(), &self.plaintext)?;
+
+        if self.compact {
+            return Ok(format!("{}.{}.{}.{}.{}", protected, base64::encode_url(&encrypted_keys[0], false), base64::encode_url(iv, false),
+                              base64::encode_url(&ciphertext, false), base64::encode_url(&tag, false)));
+        }
+        let recipients = self.recipients.iter().zip(encrypted_keys).map(|(&(algorithm, ref key), encrypted_key)| {
+            let mut members = vec![("header".to_owned(), header(algorithm, key, false))];
+            if !encrypted_key.is_empty() {
+                members.push(("encrypted_key".to_owned(), Value::String(base64::encode_url(&encrypted_key, false))));
+            }
+            Value::Object(members)
+        }).collect();
+        let mut members = vec![("protected".to_owned(), Value::String(protected))];
+        if let Some(ref extra) = self.aad {
+            members.push(("aad".to_owned(), Value::String(base64::encode_url(extra, false))));
+        }
+        members.push(("recipients".to_owned(), Value::Array(recipients)));
+        members.push(("iv".to_owned(), Value::String(base64::encode_url(iv, false))));
+        members.push(("ciphertext".to_owned(), Value::String(base64::encode_url(&ciphertext, false))));
+        members.push(("tag".to_owned(), Value::String(base64::encode_url(&tag, false))));
+        Ok(Value::Object(members).to_json())
+    }
+}
+
+impl Future for EncryptJwe {
+    type Item = String;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let random = match self.random.poll()? {
+            Async::NotReady => return Ok(Async::NotReady),
+            Async::Ready(random) => random
+        };
+        let result = self.encrypt(&random);
+        if let Ok(mut random) = random.try_mut() {
+            zeroize(&mut random);
+        }
+        result.map(Async::Ready)
+    }
+}
+
+impl Drop for EncryptJwe {
+    fn drop(&mut self) {
+        zeroize(&mut self.plaintext);
+    }
+}
+
+impl Debug for EncryptJwe {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("EncryptJwe")
+            .field("encryption", &self.encryption)
+            .field("recipients", &self.recipients)
+            .field("plaintext", &Redacted)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::slice;
+
+    use futures::Future;
+
+    use random::Generator;
+    use super::{Algorithm, Encryption, ErrorKind, Jwk, decrypt_compact, decrypt_json, encrypt_compact, encrypt_json};
+
+    #[test]
+    fn rfc7516_a128kw_a128cbc_hs256() {
+        // RFC 7516, appendix A.3
+        let key = Jwk::from_json(r#"{"kty":"oct","k":"GawgguFyGrWKav7AX4VKUg"}"#).unwrap();
+        let jwe = "eyJhbGciOiJBMTI4S1ciLCJlbmMiOiJBMTI4Q0JDLUhTMjU2In0.\
+                   6KB707dM9YTIgHtLvtgWQ8mKwboJW3of9locizkDTHzBC2IlrT1oOQ.\
+                   AxY8DCtDaGlsbGljb3RoZQ.\
+                   KDlTtXchhZTGufMYmOYGS4HffxPSUrfmqCHXaI9wOGY.\
+                   U0m_YmjN04DJvceFICbCVQ";
+        assert_eq!(decrypt_compact(jwe, slice::from_ref(&key)).unwrap(), b"Live long and prosper.");
+        let tampered = jwe.replace("U0m_", "U0n_");
+        assert_eq!(decrypt_compact(&tampered, &[key]).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let generator = Generator::new(1);
+        let encryptions = [Encryption::A128Gcm, Encryption::A192Gcm, Encryption::A256Gcm,
+                           Encryption::A128CbcHs256, Encryption::A192CbcHs384, Encryption::A256CbcHs512];
+        let algorithms = [Algorithm::Direct, Algorithm::A128Kw, Algorithm::A192Kw, Algorithm::A256Kw];
+        for &encryption in &encryptions {
+            for &algorithm in &algorithms {
+                let key = Jwk::generate_symmetric(&generator, algorithm.key_len(encryption)).wait().unwrap()
+                    .with_key_id("a");
+                let other = Jwk::symmetric(&vec![0; algorithm.key_len(encryption)]);
+                let jwe = encrypt_compact(&generator, algorithm, encryption, &key, b"plaintext").unwrap().wait().unwrap();
+                assert_eq!(decrypt_compact(&jwe, &[other, key.clone()]).unwrap(), b"plaintext");
+                let jwe = encrypt_json(&generator, encryption, &[(algorithm, &key)], b"", Some(b"aad")).unwrap()
+                    .wait().unwrap();
+                assert_eq!(decrypt_json(&jwe, &[key]).unwrap(), b"");
+            }
+        }
+    }
+
+    #[test]
+    fn multiple_recipients() {
+        let generator = Generator::new(1);
+        let first = Jwk::generate_symmetric(&generator, 16).wait().unwrap().with_key_id("first");
+        let second = Jwk::generate_symmetric(&generator, 32).wait().unwrap().with_key_id("second");
+        let recipients = [(Algorithm::A128Kw, &first), (Algorithm::A256Kw, &second)];
+        let jwe = encrypt_json(&generator, Encryption::A256Gcm, &recipients, b"plaintext", None).unwrap().wait().unwrap();
+        assert_eq!(decrypt_json(&jwe, slice::from_ref(&second)).unwrap(), b"plaintext");
+        assert_eq!(decrypt_json(&jwe, slice::from_ref(&first)).unwrap(), b"plaintext");
+        let unknown = Jwk::symmetric(&[0; 16]).with_key_id("third");
+        assert_eq!(decrypt_json(&jwe, &[unknown]).unwrap_err().kind(), ErrorKind::UnknownKey);
+        let wrong = Jwk::symmetric(&[0; 16]).with_key_id("first");
+        assert_eq!(decrypt_json(&jwe, &[wrong]).unwrap_err().kind(), ErrorKind::AuthenticationFailed);
+        // Header members must not be repeated in the unprotected header.
+        let repeated = jwe.replacen("{", r#"{"unprotected":{"enc":"A256GCM"},"#, 1);
+        assert_eq!(decrypt_json(&repeated, slice::from_ref(&first)).unwrap_err().kind(), ErrorKind::Malformed);
+
+        assert_eq!(encrypt_json(&generator, Encryption::A128Gcm, &[(Algorithm::Direct, &first), (Algorithm::A128Kw, &first)],
+                                b"", None).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(encrypt_compact(&generator, Algorithm::A256Kw, Encryption::A128Gcm, &first, b"").unwrap_err().kind(),
+                   ErrorKind::InvalidKeyLength);
+    }
+
+    #[test]
+    fn rejects_unsupported_headers() {
+        let key = Jwk::symmetric(&[0; 16]);
+        // {"alg":"dir","enc":"A128GCM","zip":"DEF"}
+        let jwe = "eyJhbGciOiJkaXIiLCJlbmMiOiJBMTI4R0NNIiwiemlwIjoiREVGIn0..AAAAAAAAAAAAAAAA..AAAAAAAAAAAAAAAAAAAAAA";
+        assert_eq!(decrypt_compact(jwe, slice::from_ref(&key)).unwrap_err().kind(), ErrorKind::Unsupported);
+        // {"alg":"RSA1_5","enc":"A128GCM"}
+        let jwe = "eyJhbGciOiJSU0ExXzUiLCJlbmMiOiJBMTI4R0NNIn0..AAAAAAAAAAAAAAAA..AAAAAAAAAAAAAAAAAAAAAA";
+        assert_eq!(decrypt_compact(jwe, slice::from_ref(&key)).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(decrypt_compact("e30.AA.AA.AA", &[key]).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+}
@@ -0,0 +1,516 @@
+//! JSON Web Keys, as specified in [RFC 7517](https://tools.ietf.org/html/rfc7517).
+
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use futures::{Async, Future, Poll};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::rsa::Rsa;
+
+use super::super::{Error, ErrorKind};
+use super::super::base64;
+use super::super::random::{Generator, RandomBytes};
+use super::super::secret::{Redacted, zeroize};
+use super::super::sign::{SigningKey, VerifyingKey};
+use super::json::{self, Value};
+
+const ED25519_KEY_LEN: usize = 32;
+
+/// Elliptic curve of an `EC` key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Curve {
+    /// The NIST P-256 curve
+    P256,
+    /// The NIST P-384 curve
+    P384,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Curve {
+    fn name(self) -> &'static str {
+        match self {
+            Curve::P256 => "P-256",
+            Curve::P384 => "P-384",
+            Curve::_Donotmatch => unreachable!()
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Curve> {
+        [Curve::P256, Curve::P384].iter().cloned().find(|curve| curve.name() == name)
+    }
+
+    fn group(self) -> Result<EcGroup, Error> {
+        let nid = match self {
+            Curve::P256 => Nid::X9_62_PRIME256V1,
+            Curve::P384 => Nid::SECP384R1,
+            Curve::_Donotmatch => unreachable!()
+        };
+        EcGroup::from_curve_name(nid).map_err(Error::from)
+    }
+
+    /// Length of a coordinate or a private scalar.
+    pub(crate) fn coordinate_len(self) -> usize {
+        match self {
+            Curve::P256 => 32,
+            Curve::P384 => 48,
+            Curve::_Donotmatch => unreachable!()
+        }
+    }
+}
+
+/// Type of a key, along with its curve.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum KeyType {
+    Symmetric,
+    Rsa,
+    Ec(Curve),
+    Ed25519
+}
+
+impl KeyType {
+    /// Names of the public and the private parameters, in the order they are serialized.
+    fn params(self) -> (&'static [&'static str], &'static [&'static str]) {
+        match self {
+            KeyType::Symmetric => (&[], &["k"]),
+            KeyType::Rsa => (&["n", "e"], &["d", "p", "q", "dp", "dq", "qi"]),
+            KeyType::Ec(_) => (&["x", "y"], &["d"]),
+            KeyType::Ed25519 => (&["x"], &["d"])
+        }
+    }
+}
+
+/// JSON Web Key (JWK).
+///
+/// Symmetric keys (`oct`), RSA keys, elliptic-curve keys on the P-256 and P-384 curves,
+/// and Ed25519 keys (`OKP`) are supported. Private keys must include all private
+/// parameters, including the CRT parameters of RSA keys.
+///
+/// Members other than the key type, the key ID and the key parameters are not
+/// preserved. Keys are validated when they are parsed.
+#[derive(Clone)]
+pub struct Jwk {
+    key_type: KeyType,
+    key_id: Option<String>,
+    params: Vec<(&'static str, Vec<u8>)>
+}
+
+impl Jwk {
+    /// Parse a key in JSON format.
+    ///
+    /// Fails with `ErrorKind::Malformed` if the key cannot be parsed or is invalid,
+    /// and with `ErrorKind::Unsupported` if the key type or curve is not supported.
+    pub fn from_json(json: &str) -> Result<Jwk, Error> {
+        Jwk::from_value(&json::parse(json.as_bytes())?)
+    }
+
+    /// Parse a key set in JSON format, skipping keys whose type or curve is not supported.
+    pub fn from_json_set(json: &str) -> Result<Vec<Jwk>, Error> {
+        let set = json::parse(json.as_bytes())?;
+        let keys = set.get("keys").and_then(Value::as_array).ok_or(ErrorKind::Malformed)?;
+        let mut output = Vec::with_capacity(keys.len());
+        for key in keys {
+            match Jwk::from_value(key) {
+                Ok(key) => output.push(key),
+                Err(ref err) if err.kind() == ErrorKind::Unsupported => {},
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(output)
+    }
+
+    fn from_value(value: &Value) -> Result<Jwk, Error> {
+        let key_type = match value.get_str("kty")?.ok_or(ErrorKind::Malformed)? {
+            "oct" => KeyType::Symmetric,
+            "RSA" => KeyType::Rsa,
+            "EC" => KeyType::Ec(value.get_str("crv")?.and_then(Curve::from_name).ok_or(ErrorKind::Unsupported)?),
+            "OKP" if value.get_str("crv")? == Some("Ed25519") => KeyType::Ed25519,
+            _ => return Err(ErrorKind::Unsupported.into())
+        };
+        let key_id = value.get_str("kid")?.map(str::to_owned);
+        let (public, private) = key_type.params();
+        let mut params = Vec::with_capacity(public.len() + private.len());
+        for &name in public {
+            params.push((name, decode_param(value, name)?.ok_or(ErrorKind::Malformed)?));
+        }
+        for &name in private {
+            if let Some(param) = decode_param(value, name)? {
+                params.push((name, param));
+            }
+        }
+        let jwk = Jwk { key_type, key_id, params };
+        match jwk.params.len() - public.len() {
+            0 if key_type != KeyType::Symmetric => {},
+            len if len == private.len() => {},
+            _ if key_type == KeyType::Rsa && jwk.param("d").is_some() => return Err(ErrorKind::Unsupported.into()),
+            _ => return Err(ErrorKind::Malformed.into())
+        }
+        jwk.validate()?;
+        Ok(jwk)
+    }
+
+    /// Create a symmetric key.
+    pub fn symmetric(key: &[u8]) -> Jwk {
+        Jwk { key_type: KeyType::Symmetric, key_id: None, params: vec![("k", key.to_vec())] }
+    }
+
+    /// Generate a symmetric key of the given length in bytes.
+    pub fn generate_symmetric(generator: &Generator, len: usize) -> GenerateJwk {
+        GenerateJwk::new(generator, KeyType::Symmetric, len)
+    }
+
+    /// Generate an elliptic-curve private key on the given curve.
+    pub fn generate_ec(generator: &Generator, curve: Curve) -> GenerateJwk {
+        GenerateJwk::new(generator, KeyType::Ec(curve), curve.coordinate_len())
+    }
+
+    /// Generate an Ed25519 private key.
+    pub fn generate_ed25519(generator: &Generator) -> GenerateJwk {
+        GenerateJwk::new(generator, KeyType::Ed25519, ED25519_KEY_LEN)
+    }
+
+    /// Serialize the key in JSON format, including the private parameters.
+    pub fn to_json(&self) -> String {
+        let mut members = Vec::new();
+        let kty = match self.key_type {
+            KeyType::Symmetric => "oct",
+            KeyType::Rsa => "RSA",
+            KeyType::Ec(_) => "EC",
+            KeyType::Ed25519 => "OKP"
+        };
+        members.push(("kty".to_owned(), Value::String(kty.to_owned())));
+        match self.key_type {
+            KeyType::Ec(curve) => members.push(("crv".to_owned(), Value::String(curve.name().to_owned()))),
+            KeyType::Ed25519 => members.push(("crv".to_owned(), Value::String("Ed25519".to_owned()))),
+            _ => {}
+        }
+        if let Some(ref key_id) = self.key_id {
+            members.push(("kid".to_owned(), Value::String(key_id.clone())));
+        }
+        for &(name, ref param) in &self.params {
+            members.push((name.to_owned(), Value::String(base64::encode_url(param, false))));
+        }
+        Value::Object(members).to_json()
+    }
+
+    /// Get the public key of a private key, or a copy of a public key.
+    ///
+    /// Fails with `ErrorKind::Unsupported` for symmetric keys.
+    pub fn public_key(&self) -> Result<Jwk, Error> {
+        if self.key_type == KeyType::Symmetric {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let public = self.key_type.params().0.len();
+        Ok(Jwk { key_type: self.key_type, key_id: self.key_id.clone(), params: self.params[..public].to_vec() })
+    }
+
+    /// Get the key ID (`kid`).
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// Set the key ID (`kid`), which is included in the headers of tokens created using the key.
+    pub fn with_key_id(mut self, key_id: &str) -> Jwk {
+        self.key_id = Some(key_id.to_owned());
+        self
+    }
+
+    /// Check whether the key is a symmetric key or a private key.
+    pub fn is_private(&self) -> bool {
+        self.params.len() > self.key_type.params().0.len()
+    }
+
+    pub(crate) fn key_type(&self) -> KeyType {
+        self.key_type
+    }
+
+    pub(crate) fn param(&self, name: &str) -> Option<&[u8]> {
+        self.params.iter().find(|&&(param, _)| param == name).map(|(_, value)| &value[..])
+    }
+
+    /// Get the key of a symmetric key.
+    pub(crate) fn symmetric_key(&self) -> Option<&[u8]> {
+        if self.key_type == KeyType::Symmetric { self.param("k") } else { None }
+    }
+
+    /// Build the private key used for signing.
+    pub(crate) fn signing_key(&self) -> Result<SigningKey, Error> {
+        if !self.is_private() {
+            return Err(ErrorKind::UnknownKey.into());
+        }
+        match self.key_type {
+            KeyType::Symmetric => Err(ErrorKind::Unsupported.into()),
+            KeyType::Rsa => SigningKey::from_der(&PKey::from_rsa(self.rsa_private()?)
+                .and_then(|pkey| pkey.private_key_to_der()).map_err(Error::from)?),
+            KeyType::Ec(curve) => SigningKey::from_der(&PKey::from_ec_key(self.ec_private_key(curve)?)
+                .and_then(|pkey| pkey.private_key_to_der()).map_err(Error::from)?),
+            KeyType::Ed25519 => SigningKey::from_ed25519_seed(self.param("d").unwrap())
+        }
+    }
+
+    /// Build the public key used for verification.
+    pub(crate) fn verifying_key(&self) -> Result<VerifyingKey, Error> {
+        let der = match self.key_type {
+            KeyType::Symmetric => return Err(ErrorKind::Unsupported.into()),
+            KeyType::Rsa => PKey::from_rsa(self.rsa_public()?).and_then(|pkey| pkey.public_key_to_der()),
+            KeyType::Ec(curve) => PKey::from_ec_key(self.ec_public_key(curve)?)
+                .and_then(|pkey| pkey.public_key_to_der()),
+            KeyType::Ed25519 => return VerifyingKey::from_ed25519_bytes(self.param("x").unwrap())
+        };
+        VerifyingKey::from_der(&der.map_err(Error::from)?)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        match self.key_type {
+            KeyType::Symmetric => if self.param("k").unwrap().is_empty() {
+                return Err(ErrorKind::InvalidKeyLength.into());
+            },
+            KeyType::Rsa => if self.is_private() {
+                let valid = self.rsa_private()?.check_key().map_err(|err| Error::new(ErrorKind::Malformed, err))?;
+                if !valid {
+                    return Err(ErrorKind::Malformed.into());
+                }
+            } else {
+                self.rsa_public()?;
+            },
+            KeyType::Ec(curve) => {
+                let len = curve.coordinate_len();
+                if self.params.iter().any(|(_, param)| param.len() != len) {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                if self.is_private() {
+                    self.ec_private_key(curve)?;
+                } else {
+                    self.ec_public_key(curve)?;
+                }
+            },
+            KeyType::Ed25519 => {
+                if self.params.iter().any(|(_, param)| param.len() != ED25519_KEY_LEN) {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                if let Some(seed) = self.param("d") {
+                    if ed25519_public_key(seed)? != self.param("x").unwrap() {
+                        return Err(ErrorKind::Malformed.into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rsa_public(&self) -> Result<Rsa<Public>, Error> {
+        let param = |name| BigNum::from_slice(self.param(name).unwrap()).map_err(Error::from);
+        Rsa::from_public_components(param("n")?, param("e")?).map_err(|err| Error::new(ErrorKind::Malformed, err))
+    }
+
+    fn rsa_private(&self) -> Result<Rsa<Private>, Error> {
+        let param = |name| BigNum::from_slice(self.param(name).unwrap()).map_err(Error::from);
+        Rsa::from_private_components(param("n")?, param("e")?, param("d")?, param("p")?, param("q")?,
+                                     param("dp")?, param("dq")?, param("qi")?)
+            .map_err(|err| Error::new(ErrorKind::Malformed, err))
+    }
+
+    fn ec_public_key(&self, curve: Curve) -> Result<EcKey<Public>, Error> {
+        let group = curve.group()?;
+        let x = BigNum::from_slice(self.param("x").unwrap()).map_err(Error::from)?;
+        let y = BigNum::from_slice(self.param("y").unwrap()).map_err(Error::from)?;
+        let ec_key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)
+            .map_err(|err| Error::new(ErrorKind::Malformed, err))?;
+        ec_key.check_key().map_err(|err| Error::new(ErrorKind::Malformed, err))?;
+        Ok(ec_key)
+    }
+
+    fn ec_private_key(&self, curve: Curve) -> Result<EcKey<Private>, Error> {
+        let public = self.ec_public_key(curve)?;
+        let d = BigNum::from_slice(self.param("d").unwrap()).map_err(Error::from)?;
+        let ec_key = EcKey::from_private_components(public.group(), &d, public.public_key()).map_err(Error::from)?;
+        ec_key.check_key().map_err(|err| Error::new(ErrorKind::Malformed, err))?;
+        Ok(ec_key)
+    }
+}
+
+fn decode_param(value: &Value, name: &str) -> Result<Option<Vec<u8>>, Error> {
+    match value.get_str(name)? {
+        None => Ok(None),
+        Some(encoded) => base64::decode_url(encoded.as_bytes(), false).map(Some)
+            .ok_or_else(|| ErrorKind::Malformed.into())
+    }
+}
+
+/// Derive the public key of an Ed25519 private key.
+fn ed25519_public_key(seed: &[u8]) -> Result<Vec<u8>, Error> {
+    PKey::private_key_from_raw_bytes(seed, Id::ED25519)
+        .and_then(|pkey| pkey.raw_public_key())
+        .map_err(Error::from)
+}
+
+impl Drop for Jwk {
+    fn drop(&mut self) {
+        for &mut (_, ref mut param) in &mut self.params {
+            zeroize(param);
+        }
+    }
+}
+
+impl Debug for Jwk {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut debug = f.debug_struct("Jwk");
+        debug.field("key_type", &self.key_type).field("key_id", &self.key_id);
+        if self.is_private() {
+            debug.field("key", &Redacted);
+        }
+        debug.finish()
+    }
+}
+
+/// Future returning a newly generated key.
+///
+/// See [`Jwk::generate_symmetric`](struct.Jwk.html#method.generate_symmetric),
+/// [`Jwk::generate_ec`](struct.Jwk.html#method.generate_ec) and
+/// [`Jwk::generate_ed25519`](struct.Jwk.html#method.generate_ed25519).
+#[derive(Debug)]
+pub struct GenerateJwk {
+    key_type: KeyType,
+    len: usize,
+    generator: Generator,
+    random: RandomBytes
+}
+
+impl GenerateJwk {
+    fn new(generator: &Generator, key_type: KeyType, len: usize) -> GenerateJwk {
+        GenerateJwk { key_type, len, generator: generator.clone(), random: generator.random_bytes(len) }
+    }
+}
+
+impl Future for GenerateJwk {
+    type Item = Jwk;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let random = match self.random.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(random) => random
+            };
+            let key = match self.key_type {
+                KeyType::Symmetric => Ok(Some(Jwk::symmetric(&random))),
+                KeyType::Ec(curve) => ec_from_scalar(curve, &random),
+                KeyType::Ed25519 => ed25519_public_key(&random).map(|public| Some(Jwk {
+                    key_type: KeyType::Ed25519, key_id: None, params: vec![("x", public), ("d", random.to_vec())]
+                })),
+                KeyType::Rsa => unreachable!()
+            };
+            if let Ok(mut random) = random.try_mut() {
+                zeroize(&mut random);
+            }
+            match key? {
+                Some(key) => return Ok(Async::Ready(key)),
+                // The scalar was out of range, try again with fresh randomness.
+                None => self.random = self.generator.random_bytes(self.len)
+            }
+        }
+    }
+}
+
+/// Create an elliptic-curve private key from a random scalar, or return `None` if
+/// the scalar is not in the range `[1, n - 1]`.
+fn ec_from_scalar(curve: Curve, scalar: &[u8]) -> Result<Option<Jwk>, Error> {
+    let group = curve.group()?;
+    let mut ctx = BigNumContext::new().map_err(Error::from)?;
+    let mut order = BigNum::new().map_err(Error::from)?;
+    group.order(&mut order, &mut ctx).map_err(Error::from)?;
+    let d = BigNum::from_slice(scalar).map_err(Error::from)?;
+    if d.num_bits() == 0 || d.ucmp(&order) != Ordering::Less {
+        return Ok(None);
+    }
+    let mut point = EcPoint::new(&group).map_err(Error::from)?;
+    point.mul_generator2(&group, &d, &mut ctx).map_err(Error::from)?;
+    let (mut x, mut y) = (BigNum::new().map_err(Error::from)?, BigNum::new().map_err(Error::from)?);
+    point.affine_coordinates_gfp(&group, &mut x, &mut y, &mut ctx).map_err(Error::from)?;
+    let len = curve.coordinate_len();
+    let params = vec![("x", pad(&x.to_vec(), len)), ("y", pad(&y.to_vec(), len)), ("d", scalar.to_vec())];
+    Ok(Some(Jwk { key_type: KeyType::Ec(curve), key_id: None, params }))
+}
+
+/// Left-pad a big-endian integer with zeros.
+fn pad(bytes: &[u8], len: usize) -> Vec<u8> {
+    let mut output = vec![0u8; len - bytes.len()];
+    output.extend_from_slice(bytes);
+    output
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use futures::Future;
+
+    use random::Generator;
+    use super::{Curve, ErrorKind, Jwk, KeyType};
+
+    // Keys from RFC 6979, appendix A.2.5, and RFC 8037, appendix A.1.
+    pub const EC_P256: &str = r#"{"kty":"EC","crv":"P-256","x":"YP7UuiVanTHJYet0xjVtaMBJuJI7Yfps5mliLmDyn7Y",
+        "y":"eQP-EAi4vJmkGunpVii8ZPLxsgwtfp9Rd6PClNRGIpk","d":"ya-p2EW6dRZrXCFXZ7HWk05Qw9s26JsSe4piKxIPZyE"}"#;
+    pub const ED25519: &str = r#"{"kty":"OKP","crv":"Ed25519","d":"nWGxne_9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A",
+        "x":"11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"}"#;
+
+    #[test]
+    fn parse_and_serialize() {
+        let key = Jwk::from_json(ED25519).unwrap();
+        assert_eq!(key.key_type(), KeyType::Ed25519);
+        assert!(key.is_private());
+        assert_eq!(key.public_key().unwrap().to_json(),
+                   r#"{"kty":"OKP","crv":"Ed25519","x":"11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"}"#);
+        let key = Jwk::from_json(EC_P256).unwrap().with_key_id("ec");
+        assert_eq!(key.key_id(), Some("ec"));
+        assert_eq!(Jwk::from_json(&key.to_json()).unwrap().to_json(), key.to_json());
+        let key = Jwk::from_json(r#"{"kty":"oct","k":"AyM1SysPpbyDfgZld3umj1qzKObwVMkoqQ-EstJQLr_T-1qS0gZH75aKtMN3Yj0iPS4hcgUuTwjAzZr1Z9CAow","kid":"x"}"#).unwrap();
+        assert_eq!(key.symmetric_key().unwrap().len(), 64);
+        assert_eq!(key.public_key().unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        let cases = [
+            (r#"{"kty":"oct"}"#, ErrorKind::Malformed),
+            (r#"{"kty":"oct","k":""}"#, ErrorKind::InvalidKeyLength),
+            (r#"{"kty":"oct","k":"+/"}"#, ErrorKind::Malformed),
+            (r#"{"kty":"EC","crv":"P-521","x":"","y":""}"#, ErrorKind::Unsupported),
+            (r#"{"kty":"EC","crv":"P-256","x":"f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+                "y":"x_FFzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"}"#, ErrorKind::Malformed),
+            (r#"{"kty":"OKP","crv":"Ed25519","d":"nWGxne_9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A",
+                "x":"11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURs"}"#, ErrorKind::Malformed),
+            (r#"{"kty":"RSA","n":"AQAB","e":"AQAB","d":"AQAB"}"#, ErrorKind::Unsupported),
+            (r#"{"kty":"foo"}"#, ErrorKind::Unsupported),
+            (r#"[]"#, ErrorKind::Malformed)
+        ];
+        for &(json, kind) in &cases {
+            assert_eq!(Jwk::from_json(json).unwrap_err().kind(), kind, "{}", json);
+        }
+    }
+
+    #[test]
+    fn key_set() {
+        let json = format!(r#"{{"keys":[{},{{"kty":"EC","crv":"P-521"}},{}]}}"#, ED25519, EC_P256);
+        let keys = Jwk::from_json_set(&json).unwrap();
+        assert_eq!(keys.iter().map(Jwk::key_type).collect::<Vec<_>>(), [KeyType::Ed25519, KeyType::Ec(Curve::P256)]);
+    }
+
+    #[test]
+    fn generate() {
+        let generator = Generator::new(1);
+        let key = Jwk::generate_symmetric(&generator, 32).wait().unwrap();
+        assert_eq!(key.symmetric_key().unwrap().len(), 32);
+        for &curve in &[Curve::P256, Curve::P384] {
+            let key = Jwk::generate_ec(&generator, curve).wait().unwrap();
+            let parsed = Jwk::from_json(&key.to_json()).unwrap();
+            assert_eq!(parsed.key_type(), KeyType::Ec(curve));
+            assert!(parsed.is_private());
+        }
+        let key = Jwk::generate_ed25519(&generator).wait().unwrap();
+        assert!(Jwk::from_json(&key.to_json()).is_ok());
+    }
+}
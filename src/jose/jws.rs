@@ -0,0 +1,387 @@
+//! JSON Web Signature (JWS), as specified in [RFC 7515](https://tools.ietf.org/html/rfc7515).
+
+use super::super::{Error, ErrorKind};
+use super::super::base64;
+use super::super::hash::{self, Hmac};
+use super::super::sign;
+use super::{Jwk, candidate_keys, decode_header, merge_headers};
+use super::json::{self, Value};
+use super::jwk::{Curve, KeyType};
+
+/// Algorithm used to sign or authenticate the payload.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Algorithm {
+    /// HMAC using SHA-256
+    Hs256,
+    /// HMAC using SHA-384
+    Hs384,
+    /// HMAC using SHA-512
+    Hs512,
+    /// RSA with PKCS #1 v1.5 padding, using SHA-256
+    Rs256,
+    /// RSA with PKCS #1 v1.5 padding, using SHA-384
+    Rs384,
+    /// RSA with PKCS #1 v1.5 padding, using SHA-512
+    Rs512,
+    /// RSA with PSS padding, using SHA-256
+    Ps256,
+    /// RSA with PSS padding, using SHA-384
+    Ps384,
+    /// RSA with PSS padding, using SHA-512
+    Ps512,
+    /// ECDSA on the P-256 curve, using SHA-256
+    Es256,
+    /// ECDSA on the P-384 curve, using SHA-384
+    Es384,
+    /// Ed25519, as specified in [RFC 8037](https://tools.ietf.org/html/rfc8037)
+    EdDsa,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+const ALGORITHMS: [(Algorithm, &str); 12] = [
+    (Algorithm::Hs256, "HS256"), (Algorithm::Hs384, "HS384"), (Algorithm::Hs512, "HS512"),
+    (Algorithm::Rs256, "RS256"), (Algorithm::Rs384, "RS384"), (Algorithm::Rs512, "RS512"),
+    (Algorithm::Ps256, "PS256"), (Algorithm::Ps384, "PS384"), (Algorithm::Ps512, "PS512"),
+    (Algorithm::Es256, "ES256"), (Algorithm::Es384, "ES384"), (Algorithm::EdDsa, "EdDSA")
+];
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        ALGORITHMS.iter().find(|&&(algo, _)| algo == self).map(|&(_, name)| name).unwrap()
+    }
+
+    fn from_name(name: &str) -> Option<Algorithm> {
+        ALGORITHMS.iter().find(|&&(_, algo_name)| algo_name == name).map(|&(algo, _)| algo)
+    }
+
+    fn hmac(self) -> Option<hash::Algorithm> {
+        match self {
+            Algorithm::Hs256 => Some(hash::Algorithm::Sha256),
+            Algorithm::Hs384 => Some(hash::Algorithm::Sha384),
+            Algorithm::Hs512 => Some(hash::Algorithm::Sha512),
+            _ => None
+        }
+    }
+
+    fn signature(self) -> sign::Algorithm {
+        match self {
+            Algorithm::Rs256 => sign::Algorithm::RsaPkcs1v15Sha256,
+            Algorithm::Rs384 => sign::Algorithm::RsaPkcs1v15Sha384,
+            Algorithm::Rs512 => sign::Algorithm::RsaPkcs1v15Sha512,
+            Algorithm::Ps256 => sign::Algorithm::RsaPssSha256,
+            Algorithm::Ps384 => sign::Algorithm::RsaPssSha384,
+            Algorithm::Ps512 => sign::Algorithm::RsaPssSha512,
+            Algorithm::Es256 => sign::Algorithm::EcdsaP256Sha256,
+            Algorithm::Es384 => sign::Algorithm::EcdsaP384Sha384,
+            Algorithm::EdDsa => sign::Algorithm::Ed25519,
+            _ => unreachable!()
+        }
+    }
+
+    fn supports_key(self, key: &Jwk) -> bool {
+        matches!((self, key.key_type()),
+                 (Algorithm::Hs256, KeyType::Symmetric) | (Algorithm::Hs384, KeyType::Symmetric) |
+                 (Algorithm::Hs512, KeyType::Symmetric) |
+                 (Algorithm::Rs256, KeyType::Rsa) | (Algorithm::Rs384, KeyType::Rsa) |
+                 (Algorithm::Rs512, KeyType::Rsa) | (Algorithm::Ps256, KeyType::Rsa) |
+                 (Algorithm::Ps384, KeyType::Rsa) | (Algorithm::Ps512, KeyType::Rsa) |
+                 (Algorithm::Es256, KeyType::Ec(Curve::P256)) | (Algorithm::Es384, KeyType::Ec(Curve::P384)) |
+                 (Algorithm::EdDsa, KeyType::Ed25519))
+    }
+}
+
+/// Sign a payload, returning the JWS in compact serialization.
+///
+/// The protected header contains the algorithm, and the key ID if the key has one.
+///
+/// Fails with `ErrorKind::Unsupported` if the key is not suitable for the algorithm,
+/// and with `ErrorKind::InvalidKeyLength` if an HMAC key is shorter than the digest.
+pub fn sign_compact(algorithm: Algorithm, key: &Jwk, payload: &[u8]) -> Result<String, Error> {
+    let signing_input = format!("{}.{}", protected_header(algorithm, key), base64::encode_url(payload, false));
+    let signature = sign(algorithm, key, signing_input.as_bytes())?;
+    Ok(format!("{}.{}", signing_input, base64::encode_url(&signature, false)))
+}
+
+/// Verify a JWS in compact serialization, returning the payload.
+///
+/// The key is selected using the key ID in the header if there is one, and must
+/// be suitable for the algorithm in the header.
+///
+/// Fails with `ErrorKind::UnknownKey` if there is no such key, with `ErrorKind::BadSignature`
+/// if the signature is invalid, and with `ErrorKind::Unsupported` if the header contains
+/// critical extensions.
+pub fn verify_compact(jws: &str, keys: &[Jwk]) -> Result<Vec<u8>, Error> {
+    let parts = jws.split('.').collect::<Vec<_>>();
+    if parts.len() != 3 {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let payload = base64::decode_url(parts[1].as_bytes(), false).ok_or(ErrorKind::Malformed)?;
+    let signature = base64::decode_url(parts[2].as_bytes(), false).ok_or(ErrorKind::Malformed)?;
+    let header = decode_header(parts[0])?;
+    let signing_input = &jws[..parts[0].len() + 1 + parts[1].len()];
+    verify_header(&header, signing_input.as_bytes(), &signature, keys)?;
+    Ok(payload)
+}
+
+/// Sign a payload with one or more keys, returning the JWS in general JSON serialization.
+///
+/// See [`sign_compact`](fn.sign_compact.html) for more information.
+pub fn sign_json(payload: &[u8], signers: &[(Algorithm, &Jwk)]) -> Result<String, Error> {
+    let payload = base64::encode_url(payload, false);
+    let mut signatures = Vec::with_capacity(signers.len());
+    for &(algorithm, key) in signers {
+        let protected = protected_header(algorithm, key);
+        let signature = sign(algorithm, key, format!("{}.{}", protected, payload).as_bytes())?;
+        signatures.push(Value::Object(vec![
+            ("protected".to_owned(), Value::String(protected)),
+            ("signature".to_owned(), Value::String(base64::encode_url(&signature, false)))
+        ]));
+    }
+    Ok(Value::Object(vec![
+        ("payload".to_owned(), Value::String(payload)),
+        ("signatures".to_owned(), Value::Array(signatures))
+    ]).to_json())
+}
+
+/// Verify a JWS in general or flattened JSON serialization, returning the payload.
+///
+/// The JWS is valid if any of its signatures can be verified using one of the keys.
+/// See [`verify_compact`](fn.verify_compact.html) for more information.
+pub fn verify_json(jws: &str, keys: &[Jwk]) -> Result<Vec<u8>, Error> {
+    let jws = json::parse(jws.as_bytes())?;
+    let encoded_payload = jws.get_str("payload")?.ok_or(ErrorKind::Malformed)?;
+    let payload = base64::decode_url(encoded_payload.as_bytes(), false).ok_or(ErrorKind::Malformed)?;
+    let flattened = [jws.clone()];
+    let signatures = match jws.get("signatures") {
+        None => &flattened[..],
+        Some(signatures) => signatures.as_array().ok_or(ErrorKind::Malformed)?
+    };
+    let mut result = Err(ErrorKind::UnknownKey.into());
+    for signature in signatures {
+        let protected = signature.get_str("protected")?;
+        let header = match protected {
+            None => Value::Object(vec![]),
+            Some(protected) => decode_header(protected)?
+        };
+        let header = match signature.get("header") {
+            None => header,
+            Some(unprotected) => merge_headers(&[&header, unprotected])?
+        };
+        let signing_input = format!("{}.{}", protected.unwrap_or(""), encoded_payload);
+        let value = base64::decode_url(signature.get_str("signature")?.ok_or(ErrorKind::Malformed)?.as_bytes(), false)
+            .ok_or(ErrorKind::Malformed)?;
+        match verify_header(&header, signing_input.as_bytes(), &value, keys) {
+            Ok(()) => return Ok(payload),
+            Err(ref err) if err.kind() == ErrorKind::UnknownKey => {},
+            Err(err) => result = Err(err)
+        }
+    }
+    result
+}
+
+/// Encode the protected header for signing with the given key.
+fn protected_header(algorithm: Algorithm, key: &Jwk) -> String {
+    let mut members = vec![("alg".to_owned(), Value::String(algorithm.name().to_owned()))];
+    if let Some(key_id) = key.key_id() {
+        members.push(("kid".to_owned(), Value::String(key_id.to_owned())));
+    }
+    base64::encode_url(Value::Object(members).to_json().as_bytes(), false)
+}
+
+fn sign(algorithm: Algorithm, key: &Jwk, input: &[u8]) -> Result<Vec<u8>, Error> {
+    if !algorithm.supports_key(key) {
+        return Err(ErrorKind::Unsupported.into());
+    }
+    if let Some(digest) = algorithm.hmac() {
+        let mut hmac = hmac(digest, key)?;
+        hmac.update(input)?;
+        return Ok(hmac.finish()?.as_ref().to_vec());
+    }
+    let signature = key.signing_key()?.sign_message(algorithm.signature(), input)?;
+    match key.key_type() {
+        KeyType::Ec(curve) => der_to_raw(signature.as_ref(), curve.coordinate_len()),
+        _ => Ok(signature.as_ref().to_vec())
+    }
+}
+
+fn verify_header(header: &Value, input: &[u8], signature: &[u8], keys: &[Jwk]) -> Result<(), Error> {
+    if header.get("crit").is_some() {
+        return Err(ErrorKind::Unsupported.into());
+    }
+    let algorithm = header.get_str("alg")?.ok_or(ErrorKind::Malformed)?;
+    let algorithm = Algorithm::from_name(algorithm).ok_or(ErrorKind::Unsupported)?;
+    let mut result = Err(ErrorKind::UnknownKey.into());
+    for key in candidate_keys(keys, header.get_str("kid")?) {
+        if algorithm.supports_key(key) {
+            result = verify(algorithm, key, input, signature);
+            if result.is_ok() {
+                break;
+            }
+        }
+    }
+    result
+}
+
+fn verify(algorithm: Algorithm, key: &Jwk, input: &[u8], signature: &[u8]) -> Result<(), Error> {
+    if let Some(digest) = algorithm.hmac() {
+        let mut hmac = hmac(digest, key)?;
+        hmac.update(input)?;
+        return if hmac.finish()?.verify(signature) { Ok(()) } else { Err(ErrorKind::BadSignature.into()) };
+    }
+    let signature = match key.key_type() {
+        KeyType::Ec(curve) => raw_to_der(signature, curve.coordinate_len())?,
+        _ => signature.to_vec()
+    };
+    key.verifying_key()?.verify_message(algorithm.signature(), input, &signature)
+}
+
+/// Create an HMAC instance, requiring the key to be at least as long as the digest.
+fn hmac(digest: hash::Algorithm, key: &Jwk) -> Result<Hmac, Error> {
+    let key = key.symmetric_key().ok_or(ErrorKind::Unsupported)?;
+    if key.len() < digest.digest_len() {
+        return Err(ErrorKind::InvalidKeyLength.into());
+    }
+    Hmac::new(digest, key)
+}
+
+/// Convert a DER-encoded ECDSA signature into the concatenation of `r` and `s`.
+fn der_to_raw(signature: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+    let mut output = vec![0u8; 2 * len];
+    let content = match signature {
+        [0x30, content_len, ref content @ ..] if *content_len as usize == content.len() => content,
+        _ => return Err(ErrorKind::Malformed.into())
+    };
+    let mut rest = content;
+    for i in 0..2 {
+        let (integer, tail) = match rest {
+            [0x02, int_len, ref tail @ ..] if (*int_len as usize) <= tail.len() => tail.split_at(*int_len as usize),
+            _ => return Err(ErrorKind::Malformed.into())
+        };
+        let integer = &integer[integer.iter().take_while(|byte| **byte == 0).count()..];
+        if integer.len() > len {
+            return Err(ErrorKind::Malformed.into());
+        }
+        output[(i + 1) * len - integer.len()..(i + 1) * len].copy_from_slice(integer);
+        rest = tail;
+    }
+    Ok(output)
+}
+
+/// Convert the concatenation of `r` and `s` into a DER-encoded ECDSA signature.
+fn raw_to_der(signature: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+    if signature.len() != 2 * len {
+        return Err(ErrorKind::BadSignature.into());
+    }
+    let mut content = Vec::with_capacity(2 * len + 6);
+    for integer in signature.chunks(len) {
+        let integer = &integer[integer.iter().take_while(|byte| **byte == 0).count()..];
+        let pad = integer.first().map(|byte| *byte & 0x80 != 0).unwrap_or(true);
+        content.push(0x02);
+        content.push((integer.len() + pad as usize) as u8);
+        if pad {
+            content.push(0);
+        }
+        content.extend_from_slice(integer);
+    }
+    let mut output = vec![0x30, content.len() as u8];
+    output.extend_from_slice(&content);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use std::slice;
+
+    use super::{Algorithm, ErrorKind, Jwk, der_to_raw, raw_to_der, sign_compact, sign_json, verify_compact, verify_json};
+    use super::super::jwk::test::{EC_P256, ED25519};
+
+    const HMAC_KEY: &str = r#"{"kty":"oct","k":"AyM1SysPpbyDfgZld3umj1qzKObwVMkoqQ-EstJQLr_T-1qS0gZH75aKtMN3Yj0iPS4hcgUuTwjAzZr1Z9CAow"}"#;
+
+    #[test]
+    fn rfc7515_hs256() {
+        // RFC 7515, appendix A.1
+        let jws = "eyJ0eXAiOiJKV1QiLA0KICJhbGciOiJIUzI1NiJ9.\
+                   eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ.\
+                   dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let payload = verify_compact(jws, &[Jwk::from_json(HMAC_KEY).unwrap()]).unwrap();
+        assert!(payload.starts_with(b"{\"iss\":\"joe\",\r\n"));
+        let tampered = jws.replace("dBjft", "dBjfu");
+        assert_eq!(verify_compact(&tampered, &[Jwk::from_json(HMAC_KEY).unwrap()]).unwrap_err().kind(), ErrorKind::BadSignature);
+    }
+
+    #[test]
+    fn rfc7515_es256() {
+        // RFC 7515, appendix A.3
+        let jws = "eyJhbGciOiJFUzI1NiJ9.\
+                   eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ.\
+                   DtEhU3ljbEg8L38VWAfUAqOyKAM6-Xx-F4GawxaepmXFCgfTjDxw5djxLa8ISlSApmWQxfKTUJqPP3-Kg6NU1Q";
+        let key = Jwk::from_json(r#"{"kty":"EC","crv":"P-256","x":"f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+                                     "y":"x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"}"#).unwrap();
+        assert!(verify_compact(jws, &[key]).unwrap().starts_with(b"{\"iss\":\"joe\""));
+    }
+
+    #[test]
+    fn rfc8037_ed25519() {
+        // RFC 8037, appendix A.4
+        let key = Jwk::from_json(ED25519).unwrap();
+        let jws = sign_compact(Algorithm::EdDsa, &key, b"Example of Ed25519 signing").unwrap();
+        assert_eq!(jws, "eyJhbGciOiJFZERTQSJ9.RXhhbXBsZSBvZiBFZDI1NTE5IHNpZ25pbmc.\
+                         hgyY0il_MGCjP0JzlnLWG1PPOt7-09PGcvMg3AIbQR6dWbhijcNR4ki4iylGjg5BhVsPt9g7sVvpAr_MuM0KAg");
+        assert_eq!(verify_compact(&jws, &[key.public_key().unwrap()]).unwrap(), b"Example of Ed25519 signing");
+    }
+
+    #[test]
+    fn roundtrip() {
+        let hmac = Jwk::from_json(HMAC_KEY).unwrap().with_key_id("hmac");
+        let ec = Jwk::from_json(EC_P256).unwrap().with_key_id("ec");
+        let ed25519 = Jwk::from_json(ED25519).unwrap();
+        let signers = [(Algorithm::Hs256, &hmac), (Algorithm::Hs512, &hmac), (Algorithm::Es256, &ec), (Algorithm::EdDsa, &ed25519)];
+        for &(algorithm, key) in &signers {
+            let jws = sign_compact(algorithm, key, b"payload").unwrap();
+            assert_eq!(verify_compact(&jws, &[ed25519.clone(), ec.clone(), hmac.clone()]).unwrap(), b"payload");
+        }
+        let jws = sign_json(b"payload", &signers).unwrap();
+        assert_eq!(verify_json(&jws, &[ec.public_key().unwrap()]).unwrap(), b"payload");
+        assert_eq!(verify_json(&jws, slice::from_ref(&hmac)).unwrap(), b"payload");
+        assert_eq!(verify_json(&jws, &[Jwk::symmetric(&[0; 64]).with_key_id("hmac")]).unwrap_err().kind(),
+                   ErrorKind::BadSignature);
+        assert_eq!(verify_json(&jws, &[Jwk::symmetric(&[0; 64]).with_key_id("other")]).unwrap_err().kind(),
+                   ErrorKind::UnknownKey);
+
+        let flattened = r#"{"payload":"cGF5bG9hZA","protected":"eyJhbGciOiJIUzI1NiJ9","header":{"kid":"x"},"signature":"AA"}"#;
+        assert_eq!(verify_json(flattened, &[hmac]).unwrap_err().kind(), ErrorKind::UnknownKey);
+    }
+
+    #[test]
+    fn rejects_unsuitable_keys_and_headers() {
+        let hmac = Jwk::from_json(HMAC_KEY).unwrap();
+        let ec = Jwk::from_json(EC_P256).unwrap();
+        assert_eq!(sign_compact(Algorithm::Es384, &ec, b"").unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(sign_compact(Algorithm::Hs256, &ec, b"").unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(sign_compact(Algorithm::Hs256, &Jwk::symmetric(&[0; 16]), b"").unwrap_err().kind(),
+                   ErrorKind::InvalidKeyLength);
+        assert_eq!(sign_compact(Algorithm::Es256, &ec.public_key().unwrap(), b"").unwrap_err().kind(),
+                   ErrorKind::UnknownKey);
+
+        // The algorithm in the header must match the key type.
+        let jws = sign_compact(Algorithm::Es256, &ec, b"").unwrap();
+        assert_eq!(verify_compact(&jws, slice::from_ref(&hmac)).unwrap_err().kind(), ErrorKind::UnknownKey);
+        // {"alg":"none"} and {"alg":"HS256","crit":["exp"],"exp":1}
+        assert_eq!(verify_compact("eyJhbGciOiJub25lIn0..", slice::from_ref(&hmac)).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(verify_compact("eyJhbGciOiJIUzI1NiIsImNyaXQiOlsiZXhwIl0sImV4cCI6MX0..", slice::from_ref(&hmac))
+                   .unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(verify_compact("e30.", &[hmac]).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn ecdsa_signature_encoding() {
+        let raw = [vec![0u8; 31], vec![0x80], vec![0x01; 32]].concat();
+        let der = raw_to_der(&raw, 32).unwrap();
+        assert_eq!(&der[..5], [0x30, 0x26, 0x02, 0x02, 0x00]);
+        assert_eq!(der_to_raw(&der, 32).unwrap(), raw);
+        assert_eq!(raw_to_der(&raw[1..], 32).unwrap_err().kind(), ErrorKind::BadSignature);
+    }
+}
@@ -0,0 +1,51 @@
+//! JSON Object Signing and Encryption (JOSE).
+//!
+//! This module implements JSON Web Signature ([`jws`](jws/index.html)) and JSON Web
+//! Encryption ([`jwe`](jwe/index.html)) in both the compact and the JSON serializations,
+//! using keys in the JSON Web Key ([`Jwk`](struct.Jwk.html)) format.
+//!
+//! When verifying or decrypting, the key is selected from a list of candidates using the
+//! key ID in the header if there is one, so keys should be given IDs when more than one
+//! key may be used.
+
+use super::{Error, ErrorKind};
+use super::base64;
+
+mod json;
+mod jwk;
+pub mod jwe;
+pub mod jws;
+
+pub use self::jwk::{Curve, GenerateJwk, Jwk};
+
+use self::json::Value;
+
+/// Select the keys which match the key ID of a header, or all keys if there is none.
+fn candidate_keys<'a>(keys: &'a [Jwk], key_id: Option<&str>) -> Vec<&'a Jwk> {
+    keys.iter().filter(|key| key_id.is_none() || key.key_id() == key_id).collect()
+}
+
+/// Decode a protected header, which must be a JSON object.
+fn decode_header(encoded: &str) -> Result<Value, Error> {
+    let header = base64::decode_url(encoded.as_bytes(), false).ok_or(ErrorKind::Malformed)?;
+    let header = json::parse(&header)?;
+    match header {
+        Value::Object(_) => Ok(header),
+        _ => Err(ErrorKind::Malformed.into())
+    }
+}
+
+/// Merge the members of several headers, failing with `ErrorKind::Malformed` if any
+/// member appears more than once or if a header is not an object.
+fn merge_headers(headers: &[&Value]) -> Result<Value, Error> {
+    let mut merged: Vec<(String, Value)> = Vec::new();
+    for header in headers {
+        for (name, value) in header.as_object().ok_or(ErrorKind::Malformed)? {
+            if merged.iter().any(|(key, _)| key == name) {
+                return Err(ErrorKind::Malformed.into());
+            }
+            merged.push((name.clone(), value.clone()));
+        }
+    }
+    Ok(Value::Object(merged))
+}
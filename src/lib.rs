@@ -9,18 +9,39 @@
 #![deny(warnings, missing_docs, missing_debug_implementations)]
 
 extern crate bytes;
+extern crate flate2;
+extern crate foreign_types;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate hex;
+extern crate libc;
 extern crate openssl;
+extern crate openssl_sys;
+extern crate zstd;
 
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
 
+mod base64;
 mod error;
-pub use self::error::Error;
+pub use self::error::{Error, ErrorKind};
 
+pub mod age;
+pub mod agreement;
+pub mod armor;
 pub mod cipher;
+pub mod cms;
+pub mod compress;
+pub mod encoding;
+pub mod envelope;
+pub mod fernet;
 pub mod random;
+pub mod recipient;
 pub mod hash;
+pub mod hybrid;
+pub mod jose;
+pub mod mac;
+pub mod openpgp;
+pub mod secret;
+pub mod sign;
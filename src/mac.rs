@@ -0,0 +1,368 @@
+//! Message authentication codes for authenticating streams.
+//!
+//! Provides [CMAC](https://tools.ietf.org/html/rfc4493) based on AES, and the
+//! [Poly1305](https://tools.ietf.org/html/rfc8439) one-time authenticator.
+//! For HMAC, see [`Hash::hmac`](../hash/struct.Hash.html#method.hmac).
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::oneshot;
+use hex::ToHex;
+use openssl;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+use openssl::symm::Cipher;
+
+use super::{Error, ErrorKind};
+
+/// Stream adapter that computes a message authentication code over the data while forwarding it.
+#[derive(Debug)]
+pub struct Mac<S> {
+    inner: MacInner<S>
+}
+
+impl<S: Stream> Mac<S> {
+    /// Given an algorithm and a key, create a new stream adapter.
+    ///
+    /// Fails with `ErrorKind::InvalidKeyLength` if the key does not
+    /// have the length required by the algorithm.
+    pub fn new(algo: Algorithm, key: &[u8], inner: S) -> Result<Self, Error> {
+        Ok(Mac { inner: MacInner::new(algo, key, inner)? })
+    }
+
+    /// Compute the authentication code and reset the internal state.
+    ///
+    /// Since Poly1305 keys must only be used for a single message, the
+    /// authentication code can only be computed once when using Poly1305.
+    /// Further calls fail with `ErrorKind::Unsupported`.
+    pub fn digest(&mut self) -> Result<Digest, Error> {
+        self.inner.digest()
+    }
+
+    /// Split the stream adapter into two halves, one to receive the computed
+    /// authentication code, and one to compute it over the stream.
+    ///
+    /// The receiving half (`SplitDigest`) is a future that resolves with the
+    /// authentication code as soon as the stream has been fully processed by the computing half.
+    ///
+    /// The computing half (`SplitMac`), similar to `Mac` itself, is a stream adapter
+    /// that computes the authentication code over the data of its underlying stream.
+    pub fn split(self) -> (SplitDigest, SplitMac<S>) {
+        let (tx, rx) = oneshot::channel();
+        let receive = SplitDigest { receiver: rx };
+        let compute = SplitMac { inner: self.inner, sender: Some(tx) };
+        (receive, compute)
+    }
+
+    /// Extract the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+impl<S: Stream> Stream for Mac<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        self.inner.poll()
+    }
+}
+
+/// The receiving half of a split authentication process.
+///
+/// This is a future that resolves with the authentication code as soon as
+/// the stream has been fully consumed.
+/// It resolves with `None` when the computing half is dropped prematurely.
+///
+/// See [`Mac::split`](struct.Mac.html#method.split) for more information.
+#[derive(Debug)]
+pub struct SplitDigest {
+    receiver: oneshot::Receiver<Result<Digest, Error>>
+}
+
+impl Future for SplitDigest {
+    type Item = Option<Digest>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.receiver.poll() {
+            Err(_) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result.map(|digest| Async::Ready(Some(digest)))
+        }
+    }
+}
+
+/// The computing half of a split authentication process.
+///
+/// See [`Mac::split`](struct.Mac.html#method.split) for more information.
+#[derive(Debug)]
+pub struct SplitMac<S> {
+    inner: MacInner<S>,
+    sender: Option<oneshot::Sender<Result<Digest, Error>>>
+}
+
+impl<S: Stream> SplitMac<S> {
+    /// Extract the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+impl<S: Stream> Stream for SplitMac<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.inner.poll() {
+            Err(err) => Err(err),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(Some(item))) => Ok(Async::Ready(Some(item))),
+            Ok(Async::Ready(None)) => {
+                if let Some(sender) = self.sender.take() {
+                    sender.send(self.inner.digest()).ok();
+                }
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+struct MacInner<S> {
+    inner: S,
+    context: Context,
+    algorithm: Algorithm
+}
+
+impl<S: Debug> Debug for MacInner<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("MacInner")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<S: Stream> MacInner<S> {
+    fn new(algorithm: Algorithm, key: &[u8], inner: S) -> Result<Self, Error> {
+        if key.len() != algorithm.key_len() {
+            return Err(ErrorKind::InvalidKeyLength.into());
+        }
+        let context = Context::new(algorithm, key)?;
+        Ok(MacInner { inner, context, algorithm })
+    }
+
+    fn digest(&mut self) -> Result<Digest, Error> {
+        let mut bytes = [0u8; DIGEST_LEN];
+        self.context.finish(&mut bytes)?;
+        Ok(Digest { bytes, algorithm: self.algorithm })
+    }
+
+    fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Stream> Stream for MacInner<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.inner.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::Ready(Some(item)) => {
+                self.context.update(item.as_ref())?;
+                Ok(Async::Ready(Some(item)))
+            }
+        }
+    }
+}
+
+/// Backend state of the computation, owned exclusively by the adapter.
+struct Context {
+    pkey: PKey<Private>,
+    // Poly1305 keys must only be used once, so there is no signer once it has finished.
+    signer: Option<Signer<'static>>,
+    algorithm: Algorithm
+}
+
+impl Context {
+    fn new(algorithm: Algorithm, key: &[u8]) -> Result<Context, Error> {
+        let pkey = match algorithm {
+            Algorithm::Aes128Cmac => PKey::cmac(&Cipher::aes_128_cbc(), key),
+            Algorithm::Aes256Cmac => PKey::cmac(&Cipher::aes_256_cbc(), key),
+            Algorithm::Poly1305 => PKey::private_key_from_raw_bytes(key, Id::POLY1305),
+            Algorithm::_Donotmatch => unreachable!()
+        }.map_err(Error::from)?;
+        let signer = Signer::new_without_digest(&pkey).map_err(Error::from)?;
+        Ok(Context { pkey, signer: Some(signer), algorithm })
+    }
+
+    fn update(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.signer {
+            Some(ref mut signer) => signer.update(data).map_err(Error::from),
+            None => Err(ErrorKind::Unsupported.into())
+        }
+    }
+
+    fn finish(&mut self, out: &mut [u8; DIGEST_LEN]) -> Result<(), Error> {
+        let signer = match self.signer.take() {
+            Some(signer) => signer,
+            None => return Err(ErrorKind::Unsupported.into())
+        };
+        signer.sign(out).map_err(Error::from)?;
+        if self.algorithm != Algorithm::Poly1305 {
+            // Restart the computation, keeping the key.
+            self.signer = Some(Signer::new_without_digest(&self.pkey).map_err(Error::from)?);
+        }
+        Ok(())
+    }
+}
+
+const DIGEST_LEN: usize = 16;
+
+/// Stack-allocated binary message authentication code.
+#[derive(Debug)]
+pub struct Digest {
+    bytes: [u8; DIGEST_LEN],
+    algorithm: Algorithm
+}
+
+impl Digest {
+    /// Get the algorithm that was used to compute the authentication code.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Convert the authentication code into a hex-encoded string.
+    pub fn to_hex_string(&self) -> String {
+        self.bytes.to_hex()
+    }
+
+    /// Compare the authentication code to an expected value in constant time.
+    pub fn verify(&self, expected: &[u8]) -> bool {
+        expected.len() == DIGEST_LEN && openssl::memcmp::eq(&self.bytes, expected)
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Algorithm that can be used to authenticate data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
+pub enum Algorithm {
+    /// CMAC using AES with 128-bit keys
+    Aes128Cmac,
+    /// CMAC using AES with 256-bit keys
+    Aes256Cmac,
+    /// Poly1305 with a 256-bit one-time key
+    Poly1305,
+
+    #[doc(hidden)]
+    _Donotmatch
+}
+
+impl Algorithm {
+    /// Get the required key length for the algorithm.
+    pub fn key_len(self) -> usize {
+        match self {
+            Algorithm::Aes128Cmac => 16,
+            Algorithm::Aes256Cmac | Algorithm::Poly1305 => 32,
+            Algorithm::_Donotmatch => unreachable!()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+    use hex::FromHex;
+
+    use super::{Algorithm, Error, ErrorKind, Mac};
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        Vec::<u8>::from_hex(hex).unwrap()
+    }
+
+    #[test]
+    fn aes128_cmac() {
+        // Test vectors from RFC 4493, section 4.
+        let key = from_hex("2b7e151628aed2a6abf7158809cf4f3c");
+        let message = from_hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+                                30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710");
+        let vectors = [
+            (0, "bb1d6929e95937287fa37d129b756746"),
+            (16, "070a16b46b4d4144f79bdd9dd04a287c"),
+            (40, "dfa66747de9ae63030ca32611497c827"),
+            (64, "51f0bebf7e3b9d92fc49741779363cfe"),
+        ];
+        let mut mac = Mac::new(Algorithm::Aes128Cmac, &key, iter_ok::<Vec<u8>, Error>(vec![])).unwrap();
+        for &(len, expected) in vectors.iter() {
+            let input = iter_ok::<_, Error>(message[..len].chunks(7).map(|chunk| chunk.to_vec()).collect::<Vec<_>>());
+            let mut mac = Mac::new(Algorithm::Aes128Cmac, &key, input).unwrap();
+            mac.by_ref().wait().collect::<Result<Vec<_>, _>>().unwrap();
+            let digest = mac.digest().unwrap();
+            assert_eq!(digest.to_hex_string(), expected);
+            assert!(digest.verify(&from_hex(expected)));
+        }
+        // The state is reset after computing the authentication code.
+        assert_eq!(mac.digest().unwrap().to_hex_string(), "bb1d6929e95937287fa37d129b756746");
+        assert_eq!(mac.digest().unwrap().to_hex_string(), "bb1d6929e95937287fa37d129b756746");
+    }
+
+    #[test]
+    fn poly1305() {
+        // Test vector from RFC 8439, section 2.5.2.
+        let key = from_hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        let input = iter_ok::<_, Error>(vec!["Cryptographic Forum ", "Research Group"]);
+        let mut mac = Mac::new(Algorithm::Poly1305, &key, input).unwrap();
+        let output = mac.by_ref().wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(output, vec!["Cryptographic Forum ", "Research Group"]);
+        let digest = mac.digest().unwrap();
+        assert_eq!(digest.algorithm(), Algorithm::Poly1305);
+        assert_eq!(digest.to_hex_string(), "a8061dc1305136c6c22b8baf0c0127a9");
+        assert_eq!(mac.digest().unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn split_poly1305() {
+        let key = from_hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        let input = iter_ok::<_, Error>(vec!["Cryptographic Forum ", "Research Group"]);
+        let (split_digest, split_mac) = Mac::new(Algorithm::Poly1305, &key, input).unwrap().split();
+        let output = split_mac.wait().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(output, vec!["Cryptographic Forum ", "Research Group"]);
+        let digest = split_digest.wait().unwrap().unwrap();
+        assert_eq!(digest.to_hex_string(), "a8061dc1305136c6c22b8baf0c0127a9");
+    }
+
+    #[test]
+    fn split_drop() {
+        let input = iter_ok::<_, Error>(vec!["foo", "bar"]);
+        let (split_digest, split_mac) = Mac::new(Algorithm::Aes256Cmac, &[0u8; 32], input).unwrap().split();
+        drop(split_mac);
+        assert!(split_digest.wait().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_key_length() {
+        let input = iter_ok::<Vec<u8>, Error>(vec![]);
+        let err = Mac::new(Algorithm::Aes128Cmac, &[0u8; 32], input).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidKeyLength);
+    }
+}
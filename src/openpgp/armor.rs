@@ -0,0 +1,152 @@
+//! ASCII armor, as specified in [RFC 4880, section 6](https://tools.ietf.org/html/rfc4880#section-6).
+//!
+//! The adapters are the generic [`armor`](../../armor/index.html) adapters with the
+//! `PGP MESSAGE` label and the CRC-24 checksum.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use bytes::Bytes;
+use futures::{Future, Poll, Stream};
+use futures::stream::iter_ok;
+
+use super::super::Error;
+use super::super::armor::{self, Checksum};
+
+const MESSAGE_LABEL: &str = "PGP MESSAGE";
+
+/// Stream adapter that encodes the data of its underlying stream as an armored `PGP MESSAGE`.
+///
+/// Lines are 64 characters long, and are followed by the CRC-24 checksum of the data.
+pub struct Armor<S>(armor::Armor<S>);
+
+impl<S: Debug> Debug for Armor<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple("Armor")
+            .field(&self.0)
+            .finish()
+    }
+}
+
+impl<S: Stream> Armor<S> {
+    /// Create an armoring stream adapter.
+    pub fn new(inner: S) -> Result<Self, Error> {
+        Ok(Armor(armor::Armor::new(MESSAGE_LABEL, Checksum::Crc24, inner)?))
+    }
+}
+
+impl<S: Stream> Stream for Armor<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        self.0.poll()
+    }
+}
+
+/// Stream adapter that decodes an armored `PGP MESSAGE` from its underlying stream.
+///
+/// Any text before the armor, as well as the armor headers, are ignored. The armor
+/// must carry a checksum, and the stream fails with `ErrorKind::DigestMismatch` at
+/// its end if it does not match.
+pub struct Dearmor<S>(armor::Dearmor<S>);
+
+impl<S: Debug> Debug for Dearmor<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple("Dearmor")
+            .field(&self.0)
+            .finish()
+    }
+}
+
+impl<S: Stream> Dearmor<S> {
+    /// Create a dearmoring stream adapter.
+    pub fn new(inner: S) -> Result<Self, Error> {
+        Ok(Dearmor(armor::Dearmor::new(MESSAGE_LABEL, Checksum::Crc24, inner)?))
+    }
+}
+
+impl<S: Stream> Stream for Dearmor<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        self.0.poll()
+    }
+}
+
+/// Decode armored data with the given label, such as `PGP PUBLIC KEY BLOCK`.
+///
+/// Binary data, which starts with a packet header, is returned unchanged.
+pub fn decode(input: &[u8], label: &str) -> Result<Vec<u8>, Error> {
+    if input.first().map(|first| first & 0x80 != 0).unwrap_or(false) {
+        return Ok(input.to_vec());
+    }
+    let dearmor = armor::Dearmor::new(label, Checksum::Crc24, iter_ok::<_, Error>(Some(input)))?;
+    dearmor.concat2().wait().map(|data| data.to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+
+    use self::itertools::Itertools;
+
+    use ErrorKind;
+    use super::{Armor, Dearmor, Error, decode};
+
+    fn dearmor(armored: &[u8]) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = armored.chunks(7).map(|chunk| chunk.to_vec()).collect();
+        Dearmor::new(iter_ok::<_, Error>(chunks)).unwrap().concat2().wait().map(|data| data.to_vec())
+    }
+
+    #[test]
+    fn headers_and_checksum() {
+        let armored = "Some text before the armor\n\
+            -----BEGIN PGP MESSAGE-----\r\n\
+            Comment: a header\r\n\
+            \r\n\
+            aGVsbG8g\r\n\
+            d29ybGQ=\r\n\
+            =AAAA\r\n\
+            -----END PGP MESSAGE-----\r\n";
+        assert_eq!(dearmor(armored.as_bytes()).unwrap_err().kind(), ErrorKind::DigestMismatch);
+        let fixed = armored.replace("AAAA", "sDy3");
+        assert_eq!(dearmor(fixed.as_bytes()).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(dearmor(b"-----BEGIN PGP MESSAGE-----\n\naGVsbG8g\n").unwrap_err().kind(), ErrorKind::Malformed);
+        assert_eq!(dearmor(b"-----BEGIN PGP MESSAGE-----\n\naGV*\n-----END PGP MESSAGE-----\n").unwrap_err().kind(),
+                   ErrorKind::Malformed);
+        assert_eq!(dearmor(b"-----BEGIN PGP MESSAGE-----\n\naGVsbG8gd29ybGQ=\n-----END PGP MESSAGE-----\n")
+                       .unwrap_err().kind(),
+                   ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn binary_keys() {
+        assert_eq!(decode(&[0x99, 1, 2], "PGP PUBLIC KEY BLOCK").unwrap(), [0x99, 1, 2]);
+        let armored = b"-----BEGIN PGP PUBLIC KEY BLOCK-----\n\naGVsbG8gd29ybGQ=\n=sDy3\n-----END PGP PUBLIC KEY BLOCK-----";
+        assert_eq!(decode(armored, "PGP PUBLIC KEY BLOCK").unwrap(), b"hello world");
+        assert_eq!(decode(armored, "PGP MESSAGE").unwrap_err().kind(), ErrorKind::Malformed);
+    }
+
+    quickcheck! {
+        fn roundtrip(chunks: Vec<Vec<u8>>) -> bool {
+            let data = chunks.iter().cloned().concat();
+            let armored = Armor::new(iter_ok::<_, Error>(chunks)).unwrap().concat2().wait().unwrap();
+            armored.split(|byte| *byte == b'\n').all(|line| line.len() <= 64) &&
+                dearmor(&armored).unwrap() == data
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! RSA keys in the OpenPGP format, as exported by `gpg --export` and
+//! `gpg --export-secret-keys`.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+use hex::ToHex;
+use openssl;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::rsa::{Padding, Rsa};
+use openssl::pkey::{Private, Public};
+
+use super::super::{Error, ErrorKind};
+use super::super::secret::{Redacted, zeroize};
+use super::Algorithm;
+use super::armor;
+use super::packet::{self, S2k, TAG_PUBLIC_KEY, TAG_PUBLIC_SUBKEY, TAG_SECRET_KEY, TAG_SECRET_SUBKEY};
+
+/// Length of a key ID, which consists of the low-order bytes of the fingerprint.
+pub(crate) const KEY_ID_LEN: usize = 8;
+
+const KEY_VERSION: u8 = 4;
+const RSA_ENCRYPT_SIGN: u8 = 1;
+const RSA_ENCRYPT: u8 = 2;
+
+const USAGE_UNENCRYPTED: u8 = 0;
+const USAGE_CHECKSUM: u8 = 255;
+const USAGE_SHA1: u8 = 254;
+const SHA1_LEN: usize = 20;
+
+/// RSA public key that messages are encrypted to.
+#[derive(Clone)]
+pub struct PublicKey {
+    key_id: [u8; KEY_ID_LEN],
+    rsa: Arc<Rsa<Public>>
+}
+
+impl PublicKey {
+    /// Import a public key, either binary or armored as a `PGP PUBLIC KEY BLOCK`.
+    ///
+    /// The last RSA subkey is used if there is one, and the primary key otherwise.
+    /// Signatures and key flags are not verified.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if the key does not contain an RSA key.
+    pub fn from_openpgp(data: &[u8]) -> Result<PublicKey, Error> {
+        let data = armor::decode(data, "PGP PUBLIC KEY BLOCK")?;
+        let packets = packet::split_packets(&data)?;
+        let (key_id, material) = select(&packets, TAG_PUBLIC_KEY, TAG_PUBLIC_SUBKEY, |_, material| Ok(Some(material)))?;
+        let n = BigNum::from_slice(material.n).map_err(Error::from)?;
+        let e = BigNum::from_slice(material.e).map_err(Error::from)?;
+        let rsa = Rsa::from_public_components(n, e).map_err(Error::from)?;
+        Ok(PublicKey { key_id, rsa: Arc::new(rsa) })
+    }
+
+    /// Get the key ID, which is the last 8 bytes of the fingerprint.
+    pub fn key_id(&self) -> &[u8] {
+        &self.key_id
+    }
+
+    /// Encrypt a session key, returning the body of the multiprecision integer.
+    pub(crate) fn encrypt(&self, session_key: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut output = vec![0; self.rsa.size() as usize];
+        let len = self.rsa.public_encrypt(session_key, &mut output, Padding::PKCS1).map_err(Error::from)?;
+        output.truncate(len);
+        Ok(output)
+    }
+}
+
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("PublicKey")
+            .field("key_id", &self.key_id.to_hex())
+            .finish()
+    }
+}
+
+/// RSA secret key that messages are decrypted with.
+#[derive(Clone)]
+pub struct SecretKey {
+    key_id: [u8; KEY_ID_LEN],
+    rsa: Arc<Rsa<Private>>
+}
+
+impl SecretKey {
+    /// Import a secret key, either binary or armored as a `PGP PRIVATE KEY BLOCK`,
+    /// and unlock it with a passphrase if it is protected.
+    ///
+    /// The last RSA subkey is used if there is one, and the primary key otherwise.
+    /// Keys that are protected with anything other than an iterated and salted
+    /// string-to-key specifier are not supported.
+    ///
+    /// Fails with `ErrorKind::AuthenticationFailed` if the passphrase is wrong, and
+    /// with `ErrorKind::Unsupported` if the key does not contain an RSA secret key.
+    pub fn from_openpgp(data: &[u8], passphrase: &[u8]) -> Result<SecretKey, Error> {
+        let data = armor::decode(data, "PGP PRIVATE KEY BLOCK")?;
+        let packets = packet::split_packets(&data)?;
+        let (key_id, rsa) = select(&packets, TAG_SECRET_KEY, TAG_SECRET_SUBKEY, |rest, material| {
+            unlock(rest, passphrase, &material)
+        })?;
+        Ok(SecretKey { key_id, rsa: Arc::new(rsa) })
+    }
+
+    /// Get the key ID, which is the last 8 bytes of the fingerprint.
+    pub fn key_id(&self) -> &[u8] {
+        &self.key_id
+    }
+
+    /// Decrypt a session key, given the body of the multiprecision integer.
+    pub(crate) fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        let size = self.rsa.size() as usize;
+        if encrypted.len() > size {
+            return Err(ErrorKind::Malformed.into());
+        }
+        let mut input = vec![0; size - encrypted.len()];
+        input.extend_from_slice(encrypted);
+        let mut output = vec![0; size];
+        let len = self.rsa.private_decrypt(&input, &mut output, Padding::PKCS1).map_err(Error::from)?;
+        output.truncate(len);
+        Ok(output)
+    }
+}
+
+impl Debug for SecretKey {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("SecretKey")
+            .field("key_id", &self.key_id.to_hex())
+            .field("key", &Redacted)
+            .finish()
+    }
+}
+
+/// Public parameters of an RSA key.
+struct Material<'a> {
+    n: &'a [u8],
+    e: &'a [u8]
+}
+
+/// Key ID, remaining body and public parameters of a key packet.
+type PublicKeyPacket<'a> = ([u8; KEY_ID_LEN], &'a [u8], Material<'a>);
+
+/// Find the last subkey, or else the primary key, for which `f` returns a key.
+fn select<'a, T, F>(packets: &[(u8, &'a [u8])], primary: u8, subkey: u8, mut f: F) -> Result<([u8; KEY_ID_LEN], T), Error>
+    where F: FnMut(&'a [u8], Material<'a>) -> Result<Option<T>, Error>
+{
+    let mut found = None;
+    for &(tag, body) in packets {
+        if tag != primary && tag != subkey {
+            continue;
+        }
+        if found.as_ref().map(|&(is_subkey, _, _)| is_subkey && tag == primary).unwrap_or(false) {
+            continue;
+        }
+        if let Some((key_id, rest, material)) = parse_public(body)? {
+            if let Some(key) = f(rest, material)? {
+                found = Some((tag == subkey, key_id, key));
+            }
+        }
+    }
+    found.map(|(_, key_id, key)| (key_id, key)).ok_or_else(|| ErrorKind::Unsupported.into())
+}
+
+/// Parse the public part of a key packet, returning `None` if it is not a version 4 RSA key.
+fn parse_public(body: &[u8]) -> Result<Option<PublicKeyPacket<'_>>, Error> {
+    if body.len() < 6 {
+        return Err(ErrorKind::Malformed.into());
+    }
+    if body[0] != KEY_VERSION || (body[5] != RSA_ENCRYPT_SIGN && body[5] != RSA_ENCRYPT) {
+        return Ok(None);
+    }
+    let mut rest = &body[6..];
+    let n = packet::read_mpi(&mut rest)?;
+    let e = packet::read_mpi(&mut rest)?;
+    let public = &body[..body.len() - rest.len()];
+    let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha1()).map_err(Error::from)?;
+    hasher.update(&[0x99, (public.len() >> 8) as u8, public.len() as u8]).map_err(Error::from)?;
+    hasher.update(public).map_err(Error::from)?;
+    let fingerprint = hasher.finish().map_err(Error::from)?;
+    let mut key_id = [0u8; KEY_ID_LEN];
+    key_id.copy_from_slice(&fingerprint[SHA1_LEN - KEY_ID_LEN..]);
+    Ok(Some((key_id, rest, Material { n, e })))
+}
+
+/// Decrypt the secret part of a key packet if needed, and build the RSA key.
+///
+/// Returns `None` for keys whose secret part has been stripped.
+fn unlock(rest: &[u8], passphrase: &[u8], material: &Material) -> Result<Option<Rsa<Private>>, Error> {
+    let (usage, mut rest) = rest.split_first().ok_or(ErrorKind::Malformed)?;
+    let mut secret = match *usage {
+        USAGE_UNENCRYPTED => {
+            if rest.len() < 2 {
+                return Err(ErrorKind::Malformed.into());
+            }
+            let (secret, sum) = rest.split_at(rest.len() - 2);
+            if packet::checksum(secret) != ((sum[0] as u16) << 8 | sum[1] as u16) {
+                return Err(ErrorKind::Malformed.into());
+            }
+            secret.to_vec()
+        },
+        USAGE_SHA1 | USAGE_CHECKSUM => {
+            let (algo, tail) = rest.split_first().ok_or(ErrorKind::Malformed)?;
+            rest = tail;
+            let algo = Algorithm::from_id(*algo).ok_or(ErrorKind::Unsupported)?;
+            let s2k = match S2k::read(&mut rest)? {
+                None => return Ok(None),
+                Some(s2k) => s2k
+            };
+            if rest.len() < algo.block_len() {
+                return Err(ErrorKind::Malformed.into());
+            }
+            let (iv, encrypted) = rest.split_at(algo.block_len());
+            let mut key = vec![0; algo.key_len()];
+            s2k.derive(passphrase, &mut key)?;
+            let result = openssl::symm::decrypt(algo.cipher().into_cipher()?, &key, Some(iv), encrypted);
+            zeroize(&mut key);
+            let mut decrypted = result.map_err(Error::from)?;
+            let check_len = if *usage == USAGE_SHA1 { SHA1_LEN } else { 2 };
+            if decrypted.len() < check_len {
+                return Err(ErrorKind::Malformed.into());
+            }
+            let split = decrypted.len() - check_len;
+            let valid = if *usage == USAGE_SHA1 {
+                openssl::hash::hash(openssl::hash::MessageDigest::sha1(), &decrypted[..split])
+                    .map(|digest| openssl::memcmp::eq(&digest, &decrypted[split..]))
+                    .map_err(Error::from)?
+            } else {
+                packet::checksum(&decrypted[..split]) == ((decrypted[split] as u16) << 8 | decrypted[split + 1] as u16)
+            };
+            if !valid {
+                zeroize(&mut decrypted);
+                return Err(ErrorKind::AuthenticationFailed.into());
+            }
+            decrypted.truncate(split);
+            decrypted
+        },
+        _ => return Err(ErrorKind::Unsupported.into())
+    };
+    let result = build_rsa(&secret, material);
+    zeroize(&mut secret);
+    result.map(Some)
+}
+
+fn build_rsa(secret: &[u8], material: &Material) -> Result<Rsa<Private>, Error> {
+    let mut input = secret;
+    let d = BigNum::from_slice(packet::read_mpi(&mut input)?).map_err(Error::from)?;
+    let p = BigNum::from_slice(packet::read_mpi(&mut input)?).map_err(Error::from)?;
+    let q = BigNum::from_slice(packet::read_mpi(&mut input)?).map_err(Error::from)?;
+    packet::read_mpi(&mut input)?;
+    if !input.is_empty() {
+        return Err(ErrorKind::Malformed.into());
+    }
+    // OpenPGP stores the inverse of p modulo q, while OpenSSL expects the
+    // inverse of q modulo p along with the CRT exponents.
+    let mut ctx = BigNumContext::new().map_err(Error::from)?;
+    let one = BigNum::from_u32(1).map_err(Error::from)?;
+    let (mut p1, mut q1) = (BigNum::new().map_err(Error::from)?, BigNum::new().map_err(Error::from)?);
+    p1.checked_sub(&p, &one).map_err(Error::from)?;
+    q1.checked_sub(&q, &one).map_err(Error::from)?;
+    let (mut dp, mut dq, mut qi) = (BigNum::new().map_err(Error::from)?, BigNum::new().map_err(Error::from)?,
+                                    BigNum::new().map_err(Error::from)?);
+    dp.checked_rem(&d, &p1, &mut ctx).map_err(Error::from)?;
+    dq.checked_rem(&d, &q1, &mut ctx).map_err(Error::from)?;
+    qi.mod_inverse(&q, &p, &mut ctx).map_err(Error::from)?;
+    let n = BigNum::from_slice(material.n).map_err(Error::from)?;
+    let e = BigNum::from_slice(material.e).map_err(Error::from)?;
+    Rsa::from_private_components(n, e, d, p, q, dp, dq, qi).map_err(Error::from)
+}
@@ -0,0 +1,481 @@
+//! Packet framing, multiprecision integers and string-to-key specifiers,
+//! as specified in [RFC 4880](https://tools.ietf.org/html/rfc4880).
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, Poll, Stream};
+use openssl;
+
+use super::super::{Error, ErrorKind};
+use super::super::hash;
+use super::super::secret::zeroize;
+
+pub const TAG_PKESK: u8 = 1;
+pub const TAG_SKESK: u8 = 3;
+pub const TAG_SECRET_KEY: u8 = 5;
+pub const TAG_PUBLIC_KEY: u8 = 6;
+pub const TAG_SECRET_SUBKEY: u8 = 7;
+pub const TAG_MARKER: u8 = 10;
+pub const TAG_LITERAL: u8 = 11;
+pub const TAG_PUBLIC_SUBKEY: u8 = 14;
+pub const TAG_SEIPD: u8 = 18;
+
+/// Length of the partial body chunks that are written, as a power of two.
+const CHUNK_POWER: u8 = 13;
+const CHUNK_LEN: usize = 1 << CHUNK_POWER;
+
+/// Length of a packet body, or of the first chunk of a packet body.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Length {
+    /// The body has the given length.
+    Definite(usize),
+    /// The chunk has the given length, and is followed by another length.
+    Partial(usize),
+    /// The body extends to the end of the input.
+    Indeterminate
+}
+
+/// Decode a packet header from the start of `input`, in either the old or the new format.
+///
+/// Returns `None` if more input is needed, or the tag, the length and the length of the header.
+pub fn decode_header(input: &[u8]) -> Result<Option<(u8, Length, usize)>, Error> {
+    let first = match input.first() {
+        None => return Ok(None),
+        Some(first) => *first
+    };
+    if first & 0x80 == 0 {
+        return Err(ErrorKind::Malformed.into());
+    }
+    if first & 0x40 != 0 {
+        return Ok(decode_length(&input[1..])?.map(|(length, len)| (first & 0x3f, length, len + 1)));
+    }
+    let tag = (first >> 2) & 0x0f;
+    let len = match first & 0x03 {
+        0 => 1,
+        1 => 2,
+        2 => 4,
+        _ => return Ok(Some((tag, Length::Indeterminate, 1)))
+    };
+    if input.len() < 1 + len {
+        return Ok(None);
+    }
+    let length = input[1..1 + len].iter().fold(0usize, |acc, byte| acc << 8 | *byte as usize);
+    Ok(Some((tag, Length::Definite(length), 1 + len)))
+}
+
+/// Decode a length in the new format from the start of `input`.
+///
+/// Returns `None` if more input is needed, or the length along with the number of bytes it occupies.
+pub fn decode_length(input: &[u8]) -> Result<Option<(Length, usize)>, Error> {
+    let first = match input.first() {
+        None => return Ok(None),
+        Some(first) => *first as usize
+    };
+    match first {
+        0..=191 => Ok(Some((Length::Definite(first), 1))),
+        192..=223 => match input.get(1) {
+            None => Ok(None),
+            Some(second) => Ok(Some((Length::Definite(((first - 192) << 8) + *second as usize + 192), 2)))
+        },
+        224..=254 => Ok(Some((Length::Partial(1 << (first & 0x1f)), 1))),
+        _ => {
+            if input.len() < 5 {
+                return Ok(None);
+            }
+            let length = input[1..5].iter().fold(0usize, |acc, byte| acc << 8 | *byte as usize);
+            Ok(Some((Length::Definite(length), 5)))
+        }
+    }
+}
+
+/// Append a packet header in the new format with a definite length.
+pub fn encode_header(tag: u8, len: usize, output: &mut BytesMut) {
+    output.reserve(6);
+    output.put_u8(0xc0 | tag);
+    encode_length(len, output);
+}
+
+fn encode_length(len: usize, output: &mut BytesMut) {
+    output.reserve(5);
+    if len < 192 {
+        output.put_u8(len as u8);
+    } else if len < 8384 {
+        output.put_u8(((len - 192) >> 8) as u8 + 192);
+        output.put_u8((len - 192) as u8);
+    } else {
+        output.put_u8(0xff);
+        output.put_u32_be(len as u32);
+    }
+}
+
+/// Split the input into complete packets with definite lengths, as found in keys.
+pub fn split_packets(mut input: &[u8]) -> Result<Vec<(u8, &[u8])>, Error> {
+    let mut packets = Vec::new();
+    while !input.is_empty() {
+        match decode_header(input)? {
+            Some((tag, Length::Definite(len), header_len)) if input.len() >= header_len + len => {
+                packets.push((tag, &input[header_len..header_len + len]));
+                input = &input[header_len + len..];
+            },
+            _ => return Err(ErrorKind::Malformed.into())
+        }
+    }
+    Ok(packets)
+}
+
+/// Read a multiprecision integer, returning its big-endian bytes.
+pub fn read_mpi<'a>(input: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    if input.len() < 2 {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let bits = (input[0] as usize) << 8 | input[1] as usize;
+    let len = bits.div_ceil(8);
+    if input.len() < 2 + len {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let mpi = &input[2..2 + len];
+    *input = &input[2 + len..];
+    Ok(mpi)
+}
+
+/// Append a multiprecision integer, given its big-endian bytes.
+pub fn write_mpi(bytes: &[u8], output: &mut Vec<u8>) {
+    let bytes = &bytes[bytes.iter().position(|byte| *byte != 0).unwrap_or(bytes.len())..];
+    let bits = bytes.first().map(|first| bytes.len() * 8 - first.leading_zeros() as usize).unwrap_or(0);
+    output.push((bits >> 8) as u8);
+    output.push(bits as u8);
+    output.extend_from_slice(bytes);
+}
+
+/// The two-octet checksum used for session keys and unencrypted secret keys.
+pub fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |acc, byte| acc.wrapping_add(*byte as u16))
+}
+
+/// String-to-key specifier, which turns a passphrase into a key.
+#[derive(Clone, Debug)]
+pub struct S2k {
+    hash: hash::Algorithm,
+    salt: Option<[u8; SALT_LEN]>,
+    count: Option<u8>
+}
+
+pub const SALT_LEN: usize = 8;
+const S2K_SIMPLE: u8 = 0;
+const S2K_SALTED: u8 = 1;
+const S2K_ITERATED: u8 = 3;
+const S2K_GNU_DUMMY: u8 = 101;
+
+/// Length of the blocks that the repeated salted passphrase is hashed in.
+const BLOCK_LEN: usize = 1 << 16;
+
+/// The coded iteration count used by default, which corresponds to 65011712 bytes.
+pub const DEFAULT_COUNT: u8 = 0xff;
+
+impl S2k {
+    /// Create an iterated and salted specifier using SHA-256.
+    pub fn iterated(salt: &[u8], count: u8) -> S2k {
+        let mut bytes = [0u8; SALT_LEN];
+        bytes.copy_from_slice(salt);
+        S2k { hash: hash::Algorithm::Sha256, salt: Some(bytes), count: Some(count) }
+    }
+
+    /// Read a specifier.
+    ///
+    /// Returns `None` for the GnuPG extension that marks keys without secret material.
+    pub fn read(input: &mut &[u8]) -> Result<Option<S2k>, Error> {
+        let (kind, hash) = match **input {
+            [kind, hash, ..] => (kind, hash),
+            _ => return Err(ErrorKind::Malformed.into())
+        };
+        if kind == S2K_GNU_DUMMY {
+            return Ok(None);
+        }
+        let hash = match hash {
+            2 => hash::Algorithm::Sha1,
+            8 => hash::Algorithm::Sha256,
+            9 => hash::Algorithm::Sha384,
+            10 => hash::Algorithm::Sha512,
+            11 => hash::Algorithm::Sha224,
+            _ => return Err(ErrorKind::Unsupported.into())
+        };
+        let len = match kind {
+            S2K_SIMPLE => 2,
+            S2K_SALTED => 2 + SALT_LEN,
+            S2K_ITERATED => 3 + SALT_LEN,
+            _ => return Err(ErrorKind::Unsupported.into())
+        };
+        if input.len() < len {
+            return Err(ErrorKind::Malformed.into());
+        }
+        let salt = if kind == S2K_SIMPLE {
+            None
+        } else {
+            let mut salt = [0u8; SALT_LEN];
+            salt.copy_from_slice(&input[2..2 + SALT_LEN]);
+            Some(salt)
+        };
+        let count = if kind == S2K_ITERATED { Some(input[2 + SALT_LEN]) } else { None };
+        *input = &input[len..];
+        Ok(Some(S2k { hash, salt, count }))
+    }
+
+    /// Append the specifier.
+    pub fn write(&self, output: &mut Vec<u8>) {
+        output.push(match (self.salt, self.count) {
+            (None, _) => S2K_SIMPLE,
+            (Some(_), None) => S2K_SALTED,
+            (Some(_), Some(_)) => S2K_ITERATED
+        });
+        output.push(match self.hash {
+            hash::Algorithm::Sha1 => 2,
+            hash::Algorithm::Sha384 => 9,
+            hash::Algorithm::Sha512 => 10,
+            hash::Algorithm::Sha224 => 11,
+            _ => 8
+        });
+        if let Some(ref salt) = self.salt {
+            output.extend_from_slice(salt);
+        }
+        if let Some(count) = self.count {
+            output.push(count);
+        }
+    }
+
+    /// Derive a key from the passphrase, filling the output.
+    pub fn derive(&self, passphrase: &[u8], output: &mut [u8]) -> Result<(), Error> {
+        let mut input = self.salt.map(|salt| salt.to_vec()).unwrap_or_default();
+        input.extend_from_slice(passphrase);
+        let count = self.count
+            .map(|count| ((16 + (count as usize & 15)) << ((count >> 4) + 6)).max(input.len()))
+            .unwrap_or(input.len());
+        // The salted passphrase is repeated up to the count, so it is hashed in large blocks.
+        let mut block = Vec::with_capacity(count.min(BLOCK_LEN) + input.len());
+        while block.len() < count.min(BLOCK_LEN) {
+            block.extend_from_slice(&input);
+        }
+        let block_len = block.len() - block.len() % input.len().max(1);
+        let digest_len = self.hash.digest_len();
+        for (i, chunk) in output.chunks_mut(digest_len).enumerate() {
+            let mut hasher = openssl::hash::Hasher::new(self.hash.into_message_digest()).map_err(Error::from)?;
+            hasher.update(&vec![0; i]).map_err(Error::from)?;
+            let mut remaining = count;
+            while remaining > 0 {
+                let len = remaining.min(block_len);
+                hasher.update(&block[..len]).map_err(Error::from)?;
+                remaining -= len;
+            }
+            let digest = hasher.finish().map_err(Error::from)?;
+            chunk.copy_from_slice(&digest[..chunk.len()]);
+        }
+        zeroize(&mut input);
+        zeroize(&mut block);
+        Ok(())
+    }
+}
+
+/// Stream adapter that frames the data of its underlying stream as the body of a packet,
+/// using partial body lengths.
+pub struct Frame<S> {
+    inner: S,
+    tag: u8,
+    buffer: BytesMut,
+    started: bool,
+    finished: bool
+}
+
+impl<S> Frame<S> {
+    /// Create a packet with the given tag, whose body starts with `prefix`.
+    pub fn new(tag: u8, prefix: &[u8], inner: S) -> Self {
+        Frame { inner, tag, buffer: BytesMut::from(prefix), started: false, finished: false }
+    }
+
+    fn chunk(&mut self, len: usize, last: bool) -> Bytes {
+        let mut output = BytesMut::with_capacity(len + 6);
+        if !self.started {
+            output.put_u8(0xc0 | self.tag);
+            self.started = true;
+        }
+        if last {
+            encode_length(len, &mut output);
+        } else {
+            output.put_u8(0xe0 | CHUNK_POWER);
+        }
+        output.extend_from_slice(&self.buffer.split_to(len));
+        output.freeze()
+    }
+}
+
+impl<S: Stream> Stream for Frame<S>
+    where S::Item: AsRef<[u8]>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            // A chunk is only written once more data follows it, since
+            // the last chunk has to have a definite length.
+            if self.buffer.len() > CHUNK_LEN {
+                return Ok(Async::Ready(Some(self.chunk(CHUNK_LEN, false))));
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => self.buffer.extend_from_slice(item.as_ref()),
+                Async::Ready(None) => {
+                    self.finished = true;
+                    let len = self.buffer.len();
+                    return Ok(Async::Ready(Some(self.chunk(len, true))));
+                }
+            }
+        }
+    }
+}
+
+/// Stream adapter that yields the body of a packet, given the underlying stream
+/// positioned after the packet header, and the length of the body or its first chunk.
+pub struct Body<S> {
+    inner: S,
+    buffer: BytesMut,
+    remaining: usize,
+    partial: bool,
+    indeterminate: bool,
+    finished: bool
+}
+
+impl<S> Body<S> {
+    pub fn new(length: Length, inner: S) -> Self {
+        let (remaining, partial, indeterminate) = match length {
+            Length::Definite(len) => (len, false, false),
+            Length::Partial(len) => (len, true, false),
+            Length::Indeterminate => (0, false, true)
+        };
+        Body { inner, buffer: BytesMut::new(), remaining, partial, indeterminate, finished: false }
+    }
+}
+
+impl<S: Stream> Body<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    /// Collect the data of the underlying stream that follows the end of the body.
+    pub fn remainder(&mut self) -> Poll<Bytes, S::Error> {
+        loop {
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => return Ok(Async::Ready(self.buffer.take().freeze())),
+                Async::Ready(Some(item)) => self.buffer.extend_from_slice(item.as_ref())
+            }
+        }
+    }
+}
+
+impl<S: Stream> Stream for Body<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            if self.indeterminate || self.remaining > 0 {
+                if !self.buffer.is_empty() {
+                    let len = if self.indeterminate { self.buffer.len() } else { self.remaining.min(self.buffer.len()) };
+                    self.remaining -= len.min(self.remaining);
+                    return Ok(Async::Ready(Some(self.buffer.split_to(len).freeze())));
+                }
+            } else if self.partial {
+                if let Some((length, len)) = decode_length(&self.buffer)? {
+                    self.buffer.advance(len);
+                    match length {
+                        Length::Definite(len) => { self.remaining = len; self.partial = false; },
+                        Length::Partial(len) => self.remaining = len,
+                        Length::Indeterminate => unreachable!()
+                    }
+                    continue;
+                }
+            } else {
+                self.finished = true;
+                return Ok(Async::Ready(None));
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(item)) => self.buffer.extend_from_slice(item.as_ref()),
+                Async::Ready(None) => {
+                    if !self.indeterminate {
+                        return Err(Error::from(ErrorKind::Malformed).into());
+                    }
+                    self.finished = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+
+    use super::{Body, Error, Frame, Length, S2k, decode_header, encode_header, read_mpi, write_mpi};
+
+    #[test]
+    fn headers() {
+        for &len in &[0, 191, 192, 8383, 8384, 100_000] {
+            let mut header = BytesMut::new();
+            encode_header(11, len, &mut header);
+            assert_eq!(decode_header(&header).unwrap(), Some((11, Length::Definite(len), header.len())));
+            assert_eq!(decode_header(&header[..header.len() - 1]).unwrap(), None);
+        }
+        // Old format, two-octet length
+        assert_eq!(decode_header(&[0x99, 0x01, 0x0d]).unwrap(), Some((6, Length::Definite(269), 3)));
+        assert_eq!(decode_header(&[0xa3, 0x01]).unwrap(), Some((8, Length::Indeterminate, 1)));
+        assert_eq!(decode_header(&[0xd2, 0xed]).unwrap(), Some((18, Length::Partial(1 << 13), 2)));
+        assert!(decode_header(&[0x12]).is_err());
+    }
+
+    #[test]
+    fn mpis() {
+        let mut output = Vec::new();
+        write_mpi(&[0x00, 0x01, 0xff], &mut output);
+        assert_eq!(output, [0x00, 0x09, 0x01, 0xff]);
+        let mut input = &output[..];
+        assert_eq!(read_mpi(&mut input).unwrap(), [0x01, 0xff]);
+        assert!(input.is_empty());
+        assert!(read_mpi(&mut &[0x00, 0x09, 0x01][..]).is_err());
+    }
+
+    #[test]
+    fn s2k_roundtrip() {
+        let s2k = S2k::iterated(&[1, 2, 3, 4, 5, 6, 7, 8], 96);
+        let mut encoded = Vec::new();
+        s2k.write(&mut encoded);
+        assert_eq!(encoded, [3, 8, 1, 2, 3, 4, 5, 6, 7, 8, 96]);
+        let decoded = S2k::read(&mut &encoded[..]).unwrap().unwrap();
+        let (mut first, mut second) = ([0u8; 48], [0u8; 48]);
+        s2k.derive(b"passphrase", &mut first).unwrap();
+        decoded.derive(b"passphrase", &mut second).unwrap();
+        assert_eq!(&first[..], &second[..]);
+        assert_ne!(&first[..32], &first[32..]);
+    }
+
+    quickcheck! {
+        fn frame_roundtrip(chunks: Vec<Vec<u8>>, repeat: u8) -> bool {
+            let chunks: Vec<Vec<u8>> = chunks.into_iter()
+                .map(|chunk| chunk.iter().cycle().take(chunk.len() * repeat as usize).cloned().collect())
+                .collect();
+            let data: Vec<u8> = chunks.concat();
+            let framed = Frame::new(11, &[], iter_ok::<_, Error>(chunks)).concat2().wait().unwrap();
+            let (tag, length, header_len) = decode_header(&framed).unwrap().unwrap();
+            let body = Body::new(length, iter_ok::<_, Error>(vec![framed[header_len..].to_vec()]));
+            tag == 11 && body.concat2().wait().unwrap() == data
+        }
+    }
+}
@@ -1,16 +1,18 @@
 //! Cryptographically strong pseudo-random number generation.
 
+use std::cmp;
+use std::collections::VecDeque;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::sync::Arc;
 
 use bytes::{BufMut, BytesMut, Bytes};
-use futures::{Async, Future, Poll};
-use futures::future::{Executor, ExecuteError};
-use futures::sync::oneshot::{Execute, SpawnHandle, spawn};
+use futures::{Async, Future, Poll, Stream};
+use futures::future::Executor;
+use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
 use openssl;
 
-use super::Error;
+use super::{Error, ErrorKind};
 
 /// Cryptographically strong pseudo-random number generator.
 #[derive(Clone, Debug)]
@@ -38,20 +40,82 @@ impl Generator {
     /// The `size` argument indicates the number of bytes to generate.
     pub fn random_bytes(&self, size: usize) -> RandomBytes {
         RandomBytes {
-            size: size,
+            size,
             executor: self.executor.clone(),
             state: State::Idle
         }
     }
+
+    /// Generate a stream of cryptographically strong pseudo-random data.
+    ///
+    /// The `chunk_size` argument indicates the number of bytes per chunk, and the `total`
+    /// argument the total number of bytes to generate, or `None` for an infinite stream.
+    /// The last chunk is shorter if the total is not a multiple of the chunk size.
+    ///
+    /// Up to four chunks are generated concurrently, ahead of being consumed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn random_stream(&self, chunk_size: usize, total: Option<u64>) -> RandomStream {
+        assert!(chunk_size != 0, "chunk size must be non-zero");
+        RandomStream {
+            chunk_size,
+            remaining: total,
+            executor: self.executor.clone(),
+            pending: VecDeque::with_capacity(MAX_PENDING_TASKS)
+        }
+    }
+
+    /// Generate a uniformly random `u32`.
+    pub fn random_u32(&self) -> RandomU32 {
+        RandomU32 { inner: self.random_bytes(4) }
+    }
+
+    /// Generate a uniformly random `u64`.
+    pub fn random_u64(&self) -> RandomU64 {
+        RandomU64 { inner: self.random_bytes(8) }
+    }
+
+    /// Generate a uniformly random number in the range from `low` (inclusive)
+    /// to `high` (exclusive).
+    ///
+    /// Random values that would bias the result towards some numbers of the range
+    /// are rejected and replaced, rather than reduced modulo the size of the range.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `low` is not less than `high`.
+    pub fn random_range(&self, low: u64, high: u64) -> RandomRange {
+        assert!(low < high, "range must be non-empty");
+        RandomRange { low, span: high - low, draws: Draws::new(self.executor.clone(), 1) }
+    }
+
+    /// Shuffle items into a uniformly random order.
+    pub fn shuffle<T>(&self, items: Vec<T>) -> Shuffle<T> {
+        let len = items.len();
+        Shuffle { draws: Draws::new(self.executor.clone(), len.saturating_sub(1)), items: Some(items), index: len }
+    }
+
+    /// Choose `count` items uniformly at random, without replacement, and in random order.
+    ///
+    /// If `count` exceeds the number of items, all items are returned in random order.
+    pub fn sample<T>(&self, items: Vec<T>, count: usize) -> Sample<T> {
+        let count = cmp::min(count, items.len());
+        Sample { draws: Draws::new(self.executor.clone(), count), items: Some(items), index: 0, count }
+    }
 }
 
 #[derive(Debug)]
 enum State {
     Idle,
-    Busy(SpawnHandle<Bytes, Error>)
+    Busy(oneshot::Receiver<Result<Bytes, Error>>)
 }
 
 /// Future returning cryptographically strong pseudo-random data.
+///
+/// Fails with `ErrorKind::ExecutorShutdown` if the executor is unable
+/// to run the generating task.
 #[derive(Debug)]
 pub struct RandomBytes {
     size: usize,
@@ -65,41 +129,268 @@ impl Future for RandomBytes {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match self.state {
-            State::Busy(ref mut future) => future.poll(),
+            State::Busy(ref mut receiver) => match receiver.poll() {
+                Err(_) => Err(ErrorKind::ExecutorShutdown.into()),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Ok(Async::Ready(result)) => result.map(Async::Ready)
+            },
             State::Idle => {
-                let task_inner = TaskInner { size: self.size };
-                let spawn_handle = spawn(task_inner, &self.executor);
-                self.state = State::Busy(spawn_handle);
+                self.state = State::Busy(self.executor.spawn(self.size)?);
                 self.poll()
             }
         }
     }
 }
 
+/// Maximum number of tasks of a `RandomStream` that are in flight at the same time.
+const MAX_PENDING_TASKS: usize = 4;
+
+/// Stream of cryptographically strong pseudo-random data.
+///
+/// Fails with `ErrorKind::ExecutorShutdown` if the executor is unable
+/// to run the generating tasks.
+#[derive(Debug)]
+pub struct RandomStream {
+    chunk_size: usize,
+    remaining: Option<u64>,
+    executor: TaskExecutor,
+    pending: VecDeque<oneshot::Receiver<Result<Bytes, Error>>>
+}
+
+impl Stream for RandomStream {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while self.pending.len() < MAX_PENDING_TASKS && self.remaining != Some(0) {
+            let size = match self.remaining {
+                None => self.chunk_size,
+                Some(ref mut remaining) => {
+                    let size = cmp::min(*remaining, self.chunk_size as u64);
+                    *remaining -= size;
+                    size as usize
+                }
+            };
+            let receiver = self.executor.spawn(size)?;
+            self.pending.push_back(receiver);
+        }
+        let result = match self.pending.front_mut() {
+            None => return Ok(Async::Ready(None)),
+            Some(receiver) => match receiver.poll() {
+                Err(_) => Err(ErrorKind::ExecutorShutdown.into()),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(result)) => result
+            }
+        };
+        self.pending.pop_front();
+        result.map(|bytes| Async::Ready(Some(bytes)))
+    }
+}
+
+/// Future returning a uniformly random `u32`.
+///
+/// Fails with `ErrorKind::ExecutorShutdown` if the executor is unable
+/// to run the generating task.
+#[derive(Debug)]
+pub struct RandomU32 {
+    inner: RandomBytes
+}
+
+impl Future for RandomU32 {
+    type Item = u32;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.inner.poll().map(|ready| ready.map(|bytes| from_be_bytes(&bytes) as u32))
+    }
+}
+
+/// Future returning a uniformly random `u64`.
+///
+/// Fails with `ErrorKind::ExecutorShutdown` if the executor is unable
+/// to run the generating task.
+#[derive(Debug)]
+pub struct RandomU64 {
+    inner: RandomBytes
+}
+
+impl Future for RandomU64 {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.inner.poll().map(|ready| ready.map(|bytes| from_be_bytes(&bytes)))
+    }
+}
+
+/// Future returning a uniformly random number in a range.
+///
+/// Fails with `ErrorKind::ExecutorShutdown` if the executor is unable
+/// to run the generating tasks.
+#[derive(Debug)]
+pub struct RandomRange {
+    low: u64,
+    span: u64,
+    draws: Draws
+}
+
+impl Future for RandomRange {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let low = self.low;
+        self.draws.poll_below(self.span).map(|ready| ready.map(|value| low + value))
+    }
+}
+
+/// Future returning items in a uniformly random order.
+///
+/// The items are shuffled using the [Fisher-Yates](https://en.wikipedia.org/wiki/Fisher%E2%80%93Yates_shuffle)
+/// algorithm.
+///
+/// Fails with `ErrorKind::ExecutorShutdown` if the executor is unable
+/// to run the generating tasks.
+#[derive(Debug)]
+pub struct Shuffle<T> {
+    items: Option<Vec<T>>,
+    // Number of items at the front which have not been placed yet.
+    index: usize,
+    draws: Draws
+}
+
+impl<T> Future for Shuffle<T> {
+    type Item = Vec<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let items = self.items.as_mut().expect("polled after completion");
+            while self.index > 1 {
+                let other = match self.draws.poll_below(self.index as u64)? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(other) => other as usize
+                };
+                self.index -= 1;
+                items.swap(self.index, other);
+            }
+        }
+        Ok(Async::Ready(self.items.take().unwrap()))
+    }
+}
+
+/// Future returning items chosen uniformly at random.
+///
+/// Fails with `ErrorKind::ExecutorShutdown` if the executor is unable
+/// to run the generating tasks.
+#[derive(Debug)]
+pub struct Sample<T> {
+    items: Option<Vec<T>>,
+    // Number of items at the front which have been chosen already.
+    index: usize,
+    count: usize,
+    draws: Draws
+}
+
+impl<T> Future for Sample<T> {
+    type Item = Vec<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let items = self.items.as_mut().expect("polled after completion");
+            while self.index < self.count {
+                let offset = match self.draws.poll_below((items.len() - self.index) as u64)? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(offset) => offset as usize
+                };
+                items.swap(self.index, self.index + offset);
+                self.index += 1;
+            }
+        }
+        let mut items = self.items.take().unwrap();
+        items.truncate(self.count);
+        Ok(Async::Ready(items))
+    }
+}
+
+/// Maximum number of random values which are generated by a single task of `Draws`.
+const MAX_DRAWS_PER_TASK: usize = 4096;
+
+/// Source of uniformly random numbers below a bound, generating random data in batches.
+#[derive(Debug)]
+struct Draws {
+    executor: TaskExecutor,
+    // Number of values which are still expected to be drawn.
+    remaining: usize,
+    buffer: Bytes,
+    pending: Option<RandomBytes>
+}
+
+impl Draws {
+    fn new(executor: TaskExecutor, count: usize) -> Draws {
+        Draws { executor, remaining: count, buffer: Bytes::new(), pending: None }
+    }
+
+    /// Draw a uniformly random number below the bound, which must not be zero.
+    fn poll_below(&mut self, bound: u64) -> Poll<u64, Error> {
+        // Rejecting values below 2^64 mod bound leaves a multiple of bound values,
+        // so that reducing the remaining ones modulo bound introduces no bias.
+        let threshold = bound.wrapping_neg() % bound;
+        loop {
+            while self.buffer.len() >= 8 {
+                let value = from_be_bytes(&self.buffer.split_to(8));
+                if value >= threshold {
+                    self.remaining = self.remaining.saturating_sub(1);
+                    return Ok(Async::Ready(value % bound));
+                }
+            }
+            if self.pending.is_none() {
+                let count = self.remaining.clamp(1, MAX_DRAWS_PER_TASK);
+                let executor = self.executor.clone();
+                self.pending = Some(RandomBytes { size: count * 8, executor, state: State::Idle });
+            }
+            match self.pending.as_mut().unwrap().poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(bytes) => {
+                    self.pending = None;
+                    self.buffer = bytes;
+                }
+            }
+        }
+    }
+}
+
+fn from_be_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
 #[derive(Clone)]
 struct TaskExecutor {
-    inner: Arc<Executor<Task>>
+    inner: Arc<dyn Executor<Task>>
 }
 
-impl Debug for TaskExecutor {
-    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        f.debug_struct("TaskExecutor").finish()
+impl TaskExecutor {
+    /// Submit a task generating the given number of bytes.
+    fn spawn(&self, size: usize) -> Result<oneshot::Receiver<Result<Bytes, Error>>, Error> {
+        let (sender, receiver) = oneshot::channel();
+        let task = Task { inner: TaskInner { size }, sender: Some(sender) };
+        self.inner.execute(task)
+            .map_err(|_| Error::from(ErrorKind::ExecutorShutdown))?;
+        Ok(receiver)
     }
 }
 
-impl Executor<Execute<TaskInner>> for TaskExecutor {
-    fn execute(&self, future: Execute<TaskInner>) -> Result<(), ExecuteError<Execute<TaskInner>>> {
-        match self.inner.execute(Task { inner: future }) {
-            Ok(()) => Ok(()),
-            Err(err) =>
-                Err(ExecuteError::new(err.kind(), err.into_future().inner))
-        }
+impl Debug for TaskExecutor {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("TaskExecutor").finish()
     }
 }
 
 /// Blocking task that should be executed on a thread pool.
 pub struct Task {
-    inner: Execute<TaskInner>
+    inner: TaskInner,
+    sender: Option<oneshot::Sender<Result<Bytes, Error>>>
 }
 
 impl Debug for Task {
@@ -113,7 +404,14 @@ impl Future for Task {
     type Error = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.inner.poll()
+        if let Some(mut sender) = self.sender.take() {
+            // Skip the work if the receiving future has been dropped in the meantime.
+            if let Ok(Async::Ready(())) = sender.poll_cancel() {
+                return Ok(Async::Ready(()));
+            }
+            sender.send(self.inner.run()).ok();
+        }
+        Ok(Async::Ready(()))
     }
 }
 
@@ -121,26 +419,47 @@ struct TaskInner {
     size: usize
 }
 
-impl Future for TaskInner {
-    type Item = Bytes;
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+impl TaskInner {
+    fn run(&self) -> Result<Bytes, Error> {
         let mut output = BytesMut::with_capacity(self.size);
         unsafe {
             openssl::rand::rand_bytes(output.bytes_mut())
-                .map_err(|err| Error(err).into())?;
+                .map_err(Error::from)?;
             output.advance_mut(self.size);
         }
-        Ok(Async::Ready(output.freeze()))
+        Ok(output.freeze())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use futures::Future;
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use futures::{Async, Future, Stream};
+    use futures::future::{self, Executor, ExecuteError, ExecuteErrorKind};
 
-    use super::Generator;
+    use ErrorKind;
+    use super::{Draws, Generator, Task, MAX_PENDING_TASKS};
+
+    struct Shutdown;
+
+    /// Executor that holds on to its tasks without running them.
+    #[derive(Clone, Default)]
+    struct Queue(Arc<Mutex<Vec<Task>>>);
+
+    impl Executor<Task> for Queue {
+        fn execute(&self, task: Task) -> Result<(), ExecuteError<Task>> {
+            self.0.lock().unwrap().push(task);
+            Ok(())
+        }
+    }
+
+    impl Executor<Task> for Shutdown {
+        fn execute(&self, task: Task) -> Result<(), ExecuteError<Task>> {
+            Err(ExecuteError::new(ExecuteErrorKind::Shutdown, task))
+        }
+    }
 
     #[test]
     fn random_bytes() {
@@ -148,4 +467,115 @@ mod test {
         let random_bytes = generator.random_bytes(128).wait().unwrap();
         assert_eq!(random_bytes.len(), 128);
     }
+
+    #[test]
+    fn executor_shutdown() {
+        let generator = Generator::with_executor(Shutdown);
+        let err = generator.random_bytes(128).wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecutorShutdown);
+        let err = generator.random_stream(128, None).wait().next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecutorShutdown);
+        let err = generator.random_range(0, 10).wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ExecutorShutdown);
+        assert_eq!(generator.shuffle(vec![1]).wait().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn random_stream() {
+        let generator = Generator::new(2);
+        let chunks = generator.random_stream(300, Some(1000)).collect().wait().unwrap();
+        assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![300, 300, 300, 100]);
+        assert_ne!(chunks[0], chunks[1]);
+        let chunks = generator.random_stream(16, None).take(100).collect().wait().unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.len() == 16));
+        assert!(generator.random_stream(16, Some(0)).collect().wait().unwrap().is_empty());
+    }
+
+    /// Run a function in the context of a task, as required to poll futures directly.
+    fn in_task<F: FnOnce() -> R, R>(f: F) -> R {
+        future::lazy(|| Ok::<_, ()>(f())).wait().unwrap()
+    }
+
+    #[test]
+    fn random_stream_in_order() {
+        let queue = Queue::default();
+        let generator = Generator::with_executor(queue.clone());
+        let mut stream = generator.random_stream(4, Some(40));
+        assert_eq!(in_task(|| stream.poll().unwrap()), Async::NotReady);
+        let mut tasks = queue.0.lock().unwrap().drain(..).collect::<Vec<_>>();
+        assert_eq!(tasks.len(), MAX_PENDING_TASKS);
+
+        // Completing a later task does not yield a chunk before the first one.
+        in_task(|| tasks[1].poll().unwrap());
+        assert_eq!(in_task(|| stream.poll().unwrap()), Async::NotReady);
+        assert!(queue.0.lock().unwrap().is_empty());
+        in_task(|| tasks[0].poll().unwrap());
+        let mut stream = stream.wait();
+        assert_eq!(stream.next().unwrap().unwrap().len(), 4);
+        assert_eq!(stream.next().unwrap().unwrap().len(), 4);
+        assert_eq!(queue.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn random_integers() {
+        let generator = Generator::new(1);
+        let values = (0..4).map(|_| generator.random_u64().wait().unwrap()).collect::<Vec<_>>();
+        assert!(values.iter().any(|value| *value > u64::from(u32::MAX)));
+        let values = (0..4).map(|_| generator.random_u32().wait().unwrap()).collect::<Vec<_>>();
+        assert!(values.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn rejection_sampling() {
+        // 2^64 mod 3 is 1, so drawing below 3 rejects 0 and reduces 5 to 2,
+        // without generating any further data.
+        let draws = |buffer: &[u8]| Draws {
+            executor: Generator::with_executor(Shutdown).executor,
+            remaining: 1,
+            buffer: Bytes::from(buffer),
+            pending: None
+        };
+        let mut values = draws(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5]);
+        assert_eq!(values.poll_below(3).unwrap(), Async::Ready(2));
+        let mut values = draws(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(values.poll_below(3).unwrap_err().kind(), ErrorKind::ExecutorShutdown);
+        let mut values = draws(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(values.poll_below(4).unwrap(), Async::Ready(0));
+    }
+
+    #[test]
+    fn random_range() {
+        let generator = Generator::new(1);
+        let mut counts = [0; 3];
+        for _ in 0..300 {
+            let value = generator.random_range(10, 13).wait().unwrap();
+            counts[(value - 10) as usize] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 50));
+        assert_eq!(generator.random_range(7, 8).wait().unwrap(), 7);
+        let value = generator.random_range(1, u64::MAX).wait().unwrap();
+        assert!((1..u64::MAX).contains(&value));
+    }
+
+    #[test]
+    fn shuffle_and_sample() {
+        let generator = Generator::new(1);
+        let items = (0..100).collect::<Vec<u32>>();
+        let mut shuffled = generator.shuffle(items.clone()).wait().unwrap();
+        assert_ne!(shuffled, items);
+        shuffled.sort();
+        assert_eq!(shuffled, items);
+        assert!(generator.shuffle(Vec::<u32>::new()).wait().unwrap().is_empty());
+
+        let mut sample = generator.sample(items.clone(), 10).wait().unwrap();
+        assert_eq!(sample.len(), 10);
+        sample.sort();
+        sample.dedup();
+        assert_eq!(sample.len(), 10);
+        assert!(sample.iter().all(|item| *item < 100));
+        let mut sample = generator.sample(vec![1, 2, 3], 5).wait().unwrap();
+        sample.sort();
+        assert_eq!(sample, vec![1, 2, 3]);
+        assert!(generator.sample(items, 0).wait().unwrap().is_empty());
+    }
 }
\ No newline at end of file
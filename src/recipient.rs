@@ -0,0 +1,669 @@
+//! Encryption of streams to multiple recipients.
+//!
+//! Every stream is encrypted once with a freshly generated file key, using
+//! [authenticated encryption](../cipher/etm/index.html). The file key is then wrapped
+//! separately for each [`Recipient`](struct.Recipient.html), which may be a symmetric
+//! key-encryption key, a passphrase or a public key. Any single recipient can decrypt
+//! the stream using the matching [`Identity`](struct.Identity.html).
+//!
+//! The wrapped keys are stored as stanzas in a header that precedes the ciphertext,
+//! with lengths in big-endian byte order:
+//!
+//! | Field         | Size                           |
+//! |---------------|--------------------------------|
+//! | magic `CNMR`  | 4 bytes                        |
+//! | version       | 1 byte                         |
+//! | algorithm     | 1 byte                         |
+//! | iv            | 1 byte length                  |
+//! | stanza count  | 1 byte                         |
+//! | stanzas       | 1 byte type, 2 bytes length    |
+//! | header MAC    | 32 bytes                       |
+//!
+//! The header MAC is an HMAC-SHA-256 over the preceding fields, keyed with a key
+//! derived from the file key, so that stanzas cannot be added or modified.
+//!
+//! Passphrases are stretched using PBKDF2-HMAC-SHA-256, and public keys are used
+//! with an ephemeral key pair for [key agreement](../agreement/index.html). In both
+//! cases the file key is wrapped using AES key wrap with padding.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::mem;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, Future, Poll, Stream};
+use futures::future::{Join, JoinAll, join_all};
+use openssl;
+
+use super::{Error, ErrorKind};
+use super::agreement::{self, GenerateKey, PrivateKey, PublicKey};
+use super::cipher::{etm, wrap};
+use super::envelope::Rest;
+use super::hash::{self, Hmac, hkdf};
+use super::hybrid::{agreement_by_id, agreement_id, algorithm_by_id, algorithm_id};
+use super::random::{Generator, RandomBytes};
+use super::secret::{Redacted, zeroize};
+
+/// Recipient that a stream is encrypted to.
+#[derive(Clone)]
+pub struct Recipient(Credential<PublicKey>);
+
+impl Recipient {
+    /// Create a recipient holding a symmetric key-encryption key.
+    pub fn key(config: wrap::Config) -> Recipient {
+        Recipient(Credential::Key(config))
+    }
+
+    /// Create a recipient holding a passphrase, using the default number of
+    /// PBKDF2 iterations.
+    pub fn passphrase(passphrase: &[u8]) -> Recipient {
+        Recipient(Credential::Passphrase(passphrase.to_vec(), DEFAULT_ITERATIONS))
+    }
+
+    /// Create a recipient holding a passphrase, using the given number of
+    /// PBKDF2 iterations.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if `iterations` is zero or exceeds
+    /// the limit of 10,000,000 that is accepted when decrypting.
+    pub fn passphrase_with_iterations(passphrase: &[u8], iterations: u32) -> Result<Recipient, Error> {
+        if iterations == 0 || iterations > MAX_ITERATIONS {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        Ok(Recipient(Credential::Passphrase(passphrase.to_vec(), iterations)))
+    }
+
+    /// Create a recipient holding a private key, given its public key.
+    pub fn public_key(key: &PublicKey) -> Recipient {
+        Recipient(Credential::Agreement(key.clone()))
+    }
+}
+
+impl Debug for Recipient {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple("Recipient").field(&self.0).finish()
+    }
+}
+
+/// Credential used to decrypt a stream.
+#[derive(Clone)]
+pub struct Identity(Credential<PrivateKey>);
+
+impl Identity {
+    /// Create an identity from a symmetric key-encryption key.
+    pub fn key(config: wrap::Config) -> Identity {
+        Identity(Credential::Key(config))
+    }
+
+    /// Create an identity from a passphrase.
+    pub fn passphrase(passphrase: &[u8]) -> Identity {
+        Identity(Credential::Passphrase(passphrase.to_vec(), 0))
+    }
+
+    /// Create an identity from a private key.
+    pub fn private_key(key: &PrivateKey) -> Identity {
+        Identity(Credential::Agreement(key.clone()))
+    }
+
+    /// Try to unwrap the file key from a stanza.
+    ///
+    /// Returns `None` if the stanza is meant for a different kind of credential,
+    /// or if the credential does not match.
+    fn unwrap(&self, stanza: &Stanza) -> Result<Option<Vec<u8>>, Error> {
+        let result = match (&self.0, stanza.kind) {
+            (Credential::Key(config), STANZA_KEY) => config.unwrap(stanza.body),
+            (Credential::Passphrase(passphrase, _), STANZA_PASSPHRASE) => {
+                if stanza.body.len() < 4 + SALT_LEN {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                let (params, wrapped) = stanza.body.split_at(4 + SALT_LEN);
+                let iterations = params[..4].iter().fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+                if iterations == 0 || iterations > MAX_ITERATIONS {
+                    return Err(ErrorKind::Malformed.into());
+                }
+                passphrase_config(passphrase, &params[4..], iterations)?.unwrap(wrapped)
+            },
+            (Credential::Agreement(key), STANZA_PUBLIC_KEY) => {
+                let (agreement, ephemeral, wrapped) = decode_agreement(stanza.body)?;
+                if agreement != key.algorithm() {
+                    return Ok(None);
+                }
+                let peer = PublicKey::from_bytes(agreement, ephemeral)?;
+                let recipient_public = key.public_key()?.to_bytes()?;
+                agreement_config(key, &peer, ephemeral, &recipient_public)?.unwrap(wrapped)
+            },
+            _ => return Ok(None)
+        };
+        match result {
+            Ok(file_key) => Ok(Some(file_key)),
+            Err(ref err) if err.kind() == ErrorKind::AuthenticationFailed => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple("Identity").field(&self.0).finish()
+    }
+}
+
+/// Key material shared by recipients and identities, with the kind
+/// of asymmetric key as a parameter.
+#[derive(Clone)]
+enum Credential<K> {
+    Key(wrap::Config),
+    Passphrase(Vec<u8>, u32),
+    Agreement(K)
+}
+
+impl<K: Debug> Debug for Credential<K> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            Credential::Key(ref config) => f.debug_tuple("Key").field(config).finish(),
+            Credential::Passphrase(_, iterations) => f.debug_struct("Passphrase")
+                .field("passphrase", &Redacted)
+                .field("iterations", &iterations)
+                .finish(),
+            Credential::Agreement(ref key) => f.debug_tuple("Agreement").field(key).finish()
+        }
+    }
+}
+
+impl<K> Drop for Credential<K> {
+    fn drop(&mut self) {
+        if let Credential::Passphrase(ref mut passphrase, _) = *self {
+            zeroize(passphrase);
+        }
+    }
+}
+
+/// Stream adapter that encrypts the data from the underlying stream to one or more recipients.
+///
+/// The first item yielded is the header, followed by the ciphertext and the authentication tag.
+pub struct Encrypt<S> {
+    recipients: Vec<Recipient>,
+    algo: etm::Algorithm,
+    state: EncryptState<S>
+}
+
+enum EncryptState<S> {
+    Generating(Join<RandomBytes, JoinAll<Vec<GenerateKey>>>, S),
+    Streaming(etm::Encrypt<S>),
+    Done
+}
+
+impl<S: Debug> Debug for Encrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Encrypt")
+            .field("recipients", &self.recipients)
+            .field("algo", &self.algo)
+            .finish()
+    }
+}
+
+impl<S: Stream> Encrypt<S> {
+    /// Create an encrypting stream adapter.
+    ///
+    /// The file key, as well as the salts and ephemeral keys needed for
+    /// the recipients, are generated using `generator`.
+    ///
+    /// Fails with `ErrorKind::Unsupported` if there are no recipients or more than 255,
+    /// if there is more than one passphrase recipient, or if the algorithm or the key
+    /// agreement of a public key cannot be stored in the header.
+    pub fn new(generator: &Generator, recipients: Vec<Recipient>, algo: etm::Algorithm,
+               inner: S) -> Result<Self, Error> {
+        if recipients.is_empty() || recipients.len() > 0xff {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        algorithm_id(algo)?;
+        for recipient in &recipients {
+            if let Credential::Agreement(ref key) = recipient.0 {
+                agreement_id(key.algorithm())?;
+            }
+        }
+        let salts = recipients.iter().filter(|r| matches!(r.0, Credential::Passphrase(..))).count();
+        if salts > 1 {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let ephemeral_keys = recipients.iter().filter_map(|r| match r.0 {
+            Credential::Agreement(ref key) => Some(PrivateKey::generate(key.algorithm(), generator)),
+            _ => None
+        }).collect::<Vec<_>>();
+        let random_bytes = generator.random_bytes(FILE_KEY_LEN + IV_LEN + salts * SALT_LEN);
+        let generating = random_bytes.join(join_all(ephemeral_keys));
+        Ok(Encrypt { recipients, algo, state: EncryptState::Generating(generating, inner) })
+    }
+}
+
+impl<S: Stream> Stream for Encrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match mem::replace(&mut self.state, EncryptState::Done) {
+            EncryptState::Generating(mut generating, inner) => {
+                let (random, ephemeral_keys) = match generating.poll()? {
+                    Async::NotReady => {
+                        self.state = EncryptState::Generating(generating, inner);
+                        return Ok(Async::NotReady);
+                    },
+                    Async::Ready(generated) => generated
+                };
+                let mut config = etm::Config::new(self.algo);
+                let header = {
+                    let (file_key, rest) = random.split_at(FILE_KEY_LEN);
+                    let (iv, salts) = rest.split_at(IV_LEN);
+                    config.key_mut().copy_from_slice(file_key);
+                    config.iv_mut().copy_from_slice(iv);
+                    encode_header(&self.recipients, self.algo, file_key, iv, salts, &ephemeral_keys)
+                };
+                if let Ok(mut random) = random.try_mut() {
+                    zeroize(&mut random);
+                }
+                self.state = EncryptState::Streaming(etm::Encrypt::new(&config, inner)?);
+                Ok(Async::Ready(Some(header?)))
+            },
+            EncryptState::Streaming(mut encrypt) => {
+                let result = encrypt.poll();
+                self.state = EncryptState::Streaming(encrypt);
+                result
+            },
+            EncryptState::Done => Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// Stream adapter that decrypts data encrypted to multiple recipients.
+///
+/// The identities are tried against every stanza of the header, until one
+/// of them is able to unwrap the file key. Only the first passphrase stanza
+/// is tried, which bounds the work an untrusted header can cause. As with
+/// [`etm::Decrypt`](../cipher/etm/struct.Decrypt.html), the plaintext is only
+/// released once the authentication tag at the end of the stream has been verified.
+pub struct Decrypt<S> {
+    identities: Vec<Identity>,
+    state: DecryptState<S>
+}
+
+enum DecryptState<S> {
+    Header(S, BytesMut),
+    Streaming(Box<etm::Decrypt<Rest<S>>>),
+    Done
+}
+
+impl<S: Debug> Debug for Decrypt<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Decrypt")
+            .field("identities", &self.identities)
+            .finish()
+    }
+}
+
+impl<S: Stream> Decrypt<S> {
+    /// Create a decrypting stream adapter, given the identities that may be
+    /// able to unwrap the file key.
+    pub fn new(identities: Vec<Identity>, inner: S) -> Self {
+        Decrypt { identities, state: DecryptState::Header(inner, BytesMut::new()) }
+    }
+}
+
+impl<S: Stream> Stream for Decrypt<S>
+    where S::Item: AsRef<[u8]>,
+          S::Error: From<Error>
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, DecryptState::Done) {
+                DecryptState::Header(mut inner, mut buffer) => {
+                    if let Some((header, len)) = decode_header(&buffer)? {
+                        let config = self.unwrap_config(&header, &buffer[..len])?;
+                        let rest = Rest::new(buffer.split_off(len).freeze(), inner);
+                        self.state = DecryptState::Streaming(Box::new(etm::Decrypt::new(&config, rest)?));
+                        continue;
+                    }
+                    match inner.poll()? {
+                        Async::NotReady => {
+                            self.state = DecryptState::Header(inner, buffer);
+                            return Ok(Async::NotReady);
+                        },
+                        Async::Ready(None) => return Err(Error::from(ErrorKind::Malformed).into()),
+                        Async::Ready(Some(item)) => {
+                            buffer.extend_from_slice(item.as_ref());
+                            self.state = DecryptState::Header(inner, buffer);
+                        }
+                    }
+                },
+                DecryptState::Streaming(mut decrypt) => {
+                    let result = decrypt.poll();
+                    self.state = DecryptState::Streaming(decrypt);
+                    return result;
+                },
+                DecryptState::Done => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+impl<S> Decrypt<S> {
+    /// Unwrap the file key using the first matching identity, and verify the header MAC.
+    fn unwrap_config(&self, header: &Header, encoded: &[u8]) -> Result<etm::Config, Error> {
+        let mut file_key = None;
+        let mut passphrase_tried = false;
+        'stanzas: for stanza in &header.stanzas {
+            // Stretching a passphrase is slow by design, so only the first passphrase stanza is tried.
+            if stanza.kind == STANZA_PASSPHRASE {
+                if passphrase_tried {
+                    continue;
+                }
+                passphrase_tried = true;
+            }
+            for identity in &self.identities {
+                if let Some(key) = identity.unwrap(stanza)? {
+                    file_key = Some(key);
+                    break 'stanzas;
+                }
+            }
+        }
+        let mut file_key = file_key.ok_or(ErrorKind::UnknownKey)?;
+        if file_key.len() != FILE_KEY_LEN {
+            zeroize(&mut file_key);
+            return Err(ErrorKind::InvalidKeyLength.into());
+        }
+        let mut config = etm::Config::new(header.algo);
+        config.key_mut().copy_from_slice(&file_key);
+        config.iv_mut().copy_from_slice(header.iv);
+        let mac = header_mac(&file_key, &encoded[..encoded.len() - MAC_LEN]);
+        zeroize(&mut file_key);
+        let valid = openssl::memcmp::eq(mac?.as_ref(), &encoded[encoded.len() - MAC_LEN..]);
+        if !valid {
+            return Err(ErrorKind::AuthenticationFailed.into());
+        }
+        Ok(config)
+    }
+}
+
+/// Derive the key-encryption key for a passphrase.
+fn passphrase_config(passphrase: &[u8], salt: &[u8], iterations: u32) -> Result<wrap::Config, Error> {
+    let mut config = wrap::Config::new(wrap::Algorithm::Aes256Kwp);
+    openssl::pkcs5::pbkdf2_hmac(passphrase, salt, iterations as usize,
+                                hash::Algorithm::Sha256.into_message_digest(), config.key_mut())
+        .map_err(Error::from)?;
+    Ok(config)
+}
+
+/// Derive the key-encryption key for a public key from the shared secret of the
+/// ephemeral key pair and the recipient's key pair, salted with both public keys.
+fn agreement_config(key: &PrivateKey, peer: &PublicKey, ephemeral_public: &[u8],
+                    recipient_public: &[u8]) -> Result<wrap::Config, Error> {
+    let mut salt = Vec::with_capacity(ephemeral_public.len() + recipient_public.len());
+    salt.extend_from_slice(ephemeral_public);
+    salt.extend_from_slice(recipient_public);
+    let mut config = wrap::Config::new(wrap::Algorithm::Aes256Kwp);
+    key.derive(peer, hash::Algorithm::Sha256, &salt, AGREEMENT_INFO, config.key_mut())?;
+    Ok(config)
+}
+
+fn header_mac(file_key: &[u8], header: &[u8]) -> Result<hash::Digest, Error> {
+    let mut mac_key = [0u8; MAC_LEN];
+    hkdf(hash::Algorithm::Sha256, &[], file_key, HEADER_INFO, &mut mac_key)?;
+    let hmac = Hmac::new(hash::Algorithm::Sha256, &mac_key);
+    zeroize(&mut mac_key);
+    let mut hmac = hmac?;
+    hmac.update(header)?;
+    hmac.finish()
+}
+
+const MAGIC: &[u8] = b"CNMR";
+const VERSION: u8 = 1;
+const FILE_KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+const DEFAULT_ITERATIONS: u32 = 600_000;
+const MAX_ITERATIONS: u32 = 10_000_000;
+const HEADER_INFO: &[u8] = b"cryptonite recipient header";
+const AGREEMENT_INFO: &[u8] = b"cryptonite recipient key wrap";
+
+const STANZA_KEY: u8 = 0;
+const STANZA_PASSPHRASE: u8 = 1;
+const STANZA_PUBLIC_KEY: u8 = 2;
+
+struct Stanza<'a> {
+    kind: u8,
+    body: &'a [u8]
+}
+
+struct Header<'a> {
+    algo: etm::Algorithm,
+    iv: &'a [u8],
+    stanzas: Vec<Stanza<'a>>
+}
+
+/// Encode the header, wrapping the file key for every recipient.
+///
+/// The salts for passphrases and the ephemeral keys for public keys are
+/// consumed in the order of the recipients.
+fn encode_header(recipients: &[Recipient], algo: etm::Algorithm, file_key: &[u8], iv: &[u8],
+                 mut salts: &[u8], ephemeral_keys: &[PrivateKey]) -> Result<Bytes, Error> {
+    let mut ephemeral_keys = ephemeral_keys.iter();
+    let mut header = BytesMut::with_capacity(MAGIC.len() + 4 + IV_LEN + MAC_LEN);
+    header.put_slice(MAGIC);
+    header.put_u8(VERSION);
+    header.put_u8(algorithm_id(algo)?);
+    header.put_u8(iv.len() as u8);
+    header.put_slice(iv);
+    header.put_u8(recipients.len() as u8);
+    for recipient in recipients {
+        let (kind, body) = match recipient.0 {
+            Credential::Key(ref config) => (STANZA_KEY, config.wrap(file_key)?),
+            Credential::Passphrase(ref passphrase, iterations) => {
+                let (salt, rest) = salts.split_at(SALT_LEN);
+                salts = rest;
+                let mut body = vec![(iterations >> 24) as u8, (iterations >> 16) as u8,
+                                    (iterations >> 8) as u8, iterations as u8];
+                body.extend_from_slice(salt);
+                body.extend_from_slice(&passphrase_config(passphrase, salt, iterations)?.wrap(file_key)?);
+                (STANZA_PASSPHRASE, body)
+            },
+            Credential::Agreement(ref key) => {
+                let ephemeral = ephemeral_keys.next().ok_or(ErrorKind::Malformed)?;
+                let ephemeral_public = ephemeral.public_key()?.to_bytes()?;
+                let mut body = vec![agreement_id(key.algorithm())?, ephemeral_public.len() as u8];
+                body.extend_from_slice(&ephemeral_public);
+                let config = agreement_config(ephemeral, key, &ephemeral_public, &key.to_bytes()?)?;
+                body.extend_from_slice(&config.wrap(file_key)?);
+                (STANZA_PUBLIC_KEY, body)
+            }
+        };
+        header.reserve(3 + body.len());
+        header.put_u8(kind);
+        header.put_u8((body.len() >> 8) as u8);
+        header.put_u8(body.len() as u8);
+        header.put_slice(&body);
+    }
+    let mac = header_mac(file_key, &header)?;
+    header.reserve(MAC_LEN);
+    header.put_slice(mac.as_ref());
+    Ok(header.freeze())
+}
+
+/// Decode the body of a public key stanza into the key agreement algorithm,
+/// the ephemeral public key and the wrapped file key.
+fn decode_agreement(body: &[u8]) -> Result<(agreement::Algorithm, &[u8], &[u8]), Error> {
+    if body.len() < 2 || body.len() < 2 + body[1] as usize {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let agreement = agreement_by_id(body[0])?;
+    let (ephemeral, wrapped) = body[2..].split_at(body[1] as usize);
+    Ok((agreement, ephemeral, wrapped))
+}
+
+/// Decode a header from the start of `input`.
+///
+/// Returns `None` if more input is needed, or the header along with its length.
+fn decode_header<'a>(input: &'a [u8]) -> Result<Option<(Header<'a>, usize)>, Error> {
+    let mut pos = 0;
+    macro_rules! take {
+        ($len:expr) => {{
+            let len = $len;
+            if input.len() < pos + len {
+                return Ok(None);
+            }
+            pos += len;
+            &input[pos - len..pos]
+        }}
+    }
+    if take!(MAGIC.len()) != MAGIC || take!(1)[0] != VERSION {
+        return Err(ErrorKind::Malformed.into());
+    }
+    let algo = algorithm_by_id(take!(1)[0])?;
+    let iv_len = take!(1)[0] as usize;
+    let iv = take!(iv_len);
+    if iv_len != IV_LEN {
+        return Err(ErrorKind::InvalidIv.into());
+    }
+    let count = take!(1)[0] as usize;
+    let mut stanzas = Vec::with_capacity(count);
+    for _ in 0..count {
+        let kind = take!(1)[0];
+        let body_len = {
+            let len = take!(2);
+            (len[0] as usize) << 8 | len[1] as usize
+        };
+        stanzas.push(Stanza { kind, body: take!(body_len) });
+    }
+    let _mac = take!(MAC_LEN);
+    Ok(Some((Header { algo, iv, stanzas }, pos)))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate itertools;
+
+    use futures::{Future, Stream};
+    use futures::stream::iter_ok;
+    use self::itertools::Itertools;
+
+    use agreement::{self, PrivateKey};
+    use cipher::{etm, wrap};
+    use random::Generator;
+    use super::{Decrypt, Encrypt, Error, ErrorKind, Identity, Recipient};
+
+    fn key(byte: u8) -> wrap::Config {
+        let mut config = wrap::Config::new(wrap::Algorithm::Aes256Kwp);
+        for b in config.key_mut() {
+            *b = byte;
+        }
+        config
+    }
+
+    fn encrypt(recipients: Vec<Recipient>, chunks: Vec<&'static str>) -> Vec<u8> {
+        let generator = Generator::new(1);
+        let encrypt = Encrypt::new(&generator, recipients, etm::Algorithm::Aes256CtrHmacSha256,
+                                   iter_ok::<_, Error>(chunks)).unwrap();
+        encrypt.wait().collect::<Result<Vec<_>, _>>().unwrap().into_iter().concat().to_vec()
+    }
+
+    fn decrypt(identities: Vec<Identity>, ciphertext: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let chunks: Vec<Vec<u8>> = ciphertext.chunks(7).map(|chunk| chunk.to_vec()).collect();
+        let decrypt = Decrypt::new(identities, iter_ok::<_, Error>(chunks));
+        decrypt.wait().collect::<Result<Vec<_>, _>>().map(|chunks| chunks.into_iter().concat().to_vec())
+    }
+
+    #[test]
+    fn every_recipient_can_decrypt() {
+        let generator = Generator::new(1);
+        let x25519 = PrivateKey::generate(agreement::Algorithm::X25519, &generator).wait().unwrap();
+        let p256 = PrivateKey::generate(agreement::Algorithm::EcdhP256, &generator).wait().unwrap();
+        let ciphertext = encrypt(vec![
+            Recipient::key(key(1)),
+            Recipient::passphrase_with_iterations(b"correct horse battery staple", 1000).unwrap(),
+            Recipient::public_key(&x25519.public_key().unwrap()),
+            Recipient::public_key(&p256.public_key().unwrap()),
+        ], vec!["foo", "bar", "baz"]);
+
+        let identities = vec![
+            Identity::key(key(1)),
+            Identity::passphrase(b"correct horse battery staple"),
+            Identity::private_key(&x25519),
+            Identity::private_key(&p256),
+        ];
+        for identity in identities {
+            assert_eq!(decrypt(vec![identity], ciphertext.clone()).unwrap(), b"foobarbaz");
+        }
+    }
+
+    #[test]
+    fn default_iterations() {
+        let ciphertext = encrypt(vec![Recipient::passphrase(b"secret")], vec!["foo"]);
+        assert_eq!(decrypt(vec![Identity::passphrase(b"secret")], ciphertext).unwrap(), b"foo");
+    }
+
+    #[test]
+    fn invalid_iterations() {
+        for &iterations in &[0, 10_000_001] {
+            let err = Recipient::passphrase_with_iterations(b"secret", iterations).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn recipient_count() {
+        let generator = Generator::new(1);
+        for &count in &[0, 256] {
+            let recipients = vec![Recipient::key(key(1)); count];
+            let err = Encrypt::new(&generator, recipients, etm::Algorithm::Aes256CtrHmacSha256,
+                                   iter_ok::<Vec<u8>, Error>(vec![])).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unsupported);
+        }
+        let recipients = vec![Recipient::key(key(1)); 255];
+        let ciphertext = encrypt(recipients, vec!["foo"]);
+        assert_eq!(decrypt(vec![Identity::key(key(1))], ciphertext).unwrap(), b"foo");
+        let recipients = vec![Recipient::passphrase_with_iterations(b"secret", 1000).unwrap(); 2];
+        let err = Encrypt::new(&generator, recipients, etm::Algorithm::Aes256CtrHmacSha256,
+                               iter_ok::<Vec<u8>, Error>(vec![])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn unknown_identity() {
+        let generator = Generator::new(1);
+        let other = PrivateKey::generate(agreement::Algorithm::X25519, &generator).wait().unwrap();
+        let ciphertext = encrypt(vec![
+            Recipient::key(key(1)),
+            Recipient::passphrase_with_iterations(b"secret", 1000).unwrap(),
+        ], vec!["foo"]);
+        let identities = vec![
+            Identity::key(key(2)),
+            Identity::passphrase(b"wrong"),
+            Identity::private_key(&other),
+        ];
+        assert_eq!(decrypt(identities, ciphertext).unwrap_err().kind(), ErrorKind::UnknownKey);
+    }
+
+    #[test]
+    fn tampered_header() {
+        let ciphertext = encrypt(vec![Recipient::key(key(1)), Recipient::key(key(2))], vec!["foo"]);
+        // Remove the second stanza, which is not needed by the first identity.
+        let stanza_start = 4 + 1 + 1 + 1 + 16 + 1 + 3 + 40;
+        let mut tampered = ciphertext[..stanza_start].to_vec();
+        tampered[23] = 1;
+        tampered.extend_from_slice(&ciphertext[stanza_start + 3 + 40..]);
+        assert_eq!(decrypt(vec![Identity::key(key(1))], tampered).unwrap_err().kind(),
+                   ErrorKind::AuthenticationFailed);
+        assert_eq!(decrypt(vec![Identity::key(key(1))], ciphertext).unwrap(), b"foo");
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut ciphertext = encrypt(vec![Recipient::key(key(1))], vec![]);
+        let len = ciphertext.len() - etm::Algorithm::Aes256CtrHmacSha256.tag_len() - 1;
+        ciphertext.truncate(len);
+        assert_eq!(decrypt(vec![Identity::key(key(1))], ciphertext).unwrap_err().kind(), ErrorKind::Malformed);
+    }
+}
@@ -0,0 +1,221 @@
+//! Handling of secret data such as encryption keys.
+//!
+//! Key material held by the configuration types in this crate is wiped from memory
+//! when it is dropped. In addition, [`SecretBuf`](struct.SecretBuf.html) provides
+//! storage that is locked into physical memory, so that it is never written to swap.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+#[cfg(unix)]
+use std::io::Error as IoError;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+#[cfg(unix)]
+use std::slice;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+#[cfg(unix)]
+use libc;
+
+use super::Error;
+
+/// Overwrite a buffer with zeros, in a way that is not optimized away by the compiler.
+pub(crate) fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Placeholder for secret values in `Debug` output.
+pub(crate) struct Redacted;
+
+impl Debug for Redacted {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Heap-allocated buffer for secret data that is locked into physical memory.
+///
+/// On Unix, the buffer occupies its own memory pages, which are locked using `mlock`,
+/// excluded from core dumps where supported, and wiped when the buffer is dropped.
+/// Note that the number of pages a process may lock is usually limited by the
+/// operating system. On other platforms, the buffer is an ordinary heap allocation,
+/// which is wiped when dropped but not locked.
+pub struct SecretBuf {
+    storage: Storage
+}
+
+enum Storage {
+    #[cfg(unix)]
+    Locked(LockedPages),
+    Unlocked(Box<[u8]>)
+}
+
+impl SecretBuf {
+    /// Allocate a zeroed, locked buffer of the given length.
+    ///
+    /// Fails with `ErrorKind::Io` if the memory cannot be allocated or locked.
+    pub fn new(len: usize) -> Result<SecretBuf, Error> {
+        #[cfg(unix)]
+        let storage = Storage::Locked(LockedPages::new(len)?);
+        #[cfg(not(unix))]
+        let storage = Storage::Unlocked(vec![0u8; len].into_boxed_slice());
+        Ok(SecretBuf { storage })
+    }
+
+    /// Allocate a zeroed buffer of the given length that is not locked into memory.
+    fn unlocked(len: usize) -> SecretBuf {
+        SecretBuf { storage: Storage::Unlocked(vec![0u8; len].into_boxed_slice()) }
+    }
+
+    /// Check whether the buffer is locked into physical memory.
+    pub fn is_locked(&self) -> bool {
+        match self.storage {
+            #[cfg(unix)]
+            Storage::Locked(_) => true,
+            Storage::Unlocked(_) => false
+        }
+    }
+}
+
+impl Drop for SecretBuf {
+    fn drop(&mut self) {
+        // Locked pages are wiped entirely when they are unmapped.
+        if let Storage::Unlocked(ref mut buf) = self.storage {
+            zeroize(buf);
+        }
+    }
+}
+
+impl Clone for SecretBuf {
+    /// Copy the contents into a newly allocated buffer.
+    ///
+    /// If the new buffer cannot be locked, for example because the limit of locked
+    /// memory has been reached, the copy is held in an unlocked buffer instead.
+    fn clone(&self) -> SecretBuf {
+        let mut buf = SecretBuf::new(self.len()).unwrap_or_else(|_| SecretBuf::unlocked(self.len()));
+        buf.copy_from_slice(self);
+        buf
+    }
+}
+
+impl Deref for SecretBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.storage {
+            #[cfg(unix)]
+            Storage::Locked(ref pages) => unsafe { slice::from_raw_parts(pages.ptr, pages.len) },
+            Storage::Unlocked(ref buf) => buf
+        }
+    }
+}
+
+impl DerefMut for SecretBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self.storage {
+            #[cfg(unix)]
+            Storage::Locked(ref mut pages) => unsafe { slice::from_raw_parts_mut(pages.ptr, pages.len) },
+            Storage::Unlocked(ref mut buf) => buf
+        }
+    }
+}
+
+impl Debug for SecretBuf {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("SecretBuf")
+            .field("len", &self.len())
+            .field("data", &Redacted)
+            .finish()
+    }
+}
+
+/// Memory pages which are mapped for exclusive use and locked into physical memory.
+#[cfg(unix)]
+struct LockedPages {
+    ptr: *mut u8,
+    len: usize,
+    mapped_len: usize
+}
+
+#[cfg(unix)]
+unsafe impl Send for LockedPages {}
+#[cfg(unix)]
+unsafe impl Sync for LockedPages {}
+
+#[cfg(unix)]
+impl LockedPages {
+    fn new(len: usize) -> Result<LockedPages, Error> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mapped_len = len.div_ceil(page_size).max(1) * page_size;
+        unsafe {
+            let ptr = libc::mmap(ptr::null_mut(), mapped_len,
+                                 libc::PROT_READ | libc::PROT_WRITE,
+                                 libc::MAP_PRIVATE | libc::MAP_ANON, -1, 0);
+            if ptr == libc::MAP_FAILED {
+                return Err(IoError::last_os_error().into());
+            }
+            if libc::mlock(ptr, mapped_len) != 0 {
+                let err = IoError::last_os_error();
+                libc::munmap(ptr, mapped_len);
+                return Err(err.into());
+            }
+            dont_dump(ptr, mapped_len);
+            Ok(LockedPages { ptr: ptr as *mut u8, len, mapped_len })
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn dont_dump(ptr: *mut libc::c_void, len: usize) {
+    libc::madvise(ptr, len, libc::MADV_DONTDUMP);
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+unsafe fn dont_dump(_ptr: *mut libc::c_void, _len: usize) {}
+
+#[cfg(unix)]
+impl Drop for LockedPages {
+    fn drop(&mut self) {
+        unsafe {
+            zeroize(slice::from_raw_parts_mut(self.ptr, self.mapped_len));
+            libc::munlock(self.ptr as *const libc::c_void, self.mapped_len);
+            libc::munmap(self.ptr as *mut libc::c_void, self.mapped_len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SecretBuf, zeroize};
+
+    #[test]
+    fn zeroize_buffer() {
+        let mut buf = [0xffu8; 32];
+        zeroize(&mut buf);
+        assert_eq!(buf, [0u8; 32]);
+    }
+
+    #[test]
+    fn secret_buf() {
+        let mut buf = SecretBuf::new(32).unwrap();
+        assert_eq!(buf.is_locked(), cfg!(unix));
+        assert_eq!(&buf[..], &[0u8; 32][..]);
+        buf.copy_from_slice(&[1u8; 32]);
+        let copy = buf.clone();
+        drop(buf);
+        assert_eq!(&copy[..], &[1u8; 32][..]);
+        assert_eq!(format!("{:?}", copy), "SecretBuf { len: 32, data: <redacted> }");
+    }
+
+    #[test]
+    fn unlocked_secret_buf() {
+        let mut buf = SecretBuf::unlocked(16);
+        assert!(!buf.is_locked());
+        buf.copy_from_slice(&[2u8; 16]);
+        let copy = buf.clone();
+        assert_eq!(&copy[..], &[2u8; 16][..]);
+        assert_eq!(format!("{:?}", buf), "SecretBuf { len: 16, data: <redacted> }");
+    }
+}